@@ -0,0 +1,4 @@
+-- Tables of the customers and their cards, as created before the migrations:
+-- an existing database already matches them and is brought to the models by the next ones
+CREATE TABLE IF NOT EXISTS "customer" ("uuid" UUID, "name" VARCHAR(255), "balance" INTEGER);
+CREATE TABLE IF NOT EXISTS "card" ("uuid" UUID, "pan" VARCHAR(255), "customer_uuid" UUID, "csv" VARCHAR(255));
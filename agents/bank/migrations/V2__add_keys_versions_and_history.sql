@@ -0,0 +1,20 @@
+-- Keys and constraints of the customers and cards, their versions, soft deletes and history
+UPDATE "customer" SET "balance" = 0 WHERE "balance" IS NULL;
+ALTER TABLE "customer" ADD PRIMARY KEY ("uuid");
+ALTER TABLE "customer" ALTER COLUMN "name" SET NOT NULL;
+ALTER TABLE "customer" ALTER COLUMN "balance" SET DEFAULT 0;
+ALTER TABLE "customer" ALTER COLUMN "balance" SET NOT NULL;
+-- The existing customers start at the first version
+ALTER TABLE "customer" ADD COLUMN "version" INTEGER;
+UPDATE "customer" SET "version" = 0;
+ALTER TABLE "customer" ADD COLUMN "deleted_at" BIGINT;
+CREATE TABLE "customer_history" ("uuid" UUID, "revision" INTEGER, "operation" VARCHAR(255), "before_image" TEXT, "after_image" TEXT, "actor" VARCHAR(255), "changed_at" BIGINT, PRIMARY KEY ("uuid", "revision"));
+ALTER TABLE "card" ADD PRIMARY KEY ("uuid");
+ALTER TABLE "card" ALTER COLUMN "pan" SET NOT NULL;
+ALTER TABLE "card" ADD UNIQUE ("pan");
+ALTER TABLE "card" ALTER COLUMN "customer_uuid" SET NOT NULL;
+ALTER TABLE "card" ADD FOREIGN KEY ("customer_uuid") REFERENCES "customer" ("uuid");
+ALTER TABLE "card" ALTER COLUMN "csv" SET NOT NULL;
+ALTER TABLE "card" ADD COLUMN "deleted_at" BIGINT;
+CREATE INDEX "card_customer_uuid_idx" ON "card" ("customer_uuid");
+CREATE TABLE "card_history" ("uuid" UUID, "revision" INTEGER, "operation" VARCHAR(255), "before_image" TEXT, "after_image" TEXT, "actor" VARCHAR(255), "changed_at" BIGINT, PRIMARY KEY ("uuid", "revision"));
@@ -0,0 +1,5 @@
+-- The existing cards expire three years after the migration
+ALTER TABLE "card" ADD COLUMN "expiry_date" DATE;
+UPDATE "card" SET "expiry_date" = CURRENT_DATE + INTERVAL '3 years';
+ALTER TABLE "card" ALTER COLUMN "expiry_date" SET NOT NULL;
+ALTER TABLE "card" ADD COLUMN "status" VARCHAR(255) NOT NULL DEFAULT 'Inactive';
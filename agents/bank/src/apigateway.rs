@@ -1,9 +1,12 @@
 use crate::usecase::BankRepository;
 use lambda_http::{
     http::{Method, StatusCode},
+    request::RequestContext,
     IntoResponse, Request, RequestExt, RequestPayloadExt, Response,
 };
 use serde_json::json;
+use shared::error::{ErrorKind, InterfaceError};
+use shared::usecase::history::as_actor;
 use tracing::{error, info, instrument, warn};
 
 type E = Box<dyn std::error::Error + Send + Sync + 'static>;
@@ -16,10 +19,7 @@ pub async fn get_balance(
 ) -> Result<impl IntoResponse, E> {
     // Ensure GET method
     if event.method() != Method::GET {
-        return Ok(response(
-            StatusCode::METHOD_NOT_ALLOWED,
-            json!({"message": "Method Not Allowed"}).to_string(),
-        ));
+        return Ok(method_not_allowed(event.method()));
     }
 
     // Retrieve customer ID from event
@@ -29,10 +29,9 @@ pub async fn get_balance(
     let uuid = match path_parameters.first("uuid") {
         Some(uuid) => uuid,
         None => {
-            warn!("Missing 'uuid' parameter in path");
-            return Ok(response(
-                StatusCode::BAD_REQUEST,
-                json!({ "message": "Missing 'uuid' parameter in path" }).to_string(),
+            return Ok(error_response(
+                "Failed to read the customer",
+                &InterfaceError::Validation("Missing 'uuid' parameter in path".to_string()),
             ));
         }
     };
@@ -41,10 +40,9 @@ pub async fn get_balance(
     let uuid = match uuid::Uuid::parse_str(uuid) {
         Ok(parsed_uuid) => parsed_uuid,
         Err(e) => {
-            warn!("Invalid UUID format: {}", e);
-            return Ok(response(
-                StatusCode::BAD_REQUEST,
-                json!({"message": "Invalid UUID format"}).to_string(),
+            return Ok(error_response(
+                "Failed to read the customer",
+                &InterfaceError::Validation(format!("Invalid UUID format: {e}")),
             ));
         }
     };
@@ -59,21 +57,12 @@ pub async fn get_balance(
         // Found
         Ok(Some(customer)) => response(StatusCode::OK, json!(customer).to_string()),
         // Doesn't exist
-        Ok(None) => {
-            warn!("Customer not found: {}", uuid);
-            response(
-                StatusCode::NOT_FOUND,
-                json!({"message": "Customer not found"}).to_string(),
-            )
-        }
+        Ok(None) => error_response(
+            "Customer not found",
+            &InterfaceError::MissingItem(format!("Customer {uuid}")),
+        ),
         // Error
-        Err(err) => {
-            error!("Error fetching the customer: {}", err);
-            response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                json!({"message": "Error fetching the customer"}).to_string(),
-            )
-        }
+        Err(err) => error_response("Error fetching the customer", &err),
     })
 }
 
@@ -85,39 +74,40 @@ pub async fn create_account(
 ) -> Result<impl IntoResponse, E> {
     // Ensure POST method
     if event.method() != Method::POST {
-        return Ok(response(
-            StatusCode::METHOD_NOT_ALLOWED,
-            json!({"message": "Method Not Allowed"}).to_string(),
-        ));
+        return Ok(method_not_allowed(event.method()));
     }
     // Read customer from request
     let customer: crate::models::customer::Customer = match event.payload() {
         Ok(Some(customer)) => customer,
         Ok(None) => {
-            warn!("Missing account details in request body");
-            return Ok(response(
-                StatusCode::BAD_REQUEST,
-                json!({"message": "Missing account details in request body"}).to_string(),
+            return Ok(error_response(
+                "Failed to read the account",
+                &InterfaceError::Validation("Missing account details in request body".to_string()),
             ));
         }
         Err(err) => {
-            warn!("Failed to parse account details from request body: {}", err);
-            return Ok(response(
-                StatusCode::BAD_REQUEST,
-                json!({"message": "Failed to parse account detials from request body"}).to_string(),
+            return Ok(error_response(
+                "Failed to read the account",
+                &InterfaceError::Validation(format!(
+                    "Failed to parse account details from request body: {err}"
+                )),
             ));
         }
     };
     info!("Parsed customer: {:?}", customer);
 
-    // Create customer
-    let resp = crate::domain::create_account(repo, &customer).await;
+    // Create customer, as the caller when it is authenticated
+    let created = crate::domain::create_account(repo, &customer);
+    let resp = match caller(&event) {
+        Some(actor) => as_actor(actor, created).await,
+        None => created.await,
+    };
 
     // Return response
     Ok(match resp {
         // Found
         Ok(_) => {
-            info!("Created customer {:?}", customer.name);
+            info!("Created customer {:?}", customer.name());
             response(
                 StatusCode::CREATED,
                 json!({"message": "Account created"}).to_string(),
@@ -125,16 +115,37 @@ pub async fn create_account(
         }
 
         // Error
-        Err(err) => {
-            error!("Failed to crate an account {}: {}", customer.name, err);
-            response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                json!({"message": "Failed to create account"}).to_string(),
-            )
-        }
+        Err(err) => error_response(
+            &format!("Failed to create account {}", customer.name()),
+            &err,
+        ),
     })
 }
 
+/// Identity of the authenticated caller of a request, recorded as the actor of its changes:
+/// the subject of a JWT, or the ARN of an IAM user
+fn caller(event: &Request) -> Option<String> {
+    let authorizer = match event.request_context_ref()? {
+        RequestContext::ApiGatewayV1(context) => {
+            if let Some(user_arn) = &context.identity.user_arn {
+                return Some(user_arn.clone());
+            }
+            &context.authorizer
+        }
+        RequestContext::ApiGatewayV2(context) => context.authorizer.as_ref()?,
+        _ => return None,
+    };
+    let subject = authorizer
+        .jwt
+        .as_ref()
+        .and_then(|jwt| jwt.claims.get("sub"));
+    let user_arn = authorizer
+        .iam
+        .as_ref()
+        .and_then(|iam| iam.user_arn.as_ref());
+    subject.or(user_arn).cloned()
+}
+
 /// HTTP Response with a JSON payload
 fn response(status_code: StatusCode, body: String) -> Response<String> {
     Response::builder()
@@ -143,3 +154,164 @@ fn response(status_code: StatusCode, body: String) -> Response<String> {
         .body(body)
         .unwrap()
 }
+
+/// HTTP Response describing an error as problem details
+fn error_response(context: &str, err: &InterfaceError) -> Response<String> {
+    match err.kind() {
+        ErrorKind::Internal | ErrorKind::Transient => error!("{}: {}", context, err),
+        _ => warn!("{}: {}", context, err),
+    }
+    problem_response(
+        StatusCode::from_u16(err.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        err.problem(),
+    )
+}
+
+/// HTTP Response refusing the method of a request, as problem details
+fn method_not_allowed(method: &Method) -> Response<String> {
+    warn!("Method not allowed: {}", method);
+    let status = StatusCode::METHOD_NOT_ALLOWED;
+    problem_response(
+        status,
+        json!({
+            "type": "/problems/method-not-allowed",
+            "title": "Method not allowed",
+            "status": status.as_u16(),
+            "detail": format!("{method} is not allowed"),
+        }),
+    )
+}
+
+/// HTTP Response with a problem details (RFC 9457) payload
+fn problem_response(status_code: StatusCode, problem: serde_json::Value) -> Response<String> {
+    Response::builder()
+        .status(status_code)
+        .header("Content-Type", "application/problem+json")
+        .body(problem.to_string())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::customer::Customer;
+    use crate::usecase::memory::BankMemoryRepository;
+    use lambda_http::aws_lambda_events::apigw::{
+        ApiGatewayRequestAuthorizer, ApiGatewayRequestAuthorizerJwtDescription,
+        ApiGatewayV2httpRequestContext,
+    };
+    use lambda_http::{Body, RequestExt};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    fn get_request(uuid: &str) -> Request {
+        let mut request = Request::new(Body::Empty);
+        *request.method_mut() = Method::GET;
+        request.with_path_parameters(HashMap::from([("uuid".to_string(), uuid.to_string())]))
+    }
+
+    #[tokio::test]
+    async fn test_get_balance_not_found() -> Result<(), E> {
+        // GIVEN an empty repository
+        let repo = BankMemoryRepository::new();
+
+        // WHEN we get the balance of an unknown customer, or of an invalid UUID
+        let missing = get_balance(&repo, get_request(&uuid::Uuid::new_v4().to_string()))
+            .await?
+            .into_response()
+            .await;
+        let invalid = get_balance(&repo, get_request("not-a-uuid"))
+            .await?
+            .into_response()
+            .await;
+
+        // THEN the customer is not found and the UUID is rejected, as problem details
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            missing.headers()["Content-Type"],
+            "application/problem+json"
+        );
+        let body: serde_json::Value = serde_json::from_slice(missing.body()).unwrap();
+        assert_eq!(body["type"], "/problems/not-found");
+        assert_eq!(body["status"], 404);
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            invalid.headers()["Content-Type"],
+            "application/problem+json"
+        );
+        let body: serde_json::Value = serde_json::from_slice(invalid.body()).unwrap();
+        assert_eq!(body["type"], "/problems/validation");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() -> Result<(), E> {
+        // GIVEN an empty repository
+        let repo = BankMemoryRepository::new();
+
+        // WHEN we get the balance with a POST request
+        let mut request = get_request(&uuid::Uuid::new_v4().to_string());
+        *request.method_mut() = Method::POST;
+        let response = get_balance(&repo, request).await?.into_response().await;
+
+        // THEN the method is refused, as problem details
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "application/problem+json"
+        );
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["status"], 405);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_account_as_caller() -> Result<(), E> {
+        // GIVEN an empty repository, and a request authenticated by a JWT
+        let repo = BankMemoryRepository::new();
+        let customer = Customer::new("customer-100".to_string(), 100);
+        let authorizer = ApiGatewayRequestAuthorizer {
+            jwt: Some(ApiGatewayRequestAuthorizerJwtDescription {
+                claims: HashMap::from([("sub".to_string(), "teller-7".to_string())]),
+                scopes: None,
+            }),
+            ..Default::default()
+        };
+        let context = ApiGatewayV2httpRequestContext {
+            authorizer: Some(authorizer),
+            ..Default::default()
+        };
+        let mut request = Request::new(Body::from(json!(customer).to_string()))
+            .with_request_context(RequestContext::ApiGatewayV2(context));
+        *request.method_mut() = Method::POST;
+        request
+            .headers_mut()
+            .insert("Content-Type", "application/json".parse().unwrap());
+
+        // WHEN the account is created
+        let response = create_account(&repo, request).await?.into_response().await;
+
+        // THEN its creation is recorded as made by the caller
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let history = repo.customer_history().history(&customer.uuid()).await?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].actor, "teller-7");
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_response() {
+        let response = error_response(
+            "Failed",
+            &InterfaceError::Conflict("Item already exists".to_string()),
+        );
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "application/problem+json"
+        );
+        let body: serde_json::Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["status"], 409);
+    }
+}
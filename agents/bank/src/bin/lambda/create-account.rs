@@ -13,7 +13,7 @@ async fn main() -> Result<(), E> {
     let repo = get_bank_repository().await;
 
     lambda_http::run(service_fn(|event: Request| {
-        bank::apigateway::create_account(&repo, event)
+        bank::apigateway::create_account(&*repo, event)
     }))
     .await?;
     Ok(())
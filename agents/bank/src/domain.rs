@@ -1,5 +1,8 @@
-use crate::{models::customer::Customer, usecase::BankRepository};
-use shared::error::InterfaceError;
+use crate::{
+    models::{card::Card, customer::Customer},
+    usecase::BankRepository,
+};
+use shared::{error::InterfaceError, ports::secondary::TypedList};
 use uuid::Uuid;
 
 /// Get the current balance of a customer
@@ -7,11 +10,7 @@ pub async fn get_balance(
     repo: &dyn BankRepository,
     uuid: Uuid,
 ) -> Result<Option<Customer>, InterfaceError> {
-    let customer = repo.customers().get(&uuid).await?;
-    match customer {
-        Some(customer) => Ok(Some(customer)),
-        None => Err(InterfaceError::MissingItem(uuid.to_string())),
-    }
+    repo.customers().get(&uuid).await
 }
 
 /// Create a customer account
@@ -22,6 +21,29 @@ pub async fn create_account(
     repo.customers().create(customer).await
 }
 
+/// List the cards of a customer
+pub async fn get_customer_cards(
+    repo: &dyn BankRepository,
+    customer_uuid: Uuid,
+) -> Result<Vec<Card>, InterfaceError> {
+    repo.cards()
+        .list_matching(&Card::columns().customer_uuid.eq(customer_uuid))
+        .await
+}
+
+/// Get a customer with its cards, loaded together
+pub async fn get_customer_with_cards(
+    repo: &dyn BankRepository,
+    uuid: Uuid,
+) -> Result<Option<(Customer, Vec<Card>)>, InterfaceError> {
+    let filter = Customer::columns().uuid.eq(uuid).into();
+    let mut customers = repo
+        .customers_with_cards()
+        .list_with_children(&Customer::cards(), &filter)
+        .await?;
+    Ok(customers.pop())
+}
+
 /// Order a new card for a customer
 pub async fn order_card(_repo: &dyn BankRepository, _uuid: Uuid) -> Result<(), InterfaceError> {
     // Need to establish a connection with a network first
@@ -29,31 +51,281 @@ pub async fn order_card(_repo: &dyn BankRepository, _uuid: Uuid) -> Result<(), I
 }
 
 /// Authorize a transaction for a customer
-/// Note: this doesn't actually perform a transaction
+/// Note: the balance is debited right away, skipping clearing and settlement
 pub async fn authorize_transaction(
     repo: &dyn BankRepository,
     uuid: Uuid,
     amount: i32,
 ) -> Result<(), InterfaceError> {
     if amount <= 0 {
-        return Err(InterfaceError::Other(
+        return Err(InterfaceError::Validation(
             "amount of transaction needs to be positive".to_string(),
         ));
     }
 
-    let customer = repo.customers().get(&uuid).await?;
-    if customer.is_none() {
-        return Err(InterfaceError::MissingItem(uuid.to_string()));
-    }
+    let transaction = repo.begin().await?;
+    let outcome = debit(&*transaction, uuid, amount).await;
+    transaction.finish(outcome).await
+}
 
-    // TODO: reserve the "authorized money"
-    // and check amount > (balance - reserved)
-    if amount > customer.unwrap().balance {
-        return Err(InterfaceError::Other(
-            "transaction refused: not enough balance".to_string(),
-        ));
+/// Debit the balance of a customer
+async fn debit<R>(repo: &R, uuid: Uuid, amount: i32) -> Result<(), InterfaceError>
+where
+    R: BankRepository + ?Sized,
+{
+    let mut customer = match repo.customers().get(&uuid).await? {
+        Some(customer) => customer,
+        None => return Err(InterfaceError::MissingItem(uuid.to_string())),
+    };
+
+    if amount > customer.balance() {
+        return Err(InterfaceError::InsufficientFunds(format!(
+            "transaction of {} refused, the balance is {}",
+            amount,
+            customer.balance()
+        )));
     }
+    customer.debit(amount);
+    repo.customers().update(&customer).await
 
     // TODO: if card is not yet activated, activate it
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::card::get_random_card;
+    use crate::models::customer::get_random_customer;
+    use crate::usecase::file::BankFileRepository;
+    use crate::usecase::memory::BankMemoryRepository;
+    use crate::usecase::sqlite::BankSqliteRepository;
+    use pretty_assertions::assert_eq;
+    use shared::settings::{FileSettings, SqliteSettings};
+    use shared::usecase::history::as_actor;
+
+    #[tokio::test]
+    async fn test_authorize_transaction() -> Result<(), InterfaceError> {
+        // GIVEN a customer with a balance of 100
+        let repo = BankMemoryRepository::new();
+        let customer = Customer::new("customer-100".to_string(), 100);
+        create_account(&repo, &customer).await?;
+
+        // WHEN we authorize a transaction of 30 and one of 80
+        authorize_transaction(&repo, customer.uuid(), 30).await?;
+        let refused = authorize_transaction(&repo, customer.uuid(), 80).await;
+
+        // THEN the first one is debited and the second one is refused
+        assert!(matches!(refused, Err(InterfaceError::InsufficientFunds(_))));
+        let customer = get_balance(&repo, customer.uuid()).await?.unwrap();
+        assert_eq!(customer.balance(), 70);
+
+        // AND unknown customers have no balance
+        assert!(get_balance(&repo, Uuid::new_v4()).await?.is_none());
+
+        // AND amounts must be positive
+        let invalid = authorize_transaction(&repo, customer.uuid(), 0).await;
+        assert!(matches!(invalid, Err(InterfaceError::Validation(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_authorize_transaction_actor() -> Result<(), InterfaceError> {
+        // GIVEN a customer with a balance of 100, created by a teller
+        let repo = BankMemoryRepository::new();
+        let customer = Customer::new("customer-100".to_string(), 100);
+        as_actor("teller-7", create_account(&repo, &customer)).await?;
+
+        // WHEN a transaction is authorized for the card network
+        as_actor(
+            "network-1",
+            authorize_transaction(&repo, customer.uuid(), 30),
+        )
+        .await?;
+
+        // THEN each change is recorded as made by its actor
+        let actors: Vec<_> = repo
+            .customer_history()
+            .history(&customer.uuid())
+            .await?
+            .into_iter()
+            .map(|change| change.actor)
+            .collect();
+        assert_eq!(actors, vec!["teller-7", "network-1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_authorize_transaction_sqlite() -> Result<(), InterfaceError> {
+        // GIVEN a customer with a balance of 100 in a SQLite database
+        let settings = SqliteSettings {
+            path: ":memory:".to_string(),
+        };
+        let repo = BankSqliteRepository::new(&settings).await?;
+        let customer = Customer::new("customer-100".to_string(), 100);
+        create_account(&repo, &customer).await?;
+
+        // WHEN we authorize a transaction of 30 and one of 80
+        authorize_transaction(&repo, customer.uuid(), 30).await?;
+        let refused = authorize_transaction(&repo, customer.uuid(), 80).await;
+
+        // THEN the first one is debited and the second one is refused
+        assert!(refused.is_err());
+        let customer = get_balance(&repo, customer.uuid()).await?.unwrap();
+        assert_eq!(customer.balance(), 70);
+        assert_eq!(customer.version(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_authorize_transaction_file() -> Result<(), InterfaceError> {
+        // GIVEN a customer with a balance of 100 in local files
+        let directory = std::env::temp_dir().join(format!("bank-{}", Uuid::new_v4()));
+        let settings = FileSettings {
+            directory: directory.to_string_lossy().to_string(),
+        };
+        let repo = BankFileRepository::new(&settings)?;
+        let customer = Customer::new("customer-100".to_string(), 100);
+        create_account(&repo, &customer).await?;
+
+        // WHEN we authorize a transaction of 30 and one of 80
+        authorize_transaction(&repo, customer.uuid(), 30).await?;
+        let refused = authorize_transaction(&repo, customer.uuid(), 80).await;
+        drop(repo);
+
+        // THEN the debit is kept once the files are opened again
+        let repo = BankFileRepository::new(&settings)?;
+        let stored = get_balance(&repo, customer.uuid()).await?.unwrap();
+        let _ = std::fs::remove_dir_all(&directory);
+        assert!(matches!(refused, Err(InterfaceError::InsufficientFunds(_))));
+        assert_eq!(stored.balance(), 70);
+        assert_eq!(stored.version(), 1);
+        Ok(())
+    }
+
+    /// Authorize a transaction of 30 while one of 80, begun earlier, is refused
+    async fn authorize_concurrently(
+        repo: &dyn BankRepository,
+        uuid: Uuid,
+    ) -> Result<(), InterfaceError> {
+        let earlier = repo.begin().await?;
+        authorize_transaction(repo, uuid, 30).await?;
+        let refused = debit(&*earlier, uuid, 80).await;
+        assert!(matches!(refused, Err(InterfaceError::InsufficientFunds(_))));
+        assert!(earlier.finish(refused).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_authorize_transaction_concurrent() -> Result<(), InterfaceError> {
+        // GIVEN a customer with a balance of 100
+        let repo = BankMemoryRepository::new();
+        let customer = Customer::new("customer-100".to_string(), 100);
+        create_account(&repo, &customer).await?;
+
+        // WHEN a transaction is authorized while an earlier one is refused
+        authorize_concurrently(&repo, customer.uuid()).await?;
+
+        // THEN the rollback of the refused one keeps the debit and its history
+        let stored = get_balance(&repo, customer.uuid()).await?.unwrap();
+        assert_eq!(stored.balance(), 70);
+        let history = repo.customer_history().history(&customer.uuid()).await?;
+        assert_eq!(history.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_authorize_transaction_concurrent_file() -> Result<(), InterfaceError> {
+        // GIVEN a customer with a balance of 100 in local files
+        let directory = std::env::temp_dir().join(format!("bank-{}", Uuid::new_v4()));
+        let settings = FileSettings {
+            directory: directory.to_string_lossy().to_string(),
+        };
+        let repo = BankFileRepository::new(&settings)?;
+        let customer = Customer::new("customer-100".to_string(), 100);
+        create_account(&repo, &customer).await?;
+
+        // WHEN a transaction is authorized while an earlier one is refused
+        authorize_concurrently(&repo, customer.uuid()).await?;
+        drop(repo);
+
+        // THEN the debit and its history are kept once the files are opened again
+        let repo = BankFileRepository::new(&settings)?;
+        let stored = get_balance(&repo, customer.uuid()).await?.unwrap();
+        let history = repo.customer_history().history(&customer.uuid()).await?;
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(stored.balance(), 70);
+        assert_eq!(history.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_customer_with_cards() -> Result<(), InterfaceError> {
+        // GIVEN a customer with two cards, and a customer without card
+        let repo = BankMemoryRepository::new();
+        let (customer, other) = (get_random_customer(), get_random_customer());
+        create_account(&repo, &customer).await?;
+        create_account(&repo, &other).await?;
+        let cards = [get_random_card(&customer), get_random_card(&customer)];
+        repo.cards().create_many(&cards).await?;
+
+        // WHEN we get the customers with their cards
+        let (found, found_cards) = get_customer_with_cards(&repo, customer.uuid())
+            .await?
+            .unwrap();
+        let (_, other_cards) = get_customer_with_cards(&repo, other.uuid()).await?.unwrap();
+
+        // THEN each customer comes with its own cards
+        assert_eq!(found.uuid(), customer.uuid());
+        let mut expected: Vec<Uuid> = cards.iter().map(Card::uuid).collect();
+        expected.sort();
+        let uuids: Vec<Uuid> = found_cards.iter().map(Card::uuid).collect();
+        assert_eq!(uuids, expected);
+        assert!(other_cards.is_empty());
+
+        // AND unknown customers are not found
+        assert!(get_customer_with_cards(&repo, Uuid::new_v4())
+            .await?
+            .is_none());
+
+        // AND cards of unknown customers are refused
+        let stray = get_random_card(&get_random_customer());
+        let refused = repo.cards().create(&stray).await;
+        assert!(matches!(refused, Err(InterfaceError::Validation(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_customer_with_cards_sqlite() -> Result<(), InterfaceError> {
+        // GIVEN a customer with a card in a SQLite database
+        let settings = SqliteSettings {
+            path: ":memory:".to_string(),
+        };
+        let repo = BankSqliteRepository::new(&settings).await?;
+        let customer = get_random_customer();
+        create_account(&repo, &customer).await?;
+        let card = get_random_card(&customer);
+        repo.cards().create(&card).await?;
+
+        // WHEN we get the customer with its cards
+        let (found, cards) = get_customer_with_cards(&repo, customer.uuid())
+            .await?
+            .unwrap();
+
+        // THEN the card is loaded with the customer
+        assert_eq!(found.uuid(), customer.uuid());
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].customer_uuid(), customer.uuid());
+
+        // AND the card belongs to the customer
+        let cards = repo
+            .cards_with_customer()
+            .list_with_parent(
+                &Card::customer(),
+                &Card::columns().uuid.eq(card.uuid()).into(),
+            )
+            .await?;
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].1.uuid(), customer.uuid());
+        Ok(())
+    }
 }
@@ -1,33 +1,65 @@
 //! Card domain entity
 
+use chrono::{Months, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+use shared::error::InterfaceError;
+use shared::ports::secondary::HasKey;
+use shared::query::Column;
+use shared::relation::BelongsTo;
 use shared::sql_macros::struct_to_sql;
-use shared::usecase::rds::GetFieldsAsParams;
+use shared::usecase::rds::{FromRow, GetFieldsAsParams, RdsRow};
 use shared::QuerySet;
 use uuid::Uuid;
 
+use super::customer::Customer;
+
 #[cfg(test)]
 use rand::{thread_rng, Rng};
 
 /// Card
-#[derive(Deserialize, Serialize)]
-#[struct_to_sql]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[struct_to_sql(soft_delete, audit)]
 pub struct Card {
     #[serde(default = "uuid::Uuid::new_v4")]
     uuid: Uuid,
     #[serde(default)]
+    #[sql(unique, not_null)]
     pan: String,
     #[serde(default)]
+    #[sql(not_null, belongs_to(customer = "Customer"))]
     customer_uuid: Uuid,
     #[serde(default)]
+    #[sql(not_null)]
     csv: String,
+    #[serde(default = "default_expiry_date")]
+    #[sql(not_null)]
+    expiry_date: NaiveDate,
+    #[serde(default)]
+    #[sql(enum, not_null, default = "'Inactive'")]
+    status: CardStatus,
     //TODO
     // #[serde(default)]
     // created_at:
-    // expiry date
-    // status: activated / not
 }
 
+/// Months during which a new card is valid
+const VALIDITY_MONTHS: u32 = 36;
+
+/// Expiry date of a card issued today
+fn default_expiry_date() -> NaiveDate {
+    Utc::now().date_naive() + Months::new(VALIDITY_MONTHS)
+}
+
+/// Status of a card, activated by its first payment
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum CardStatus {
+    #[default]
+    Inactive,
+    Active,
+}
+
+shared::comparable_enum!(CardStatus);
+
 pub type Pan = String;
 
 /// Read the Major Industry Identifier associated with the PAN
@@ -45,6 +77,30 @@ pub fn get_pan_bin(pan: &Pan) -> &str {
     &pan[0..8]
 }
 
+impl Card {
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Customer owning the card
+    pub fn customer_uuid(&self) -> Uuid {
+        self.customer_uuid
+    }
+}
+
+#[cfg(test)]
+/// Generate an inactive card of a customer, with a random pan
+pub fn get_random_card(customer: &Customer) -> Card {
+    Card {
+        uuid: Uuid::new_v4(),
+        pan: generate_random_pan(),
+        customer_uuid: customer.uuid(),
+        csv: format!("{:03}", thread_rng().gen_range(0..1000)),
+        expiry_date: default_expiry_date(),
+        status: CardStatus::Inactive,
+    }
+}
+
 #[cfg(test)]
 /// Generate a random pan
 pub fn generate_random_pan() -> Pan {
@@ -108,6 +164,7 @@ fn is_valid_pan(pan: &str) -> bool {
 mod tests {
     use super::*;
     use pretty_assertions::{assert_eq, assert_ne};
+    use shared::query::Filter;
 
     #[test]
     fn test_valid_pan() {
@@ -149,4 +206,23 @@ mod tests {
         assert_eq!(bin, "49024553");
         assert_eq!(account_number, "45539788894");
     }
+
+    #[test]
+    fn test_typed_columns() {
+        // GIVEN the typed columns of the cards
+        let columns = Card::columns();
+        let today = NaiveDate::from_ymd_opt(2025, 6, 1).unwrap();
+
+        // WHEN we filter on the expiry date and the status
+        let condition = columns
+            .expiry_date
+            .lt(today)
+            .and(columns.status.eq(CardStatus::Active));
+
+        // THEN they are compared with their serialized values
+        assert_eq!(
+            Filter::from(condition),
+            Filter::lt("expiry_date", "2025-06-01").and(Filter::eq("status", "Active"))
+        );
+    }
 }
@@ -1,25 +1,73 @@
 //! Customer domain entity
 
 use serde::{Deserialize, Serialize};
+use shared::error::InterfaceError;
+use shared::ports::secondary::HasKey;
+use shared::query::Column;
+use shared::relation::HasMany;
 use shared::sql_macros::struct_to_sql;
-use shared::usecase::rds::GetFieldsAsParams;
+use shared::usecase::rds::{FromRow, GetFieldsAsParams, RdsRow};
 use shared::QuerySet;
 use uuid::Uuid;
 
+use super::card::Card;
+
 /// Customer
-#[derive(Deserialize, Serialize)]
-#[struct_to_sql]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[struct_to_sql(soft_delete, audit)]
+#[sql(has_many(cards = "Card(customer_uuid)"))]
 pub struct Customer {
     #[serde(default = "uuid::Uuid::new_v4")]
     uuid: Uuid,
     // #[serde(default)]
     // account_number: String,
     #[serde(default)]
+    #[sql(not_null)]
     name: String,
     #[serde(default)]
+    #[sql(not_null, default = "0")]
     balance: i32, //TODO
-                  // #[serde(default)]
-                  // created_at:
+    // #[serde(default)]
+    // created_at:
+    #[serde(default)]
+    #[sql(version)]
+    version: i32,
+}
+
+impl Customer {
+    /// A new customer, with a new UUID
+    pub fn new(name: String, balance: i32) -> Self {
+        Customer {
+            uuid: Uuid::new_v4(),
+            name,
+            balance,
+            version: 0,
+        }
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Name of the customer
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Balance of the customer's account
+    pub fn balance(&self) -> i32 {
+        self.balance
+    }
+
+    /// Number of updates of the customer
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    /// Withdraw an amount from the balance of the account
+    pub fn debit(&mut self, amount: i32) {
+        self.balance -= amount;
+    }
 }
 
 #[cfg(test)]
@@ -36,5 +84,6 @@ pub fn get_random_customer() -> Customer {
         name: format!("customer-{}", rng.gen_range(1..=1000)),
         // account_number: account_number,
         balance: rng.gen_range(0..=1000),
+        version: 0,
     }
 }
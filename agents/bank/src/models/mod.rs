@@ -1,2 +1,30 @@
 pub mod card;
 pub mod customer;
+
+#[cfg(test)]
+mod tests {
+    use super::{card::Card, customer::Customer};
+    use shared::migration::load_migrations;
+    use shared::QuerySet;
+    use std::path::Path;
+
+    #[test]
+    fn test_migrations_cover_the_models() {
+        // GIVEN the migrations of the bank
+        let migrations =
+            load_migrations(&Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations")).unwrap();
+        let scripts: String = migrations.iter().map(|m| m.script.as_str()).collect();
+
+        // WHEN we list the columns of the models
+        let mut columns = Customer::queryset().column_definitions();
+        columns.extend(Card::queryset().column_definitions());
+
+        // THEN each column is created by a migration
+        for (column, _) in columns {
+            assert!(
+                scripts.contains(&format!("\"{column}\"")),
+                "No migration creates the column {column}"
+            );
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::models::{card::Card, customer::Customer};
+use crate::usecase::{nested_transaction_error, BankRepository, BankTransaction};
+use async_trait::async_trait;
+use shared::error::InterfaceError;
+use shared::ports::secondary::{History, Join, Repository, Transaction};
+use shared::settings::FileSettings;
+use shared::usecase::file::{FileRepository, FileTransaction, FileTransactionRepository};
+use shared::QuerySet;
+use std::path::Path;
+
+pub struct BankFileRepository {
+    customers: FileRepository<Customer>,
+    cards: FileRepository<Card>,
+}
+
+impl BankFileRepository {
+    /// Open the repositories stored in the directory, creating the missing ones
+    pub fn new(settings: &FileSettings) -> Result<Self, InterfaceError> {
+        let directory = Path::new(&settings.directory);
+        let customers = FileRepository::open(directory.join(Customer::queryset().table()))?
+            .with_version("version")
+            .with_soft_delete()
+            .with_history();
+        let cards = FileRepository::open(directory.join(Card::queryset().table()))?
+            .with_soft_delete()
+            .with_history();
+        cards.belongs_to(&Card::customer(), &customers);
+        Ok(BankFileRepository { customers, cards })
+    }
+
+    /// Audit history of the customers
+    pub fn customer_history(&self) -> &dyn History<Customer> {
+        &self.customers
+    }
+}
+
+#[async_trait]
+impl BankRepository for BankFileRepository {
+    fn customers(&self) -> &dyn Repository<Customer> {
+        &self.customers
+    }
+
+    fn cards(&self) -> &dyn Repository<Card> {
+        &self.cards
+    }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        let transaction = FileTransaction::new();
+        Ok(Box::new(BankFileTransaction {
+            customers: self.customers.in_transaction(&transaction),
+            cards: self.cards.in_transaction(&transaction),
+            transaction,
+        }))
+    }
+}
+
+/// File bank repositories bound to a transaction
+pub struct BankFileTransaction<'a> {
+    customers: FileTransactionRepository<'a, Customer>,
+    cards: FileTransactionRepository<'a, Card>,
+    transaction: FileTransaction<'a>,
+}
+
+#[async_trait]
+impl BankRepository for BankFileTransaction<'_> {
+    fn customers(&self) -> &dyn Repository<Customer> {
+        &self.customers
+    }
+
+    fn cards(&self) -> &dyn Repository<Card> {
+        &self.cards
+    }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        Err(nested_transaction_error())
+    }
+}
+
+#[async_trait]
+impl Transaction for BankFileTransaction<'_> {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.transaction.commit().await
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.transaction.rollback().await
+    }
+}
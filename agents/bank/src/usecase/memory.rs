@@ -1,31 +1,33 @@
 use crate::models::{card::Card, customer::Customer};
-use crate::usecase::BankRepository;
-use shared::ports::secondary::Repository;
-use shared::usecase::memory::{HasUuid, InMemoryRepository};
-
-impl HasUuid for Customer {
-    fn get_uuid(&self) -> uuid::Uuid {
-        self.uuid
-    }
-}
-
-impl HasUuid for Card {
-    fn get_uuid(&self) -> uuid::Uuid {
-        self.uuid
-    }
-}
+use crate::usecase::{nested_transaction_error, BankRepository, BankTransaction};
+use async_trait::async_trait;
+use shared::error::InterfaceError;
+use shared::ports::secondary::{History, Join, Repository, Transaction};
+use shared::usecase::memory::{InMemoryRepository, MemoryTransaction, MemoryTransactionRepository};
+use std::sync::Arc;
 
 pub struct BankMemoryRepository {
-    customers: InMemoryRepository<Customer>,
-    cards: InMemoryRepository<Card>,
+    customers: Arc<InMemoryRepository<Customer>>,
+    cards: Arc<InMemoryRepository<Card>>,
 }
 
 impl BankMemoryRepository {
     pub fn new() -> Self {
-        let customers: InMemoryRepository<Customer> = InMemoryRepository::new();
-        let cards: InMemoryRepository<Card> = InMemoryRepository::new();
+        let customers: InMemoryRepository<Customer> = InMemoryRepository::new()
+            .with_version("version")
+            .with_soft_delete()
+            .with_history();
+        let cards: InMemoryRepository<Card> =
+            InMemoryRepository::new().with_soft_delete().with_history();
+        let (customers, cards) = (Arc::new(customers), Arc::new(cards));
+        cards.belongs_to(&Card::customer(), &customers);
         Self { customers, cards }
     }
+
+    /// Audit history of the customers
+    pub fn customer_history(&self) -> &dyn History<Customer> {
+        &*self.customers
+    }
 }
 
 impl Default for BankMemoryRepository {
@@ -34,7 +36,43 @@ impl Default for BankMemoryRepository {
     }
 }
 
+#[async_trait]
 impl BankRepository for BankMemoryRepository {
+    fn customers(&self) -> &dyn Repository<Customer> {
+        &*self.customers
+    }
+
+    fn cards(&self) -> &dyn Repository<Card> {
+        &*self.cards
+    }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &*self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &*self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        let transaction = MemoryTransaction::new();
+        Ok(Box::new(BankMemoryTransaction {
+            customers: self.customers.in_transaction(&transaction),
+            cards: self.cards.in_transaction(&transaction),
+            transaction,
+        }))
+    }
+}
+
+/// In memory bank repositories bound to a transaction
+pub struct BankMemoryTransaction<'a> {
+    customers: MemoryTransactionRepository<'a, Customer>,
+    cards: MemoryTransactionRepository<'a, Card>,
+    transaction: MemoryTransaction<'a>,
+}
+
+#[async_trait]
+impl BankRepository for BankMemoryTransaction<'_> {
     fn customers(&self) -> &dyn Repository<Customer> {
         &self.customers
     }
@@ -42,4 +80,27 @@ impl BankRepository for BankMemoryRepository {
     fn cards(&self) -> &dyn Repository<Card> {
         &self.cards
     }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        Err(nested_transaction_error())
+    }
+}
+
+#[async_trait]
+impl Transaction for BankMemoryTransaction<'_> {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.transaction.commit().await
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.transaction.rollback().await
+    }
 }
@@ -1,12 +1,38 @@
+pub mod file;
 pub mod memory;
+pub mod postgres;
 pub mod rds;
+pub mod sqlite;
 
-use shared::ports::secondary::Repository;
+use async_trait::async_trait;
+use shared::error::InterfaceError;
+use shared::ports::secondary::{Join, Repository, Transaction};
 
 use crate::models::{card::Card, customer::Customer};
 
+#[async_trait]
 pub trait BankRepository: Send + Sync {
     fn customers(&self) -> &dyn Repository<Customer>;
 
     fn cards(&self) -> &dyn Repository<Card>;
+
+    /// The customers, loaded with their cards
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card>;
+
+    /// The cards, loaded with their customer
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer>;
+
+    /// Begin a transaction: the operations made through the returned
+    /// repositories are committed or rolled back together
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError>;
+}
+
+/// Bank repositories bound to a transaction
+pub trait BankTransaction: BankRepository + Transaction {}
+
+impl<T> BankTransaction for T where T: BankRepository + Transaction {}
+
+/// Error returned when beginning a transaction inside a transaction
+fn nested_transaction_error() -> InterfaceError {
+    InterfaceError::Other("Nested transactions are not supported".to_string())
 }
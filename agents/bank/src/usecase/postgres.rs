@@ -0,0 +1,102 @@
+use crate::models::{
+    card::{Card, CardQuerySet},
+    customer::{Customer, CustomerQuerySet},
+};
+use crate::usecase::{nested_transaction_error, BankRepository, BankTransaction};
+use async_trait::async_trait;
+use shared::error::InterfaceError;
+use shared::ports::secondary::{Join, Repository, Transaction};
+use shared::settings::PostgresSettings;
+use shared::usecase::postgres::{create_pool, Pool, PostgresRepository, PostgresTransaction};
+
+pub struct BankPostgresRepository {
+    pool: Pool,
+    customers: PostgresRepository<Customer, CustomerQuerySet<Customer>>,
+    cards: PostgresRepository<Card, CardQuerySet<Card>>,
+}
+
+impl BankPostgresRepository {
+    pub fn new(settings: &PostgresSettings) -> Result<Self, InterfaceError> {
+        let pool = create_pool(settings)?;
+        let customer_queryset: Box<CustomerQuerySet<Customer>> = Box::new(Customer::queryset());
+        let customers = PostgresRepository::new(pool.clone(), customer_queryset);
+
+        let card_queryset: Box<CardQuerySet<Card>> = Box::new(Card::queryset());
+        let cards = PostgresRepository::new(pool.clone(), card_queryset);
+
+        Ok(BankPostgresRepository {
+            pool,
+            customers,
+            cards,
+        })
+    }
+}
+
+#[async_trait]
+impl BankRepository for BankPostgresRepository {
+    fn customers(&self) -> &dyn Repository<Customer> {
+        &self.customers
+    }
+
+    fn cards(&self) -> &dyn Repository<Card> {
+        &self.cards
+    }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        let transaction = PostgresTransaction::begin(&self.pool).await?;
+        Ok(Box::new(BankPostgresTransaction {
+            customers: self.customers.in_transaction(&transaction),
+            cards: self.cards.in_transaction(&transaction),
+            transaction,
+        }))
+    }
+}
+
+/// PostgreSQL bank repositories bound to a transaction
+pub struct BankPostgresTransaction {
+    customers: PostgresRepository<Customer, CustomerQuerySet<Customer>>,
+    cards: PostgresRepository<Card, CardQuerySet<Card>>,
+    transaction: PostgresTransaction,
+}
+
+#[async_trait]
+impl BankRepository for BankPostgresTransaction {
+    fn customers(&self) -> &dyn Repository<Customer> {
+        &self.customers
+    }
+
+    fn cards(&self) -> &dyn Repository<Card> {
+        &self.cards
+    }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        Err(nested_transaction_error())
+    }
+}
+
+#[async_trait]
+impl Transaction for BankPostgresTransaction {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.transaction.commit().await
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.transaction.rollback().await
+    }
+}
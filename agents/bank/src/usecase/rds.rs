@@ -2,32 +2,91 @@ use crate::models::{
     card::{Card, CardQuerySet},
     customer::{Customer, CustomerQuerySet},
 };
-use crate::usecase::BankRepository;
+use crate::usecase::{nested_transaction_error, BankRepository, BankTransaction};
+use async_trait::async_trait;
 use aws_config::SdkConfig;
-use shared::ports::secondary::Repository;
-use shared::settings::RdsSettings;
-use shared::{rds_client::RdsClient, usecase::rds::RdsRepository};
+use shared::error::InterfaceError;
+use shared::ports::secondary::{Join, Repository, Transaction};
+use shared::settings::{CacheSettings, RdsSettings, RetrySettings};
+use shared::QuerySet;
+use shared::{
+    rds_client::RdsClient,
+    usecase::cache::CachedRepository,
+    usecase::instrument::{Instrumented, RepositoryMetrics},
+    usecase::rds::{RdsRepository, RdsTransaction},
+    usecase::retry::{RetryPolicy, RetryingRepository},
+};
 
 use std::sync::Arc;
+use std::time::Duration;
+
+type CustomerRepository = RdsRepository<Customer, CustomerQuerySet<Customer>>;
+type CardRepository = RdsRepository<Card, CardQuerySet<Card>>;
+
+type Observed<T, R> = Instrumented<CachedRepository<T, R>>;
 
 pub struct BankRdsRepository {
-    customers: RdsRepository<Customer, CustomerQuerySet<Customer>>,
-    cards: RdsRepository<Card, CardQuerySet<Card>>,
+    client: Arc<RdsClient>,
+    customers: Instrumented<RetryingRepository<Customer, CustomerRepository>>,
+    cards: Observed<Card, RetryingRepository<Card, CardRepository>>,
+    metrics: Arc<RepositoryMetrics>,
 }
 
 impl BankRdsRepository {
     pub fn new(settings: &RdsSettings, sdk_config: &SdkConfig) -> Self {
         let client = Arc::new(RdsClient::new(settings, sdk_config));
         let customer_queryset: Box<CustomerQuerySet<Customer>> = Box::new(Customer::queryset());
+        let customer_table = customer_queryset.table();
         let customers = RdsRepository::new(Arc::clone(&client), customer_queryset);
 
         let card_queryset: Box<CardQuerySet<Card>> = Box::new(Card::queryset());
+        let card_table = card_queryset.table();
         let cards = RdsRepository::new(Arc::clone(&client), card_queryset);
 
-        BankRdsRepository { customers, cards }
+        // Customers and cards are keyed by UUIDs generated by the clients,
+        // transient errors are retried but nothing is cached unless configured
+        let customers = RetryingRepository::new(customers, RetryPolicy::default())
+            .versioned()
+            .with_idempotency_key();
+        let cards = RetryingRepository::new(cards, RetryPolicy::default()).with_idempotency_key();
+
+        // Every call is traced and counted, including the ones served by the cache.
+        // Customers are never cached: their balance is debited concurrently by
+        // other instances and a stale copy would authorize overdrawn transactions
+        let metrics = Arc::new(RepositoryMetrics::default());
+        let cards = CachedRepository::new(cards).with_capacity(0);
+        BankRdsRepository {
+            client,
+            customers: Instrumented::new(customers, customer_table)
+                .with_metrics(Arc::clone(&metrics)),
+            cards: Instrumented::new(cards, card_table).with_metrics(Arc::clone(&metrics)),
+            metrics,
+        }
+    }
+
+    /// Retry the operations failing with a transient error as configured
+    pub fn with_retry(mut self, settings: &RetrySettings) -> Self {
+        let policy = RetryPolicy::from(settings);
+        self.customers.inner_mut().set_policy(policy.clone());
+        self.cards.inner_mut().inner_mut().set_policy(policy);
+        self
+    }
+
+    /// Cache the cards read from the database, the customers are never cached
+    pub fn with_cache(mut self, settings: &CacheSettings) -> Self {
+        let cards = self.cards.inner_mut();
+        cards.set_ttl(Duration::from_secs(settings.ttl_seconds));
+        cards.set_capacity(settings.capacity);
+        self
+    }
+
+    /// Metrics of the operations on the customers, the cards and the transactions
+    pub fn metrics(&self) -> &Arc<RepositoryMetrics> {
+        &self.metrics
     }
 }
 
+#[async_trait]
 impl BankRepository for BankRdsRepository {
     fn customers(&self) -> &dyn Repository<Customer> {
         &self.customers
@@ -36,4 +95,70 @@ impl BankRepository for BankRdsRepository {
     fn cards(&self) -> &dyn Repository<Card> {
         &self.cards
     }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        let (customers, cards) = (self.customers.inner(), self.cards.inner());
+        let policy = customers.policy();
+        let transaction = policy.run(|| RdsTransaction::begin(&self.client)).await?;
+        let customers = customers.inner().in_transaction(&transaction);
+        let cards = cards.in_transaction(cards.inner().inner().in_transaction(&transaction));
+        Ok(Box::new(BankRdsTransaction {
+            customers: Instrumented::new(customers, Customer::queryset().table())
+                .with_metrics(Arc::clone(&self.metrics)),
+            cards: Instrumented::new(cards, Card::queryset().table())
+                .with_metrics(Arc::clone(&self.metrics)),
+            transaction: Instrumented::new(transaction, "transaction")
+                .with_metrics(Arc::clone(&self.metrics)),
+        }))
+    }
+}
+
+/// RDS bank repositories bound to a transaction, the cards they write
+/// are evicted from the cache once the transaction is dropped
+pub struct BankRdsTransaction {
+    customers: Instrumented<CustomerRepository>,
+    cards: Observed<Card, CardRepository>,
+    transaction: Instrumented<RdsTransaction>,
+}
+
+#[async_trait]
+impl BankRepository for BankRdsTransaction {
+    fn customers(&self) -> &dyn Repository<Customer> {
+        &self.customers
+    }
+
+    fn cards(&self) -> &dyn Repository<Card> {
+        &self.cards
+    }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        Err(nested_transaction_error())
+    }
+}
+
+#[async_trait]
+impl Transaction for BankRdsTransaction {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.transaction.commit().await
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.transaction.rollback().await
+    }
 }
@@ -0,0 +1,105 @@
+use crate::models::{
+    card::{Card, CardQuerySet},
+    customer::{Customer, CustomerQuerySet},
+};
+use crate::usecase::{nested_transaction_error, BankRepository, BankTransaction};
+use async_trait::async_trait;
+use shared::error::InterfaceError;
+use shared::ports::secondary::{Join, Repository, Transaction};
+use shared::settings::SqliteSettings;
+use shared::usecase::sqlite::{SqliteDatabase, SqliteRepository, SqliteTransaction};
+
+pub struct BankSqliteRepository {
+    database: SqliteDatabase,
+    customers: SqliteRepository<Customer, CustomerQuerySet<Customer>>,
+    cards: SqliteRepository<Card, CardQuerySet<Card>>,
+}
+
+impl BankSqliteRepository {
+    /// Open the database, creating the missing tables
+    pub async fn new(settings: &SqliteSettings) -> Result<Self, InterfaceError> {
+        let database = SqliteDatabase::open(settings)?;
+        let customer_queryset: Box<CustomerQuerySet<Customer>> = Box::new(Customer::queryset());
+        let customers = SqliteRepository::new(database.clone(), customer_queryset);
+        customers.create_table().await?;
+
+        let card_queryset: Box<CardQuerySet<Card>> = Box::new(Card::queryset());
+        let cards = SqliteRepository::new(database.clone(), card_queryset);
+        cards.create_table().await?;
+
+        Ok(BankSqliteRepository {
+            database,
+            customers,
+            cards,
+        })
+    }
+}
+
+#[async_trait]
+impl BankRepository for BankSqliteRepository {
+    fn customers(&self) -> &dyn Repository<Customer> {
+        &self.customers
+    }
+
+    fn cards(&self) -> &dyn Repository<Card> {
+        &self.cards
+    }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        let transaction = SqliteTransaction::begin(&self.database).await?;
+        Ok(Box::new(BankSqliteTransaction {
+            customers: self.customers.in_transaction(&transaction),
+            cards: self.cards.in_transaction(&transaction),
+            transaction,
+        }))
+    }
+}
+
+/// SQLite bank repositories bound to a transaction
+pub struct BankSqliteTransaction {
+    customers: SqliteRepository<Customer, CustomerQuerySet<Customer>>,
+    cards: SqliteRepository<Card, CardQuerySet<Card>>,
+    transaction: SqliteTransaction,
+}
+
+#[async_trait]
+impl BankRepository for BankSqliteTransaction {
+    fn customers(&self) -> &dyn Repository<Customer> {
+        &self.customers
+    }
+
+    fn cards(&self) -> &dyn Repository<Card> {
+        &self.cards
+    }
+
+    fn customers_with_cards(&self) -> &dyn Join<Customer, Card> {
+        &self.customers
+    }
+
+    fn cards_with_customer(&self) -> &dyn Join<Card, Customer> {
+        &self.cards
+    }
+
+    async fn begin(&self) -> Result<Box<dyn BankTransaction + '_>, InterfaceError> {
+        Err(nested_transaction_error())
+    }
+}
+
+#[async_trait]
+impl Transaction for BankSqliteTransaction {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.transaction.commit().await
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.transaction.rollback().await
+    }
+}
@@ -5,20 +5,51 @@ use tracing::instrument;
 // Setup repository
 #[instrument]
 #[cfg(test)]
-pub async fn get_bank_repository() -> impl crate::usecase::BankRepository {
-    crate::usecase::memory::BankMemoryRepository::new()
+pub async fn get_bank_repository() -> Box<dyn crate::usecase::BankRepository> {
+    Box::new(crate::usecase::memory::BankMemoryRepository::new())
 }
 
 // Setup repository
 #[instrument]
 #[cfg(not(test))]
-pub async fn get_bank_repository() -> impl crate::usecase::BankRepository {
-    // Get AWS Config
-    let sdk_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
-
+pub async fn get_bank_repository() -> Box<dyn crate::usecase::BankRepository> {
     // Load settings
     let settings = get_settings().await.expect("Failed to load configuration");
 
+    // Prefer an embedded SQLite database, then local files, then a direct
+    // connection to PostgreSQL, when they are configured
+    if let Some(sqlite) = &settings.sqlite {
+        return Box::new(
+            crate::usecase::sqlite::BankSqliteRepository::new(sqlite)
+                .await
+                .expect("Failed to open the SQLite database"),
+        );
+    }
+
+    if let Some(file) = &settings.file {
+        return Box::new(
+            crate::usecase::file::BankFileRepository::new(file)
+                .expect("Failed to open the file repositories"),
+        );
+    }
+
+    if let Some(postgres) = &settings.postgres {
+        return Box::new(
+            crate::usecase::postgres::BankPostgresRepository::new(postgres)
+                .expect("Failed to create the PostgreSQL pool"),
+        );
+    }
+
+    // Get AWS Config
+    let sdk_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+
     // Initialize Rds Repository
-    crate::usecase::rds::BankRdsRepository::new(&settings.rds, &sdk_config)
+    let mut repo = crate::usecase::rds::BankRdsRepository::new(&settings.rds, &sdk_config);
+    if let Some(retry) = &settings.retry {
+        repo = repo.with_retry(retry);
+    }
+    match &settings.cache {
+        Some(cache) => Box::new(repo.with_cache(cache)),
+        None => Box::new(repo),
+    }
 }
@@ -5,7 +5,6 @@ use bank::models::customer::Customer;
 use pretty_assertions::assert_eq;
 use rand::Rng;
 use reqwest::StatusCode;
-use uuid::Uuid;
 
 type E = Box<dyn std::error::Error + Send + Sync + 'static>;
 
@@ -15,12 +14,11 @@ pub fn get_random_customer() -> Customer {
     // let account_number: String = (0..11)
     //     .map(|_| rng.gen_range(0..10).to_string())
     //     .collect();
-    Customer {
-        uuid: Uuid::new_v4(),
-        name: format!("customer-{}", rng.gen_range(1..=1000)),
+    Customer::new(
+        format!("customer-{}", rng.gen_range(1..=1000)),
         // account_number: account_number,
-        balance: rng.gen_range(0..=1000),
-    }
+        rng.gen_range(0..=1000),
+    )
 }
 
 #[tokio::test]
@@ -30,12 +28,12 @@ async fn test_account_management_flow() -> Result<(), E> {
     let api_url: String = std::env::var("API_URL").expect("API_URL not set");
 
     let customer = get_random_customer();
-    dbg!(&customer.uuid);
+    dbg!(&customer.uuid());
 
     // Create account for customer
     println!(
         "Creating an account for customer with name {}",
-        customer.name
+        customer.name()
     );
     let res = client
         .post(format!("{}/create-account", api_url))
@@ -48,13 +46,13 @@ async fn test_account_management_flow() -> Result<(), E> {
     // Get balance
     println!("Get customer balance");
     let res = client
-        .get(format!("{}/get-balance/uuid/{}", api_url, customer.uuid))
+        .get(format!("{}/get-balance/uuid/{}", api_url, customer.uuid()))
         .send()
         .await?;
     dbg!(&res);
     assert_eq!(res.status(), StatusCode::OK);
     let res_customer: Customer = res.json().await?;
-    assert_eq!(customer.balance, res_customer.balance);
+    assert_eq!(customer.balance(), res_customer.balance());
 
     Ok(())
 }
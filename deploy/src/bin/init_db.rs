@@ -10,7 +10,7 @@ async fn init_agent(client: &RdsClient, name: &str) -> Result<(), InterfaceError
         .sql(format!("CREATE DATABASE {}", name))
         .send()
         .await
-        .map_err(|err| InterfaceError::RdsError(err.into()))?;
+        .map_err(|err| InterfaceError::RdsError(Box::new(err.into())))?;
 
     Ok(())
 }
@@ -0,0 +1,95 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bank::models::{card::Card, customer::Customer};
+use clap::Parser;
+use shared::error::InterfaceError;
+use shared::migration::{
+    load_migrations, migrate, pending_migrations, propose_migration, Migration,
+};
+use shared::rds_client::RdsClient;
+use shared::settings::get_settings;
+use tracing_subscriber::EnvFilter;
+
+/// Apply the migrations of an agent's database, or generate the next one
+#[derive(Parser)]
+struct Args {
+    /// Database of the agent
+    #[arg(long, default_value = "bank_1")]
+    database: String,
+
+    /// Directory of the migrations of the agent
+    #[arg(long, default_value = "../agents/bank/migrations")]
+    directory: PathBuf,
+
+    /// List the pending migrations without applying them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Write a migration with this name, bringing the database to the models,
+    /// to be reviewed before it is applied
+    #[arg(long, value_name = "NAME")]
+    generate: Option<String>,
+}
+
+/// Write the next migration, from the differences between the database and the models
+async fn generate(
+    client: &RdsClient,
+    args: &Args,
+    migrations: &[Migration],
+    name: &str,
+) -> Result<(), InterfaceError> {
+    if !pending_migrations(client, migrations).await?.is_empty() {
+        return Err(InterfaceError::Validation(
+            "Apply the pending migrations before generating a new one".to_string(),
+        ));
+    }
+
+    let mut statements = propose_migration(client, &Customer::queryset()).await?;
+    statements.extend(propose_migration(client, &Card::queryset()).await?);
+    if statements.is_empty() {
+        tracing::info!("The database matches the models");
+        return Ok(());
+    }
+
+    let migration = Migration::next(migrations, name, &statements);
+    let path = args.directory.join(migration.file_name());
+    fs::write(&path, &migration.script).map_err(|err| {
+        InterfaceError::Other(format!("Failed to write {}: {err}", path.display()))
+    })?;
+    tracing::info!("Review the migration {}", path.display());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), InterfaceError> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
+    let args = Args::parse();
+
+    // Get AWS Config
+    let sdk_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+
+    // Load settings, for the database of the agent
+    let mut settings = get_settings().await.expect("Failed to load configuration");
+    settings.rds.dbinstance = args.database.clone();
+
+    // Get client
+    let client = RdsClient::new(&settings.rds, &sdk_config);
+
+    let migrations = load_migrations(&args.directory)?;
+    if let Some(name) = &args.generate {
+        return generate(&client, &args, &migrations, name).await;
+    }
+    if args.dry_run {
+        for migration in pending_migrations(&client, &migrations).await? {
+            tracing::info!("Pending migration {}", migration.file_name());
+        }
+        return Ok(());
+    }
+
+    let applied = migrate(&client, &migrations).await?;
+    tracing::info!("Applied {} migrations", applied.len());
+    Ok(())
+}
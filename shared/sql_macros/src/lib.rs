@@ -1,132 +1,1043 @@
 extern crate proc_macro2;
 
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, Data, DeriveInput, Field, Fields, Ident, Type};
+use quote::{format_ident, quote, ToTokens};
+use syn::meta::ParseNestedMeta;
+use syn::{
+    parse_macro_input, Attribute, Data, DataStruct, DeriveInput, Field, Fields, GenericArgument,
+    Ident, LitStr, PathArguments, PathSegment, Type,
+};
 
+/// SQL type of a field, or of the value of an `Option` field
 enum SqlTypes {
     String,
     Uuid,
     Integer,
+    BigInt,
+    Bool,
+    Double,
+    Decimal,
+    Date,
+    Timestamp,
+    /// Timestamp with a time zone, stored in UTC
+    TimestampTz,
+    Bytes,
+    Json,
+    /// String-backed enum, stored as its serialized variant
+    Enum,
 }
 
 impl SqlTypes {
-    fn from_field(field: &Field) -> SqlTypes {
-        let field_type = &field.ty;
-        match field_type {
-            Type::Path(type_path) => {
-                let type_name = type_path.path.segments.first().unwrap().ident.to_string();
-                match type_name.as_str() {
-                    "String" => SqlTypes::String,
-                    "i32" => SqlTypes::Integer,
-                    "Uuid" => SqlTypes::Uuid,
-                    _ => unimplemented!("Unimplemented type {}", type_name),
-                }
-            }
-            _ => unimplemented!("Unkown type is not implemented"),
+    fn from_field(field: &Field, options: &FieldOptions) -> syn::Result<SqlTypes> {
+        if options.json {
+            return Ok(SqlTypes::Json);
+        }
+        if options.enumeration {
+            return Ok(SqlTypes::Enum);
         }
+        let field_type = option_inner(&field.ty).unwrap_or(&field.ty);
+        let segment = match field_type {
+            Type::Path(type_path) => type_path.path.segments.last(),
+            _ => None,
+        };
+        let sql_type = match segment {
+            Some(segment) => match segment.ident.to_string().as_str() {
+                "String" => Some(SqlTypes::String),
+                "i16" | "i32" => Some(SqlTypes::Integer),
+                "i64" => Some(SqlTypes::BigInt),
+                "bool" => Some(SqlTypes::Bool),
+                "f32" | "f64" => Some(SqlTypes::Double),
+                "Uuid" => Some(SqlTypes::Uuid),
+                "Decimal" | "BigDecimal" => Some(SqlTypes::Decimal),
+                "NaiveDate" | "Date" => Some(SqlTypes::Date),
+                "NaiveDateTime" | "PrimitiveDateTime" => Some(SqlTypes::Timestamp),
+                "DateTime" | "OffsetDateTime" => Some(SqlTypes::TimestampTz),
+                "Value" => Some(SqlTypes::Json),
+                "Vec" if is_bytes(segment) => Some(SqlTypes::Bytes),
+                _ => None,
+            },
+            None => None,
+        };
+        sql_type.ok_or_else(|| {
+            syn::Error::new_spanned(
+                field_type,
+                format!(
+                    "unsupported field type `{}`, mark the field `#[sql(json)]` to store it \
+                     as JSON, `#[sql(enum)]` if it is a string-backed enum, or `#[sql(skip)]` \
+                     to not store it",
+                    field_type.to_token_stream().to_string().replace(' ', "")
+                ),
+            )
+        })
     }
 
     fn to_sql_syntax(&self) -> &str {
         match self {
-            SqlTypes::String => "VARCHAR(255)",
+            SqlTypes::String | SqlTypes::Enum => "VARCHAR(255)",
             SqlTypes::Integer => "INTEGER",
+            SqlTypes::BigInt => "BIGINT",
+            SqlTypes::Bool => "BOOLEAN",
+            SqlTypes::Double => "DOUBLE PRECISION",
             SqlTypes::Uuid => "UUID",
+            SqlTypes::Decimal => "NUMERIC",
+            SqlTypes::Date => "DATE",
+            SqlTypes::Timestamp => "TIMESTAMP",
+            SqlTypes::TimestampTz => "TIMESTAMPTZ",
+            SqlTypes::Bytes => "BYTEA",
+            SqlTypes::Json => "JSONB",
+        }
+    }
+
+    /// SQLite has no UUID, date or decimal types, they are stored as strings.
+    /// The declared BOOLEAN and JSON types tell how to read their columns back.
+    fn to_sqlite_syntax(&self) -> &str {
+        match self {
+            SqlTypes::String
+            | SqlTypes::Uuid
+            | SqlTypes::Decimal
+            | SqlTypes::Date
+            | SqlTypes::Timestamp
+            | SqlTypes::TimestampTz
+            | SqlTypes::Enum => "TEXT",
+            SqlTypes::Integer | SqlTypes::BigInt => "INTEGER",
+            SqlTypes::Bool => "BOOLEAN",
+            SqlTypes::Double => "REAL",
+            SqlTypes::Bytes => "BLOB",
+            SqlTypes::Json => "JSON",
         }
     }
 
-    fn to_awsdata(&self, field_name: &&Ident) -> proc_macro2::TokenStream {
+    /// RDS `Field` of a value, given as a reference to the field's type,
+    /// or to the type in its `Option`
+    fn to_awsdata(&self, value: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
         match self {
             SqlTypes::String | SqlTypes::Uuid => {
-                quote!(aws_sdk_rdsdata::types::Field::StringValue(self.#field_name.to_string().clone()))
+                quote!(aws_sdk_rdsdata::types::Field::StringValue(#value.to_string()))
+            }
+            SqlTypes::Integer | SqlTypes::BigInt => {
+                quote!(aws_sdk_rdsdata::types::Field::LongValue((*#value).into()))
             }
-            SqlTypes::Integer => {
-                quote!(aws_sdk_rdsdata::types::Field::LongValue(self.#field_name.clone().into()))
+            SqlTypes::Bool => quote!(aws_sdk_rdsdata::types::Field::BooleanValue(*#value)),
+            SqlTypes::Double => {
+                quote!(aws_sdk_rdsdata::types::Field::DoubleValue((*#value).into()))
             }
+            SqlTypes::Bytes => quote!(aws_sdk_rdsdata::types::Field::BlobValue(
+                aws_sdk_rdsdata::primitives::Blob::new(#value.to_vec())
+            )),
+            SqlTypes::Json => quote!(aws_sdk_rdsdata::types::Field::StringValue(
+                serde_json::to_string(#value).ok()?
+            )),
+            // Dates, decimals and enums are sent as their serialized string,
+            // the one read back when the rows are deserialized
+            SqlTypes::Decimal
+            | SqlTypes::Date
+            | SqlTypes::Timestamp
+            | SqlTypes::TimestampTz
+            | SqlTypes::Enum => quote!(aws_sdk_rdsdata::types::Field::StringValue(
+                match serde_json::to_value(#value).ok()? {
+                    serde_json::Value::String(s) => s,
+                    other => other.to_string(),
+                }
+            )),
         }
     }
 
     fn to_typehint(&self) -> proc_macro2::TokenStream {
         match self {
             SqlTypes::Uuid => quote!(Some(aws_sdk_rdsdata::types::TypeHint::Uuid)),
+            SqlTypes::Decimal => quote!(Some(aws_sdk_rdsdata::types::TypeHint::Decimal)),
+            SqlTypes::Date => quote!(Some(aws_sdk_rdsdata::types::TypeHint::Date)),
+            SqlTypes::Timestamp | SqlTypes::TimestampTz => {
+                quote!(Some(aws_sdk_rdsdata::types::TypeHint::Timestamp))
+            }
+            SqlTypes::Json => quote!(Some(aws_sdk_rdsdata::types::TypeHint::Json)),
             _ => quote!(None),
         }
     }
 }
 
-/// Generate UPDATE ROW query
-/// TODO: Manage other ids than uuid
-fn update_row_query(fields: &Fields, struct_name: &Ident) -> String {
-    let mut fields_sql = Vec::new();
+/// Type in the angle brackets of a path segment, e.g. `T` in `Option<T>`
+fn generic_argument(segment: &PathSegment) -> Option<&Type> {
+    match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) => {
+            arguments.args.iter().find_map(|argument| match argument {
+                GenericArgument::Type(argument) => Some(argument),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Type of the value of an `Option`, the field being nullable
+fn option_inner(field_type: &Type) -> Option<&Type> {
+    match field_type {
+        Type::Path(type_path) => {
+            let segment = type_path.path.segments.last()?;
+            if segment.ident != "Option" {
+                return None;
+            }
+            generic_argument(segment)
+        }
+        _ => None,
+    }
+}
+
+/// `Vec<u8>`, stored as binary
+fn is_bytes(segment: &PathSegment) -> bool {
+    matches!(generic_argument(segment), Some(Type::Path(item)) if item.path.is_ident("u8"))
+}
+
+/// Options read from the `#[sql(...)]` attributes of a field
+#[derive(Default)]
+struct FieldOptions {
+    /// Part of the primary key
+    primary_key: bool,
+    /// Version column used for optimistic concurrency control
+    version: bool,
+    /// Stored as JSON, for any serializable type
+    json: bool,
+    /// String-backed enum, stored as its serialized variant
+    enumeration: bool,
+    /// Name of the column, when it is not the name of the field
+    rename: Option<String>,
+    /// Not stored, the field must deserialize without its column
+    skip: bool,
+    unique: bool,
+    not_null: bool,
+    /// SQL expression of the default value of the column
+    default: Option<String>,
+    /// The column has its own index
+    index: bool,
+    /// Referenced `table`, or `table(column)`
+    references: Option<String>,
+    /// Relation to the referenced model: name of its method, and the model
+    belongs_to: Option<(Ident, Type)>,
+}
+
+impl FieldOptions {
+    fn from_field(field: &Field) -> syn::Result<FieldOptions> {
+        let mut options = FieldOptions::default();
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("sql"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("primary_key") {
+                    options.primary_key = true;
+                    Ok(())
+                } else if meta.path.is_ident("version") {
+                    options.version = true;
+                    Ok(())
+                } else if meta.path.is_ident("json") {
+                    options.json = true;
+                    Ok(())
+                } else if meta.path.is_ident("enum") {
+                    options.enumeration = true;
+                    Ok(())
+                } else if meta.path.is_ident("rename") {
+                    options.rename = Some(string_value(&meta)?);
+                    Ok(())
+                } else if meta.path.is_ident("skip") {
+                    options.skip = true;
+                    Ok(())
+                } else if meta.path.is_ident("unique") {
+                    options.unique = true;
+                    Ok(())
+                } else if meta.path.is_ident("not_null") {
+                    options.not_null = true;
+                    Ok(())
+                } else if meta.path.is_ident("default") {
+                    options.default = Some(string_value(&meta)?);
+                    Ok(())
+                } else if meta.path.is_ident("index") {
+                    options.index = true;
+                    Ok(())
+                } else if meta.path.is_ident("references") {
+                    options.references = Some(string_value(&meta)?);
+                    Ok(())
+                } else if meta.path.is_ident("belongs_to") {
+                    meta.parse_nested_meta(|relation| {
+                        let (name, model) = relation_value(&relation)?;
+                        options.belongs_to = Some((name, model.parse()?));
+                        Ok(())
+                    })
+                } else {
+                    Err(meta.error(
+                        "unsupported sql attribute, expected one of `primary_key`, `version`, \
+                         `json`, `enum`, `rename`, `skip`, `unique`, `not_null`, `default`, \
+                         `index`, `references` or `belongs_to`",
+                    ))
+                }
+            })?;
+        }
+        // The foreign key of a relation references the table of the model, and is indexed
+        if let Some((_, model)) = &options.belongs_to {
+            if options.skip {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "a `#[sql(belongs_to(...))]` field cannot be skipped",
+                ));
+            }
+            if options.references.is_none() {
+                options.references = Some(snake_case(&type_name(model)?.to_string()));
+            }
+            options.index = true;
+        }
+        if options.skip && (options.primary_key || options.version) {
+            return Err(syn::Error::new_spanned(
+                field,
+                "a `#[sql(primary_key)]` or `#[sql(version)]` field cannot be skipped",
+            ));
+        }
+        if options.json && options.enumeration {
+            return Err(syn::Error::new_spanned(
+                field,
+                "a field is stored either as `#[sql(json)]` or as `#[sql(enum)]`, not both",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// String value of an attribute argument: `name = "value"`
+fn string_value(meta: &ParseNestedMeta) -> syn::Result<String> {
+    Ok(meta.value()?.parse::<LitStr>()?.value())
+}
+
+/// Name and value of a relation: `name = "Model"`
+fn relation_value(meta: &ParseNestedMeta) -> syn::Result<(Ident, LitStr)> {
+    let name = meta
+        .path
+        .get_ident()
+        .cloned()
+        .ok_or_else(|| meta.error("expected the name of the relation, `name = \"Model\"`"))?;
+    Ok((name, meta.value()?.parse()?))
+}
+
+/// Name of the struct of a model type, e.g. `Customer` in `models::Customer`
+fn type_name(model: &Type) -> syn::Result<&Ident> {
+    match model {
+        Type::Path(path) if path.qself.is_none() => path
+            .path
+            .segments
+            .last()
+            .map(|segment| &segment.ident)
+            .ok_or_else(|| syn::Error::new_spanned(model, "expected a model")),
+        _ => Err(syn::Error::new_spanned(model, "expected a model")),
+    }
+}
+
+/// Options read from the arguments of `#[struct_to_sql(...)]`,
+/// and from the `#[sql(...)]` attributes of the struct
+#[derive(Default)]
+struct StructOptions {
+    /// Deleted rows are kept, marked with the time of their deletion
+    soft_delete: bool,
+    /// Every mutation is recorded in a history table
+    audit: bool,
+    /// Name of the table, when it is not the snake_case name of the struct
+    table: Option<String>,
+    /// Relations to the models referencing this one: name of their method,
+    /// the model, and its foreign key field
+    has_many: Vec<(Ident, Type, String)>,
+}
+
+impl StructOptions {
+    fn parse_attributes(&mut self, attrs: &[Attribute]) -> syn::Result<()> {
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("sql")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("table") {
+                    self.table = Some(string_value(&meta)?);
+                    Ok(())
+                } else if meta.path.is_ident("has_many") {
+                    meta.parse_nested_meta(|relation| {
+                        let (name, value) = relation_value(&relation)?;
+                        let (model, foreign_key) = value
+                            .value()
+                            .strip_suffix(')')
+                            .and_then(|value| {
+                                value
+                                    .split_once('(')
+                                    .map(|(model, key)| (model.to_string(), key.to_string()))
+                            })
+                            .ok_or_else(|| {
+                                syn::Error::new_spanned(
+                                    &value,
+                                    "expected the model and its foreign key, `\"Model(field)\"`",
+                                )
+                            })?;
+                        let model = LitStr::new(model.trim(), value.span()).parse()?;
+                        self.has_many
+                            .push((name, model, foreign_key.trim().to_string()));
+                        Ok(())
+                    })
+                } else {
+                    Err(meta.error("unsupported sql attribute, expected `table` or `has_many`"))
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Predicate matching the rows that are not soft deleted, prefixed with `AND`
+    fn live(&self) -> String {
+        match self.soft_delete {
+            true => format!(" AND {} IS NULL", quote_identifier(DELETED_COLUMN)),
+            false => String::new(),
+        }
+    }
+}
+
+/// Marker column of the soft deleted rows, holding their deletion time in milliseconds
+const DELETED_COLUMN: &str = "deleted_at";
+
+/// Columns of a history table, after the primary key of the entity
+const HISTORY_COLUMNS: [&str; 6] = [
+    "revision",
+    "operation",
+    "before_image",
+    "after_image",
+    "actor",
+    "changed_at",
+];
+
+/// Column types of a SQL dialect
+struct Dialect {
+    column_type: fn(&SqlTypes) -> &str,
+    /// Type of the timestamps, in milliseconds
+    timestamp: &'static str,
+}
+
+const POSTGRES: Dialect = Dialect {
+    column_type: SqlTypes::to_sql_syntax,
+    timestamp: "BIGINT",
+};
+
+const SQLITE: Dialect = Dialect {
+    column_type: SqlTypes::to_sqlite_syntax,
+    timestamp: "INTEGER",
+};
+
+/// Quote an identifier, so that any name can be used, even a reserved word
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// snake_case name of a table, from the CamelCase name of its struct
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let previous = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            // Split `BaseModel`, `Item2Model` and `HTTPServer` before `M`, `M` and `S`
+            if previous.is_lowercase()
+                || previous.is_ascii_digit()
+                || (previous.is_uppercase() && next_is_lower)
+            {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+/// A stored field, with its options and its SQL type
+struct Column<'a> {
+    field: &'a Field,
+    ident: &'a Ident,
+    options: FieldOptions,
+    sql_type: SqlTypes,
+}
+
+impl Column<'_> {
+    /// Name of the field, and of its parameter in the prepared queries
+    fn name(&self) -> String {
+        self.ident.to_string()
+    }
+
+    /// Name of the column of the field
+    fn column_name(&self) -> String {
+        self.options.rename.clone().unwrap_or_else(|| self.name())
+    }
+
+    fn quoted(&self) -> String {
+        quote_identifier(&self.column_name())
+    }
+}
+
+/// Columns of the stored fields, the ones not marked with `#[sql(skip)]`
+fn stored_columns(input: &DeriveInput) -> syn::Result<Vec<Column<'_>>> {
+    let fields = match &input.data {
+        Data::Struct(DataStruct {
+            fields: Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        Data::Struct(data) => {
+            return Err(syn::Error::new_spanned(
+                &data.fields,
+                "struct_to_sql only supports structs with named fields, \
+                 their names are the names of the columns",
+            ))
+        }
+        Data::Enum(data) => {
+            return Err(syn::Error::new_spanned(
+                data.enum_token,
+                "struct_to_sql only supports structs, not enums",
+            ))
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new_spanned(
+                data.union_token,
+                "struct_to_sql only supports structs, not unions",
+            ))
+        }
+    };
+    let mut columns = Vec::new();
     for field in fields {
-        let field_name = &field.ident;
+        let options = FieldOptions::from_field(field)?;
+        if options.skip {
+            continue;
+        }
+        let sql_type = SqlTypes::from_field(field, &options)?;
+        columns.push(Column {
+            field,
+            ident: field
+                .ident
+                .as_ref()
+                .expect("named fields have an identifier"),
+            options,
+            sql_type,
+        });
+    }
+    validate_columns(&columns, &input.ident)?;
+    Ok(columns)
+}
 
-        if *field_name.as_ref().unwrap() != "uuid" {
-            fields_sql.push(format!(
-                "{} = :{}",
-                field_name.as_ref().unwrap(),
-                field_name.as_ref().unwrap()
+/// Ensure that the columns have a primary key, and a valid version if any
+fn validate_columns(columns: &[Column], struct_name: &Ident) -> syn::Result<()> {
+    if primary_key_fields(columns).is_empty() {
+        return Err(syn::Error::new_spanned(
+            struct_name,
+            "no primary key, mark the key fields with `#[sql(primary_key)]` \
+             or name the key field `uuid`",
+        ));
+    }
+    let mut versions = columns.iter().filter(|column| column.options.version);
+    if let (Some(_), Some(second)) = (versions.next(), versions.next()) {
+        return Err(syn::Error::new_spanned(
+            second.field,
+            "only one field can be marked with `#[sql(version)]`",
+        ));
+    }
+    if let Some(version) = version_field(columns) {
+        if option_inner(&version.field.ty).is_some()
+            || !matches!(version.sql_type, SqlTypes::Integer | SqlTypes::BigInt)
+        {
+            return Err(syn::Error::new_spanned(
+                &version.field.ty,
+                "the `#[sql(version)]` field must be an `i16`, `i32` or `i64`",
             ));
         }
     }
+    Ok(())
+}
+
+/// Fields of the primary key: the fields marked with `#[sql(primary_key)]`,
+/// or the `uuid` field if none is marked
+fn primary_key_fields<'a, 'b>(columns: &'b [Column<'a>]) -> Vec<&'b Column<'a>> {
+    let marked: Vec<&Column> = columns
+        .iter()
+        .filter(|column| column.options.primary_key)
+        .collect();
+    if !marked.is_empty() {
+        return marked;
+    }
+    columns
+        .iter()
+        .filter(|column| column.ident == "uuid")
+        .collect()
+}
+
+/// Prepared condition matching the primary key
+fn primary_key_condition(columns: &[Column]) -> String {
+    primary_key_fields(columns)
+        .iter()
+        .map(|column| format!("{} = :{}", column.quoted(), column.name()))
+        .collect::<Vec<String>>()
+        .join(" AND ")
+}
+
+/// The version field, if any
+fn version_field<'a, 'b>(columns: &'b [Column<'a>]) -> Option<&'b Column<'a>> {
+    columns.iter().find(|column| column.options.version)
+}
+
+/// Fields updated by an update: the ones that are neither in the key nor the version
+fn updated_fields<'a, 'b>(columns: &'b [Column<'a>]) -> Vec<&'b Column<'a>> {
+    let key = primary_key_fields(columns);
+    columns
+        .iter()
+        .filter(|column| {
+            !key.iter().any(|key| key.ident == column.ident) && !column.options.version
+        })
+        .collect()
+}
+
+/// Columns of a SELECT query, aliased to their field when they are renamed
+fn select_columns(columns: &[Column]) -> String {
+    columns
+        .iter()
+        .map(|column| match column.options.rename {
+            Some(_) => format!(
+                "{} AS {}",
+                column.quoted(),
+                quote_identifier(&column.name())
+            ),
+            None => column.quoted(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Generate UPDATE ROW query
+/// The version column, if any, is incremented and checked against the item's version
+fn update_row_query(columns: &[Column], table: &str, options: &StructOptions) -> String {
+    let mut fields_sql: Vec<String> = updated_fields(columns)
+        .iter()
+        .map(|column| format!("{} = :{}", column.quoted(), column.name()))
+        .collect();
+    let mut condition = primary_key_condition(columns);
+    if let Some(version) = version_field(columns) {
+        let column = version.quoted();
+        fields_sql.push(format!("{} = {} + 1", column, column));
+        condition.push_str(&format!(" AND {} = :{}", column, version.name()));
+    }
+    condition.push_str(&options.live());
     format!(
-        "UPDATE {} SET {} WHERE uuid = :uuid",
-        struct_name,
-        &fields_sql.join(", ")
+        "UPDATE {} SET {} WHERE {}",
+        table,
+        &fields_sql.join(", "),
+        condition
     )
 }
 
+/// Generate INSERT ... ON CONFLICT query, updating the existing row
+/// The version column, if any, is checked and incremented like in an update,
+/// and a soft deleted row is not updated
+fn upsert_row_query(columns: &[Column], table: &str, options: &StructOptions) -> String {
+    let key: Vec<String> = primary_key_fields(columns)
+        .iter()
+        .map(|column| column.quoted())
+        .collect();
+    let mut fields_sql: Vec<String> = updated_fields(columns)
+        .iter()
+        .map(|column| {
+            let column = column.quoted();
+            format!("{} = EXCLUDED.{}", column, column)
+        })
+        .collect();
+    let mut conditions = Vec::new();
+    if let Some(version) = version_field(columns) {
+        let column = version.quoted();
+        fields_sql.push(format!("{} = {}.{} + 1", column, table, column));
+        conditions.push(format!("{}.{} = EXCLUDED.{}", table, column, column));
+    }
+    if options.soft_delete {
+        let deleted = quote_identifier(DELETED_COLUMN);
+        // Touch the row when all the columns are part of the key, to count it as upserted
+        if fields_sql.is_empty() {
+            fields_sql.push(format!("{} = {}.{}", deleted, table, deleted));
+        }
+        conditions.push(format!("{}.{} IS NULL", table, deleted));
+    }
+    let action = if fields_sql.is_empty() {
+        // Nothing to update when all the columns are part of the key
+        "DO NOTHING".to_string()
+    } else if conditions.is_empty() {
+        format!("DO UPDATE SET {}", fields_sql.join(", "))
+    } else {
+        format!(
+            "DO UPDATE SET {} WHERE {}",
+            fields_sql.join(", "),
+            conditions.join(" AND ")
+        )
+    };
+    format!(
+        "{} ON CONFLICT ({}) {}",
+        insert_row_query(columns, table),
+        key.join(", "),
+        action
+    )
+}
+
+/// Generate the implementation of `HasKey`
+fn has_key_impl(columns: &[Column], input: &DeriveInput) -> proc_macro2::TokenStream {
+    let struct_name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let key_fields = primary_key_fields(columns);
+    let names = key_fields.iter().map(|column| column.name());
+    let idents = key_fields.iter().map(|column| column.ident);
+    let types = key_fields.iter().map(|column| &column.field.ty);
+
+    let (key_type, key_value) = if key_fields.len() == 1 {
+        (quote!(#(#types)*), quote!(#(self.#idents.clone())*))
+    } else {
+        (quote!((#(#types),*)), quote!((#(self.#idents.clone()),*)))
+    };
+
+    quote! {
+        impl #impl_generics HasKey for #struct_name #ty_generics #where_clause {
+            type Key = #key_type;
+
+            const KEY_FIELDS: &'static [&'static str] = &[#(#names),*];
+
+            fn key(&self) -> Self::Key {
+                #key_value
+            }
+        }
+    }
+}
+
+/// Generate the implementation of `FromRow`, decoding each column to the type of its field.
+/// The columns are read by field name, as the renamed ones are aliased in the SELECT,
+/// and the skipped fields take their default value.
+fn from_row_impl(columns: &[Column], input: &DeriveInput) -> proc_macro2::TokenStream {
+    let struct_name = &input.ident;
+    let mut generics = input.generics.clone();
+    if !input.generics.params.is_empty() {
+        let where_clause = generics.make_where_clause();
+        for column in columns {
+            let field_type = &column.field.ty;
+            where_clause
+                .predicates
+                .push(syn::parse_quote!(#field_type: serde::de::DeserializeOwned));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let idents = columns.iter().map(|column| column.ident);
+    let names = columns.iter().map(|column| column.name());
+    let field_count = match &input.data {
+        Data::Struct(data) => data.fields.len(),
+        _ => columns.len(),
+    };
+    let skipped = (field_count > columns.len()).then(|| quote!(..Default::default()));
+
+    quote! {
+        impl #impl_generics FromRow for #struct_name #ty_generics #where_clause {
+            fn from_row(row: &RdsRow) -> Result<Self, InterfaceError> {
+                Ok(Self {
+                    #(#idents: row.get(#names)?,)*
+                    #skipped
+                })
+            }
+        }
+    }
+}
+
+/// Generate the typed column handles of the model, `Model::columns().field`,
+/// each one carrying the type of the model and of its field,
+/// so that a condition can only compare a column with a value of its type
+fn columns_impl(columns: &[Column], input: &DeriveInput) -> proc_macro2::TokenStream {
+    let struct_name = &input.ident;
+    let visibility = &input.vis;
+    let columns_name = format_ident!("{}Columns", struct_name);
+    let generics = &input.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let idents: Vec<&Ident> = columns.iter().map(|column| column.ident).collect();
+    let types = columns.iter().map(|column| &column.field.ty);
+    let names = columns.iter().map(|column| column.name());
+    let doc = format!("Typed columns of [`{}`]", struct_name);
+
+    quote! {
+        #[doc = #doc]
+        #[allow(dead_code)]
+        #visibility struct #columns_name #generics #where_clause {
+            #(pub #idents: Column<#struct_name #ty_generics, #types>,)*
+        }
+
+        impl #impl_generics #struct_name #ty_generics #where_clause {
+            /// Typed columns of the model, to build conditions, orderings and projections
+            pub fn columns() -> #columns_name #ty_generics {
+                #columns_name {
+                    #(#idents: Column::new(#names),)*
+                }
+            }
+        }
+    }
+}
+
+/// Generate the relations of the model, `Model::name()`, to load the related models
+fn relations_impl(
+    columns: &[Column],
+    options: &StructOptions,
+    input: &DeriveInput,
+) -> proc_macro2::TokenStream {
+    let struct_name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let belongs_to = columns.iter().filter_map(|column| {
+        let (name, model) = column.options.belongs_to.as_ref()?;
+        let foreign_key = column.name();
+        let doc = format!(
+            "Relation to the `{}` referenced by `{}`",
+            model.to_token_stream(),
+            foreign_key
+        );
+        Some(quote! {
+            #[doc = #doc]
+            pub fn #name() -> BelongsTo<Self, #model> {
+                BelongsTo::new(#foreign_key, Box::new(<#model>::queryset()))
+            }
+        })
+    });
+    let has_many = options.has_many.iter().map(|(name, model, foreign_key)| {
+        let doc = format!(
+            "Relation to the `{}` items referencing it by `{}`",
+            model.to_token_stream(),
+            foreign_key
+        );
+        quote! {
+            #[doc = #doc]
+            pub fn #name() -> HasMany<Self, #model> {
+                HasMany::new(#foreign_key, Box::new(<#model>::queryset()))
+            }
+        }
+    });
+    let relations: Vec<proc_macro2::TokenStream> = belongs_to.chain(has_many).collect();
+    if relations.is_empty() {
+        return quote!();
+    }
+
+    quote! {
+        impl #impl_generics #struct_name #ty_generics #where_clause {
+            #(#relations)*
+        }
+    }
+}
+
 /// Generate INSERT ROW query
-fn insert_row_query(fields: &Fields, struct_name: &Ident) -> String {
+fn insert_row_query(columns: &[Column], table: &str) -> String {
     let mut fields_sql1 = Vec::new();
     let mut fields_sql2 = Vec::new();
-    for field in fields {
-        let field_name = &field.ident;
-
-        fields_sql1.push(format!("{}", field_name.as_ref().unwrap()));
-        fields_sql2.push(format!(":{}", field_name.as_ref().unwrap()));
+    for column in columns {
+        fields_sql1.push(column.quoted());
+        fields_sql2.push(format!(":{}", column.name()));
     }
     format!(
         "INSERT INTO {} ({}) VALUES ({})",
-        struct_name,
+        table,
         &fields_sql1.join(", "),
         &fields_sql2.join(", ")
     )
 }
 
-/// Generate CREATE TABLE query
-fn create_table_query(fields: &Fields, struct_name: &Ident) -> String {
+/// Referenced `table`, or `table(column)`, with quoted identifiers
+fn reference(references: &str) -> String {
+    match references.split_once('(') {
+        Some((table, column)) => format!(
+            "{} ({})",
+            quote_identifier(table.trim()),
+            quote_identifier(column.trim_end_matches(')').trim())
+        ),
+        None => quote_identifier(references.trim()),
+    }
+}
+
+/// Definition of the column of a field, with its constraints
+fn column_definition(column: &Column, dialect: &Dialect) -> String {
+    format!(
+        "{} {}",
+        column.quoted(),
+        column_type_definition(column, dialect)
+    )
+}
+
+/// Type of the column of a field, followed by its constraints
+fn column_type_definition(column: &Column, dialect: &Dialect) -> String {
+    let options = &column.options;
+    let mut definition = (dialect.column_type)(&column.sql_type).to_string();
+    if options.not_null {
+        definition.push_str(" NOT NULL");
+    }
+    if options.unique {
+        definition.push_str(" UNIQUE");
+    }
+    if let Some(default) = &options.default {
+        definition.push_str(&format!(" DEFAULT {}", default));
+    }
+    if let Some(references) = &options.references {
+        definition.push_str(&format!(" REFERENCES {}", reference(references)));
+    }
+    definition
+}
+
+/// Generate CREATE TABLE query, with the column types of a SQL dialect
+fn create_table_query(
+    columns: &[Column],
+    table: &str,
+    dialect: &Dialect,
+    options: &StructOptions,
+) -> String {
+    let mut fields_sql: Vec<String> = columns
+        .iter()
+        .map(|column| column_definition(column, dialect))
+        .collect();
+    if options.soft_delete {
+        fields_sql.push(format!(
+            "{} {}",
+            quote_identifier(DELETED_COLUMN),
+            dialect.timestamp
+        ));
+    }
+    let primary_key: Vec<String> = primary_key_fields(columns)
+        .iter()
+        .map(|column| column.quoted())
+        .collect();
+    fields_sql.push(format!("PRIMARY KEY ({})", primary_key.join(", ")));
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} ({})",
+        table,
+        &fields_sql.join(", ")
+    )
+}
+
+/// Generate the CREATE INDEX queries of the columns marked with `#[sql(index)]`
+fn create_indexes_queries(columns: &[Column], table_name: &str) -> Vec<String> {
+    columns
+        .iter()
+        .filter(|column| column.options.index)
+        .map(|column| {
+            let column = column.column_name();
+            format!(
+                "CREATE INDEX IF NOT EXISTS {} ON {} ({})",
+                quote_identifier(&format!("{}_{}_idx", table_name, column)),
+                quote_identifier(table_name),
+                quote_identifier(&column)
+            )
+        })
+        .collect()
+}
+
+/// Generate CREATE TABLE query of the history table: the primary key of the entity,
+/// and for each of its revisions the operation, the JSON images of the entity
+/// before and after it, the actor and the time of the change
+fn create_history_table_query(
+    columns: &[Column],
+    history_table: &str,
+    dialect: &Dialect,
+) -> String {
     let mut fields_sql = Vec::new();
-    for field in fields {
-        let field_name = &field.ident;
-        let sql_type = SqlTypes::from_field(field);
+    let mut primary_key = Vec::new();
+    for column in primary_key_fields(columns) {
+        let quoted = column.quoted();
 
         fields_sql.push(format!(
             "{} {}",
-            field_name.as_ref().unwrap(),
-            sql_type.to_sql_syntax()
+            quoted,
+            (dialect.column_type)(&column.sql_type)
         ));
+        primary_key.push(quoted);
+    }
+    let types = [
+        "INTEGER",
+        (dialect.column_type)(&SqlTypes::String),
+        "TEXT",
+        "TEXT",
+        (dialect.column_type)(&SqlTypes::String),
+        dialect.timestamp,
+    ];
+    for (column, sql_type) in HISTORY_COLUMNS.iter().zip(types) {
+        fields_sql.push(format!("{} {}", quote_identifier(column), sql_type));
     }
+    primary_key.push(quote_identifier("revision"));
+    fields_sql.push(format!("PRIMARY KEY ({})", primary_key.join(", ")));
     format!(
         "CREATE TABLE IF NOT EXISTS {} ({})",
-        struct_name,
+        history_table,
         &fields_sql.join(", ")
     )
 }
 
+/// Generate INSERT query of a revision in the history table
+fn insert_history_query(columns: &[Column], history_table: &str) -> (String, String) {
+    let key = primary_key_fields(columns);
+    let names: Vec<String> = key
+        .iter()
+        .map(|column| column.quoted())
+        .chain(
+            HISTORY_COLUMNS
+                .iter()
+                .map(|column| quote_identifier(column)),
+        )
+        .collect();
+    let condition: Vec<String> = key
+        .iter()
+        .map(|column| format!("{} = :{}_{{index}}", column.quoted(), column.name()))
+        .collect();
+    let next_revision = format!(
+        "(SELECT COALESCE(MAX({}), 0) + 1 FROM {} WHERE {})",
+        quote_identifier("revision"),
+        history_table,
+        condition.join(" AND ")
+    );
+    let values: Vec<String> = key
+        .iter()
+        .map(|column| format!(":{}_{{index}}", column.name()))
+        .chain([next_revision])
+        .chain(
+            HISTORY_COLUMNS[1..]
+                .iter()
+                .map(|column| format!(":{}_{{index}}", column)),
+        )
+        .collect();
+    (
+        format!(
+            "INSERT INTO {} ({}) VALUES ",
+            history_table,
+            names.join(", ")
+        ),
+        format!("({})", values.join(", ")),
+    )
+}
+
+/// The struct without the `#[sql(...)]` attributes, which are only read by the macro
+fn without_sql_attributes(input: &DeriveInput) -> DeriveInput {
+    let is_sql = |attr: &Attribute| attr.path().is_ident("sql");
+    let mut definition = input.clone();
+    definition.attrs.retain(|attr| !is_sql(attr));
+    if let Data::Struct(data) = &mut definition.data {
+        for field in data.fields.iter_mut() {
+            field.attrs.retain(|attr| !is_sql(attr));
+        }
+    }
+    definition
+}
+
 /// Generate SqlParameters from the fields
-fn fields_as_params(fields: &Fields) -> Vec<proc_macro2::TokenStream> {
+fn fields_as_params(columns: &[Column]) -> Vec<proc_macro2::TokenStream> {
     let mut fields_params = Vec::new();
 
-    for field in fields {
-        let field_name = &field.ident.as_ref().unwrap();
-        let field_name_as_string = field_name.to_owned().to_string();
-        let sql_type = SqlTypes::from_field(field);
-
-        // let value = match sql_type {
-        //     Sq
-        // };
-        let value = sql_type.to_awsdata(field_name);
+    for column in columns {
+        let field_name = column.ident;
+        let field_name_as_string = column.name();
+        let sql_type = &column.sql_type;
+
+        let value = match option_inner(&column.field.ty) {
+            Some(_) => {
+                let value = sql_type.to_awsdata(quote!(value));
+                quote! {
+                    match &self.#field_name {
+                        Some(value) => #value,
+                        None => aws_sdk_rdsdata::types::Field::IsNull(true),
+                    }
+                }
+            }
+            None => sql_type.to_awsdata(quote!((&self.#field_name))),
+        };
         let type_hint = sql_type.to_typehint();
 
         fields_params.push(quote! {
@@ -143,96 +1054,390 @@ fn fields_as_params(fields: &Fields) -> Vec<proc_macro2::TokenStream> {
 #[proc_macro_attribute]
 /// The macro attribute `struct_to_sql` enriches a struct
 /// to dynamically create sql queries
-pub fn struct_to_sql(_metadata: TokenStream, item: TokenStream) -> TokenStream {
+///
+/// With `#[struct_to_sql(soft_delete)]` deleted rows are kept, marked as deleted,
+/// and hidden from the queries. With `#[struct_to_sql(audit)]` the queries of a
+/// history table recording every mutation are generated.
+///
+/// Fields may be strings, integers, booleans, floats, UUIDs, `chrono` or `time`
+/// dates and timestamps, decimals, `Vec<u8>`, JSON values, or an `Option` of one
+/// of them for a nullable column. `#[sql(json)]` stores any serializable field as
+/// JSON, and `#[sql(enum)]` stores a string-backed enum as its serialized variant.
+/// Dates, decimals and enums are sent as their serialized strings, which their
+/// types must read back, e.g. `time` timestamps serialized as RFC 3339.
+///
+/// The table is named after the struct in snake_case, unless a `#[sql(table = "name")]`
+/// attribute follows `#[struct_to_sql]`. The columns are named after the fields,
+/// and their `#[sql(...)]` attributes describe the schema:
+/// - `rename = "column"`: name of the column, the rows are still read by field name
+/// - `skip`: the field is not stored, it must deserialize without its column,
+///   and takes its default value in the rows of the Data API
+/// - `primary_key`, `version`: see `HasKey` and the optimistic concurrency control
+/// - `unique`, `not_null`, `default = "SQL expression"`: column constraints
+/// - `index`: the column has its own index, created with the table
+/// - `references = "table"` or `references = "table(column)"`: foreign key
+/// - `belongs_to(name = "Model")`: foreign key to the table of `Model` in snake_case,
+///   unless it `references` another one, indexed, and `Self::name()` is the relation
+///   to the referenced model, a `BelongsTo`
+///
+/// `#[sql(has_many(name = "Model(field)"))]` on the struct declares the models
+/// referencing it by their foreign key `field`: `Self::name()` is the relation, a `HasMany`.
+/// The relation types must be in scope, like the traits.
+///
+/// All the identifiers are quoted in the generated queries.
+///
+/// The rows of the Data API are decoded by the generated `FromRow`, column by column,
+/// with an error naming the column that does not decode to the type of its field.
+///
+/// `Model::columns()` holds a typed `Column` per stored field, to build conditions,
+/// orderings and projections, e.g. `Customer::columns().balance.gt(100)`: a column
+/// is only compared with values of the type of its field, and only in the queries
+/// of its model. The `Column` type must be in scope, like the traits.
+///
+/// The struct is left as it is written, with its attributes, visibility and generics,
+/// only the `#[sql(...)]` attributes are removed. The generated queryset has the
+/// visibility of the struct. A field of a generic type must be marked `#[sql(json)]`,
+/// and its type parameter bounded by `serde::Serialize`.
+pub fn struct_to_sql(metadata: TokenStream, item: TokenStream) -> TokenStream {
+    let mut options = StructOptions::default();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("soft_delete") {
+            options.soft_delete = true;
+            Ok(())
+        } else if meta.path.is_ident("audit") {
+            options.audit = true;
+            Ok(())
+        } else {
+            Err(meta.error("unsupported struct_to_sql argument, expected `soft_delete` or `audit`"))
+        }
+    });
+    parse_macro_input!(metadata with parser);
+
     let input = parse_macro_input!(item as DeriveInput);
-    let struct_name = &input.ident; // The name of the struct will be used a the name of the table
+    match expand(options, &input) {
+        Ok(expanded) => expanded.into(),
+        // Keep the struct, so that the error is the only one reported
+        Err(error) => {
+            let definition = without_sql_attributes(&input);
+            let error = error.to_compile_error();
+            quote!(#definition #error).into()
+        }
+    }
+}
 
-    // Clone the fields
-    let fields = match input.data {
-        Data::Struct(ref data) => &data.fields,
-        _ => unimplemented!("Only structs are supported"),
-    };
+/// Generate the struct, its queryset and its implementations
+fn expand(
+    mut options: StructOptions,
+    input: &DeriveInput,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let struct_name = &input.ident;
+    let visibility = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    options.parse_attributes(&input.attrs)?;
+    let columns = &stored_columns(input)?[..];
 
-    // Generate the fields for the new struct
-    let field_defs = fields.iter().map(|field: &Field| {
-        let field_name = &field.ident;
-        let field_type = &field.ty;
-        quote! {
-            pub #field_name: #field_type,
-        }
-    });
+    // The struct is emitted as it is written, without the `#[sql(...)]` attributes
+    let definition = without_sql_attributes(input);
+
+    // The table is named after the struct, unless it is renamed
+    let table_name = options
+        .table
+        .clone()
+        .unwrap_or_else(|| snake_case(&struct_name.to_string()));
+    let table = quote_identifier(&table_name);
 
     // Create table query
-    let create_table_sql = create_table_query(fields, struct_name);
+    let create_table_sql = create_table_query(columns, &table, &POSTGRES, &options);
+    let create_table_sqlite_sql = create_table_query(columns, &table, &SQLITE, &options);
+    let create_indexes_sql = create_indexes_queries(columns, &table_name);
 
     // Insert row query
-    let insert_row_sql = insert_row_query(fields, struct_name);
+    let insert_row_sql = insert_row_query(columns, &table);
 
     // Update row query
-    let update_row_sql = update_row_query(fields, struct_name);
+    let update_row_sql = update_row_query(columns, &table, &options);
+
+    // Upsert row query
+    let upsert_row_sql = upsert_row_query(columns, &table, &options);
+
+    // Primary key
+    let primary_key = primary_key_fields(columns).into_iter().map(Column::name);
+    let primary_key_sql = primary_key_condition(columns);
+    let has_key = has_key_impl(columns, input);
+    let from_row = from_row_impl(columns, input);
+    let typed_columns = columns_impl(columns, input);
+    let relations = relations_impl(columns, &options, input);
+
+    // Version field
+    let version = match version_field(columns) {
+        Some(version) => {
+            let version = version.name();
+            quote!(Some(#version.to_string()))
+        }
+        None => quote!(None),
+    };
+
+    // Columns read by the queries, named after their fields
+    let select = select_columns(columns);
+    let select_from = format!("SELECT {} FROM {}", select, table);
+
+    // Soft delete: the deleted rows are marked, and filtered out of the queries
+    let live = options.live();
+    let live_key_sql = format!("{}{}", primary_key_sql, live);
+    let (deleted_column, scoped, delete_from, delete_by_key_sql) = if options.soft_delete {
+        let mark = format!(
+            "UPDATE {} SET {} = :{} WHERE",
+            table,
+            quote_identifier(DELETED_COLUMN),
+            DELETED_COLUMN
+        );
+        let deleted = quote_identifier(DELETED_COLUMN);
+        (
+            quote!(Some(#DELETED_COLUMN.to_string())),
+            quote!(format!("{} IS NULL AND ({})", #deleted, condition)),
+            mark.clone(),
+            format!("{} {}", mark, live_key_sql),
+        )
+    } else {
+        let delete_from = format!("DELETE FROM {} WHERE", table);
+        (
+            quote!(None),
+            quote!(condition),
+            delete_from.clone(),
+            format!("{} {}", delete_from, primary_key_sql),
+        )
+    };
+    let list_sql = match options.soft_delete {
+        true => format!(
+            "{} WHERE {} IS NULL",
+            select_from,
+            quote_identifier(DELETED_COLUMN)
+        ),
+        false => select_from.clone(),
+    };
+
+    // Columns of the table, with their definition, to migrate an existing table
+    let mut definitions: Vec<(String, String)> = columns
+        .iter()
+        .map(|column| {
+            (
+                column.column_name(),
+                column_type_definition(column, &POSTGRES),
+            )
+        })
+        .collect();
+    if options.soft_delete {
+        definitions.push((DELETED_COLUMN.to_string(), POSTGRES.timestamp.to_string()));
+    }
+    let (definition_columns, definition_types): (Vec<String>, Vec<String>) =
+        definitions.into_iter().unzip();
+
+    // Mapping of the fields to their quoted columns
+    let column_fields = columns.iter().map(Column::name);
+    let quoted_columns = columns.iter().map(Column::quoted);
+
+    // History table
+    let history_table_name = format!("{}_history", table_name);
+    let history_table_sql = quote_identifier(&history_table_name);
+    let history_table = match options.audit {
+        true => quote!(Some(#history_table_name.to_string())),
+        false => quote!(None),
+    };
+    let create_history_table_sql =
+        create_history_table_query(columns, &history_table_sql, &POSTGRES);
+    let create_history_table_sqlite_sql =
+        create_history_table_query(columns, &history_table_sql, &SQLITE);
+    let (insert_history_sql, history_row_sql) = insert_history_query(columns, &history_table_sql);
+    let list_history_sql = format!(
+        "SELECT * FROM {} WHERE {} ORDER BY {}",
+        history_table_sql,
+        primary_key_sql,
+        quote_identifier("revision")
+    );
 
     // Fiels as params
-    let fap = fields_as_params(fields);
+    let fap = fields_as_params(columns);
+
+    // Field names
+    let names = columns.iter().map(Column::name);
 
     // Generate methods for the new struct
     let mut methods = Vec::new();
     methods.push(quote!(
-            /// The name of the struct is the name of the table
-            fn table(&self) -> String {
-                stringify!(#struct_name).to_string()
-            }
+        /// Name of the table, unquoted
+        fn table(&self) -> String {
+            #table_name.to_string()
+        }
 
-            /// SQL query to create a new table
-            fn create_table(&self) -> String {
-                #create_table_sql.to_string()
-            }
+        /// SQL query to create a new table
+        fn create_table(&self) -> String {
+            #create_table_sql.to_string()
+        }
 
-            /// SQL query to drop a table
-            fn drop_table(&self) -> String {
-                format!("DROP TABLE IF EXISTS {}", stringify!(#struct_name)).to_string()
-            }
+        /// SQL query to create a new table in a SQLite database
+        fn create_table_sqlite(&self) -> String {
+            #create_table_sqlite_sql.to_string()
+        }
 
-            /// SQL query to delete an object by field (prepared)
-            fn delete(&self, field_name: &str) -> String {
-                format!("DELETE FROM {} WHERE {} = :{}", stringify!(#struct_name), field_name, field_name).to_string()
-            }
+        /// SQL queries to create the indexes of the table, after the table
+        fn create_indexes(&self) -> Vec<String> {
+            vec![#(#create_indexes_sql.to_string()),*]
+        }
 
-            /// SQL query to get an object by field (prepared)
-            fn get(&self, field_name: &str) -> String{
-                format!("SELECT * FROM {} WHERE {} = :{}", stringify!(#struct_name), field_name, field_name).to_string()
-            }
+        /// Columns of the table with their PostgreSQL type and constraints
+        fn column_definitions(&self) -> Vec<(String, String)> {
+            vec![#((#definition_columns.to_string(), #definition_types.to_string())),*]
+        }
 
-            /// SQL query to create an object (prepared)
-            fn create(&self) -> String{
-                #insert_row_sql.to_string()
-            }
+        /// SQL query to drop a table
+        fn drop_table(&self) -> String {
+            format!("DROP TABLE IF EXISTS {}", #table)
+        }
 
-            /// SQL query to update an object (prepared)
-            fn update(&self) -> String {
-                #update_row_sql.to_string()
-            }
+        /// SQL query to delete an object by field (prepared)
+        fn delete(&self, field_name: &str) -> String {
+            format!("{} {} = :{}{}", #delete_from, self.column(field_name), field_name, #live)
+        }
+
+        /// SQL query to get an object by field (prepared)
+        fn get(&self, field_name: &str) -> String{
+            format!("{} WHERE {} = :{}{}", #select_from, self.column(field_name), field_name, #live)
+        }
+
+        /// SQL query to create an object (prepared)
+        fn create(&self) -> String{
+            #insert_row_sql.to_string()
+        }
+
+        /// SQL query to update an object (prepared)
+        fn update(&self) -> String {
+            #update_row_sql.to_string()
+        }
+
+        /// SQL query to create an object, or update it if its key exists (prepared)
+        fn upsert(&self) -> String {
+            #upsert_row_sql.to_string()
+        }
 
-            /// SQL query to list all items
-            fn list(&self) -> String {
-                format!("SELECT * FROM {}", stringify!(#struct_name)).to_string()
+        /// SQL query to list all items
+        fn list(&self) -> String {
+            #list_sql.to_string()
+        }
+
+        /// Names of the stored fields
+        fn columns(&self) -> Vec<String> {
+            vec![#(#names.to_string()),*]
+        }
+
+        /// Quoted column of a field, to use in a condition or an ordering
+        fn column(&self, field_name: &str) -> String {
+            match field_name {
+                #(#column_fields => #quoted_columns.to_string(),)*
+                field_name => format!("\"{}\"", field_name.replace('"', "\"\"")),
             }
+        }
 
+        /// SQL query to list the items matching a condition (prepared)
+        fn list_where(&self, condition: &str) -> String {
+            format!("{} WHERE {}", #select_from, #scoped)
+        }
 
-        ));
+        /// SQL query to read some fields of the items matching a condition (prepared)
+        fn select_where(&self, fields: &[&str], condition: &str) -> String {
+            let columns: Vec<String> = fields
+                .iter()
+                .map(|field| format!("{} AS \"{}\"", self.column(field), field.replace('"', "\"\"")))
+                .collect();
+            format!("SELECT {} FROM {} WHERE {}", columns.join(", "), #table, #scoped)
+        }
+
+        /// Fields of the primary key
+        fn primary_key(&self) -> Vec<String> {
+            vec![#(#primary_key.to_string()),*]
+        }
+
+        /// SQL query to get an object by primary key (prepared)
+        fn get_by_key(&self) -> String {
+            format!("{} WHERE {}", #select_from, #live_key_sql)
+        }
+
+        /// SQL query to delete an object by primary key (prepared)
+        fn delete_by_key(&self) -> String {
+            #delete_by_key_sql.to_string()
+        }
+
+        /// SQL query to check whether an object exists by primary key (prepared)
+        fn exists(&self) -> String {
+            format!("SELECT EXISTS (SELECT 1 FROM {} WHERE {}) AS found", #table, #live_key_sql)
+        }
+
+        /// SQL query to count the items matching a condition (prepared)
+        fn count(&self, condition: &str) -> String {
+            format!("SELECT COUNT(*) AS count FROM {} WHERE {}", #table, #scoped)
+        }
+
+        /// Name of the version field, if the table uses optimistic concurrency control
+        fn version(&self) -> Option<String> {
+            #version
+        }
+
+        /// SQL query to list an ordered page of the items matching a condition (prepared)
+        fn list_page(&self, condition: &str, order_by: &str, limit: u32) -> String {
+            format!(
+                "{} WHERE {} ORDER BY {} LIMIT {}",
+                #select_from, #scoped, order_by, limit
+            )
+        }
+
+        /// Name of the column marking the soft deleted rows, if deletions are soft
+        fn deleted_column(&self) -> Option<String> {
+            #deleted_column
+        }
+
+        /// Name of the history table, if the mutations are audited
+        fn history_table(&self) -> Option<String> {
+            #history_table
+        }
+
+        /// SQL query to create the history table
+        fn create_history_table(&self) -> String {
+            #create_history_table_sql.to_string()
+        }
+
+        /// SQL query to create the history table in a SQLite database
+        fn create_history_table_sqlite(&self) -> String {
+            #create_history_table_sqlite_sql.to_string()
+        }
+
+        /// SQL query to drop the history table
+        fn drop_history_table(&self) -> String {
+            format!("DROP TABLE IF EXISTS {}", #history_table_sql)
+        }
+
+        /// SQL query to record the next revision of `count` objects (prepared), the
+        /// parameters of the i-th one suffixed by `_i`
+        fn insert_history(&self, count: usize) -> String {
+            let rows: Vec<String> = (0..count)
+                .map(|index| #history_row_sql.replace("{index}", &index.to_string()))
+                .collect();
+            format!("{}{}", #insert_history_sql, rows.join(", "))
+        }
+
+        /// SQL query to list the revisions of an object, oldest first (prepared)
+        fn list_history(&self) -> String {
+            #list_history_sql.to_string()
+        }
+    ));
 
     let queryset_name = Ident::new(
         format!("{}QuerySet", struct_name).as_str(),
         proc_macro2::Span::call_site(),
     );
-    quote! {
-        // Don't modify the struct's fields
-        #[derive(Clone, Debug, Default)]
-        pub struct #struct_name {
-            #(#field_defs)*
-        }
+    Ok(quote! {
+        #definition
 
         // Define a queryset for the model
-        pub struct #queryset_name<#struct_name> {
+        #visibility struct #queryset_name<#struct_name> {
             _struct: std::marker::PhantomData<#struct_name>
         }
 
@@ -243,17 +1448,25 @@ pub fn struct_to_sql(_metadata: TokenStream, item: TokenStream) -> TokenStream {
 
         // Define a constructor for a query set
         // from the model
-        impl #struct_name {
-            pub fn queryset() -> #queryset_name<#struct_name> {
+        impl #impl_generics #struct_name #ty_generics #where_clause {
+            pub fn queryset() -> #queryset_name<Self> {
                 #queryset_name {
                     _struct: std::marker::PhantomData
                 }
             }
         }
 
+        #has_key
+
+        #from_row
+
+        #typed_columns
+
+        #relations
+
         /// Build a Vec<SqlParameter> to use in ExecuteStatementBuilder::set_parameters.
         /// from an items fields
-        impl GetFieldsAsParams for #struct_name {
+        impl #impl_generics GetFieldsAsParams for #struct_name #ty_generics #where_clause {
 
             fn get_fields_as_params(&self) -> Option<Vec<aws_sdk_rdsdata::types::SqlParameter>> {
                 Some(vec![
@@ -262,17 +1475,5 @@ pub fn struct_to_sql(_metadata: TokenStream, item: TokenStream) -> TokenStream {
             }
         }
 
-        // /// Get the primary key as a param
-        // fn get_uuid_as_param(&self) -> Option<aws_sdk_rdsdata::types::SqlParameter>{
-        //     Some(
-        //         aws_sdk_rdsdata::types::SqlParameter::builder()
-        //             .name("uuid".to_string())
-        //             .value(aws_sdk_rdsdata::types::Field::StringValue(self.uuid.to_string().clone()))
-        //             .build()
-        //     )
-        // }
-
-
-    }
-    .into()
+    })
 }
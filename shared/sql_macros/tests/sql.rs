@@ -1,17 +1,29 @@
 use std::collections::HashMap;
+use std::marker::PhantomData;
 
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use rust_decimal::Decimal;
 use sql_macros::struct_to_sql;
-// use std::marker::PhantomData;
 use uuid::Uuid;
 
 /// Redefining the trait here for testing
 trait QuerySet<T> {
-    /// Table name
+    /// Table name, unquoted
     fn table(&self) -> String;
 
     /// SQL query to create a new table
     fn create_table(&self) -> String;
 
+    /// SQL query to create a new table in a SQLite database
+    fn create_table_sqlite(&self) -> String;
+
+    /// SQL queries to create the indexes of the table, after the table
+    fn create_indexes(&self) -> Vec<String>;
+
+    /// Columns of the table with their PostgreSQL type and constraints,
+    /// e.g. `("pan", "VARCHAR(255) NOT NULL UNIQUE")`
+    fn column_definitions(&self) -> Vec<(String, String)>;
+
     /// SQL query to drop a table
     fn drop_table(&self) -> String;
 
@@ -27,8 +39,66 @@ trait QuerySet<T> {
     /// SQL query to update an object (prepared)
     fn update(&self) -> String;
 
+    /// SQL query to create an object, or update it if its key exists (prepared)
+    fn upsert(&self) -> String;
+
+    /// Fields of the primary key
+    fn primary_key(&self) -> Vec<String>;
+
+    /// SQL query to get an object by primary key (prepared)
+    fn get_by_key(&self) -> String;
+
+    /// SQL query to delete an object by primary key (prepared)
+    fn delete_by_key(&self) -> String;
+
+    /// SQL query to check whether an object exists by primary key (prepared)
+    fn exists(&self) -> String;
+
+    /// SQL query to count the items matching a condition (prepared)
+    fn count(&self, condition: &str) -> String;
+
+    /// Name of the version field, if the table uses optimistic concurrency control
+    fn version(&self) -> Option<String>;
+
     /// SQL query to list all items
     fn list(&self) -> String;
+
+    /// Names of the stored fields
+    fn columns(&self) -> Vec<String>;
+
+    /// Quoted column of a field, to use in a condition or an ordering
+    fn column(&self, field_name: &str) -> String;
+
+    /// SQL query to list the items matching a condition (prepared)
+    fn list_where(&self, condition: &str) -> String;
+
+    /// SQL query to read some fields of the items matching a condition (prepared)
+    fn select_where(&self, fields: &[&str], condition: &str) -> String;
+
+    /// SQL query to list an ordered page of the items matching a condition (prepared)
+    fn list_page(&self, condition: &str, order_by: &str, limit: u32) -> String;
+
+    /// Name of the column marking the soft deleted rows, if deletions are soft
+    fn deleted_column(&self) -> Option<String>;
+
+    /// Name of the history table, if the mutations are audited
+    fn history_table(&self) -> Option<String>;
+
+    /// SQL query to create the history table
+    fn create_history_table(&self) -> String;
+
+    /// SQL query to create the history table in a SQLite database
+    fn create_history_table_sqlite(&self) -> String;
+
+    /// SQL query to drop the history table
+    fn drop_history_table(&self) -> String;
+
+    /// SQL query to record the next revision of `count` objects (prepared), the
+    /// parameters of the i-th one suffixed by `_i`
+    fn insert_history(&self, count: usize) -> String;
+
+    /// SQL query to list the revisions of an object, oldest first (prepared)
+    fn list_history(&self) -> String;
 }
 
 /// Build a Vec<SqlParameter> to use in ExecuteStatementBuilder::set_parameters.
@@ -37,6 +107,97 @@ trait GetFieldsAsParams {
     fn get_fields_as_params(&self) -> Option<Vec<aws_sdk_rdsdata::types::SqlParameter>>;
 }
 
+/// Entity identified by a primary key
+trait HasKey {
+    type Key;
+
+    /// Names of the fields forming the primary key
+    const KEY_FIELDS: &'static [&'static str];
+
+    fn key(&self) -> Self::Key;
+}
+
+/// Stand-in for the error of the repositories
+#[derive(Debug, PartialEq)]
+enum InterfaceError {
+    FromFields(String),
+}
+
+/// Stand-in for a record of the Data API, holding the decoded value of each column
+struct RdsRow {
+    values: HashMap<String, serde_json::Value>,
+}
+
+impl RdsRow {
+    fn get<V: serde::de::DeserializeOwned>(&self, column: &str) -> Result<V, InterfaceError> {
+        let value = self
+            .values
+            .get(column)
+            .ok_or_else(|| InterfaceError::FromFields(format!("Missing column: {column}")))?;
+        serde_json::from_value(value.clone())
+            .map_err(|e| InterfaceError::FromFields(format!("{column}: {e}")))
+    }
+}
+
+/// Decode an item from a record of the Data API, field by field
+trait FromRow: Sized {
+    fn from_row(row: &RdsRow) -> Result<Self, InterfaceError>;
+}
+
+/// Stand-in for the typed column handle of a model field
+struct Column<T, V> {
+    field: &'static str,
+    _marker: PhantomData<fn() -> (T, V)>,
+}
+
+impl<T, V> Column<T, V> {
+    const fn new(field: &'static str) -> Self {
+        Column {
+            field,
+            _marker: PhantomData,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.field
+    }
+}
+
+/// Stand-in for the relation to a referenced model
+struct BelongsTo<T, U> {
+    foreign_key: &'static str,
+    parent: Box<dyn QuerySet<U>>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, U> BelongsTo<T, U> {
+    fn new(foreign_key: &'static str, parent: Box<dyn QuerySet<U>>) -> Self {
+        BelongsTo {
+            foreign_key,
+            parent,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Stand-in for the relation to the referencing models
+struct HasMany<T, U> {
+    foreign_key: &'static str,
+    children: Box<dyn QuerySet<U>>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, U> HasMany<T, U> {
+    fn new(foreign_key: &'static str, children: Box<dyn QuerySet<U>>) -> Self {
+        HasMany {
+            foreign_key,
+            children,
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 #[struct_to_sql]
 struct BaseModel {
     name: String,
@@ -58,33 +219,81 @@ fn test_base() {
     assert_eq!(item.name, "abc".to_string());
     assert_eq!(item.id, 5);
 
-    assert_eq!(queryset.table(), "BaseModel".to_string());
+    assert_eq!(queryset.table(), "base_model".to_string());
     assert_eq!(
         queryset.create_table(),
-        "CREATE TABLE IF NOT EXISTS BaseModel (name VARCHAR(255), id INTEGER, uuid UUID)"
+        r#"CREATE TABLE IF NOT EXISTS "base_model" ("name" VARCHAR(255), "id" INTEGER, "uuid" UUID, PRIMARY KEY ("uuid"))"#
             .to_string()
     );
+    assert_eq!(
+        queryset.create_table_sqlite(),
+        r#"CREATE TABLE IF NOT EXISTS "base_model" ("name" TEXT, "id" INTEGER, "uuid" TEXT, PRIMARY KEY ("uuid"))"#.to_string()
+    );
+    assert_eq!(queryset.create_indexes(), Vec::<String>::new());
     assert_eq!(
         queryset.drop_table(),
-        "DROP TABLE IF EXISTS BaseModel".to_string()
+        r#"DROP TABLE IF EXISTS "base_model""#.to_string()
     );
     assert_eq!(
         queryset.delete("id"),
-        "DELETE FROM BaseModel WHERE id = :id".to_string()
+        r#"DELETE FROM "base_model" WHERE "id" = :id"#.to_string()
     );
     assert_eq!(
         queryset.get("id"),
-        "SELECT * FROM BaseModel WHERE id = :id".to_string()
+        r#"SELECT "name", "id", "uuid" FROM "base_model" WHERE "id" = :id"#.to_string()
+    );
+    assert_eq!(
+        queryset.list(),
+        r#"SELECT "name", "id", "uuid" FROM "base_model""#.to_string()
+    );
+    assert_eq!(
+        queryset.columns(),
+        vec!["name".to_string(), "id".to_string(), "uuid".to_string()]
+    );
+    assert_eq!(queryset.column("id"), r#""id""#.to_string());
+    assert_eq!(
+        queryset.list_where(r#""id" > :p0"#),
+        r#"SELECT "name", "id", "uuid" FROM "base_model" WHERE "id" > :p0"#.to_string()
+    );
+    assert_eq!(
+        queryset.list_page(r#""id" > :p0"#, r#""name" ASC, "uuid" ASC"#, 11),
+        r#"SELECT "name", "id", "uuid" FROM "base_model" WHERE "id" > :p0 ORDER BY "name" ASC, "uuid" ASC LIMIT 11"#.to_string()
     );
-    assert_eq!(queryset.list(), r#"SELECT * FROM BaseModel"#.to_string());
     assert_eq!(
         queryset.create(),
-        r#"INSERT INTO BaseModel (name, id, uuid) VALUES (:name, :id, :uuid)"#.to_string()
+        r#"INSERT INTO "base_model" ("name", "id", "uuid") VALUES (:name, :id, :uuid)"#.to_string()
     );
     assert_eq!(
         queryset.update(),
-        r#"UPDATE BaseModel SET name = :name, id = :id WHERE uuid = :uuid"#.to_string()
+        r#"UPDATE "base_model" SET "name" = :name, "id" = :id WHERE "uuid" = :uuid"#.to_string()
+    );
+    assert_eq!(queryset.version(), None);
+    assert_eq!(queryset.primary_key(), vec!["uuid".to_string()]);
+    assert_eq!(
+        queryset.get_by_key(),
+        r#"SELECT "name", "id", "uuid" FROM "base_model" WHERE "uuid" = :uuid"#.to_string()
+    );
+    assert_eq!(
+        queryset.delete_by_key(),
+        r#"DELETE FROM "base_model" WHERE "uuid" = :uuid"#.to_string()
+    );
+    assert_eq!(
+        queryset.upsert(),
+        r#"INSERT INTO "base_model" ("name", "id", "uuid") VALUES (:name, :id, :uuid) ON CONFLICT ("uuid") DO UPDATE SET "name" = EXCLUDED."name", "id" = EXCLUDED."id""#
+            .to_string()
     );
+    assert_eq!(
+        queryset.exists(),
+        r#"SELECT EXISTS (SELECT 1 FROM "base_model" WHERE "uuid" = :uuid) AS found"#.to_string()
+    );
+    assert_eq!(
+        queryset.count(r#""id" > :p0"#),
+        r#"SELECT COUNT(*) AS count FROM "base_model" WHERE "id" > :p0"#.to_string()
+    );
+    assert_eq!(queryset.deleted_column(), None);
+    assert_eq!(queryset.history_table(), None);
+    assert_eq!(BaseModel::KEY_FIELDS, &["uuid"]);
+    assert_eq!(item.key(), item.uuid);
 
     let mut ground = HashMap::new();
     ground.insert("name", "abc");
@@ -93,7 +302,7 @@ fn test_base() {
     let fields_as_params = item.get_fields_as_params().unwrap();
     for param in fields_as_params {
         let name = param.name().unwrap();
-        let _ = match param.value().unwrap() {
+        match param.value().unwrap() {
             aws_sdk_rdsdata::types::Field::StringValue(s) => {
                 assert_eq!(s.to_string(), *ground.get(name).unwrap())
             }
@@ -106,33 +315,665 @@ fn test_base() {
     }
 }
 
-// This should not compile
-
-// #[struct_to_sql]
-// pub struct ModelPhantomData<T> {
-//     name: String,
-//     _unused: PhantomData<T>
-// }
-
-// #[struct_to_sql]
-// pub struct ModelUnimplemented {
-//     name: String,
-//     id: u32
-// }
-
-// #[test]
-// fn test_phantom_data() {
-//     let model = ModelPhantomData {
-//         name: "abc".to_string(),
-//         _unused: PhantomData
-//     };
-
-// }
-
-// #[test]
-// fn test_not_implemented() {
-//     let model = ModelUnimplemented {
-//         name: "abc".to_string(),
-//         id: 5,
-//     };
-// }
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql]
+struct VersionedModel {
+    uuid: Uuid,
+    name: String,
+    #[sql(version)]
+    version: i32,
+}
+
+#[test]
+fn test_versioned() {
+    use pretty_assertions::assert_eq;
+    let item = VersionedModel {
+        uuid: Uuid::new_v4(),
+        name: "abc".to_string(),
+        version: 0,
+    };
+    let queryset: VersionedModelQuerySet<VersionedModel> = VersionedModel::queryset();
+
+    assert_eq!(queryset.version(), Some("version".to_string()));
+    assert_eq!(
+        queryset.update(),
+        r#"UPDATE "versioned_model" SET "name" = :name, "version" = "version" + 1 WHERE "uuid" = :uuid AND "version" = :version"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.upsert(),
+        r#"INSERT INTO "versioned_model" ("uuid", "name", "version") VALUES (:uuid, :name, :version) ON CONFLICT ("uuid") DO UPDATE SET "name" = EXCLUDED."name", "version" = "versioned_model"."version" + 1 WHERE "versioned_model"."version" = EXCLUDED."version""#
+            .to_string()
+    );
+    assert_eq!(item.get_fields_as_params().unwrap().len(), 3);
+}
+
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql]
+struct CompositeKeyModel {
+    #[sql(primary_key)]
+    stan: String,
+    #[sql(primary_key)]
+    rrn: String,
+    amount: i32,
+}
+
+#[test]
+fn test_composite_key() {
+    use pretty_assertions::assert_eq;
+    let item = CompositeKeyModel {
+        stan: "000001".to_string(),
+        rrn: "123456789012".to_string(),
+        amount: 10,
+    };
+    let queryset: CompositeKeyModelQuerySet<CompositeKeyModel> = CompositeKeyModel::queryset();
+
+    assert_eq!(
+        queryset.primary_key(),
+        vec!["stan".to_string(), "rrn".to_string()]
+    );
+    assert_eq!(
+        queryset.create_table(),
+        r#"CREATE TABLE IF NOT EXISTS "composite_key_model" ("stan" VARCHAR(255), "rrn" VARCHAR(255), "amount" INTEGER, PRIMARY KEY ("stan", "rrn"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.get_by_key(),
+        r#"SELECT "stan", "rrn", "amount" FROM "composite_key_model" WHERE "stan" = :stan AND "rrn" = :rrn"#.to_string()
+    );
+    assert_eq!(
+        queryset.delete_by_key(),
+        r#"DELETE FROM "composite_key_model" WHERE "stan" = :stan AND "rrn" = :rrn"#.to_string()
+    );
+    assert_eq!(
+        queryset.update(),
+        r#"UPDATE "composite_key_model" SET "amount" = :amount WHERE "stan" = :stan AND "rrn" = :rrn"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.upsert(),
+        r#"INSERT INTO "composite_key_model" ("stan", "rrn", "amount") VALUES (:stan, :rrn, :amount) ON CONFLICT ("stan", "rrn") DO UPDATE SET "amount" = EXCLUDED."amount""#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.exists(),
+        r#"SELECT EXISTS (SELECT 1 FROM "composite_key_model" WHERE "stan" = :stan AND "rrn" = :rrn) AS found"#
+            .to_string()
+    );
+    assert_eq!(CompositeKeyModel::KEY_FIELDS, &["stan", "rrn"]);
+    assert_eq!(
+        item.key(),
+        ("000001".to_string(), "123456789012".to_string())
+    );
+}
+
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql(soft_delete, audit)]
+struct AuditedModel {
+    uuid: Uuid,
+    name: String,
+    #[sql(version)]
+    version: i32,
+}
+
+#[test]
+fn test_soft_delete_and_audit() {
+    use pretty_assertions::assert_eq;
+    let queryset: AuditedModelQuerySet<AuditedModel> = AuditedModel::queryset();
+
+    assert_eq!(queryset.deleted_column(), Some("deleted_at".to_string()));
+    assert_eq!(
+        queryset.column_definitions().last(),
+        Some(&("deleted_at".to_string(), "BIGINT".to_string()))
+    );
+    assert_eq!(
+        queryset.create_table(),
+        r#"CREATE TABLE IF NOT EXISTS "audited_model" ("uuid" UUID, "name" VARCHAR(255), "version" INTEGER, "deleted_at" BIGINT, PRIMARY KEY ("uuid"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.create_table_sqlite(),
+        r#"CREATE TABLE IF NOT EXISTS "audited_model" ("uuid" TEXT, "name" TEXT, "version" INTEGER, "deleted_at" INTEGER, PRIMARY KEY ("uuid"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.columns(),
+        vec![
+            "uuid".to_string(),
+            "name".to_string(),
+            "version".to_string()
+        ]
+    );
+
+    // Soft deleted rows are marked, and hidden from the queries
+    assert_eq!(
+        queryset.delete_by_key(),
+        r#"UPDATE "audited_model" SET "deleted_at" = :deleted_at WHERE "uuid" = :uuid AND "deleted_at" IS NULL"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.delete("name"),
+        r#"UPDATE "audited_model" SET "deleted_at" = :deleted_at WHERE "name" = :name AND "deleted_at" IS NULL"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.get("name"),
+        r#"SELECT "uuid", "name", "version" FROM "audited_model" WHERE "name" = :name AND "deleted_at" IS NULL"#.to_string()
+    );
+    assert_eq!(
+        queryset.get_by_key(),
+        r#"SELECT "uuid", "name", "version" FROM "audited_model" WHERE "uuid" = :uuid AND "deleted_at" IS NULL"#.to_string()
+    );
+    assert_eq!(
+        queryset.list(),
+        r#"SELECT "uuid", "name", "version" FROM "audited_model" WHERE "deleted_at" IS NULL"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.list_where(r#"("name" = :p0) OR ("name" = :p1)"#),
+        r#"SELECT "uuid", "name", "version" FROM "audited_model" WHERE "deleted_at" IS NULL AND (("name" = :p0) OR ("name" = :p1))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.count("1 = 1"),
+        r#"SELECT COUNT(*) AS count FROM "audited_model" WHERE "deleted_at" IS NULL AND (1 = 1)"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.update(),
+        r#"UPDATE "audited_model" SET "name" = :name, "version" = "version" + 1 WHERE "uuid" = :uuid AND "version" = :version AND "deleted_at" IS NULL"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.upsert(),
+        r#"INSERT INTO "audited_model" ("uuid", "name", "version") VALUES (:uuid, :name, :version) ON CONFLICT ("uuid") DO UPDATE SET "name" = EXCLUDED."name", "version" = "audited_model"."version" + 1 WHERE "audited_model"."version" = EXCLUDED."version" AND "audited_model"."deleted_at" IS NULL"#
+            .to_string()
+    );
+
+    // Every mutation is recorded in the history table
+    assert_eq!(
+        queryset.history_table(),
+        Some("audited_model_history".to_string())
+    );
+    assert_eq!(
+        queryset.create_history_table(),
+        r#"CREATE TABLE IF NOT EXISTS "audited_model_history" ("uuid" UUID, "revision" INTEGER, "operation" VARCHAR(255), "before_image" TEXT, "after_image" TEXT, "actor" VARCHAR(255), "changed_at" BIGINT, PRIMARY KEY ("uuid", "revision"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.create_history_table_sqlite(),
+        r#"CREATE TABLE IF NOT EXISTS "audited_model_history" ("uuid" TEXT, "revision" INTEGER, "operation" TEXT, "before_image" TEXT, "after_image" TEXT, "actor" TEXT, "changed_at" INTEGER, PRIMARY KEY ("uuid", "revision"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.insert_history(2),
+        r#"INSERT INTO "audited_model_history" ("uuid", "revision", "operation", "before_image", "after_image", "actor", "changed_at") VALUES (:uuid_0, (SELECT COALESCE(MAX("revision"), 0) + 1 FROM "audited_model_history" WHERE "uuid" = :uuid_0), :operation_0, :before_image_0, :after_image_0, :actor_0, :changed_at_0), (:uuid_1, (SELECT COALESCE(MAX("revision"), 0) + 1 FROM "audited_model_history" WHERE "uuid" = :uuid_1), :operation_1, :before_image_1, :after_image_1, :actor_1, :changed_at_1)"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.drop_history_table(),
+        r#"DROP TABLE IF EXISTS "audited_model_history""#.to_string()
+    );
+    assert_eq!(
+        queryset.list_history(),
+        r#"SELECT * FROM "audited_model_history" WHERE "uuid" = :uuid ORDER BY "revision""#
+            .to_string()
+    );
+}
+
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql(soft_delete)]
+struct SoftDeletedKeyModel {
+    #[sql(primary_key)]
+    stan: String,
+    #[sql(primary_key)]
+    rrn: String,
+}
+
+#[test]
+fn test_soft_delete_key_only() {
+    use pretty_assertions::assert_eq;
+    let queryset: SoftDeletedKeyModelQuerySet<SoftDeletedKeyModel> =
+        SoftDeletedKeyModel::queryset();
+
+    // A live row is touched, to be counted as upserted
+    assert_eq!(
+        queryset.upsert(),
+        r#"INSERT INTO "soft_deleted_key_model" ("stan", "rrn") VALUES (:stan, :rrn) ON CONFLICT ("stan", "rrn") DO UPDATE SET "deleted_at" = "soft_deleted_key_model"."deleted_at" WHERE "soft_deleted_key_model"."deleted_at" IS NULL"#
+            .to_string()
+    );
+    assert_eq!(queryset.history_table(), None);
+}
+
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql]
+#[sql(table = "account")]
+struct SchemaModel {
+    #[sql(primary_key, rename = "account_id")]
+    id: i64,
+    #[sql(rename = "full_name", not_null)]
+    name: String,
+    #[sql(unique, not_null)]
+    iban: String,
+    #[sql(not_null, default = "0")]
+    balance: i64,
+    #[sql(references = "customer(uuid)", index)]
+    customer_uuid: Uuid,
+    #[sql(references = "branch")]
+    branch: Option<i32>,
+    #[sql(skip)]
+    display: Option<String>,
+}
+
+#[test]
+fn test_schema() {
+    use pretty_assertions::assert_eq;
+    let item = SchemaModel {
+        id: 1,
+        name: "abc".to_string(),
+        iban: "FR76".to_string(),
+        balance: 10,
+        customer_uuid: Uuid::new_v4(),
+        branch: None,
+        display: Some("abc (FR76)".to_string()),
+    };
+    let queryset: SchemaModelQuerySet<SchemaModel> = SchemaModel::queryset();
+
+    // The table is renamed, the columns have constraints, and the skipped field is not stored
+    assert_eq!(queryset.table(), "account".to_string());
+    assert_eq!(
+        queryset.create_table(),
+        r#"CREATE TABLE IF NOT EXISTS "account" ("account_id" BIGINT, "full_name" VARCHAR(255) NOT NULL, "iban" VARCHAR(255) NOT NULL UNIQUE, "balance" BIGINT NOT NULL DEFAULT 0, "customer_uuid" UUID REFERENCES "customer" ("uuid"), "branch" INTEGER REFERENCES "branch", PRIMARY KEY ("account_id"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.create_table_sqlite(),
+        r#"CREATE TABLE IF NOT EXISTS "account" ("account_id" INTEGER, "full_name" TEXT NOT NULL, "iban" TEXT NOT NULL UNIQUE, "balance" INTEGER NOT NULL DEFAULT 0, "customer_uuid" TEXT REFERENCES "customer" ("uuid"), "branch" INTEGER REFERENCES "branch", PRIMARY KEY ("account_id"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.create_indexes(),
+        vec![
+            r#"CREATE INDEX IF NOT EXISTS "account_customer_uuid_idx" ON "account" ("customer_uuid")"#
+                .to_string()
+        ]
+    );
+
+    // The definitions of the columns are the ones of the created table
+    let definition = |column: &str, definition: &str| (column.to_string(), definition.to_string());
+    assert_eq!(
+        queryset.column_definitions(),
+        vec![
+            definition("account_id", "BIGINT"),
+            definition("full_name", "VARCHAR(255) NOT NULL"),
+            definition("iban", "VARCHAR(255) NOT NULL UNIQUE"),
+            definition("balance", "BIGINT NOT NULL DEFAULT 0"),
+            definition("customer_uuid", r#"UUID REFERENCES "customer" ("uuid")"#),
+            definition("branch", r#"INTEGER REFERENCES "branch""#),
+        ]
+    );
+
+    // Renamed columns are read back as their fields
+    assert_eq!(
+        queryset.get_by_key(),
+        r#"SELECT "account_id" AS "id", "full_name" AS "name", "iban", "balance", "customer_uuid", "branch" FROM "account" WHERE "account_id" = :id"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.update(),
+        r#"UPDATE "account" SET "full_name" = :name, "iban" = :iban, "balance" = :balance, "customer_uuid" = :customer_uuid, "branch" = :branch WHERE "account_id" = :id"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.get("name"),
+        r#"SELECT "account_id" AS "id", "full_name" AS "name", "iban", "balance", "customer_uuid", "branch" FROM "account" WHERE "full_name" = :name"#
+            .to_string()
+    );
+    assert_eq!(queryset.column("name"), r#""full_name""#.to_string());
+    assert_eq!(queryset.column("iban"), r#""iban""#.to_string());
+    assert_eq!(
+        queryset.columns(),
+        vec!["id", "name", "iban", "balance", "customer_uuid", "branch"]
+    );
+    assert_eq!(queryset.primary_key(), vec!["id".to_string()]);
+    assert_eq!(SchemaModel::KEY_FIELDS, &["id"]);
+    assert_eq!(item.get_fields_as_params().unwrap().len(), 6);
+    assert_eq!(item.display, Some("abc (FR76)".to_string()));
+}
+
+#[test]
+fn test_from_row() {
+    use pretty_assertions::assert_eq;
+
+    // GIVEN a row read by field name, the renamed columns being aliased
+    let customer_uuid = Uuid::new_v4();
+    let mut values: HashMap<String, serde_json::Value> = [
+        ("id", serde_json::json!(1)),
+        ("name", serde_json::json!("abc")),
+        ("iban", serde_json::json!("FR76")),
+        ("balance", serde_json::json!(10)),
+        ("customer_uuid", serde_json::json!(customer_uuid)),
+        ("branch", serde_json::Value::Null),
+    ]
+    .into_iter()
+    .map(|(column, value)| (column.to_string(), value))
+    .collect();
+
+    // WHEN it is decoded
+    let item = SchemaModel::from_row(&RdsRow {
+        values: values.clone(),
+    })
+    .unwrap();
+
+    // THEN each field has the value of its column, and the skipped field its default
+    assert_eq!(item.id, 1);
+    assert_eq!(item.name, "abc".to_string());
+    assert_eq!(item.iban, "FR76".to_string());
+    assert_eq!(item.balance, 10);
+    assert_eq!(item.customer_uuid, customer_uuid);
+    assert_eq!(item.branch, None);
+    assert_eq!(item.display, None);
+
+    // GIVEN a column of the wrong type
+    values.insert("balance".to_string(), serde_json::json!("ten"));
+
+    // WHEN it is decoded
+    let error = SchemaModel::from_row(&RdsRow {
+        values: values.clone(),
+    })
+    .unwrap_err();
+
+    // THEN the error names the column
+    let InterfaceError::FromFields(message) = error;
+    assert!(message.starts_with("balance: invalid type"), "{message}");
+
+    // GIVEN a missing column
+    values.remove("iban");
+
+    // WHEN it is decoded
+    let error = SchemaModel::from_row(&RdsRow { values }).unwrap_err();
+
+    // THEN the error names the column
+    assert_eq!(
+        error,
+        InterfaceError::FromFields("Missing column: iban".to_string())
+    );
+}
+
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql]
+struct HTTPServer {
+    uuid: Uuid,
+    #[sql(rename = "select")]
+    order: i32,
+}
+
+#[test]
+fn test_quoted_identifiers() {
+    use pretty_assertions::assert_eq;
+    let queryset: HTTPServerQuerySet<HTTPServer> = HTTPServer::queryset();
+
+    // Acronyms are kept together, and reserved words can be columns
+    assert_eq!(queryset.table(), "http_server".to_string());
+    assert_eq!(
+        queryset.create(),
+        r#"INSERT INTO "http_server" ("uuid", "select") VALUES (:uuid, :order)"#.to_string()
+    );
+    assert_eq!(
+        queryset.list(),
+        r#"SELECT "uuid", "select" AS "order" FROM "http_server""#.to_string()
+    );
+}
+
+#[test]
+fn test_typed_columns() {
+    use pretty_assertions::assert_eq;
+
+    // Each stored field has a column handle, named after the field
+    let columns = HTTPServer::columns();
+    assert_eq!(columns.uuid.name(), "uuid");
+    assert_eq!(columns.order.name(), "order");
+    let _: &Column<HTTPServer, i32> = &columns.order;
+
+    // The selected columns are aliased to their field
+    let queryset: HTTPServerQuerySet<HTTPServer> = HTTPServer::queryset();
+    assert_eq!(
+        queryset.select_where(&["order"], r#""select" > :p0"#),
+        r#"SELECT "select" AS "order" FROM "http_server" WHERE "select" > :p0"#.to_string()
+    );
+
+    // The soft deleted rows are not selected
+    let queryset: AuditedModelQuerySet<AuditedModel> = AuditedModel::queryset();
+    assert_eq!(
+        queryset.select_where(&["uuid", "name"], "1 = 1"),
+        r#"SELECT "uuid" AS "uuid", "name" AS "name" FROM "audited_model" WHERE "deleted_at" IS NULL AND (1 = 1)"#
+            .to_string()
+    );
+}
+
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql]
+#[sql(has_many(pets = "Pet(owner_uuid)"))]
+struct Owner {
+    uuid: Uuid,
+}
+
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql]
+struct Pet {
+    uuid: Uuid,
+    #[sql(not_null, belongs_to(owner = "Owner"))]
+    owner_uuid: Uuid,
+}
+
+#[test]
+fn test_relations() {
+    use pretty_assertions::assert_eq;
+
+    // The foreign key of a relation references the table of the model, and is indexed
+    let queryset: PetQuerySet<Pet> = Pet::queryset();
+    assert_eq!(
+        queryset.create_table(),
+        r#"CREATE TABLE IF NOT EXISTS "pet" ("uuid" UUID, "owner_uuid" UUID NOT NULL REFERENCES "owner", PRIMARY KEY ("uuid"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.create_indexes(),
+        vec![
+            r#"CREATE INDEX IF NOT EXISTS "pet_owner_uuid_idx" ON "pet" ("owner_uuid")"#
+                .to_string()
+        ]
+    );
+
+    // Each side of the relation has its method, holding the queryset of the other side
+    let owner = Pet::owner();
+    assert_eq!(owner.foreign_key, "owner_uuid");
+    assert_eq!(owner.parent.table(), "owner".to_string());
+    let pets = Owner::pets();
+    assert_eq!(pets.foreign_key, "owner_uuid");
+    assert_eq!(pets.children.table(), "pet".to_string());
+}
+
+/// The struct is kept as written: attributes, visibility and generics
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[struct_to_sql]
+pub(crate) struct GenericModel<T: serde::Serialize> {
+    #[serde(default = "uuid::Uuid::new_v4")]
+    uuid: Uuid,
+    #[sql(json)]
+    payload: T,
+}
+
+#[test]
+fn test_preserved_definition() {
+    use pretty_assertions::assert_eq;
+
+    // The serde attributes of the fields are applied
+    let item: GenericModel<Vec<i32>> = serde_json::from_str(r#"{"payload": [1, 2]}"#).unwrap();
+    assert_ne!(item.uuid, Uuid::nil());
+    assert_eq!(item.payload, vec![1, 2]);
+
+    // The generated items are generic
+    let queryset: GenericModelQuerySet<GenericModel<Vec<i32>>> = GenericModel::queryset();
+    assert_eq!(queryset.table(), "generic_model".to_string());
+    assert_eq!(item.key(), item.uuid);
+    let params = item.get_fields_as_params().unwrap();
+    assert_eq!(
+        params[1].value(),
+        Some(&aws_sdk_rdsdata::types::Field::StringValue(
+            "[1,2]".to_string()
+        ))
+    );
+    let row = RdsRow {
+        values: HashMap::from([
+            ("uuid".to_string(), serde_json::json!(item.uuid)),
+            ("payload".to_string(), serde_json::json!([3])),
+        ]),
+    };
+    let decoded: GenericModel<Vec<i32>> = GenericModel::from_row(&row).unwrap();
+    assert_eq!(decoded.payload, vec![3]);
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+enum Status {
+    #[default]
+    Active,
+    Blocked,
+}
+
+#[derive(Clone, Debug, Default)]
+#[struct_to_sql]
+struct TypedModel {
+    uuid: Uuid,
+    active: bool,
+    amount: i64,
+    rate: f64,
+    balance: Decimal,
+    birth_date: NaiveDate,
+    opened_at: NaiveDateTime,
+    updated_at: DateTime<Utc>,
+    closed_on: Option<time::Date>,
+    logo: Vec<u8>,
+    settings: serde_json::Value,
+    #[sql(json)]
+    tags: Vec<String>,
+    #[sql(enum)]
+    status: Status,
+    note: Option<String>,
+}
+
+#[test]
+fn test_types() {
+    use aws_sdk_rdsdata::primitives::Blob;
+    use aws_sdk_rdsdata::types::{Field, TypeHint};
+    use pretty_assertions::assert_eq;
+    use std::str::FromStr;
+
+    let item = TypedModel {
+        uuid: Uuid::parse_str("0829b81a-f86e-4411-870a-ca16e6b73189").unwrap(),
+        active: true,
+        amount: 1 << 40,
+        rate: 0.5,
+        balance: Decimal::from_str("12.50").unwrap(),
+        birth_date: NaiveDate::from_ymd_opt(1990, 3, 4).unwrap(),
+        opened_at: NaiveDate::from_ymd_opt(2024, 1, 2)
+            .unwrap()
+            .and_hms_opt(3, 4, 5)
+            .unwrap(),
+        updated_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        closed_on: None,
+        logo: vec![0, 255],
+        settings: serde_json::json!({"theme": "dark"}),
+        tags: vec!["vip".to_string()],
+        status: Status::Blocked,
+        note: Some("abc".to_string()),
+    };
+
+    let queryset: TypedModelQuerySet<TypedModel> = TypedModel::queryset();
+    assert_eq!(
+        queryset.create_table(),
+        r#"CREATE TABLE IF NOT EXISTS "typed_model" ("uuid" UUID, "active" BOOLEAN, "amount" BIGINT, "rate" DOUBLE PRECISION, "balance" NUMERIC, "birth_date" DATE, "opened_at" TIMESTAMP, "updated_at" TIMESTAMPTZ, "closed_on" DATE, "logo" BYTEA, "settings" JSONB, "tags" JSONB, "status" VARCHAR(255), "note" VARCHAR(255), PRIMARY KEY ("uuid"))"#
+            .to_string()
+    );
+    assert_eq!(
+        queryset.create_table_sqlite(),
+        r#"CREATE TABLE IF NOT EXISTS "typed_model" ("uuid" TEXT, "active" BOOLEAN, "amount" INTEGER, "rate" REAL, "balance" TEXT, "birth_date" TEXT, "opened_at" TEXT, "updated_at" TEXT, "closed_on" TEXT, "logo" BLOB, "settings" JSON, "tags" JSON, "status" TEXT, "note" TEXT, PRIMARY KEY ("uuid"))"#
+            .to_string()
+    );
+
+    let params: Vec<(String, Field, Option<TypeHint>)> = item
+        .get_fields_as_params()
+        .unwrap()
+        .into_iter()
+        .map(|param| {
+            (
+                param.name().unwrap().to_string(),
+                param.value().unwrap().clone(),
+                param.type_hint().cloned(),
+            )
+        })
+        .collect();
+    let string = |s: &str| Field::StringValue(s.to_string());
+    assert_eq!(
+        params,
+        vec![
+            (
+                "uuid".to_string(),
+                string("0829b81a-f86e-4411-870a-ca16e6b73189"),
+                Some(TypeHint::Uuid)
+            ),
+            ("active".to_string(), Field::BooleanValue(true), None),
+            ("amount".to_string(), Field::LongValue(1 << 40), None),
+            ("rate".to_string(), Field::DoubleValue(0.5), None),
+            (
+                "balance".to_string(),
+                string("12.50"),
+                Some(TypeHint::Decimal)
+            ),
+            (
+                "birth_date".to_string(),
+                string("1990-03-04"),
+                Some(TypeHint::Date)
+            ),
+            (
+                "opened_at".to_string(),
+                string("2024-01-02T03:04:05"),
+                Some(TypeHint::Timestamp)
+            ),
+            (
+                "updated_at".to_string(),
+                string("2023-11-14T22:13:20Z"),
+                Some(TypeHint::Timestamp)
+            ),
+            (
+                "closed_on".to_string(),
+                Field::IsNull(true),
+                Some(TypeHint::Date)
+            ),
+            (
+                "logo".to_string(),
+                Field::BlobValue(Blob::new(vec![0, 255])),
+                None
+            ),
+            (
+                "settings".to_string(),
+                string(r#"{"theme":"dark"}"#),
+                Some(TypeHint::Json)
+            ),
+            (
+                "tags".to_string(),
+                string(r#"["vip"]"#),
+                Some(TypeHint::Json)
+            ),
+            ("status".to_string(), string("Blocked"), None),
+            ("note".to_string(), string("abc"), None),
+        ]
+    );
+}
+
+/// Unsupported structs and fields are rejected with a message pointing at them
+#[test]
+fn test_compile_errors() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
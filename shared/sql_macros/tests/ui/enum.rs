@@ -0,0 +1,9 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub enum EnumModel {
+    Active,
+    Blocked,
+}
+
+fn main() {}
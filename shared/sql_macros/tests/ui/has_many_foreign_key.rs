@@ -0,0 +1,9 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+#[sql(has_many(pets = "Pet"))]
+pub struct Owner {
+    uuid: String,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub struct StatusModel {
+    uuid: String,
+    #[sql(json, enum)]
+    status: String,
+}
+
+fn main() {}
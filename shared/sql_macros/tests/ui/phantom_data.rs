@@ -0,0 +1,11 @@
+use std::marker::PhantomData;
+
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub struct ModelPhantomData<T> {
+    uuid: String,
+    _unused: PhantomData<T>,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub struct SkippedKeyModel {
+    #[sql(primary_key, skip)]
+    id: i32,
+    name: String,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+#[sql(table = 1)]
+pub struct TableModel {
+    uuid: String,
+}
+
+fn main() {}
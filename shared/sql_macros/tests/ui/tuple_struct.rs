@@ -0,0 +1,6 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub struct TupleModel(String, i32);
+
+fn main() {}
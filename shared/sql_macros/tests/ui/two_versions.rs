@@ -0,0 +1,12 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub struct VersionsModel {
+    uuid: String,
+    #[sql(version)]
+    version: i32,
+    #[sql(version)]
+    revision: i32,
+}
+
+fn main() {}
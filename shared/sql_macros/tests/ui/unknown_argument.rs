@@ -0,0 +1,8 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql(soft_deleted)]
+pub struct ArgumentModel {
+    uuid: String,
+}
+
+fn main() {}
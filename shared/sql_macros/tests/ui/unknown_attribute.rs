@@ -0,0 +1,9 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub struct AttributeModel {
+    #[sql(primary)]
+    id: i32,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub struct ModelUnimplemented {
+    uuid: String,
+    id: u32,
+}
+
+fn main() {}
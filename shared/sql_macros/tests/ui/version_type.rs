@@ -0,0 +1,10 @@
+use sql_macros::struct_to_sql;
+
+#[struct_to_sql]
+pub struct VersionModel {
+    uuid: String,
+    #[sql(version)]
+    version: Option<i32>,
+}
+
+fn main() {}
@@ -0,0 +1,649 @@
+//! Conformance suite holding every `Repository` backend to the same contract
+//!
+//! - `create` of an existing key is a `Conflict`, the stored item is kept
+//! - `update` of a missing item is a `MissingItem`, of a versioned item
+//!   modified since it was read a `Conflict`
+//! - `upsert` creates a missing item and updates an existing one, with the same
+//!   version check as `update`
+//! - `get` of a missing key is `None`, `delete` of a missing key succeeds
+//! - `create_many` and `update_many` apply all the items or none of them
+//! - concurrent writes of the same key never both succeed
+//!
+//! A backend provides a `RepositoryFactory` and generates one test per check with
+//! `repository_conformance_tests!`, extra attributes are added to every test:
+//! ```ignore
+//! shared::repository_conformance_tests!(MyFactory::new(), #[serial_test::serial], #[ignore]);
+//! ```
+use std::sync::Arc;
+
+use crate::error::InterfaceError;
+use crate::ports::secondary::{Create, Delete, Get, HasKey, List, Repository, TypedList, Update};
+use crate::query::{Column, Filter, PageRequest};
+use crate::usecase::rds::{FromRow, GetFieldsAsParams, RdsRow};
+use crate::QuerySet;
+use async_trait::async_trait;
+use chrono::{Days, NaiveDate, NaiveDateTime};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sql_macros::struct_to_sql;
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+/// Number of tasks racing in the concurrency checks
+const TASKS: usize = 8;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[struct_to_sql]
+pub struct ConformanceItem {
+    pub(crate) uuid: Uuid,
+    pub(crate) field1: i32,
+    pub(crate) name: String,
+    pub(crate) day: NaiveDate,
+    pub(crate) at: NaiveDateTime,
+    pub(crate) amount: Decimal,
+    #[sql(enum)]
+    pub(crate) status: ConformanceStatus,
+    pub(crate) tags: serde_json::Value,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub enum ConformanceStatus {
+    #[default]
+    Inactive,
+    Active,
+}
+
+crate::comparable_enum!(ConformanceStatus);
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[struct_to_sql]
+pub struct VersionedConformanceItem {
+    uuid: Uuid,
+    field1: i32,
+    #[sql(version)]
+    version: i32,
+}
+
+/// Build the repositories checked by the conformance suite
+#[async_trait]
+pub trait RepositoryFactory: Send + Sync {
+    type Repo: Repository<ConformanceItem> + Send + Sync + 'static;
+    type VersionedRepo: Repository<VersionedConformanceItem> + Send + Sync + 'static;
+
+    /// A new, empty repository of `ConformanceItem`
+    async fn repository(&self) -> Result<Self::Repo, InterfaceError>;
+
+    /// A new, empty repository of `VersionedConformanceItem`, versioned by `version`
+    async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError>;
+}
+
+/// A new item with a random key, also the fixture of the decorator tests
+pub(crate) fn gen_item(field1: i32) -> ConformanceItem {
+    let day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap() + Days::new(field1 as u64);
+    ConformanceItem {
+        uuid: Uuid::new_v4(),
+        field1,
+        name: format!("item {field1}"),
+        day,
+        at: day.and_hms_opt(12, 30, 0).unwrap(),
+        amount: Decimal::new(100 * field1 as i64 + 5, 2),
+        status: match field1 % 2 {
+            0 => ConformanceStatus::Active,
+            _ => ConformanceStatus::Inactive,
+        },
+        tags: json!({ "field1": field1 }),
+    }
+}
+
+fn gen_versioned_item(field1: i32) -> VersionedConformanceItem {
+    VersionedConformanceItem {
+        uuid: Uuid::new_v4(),
+        field1,
+        version: 0,
+    }
+}
+
+fn sorted(mut items: Vec<ConformanceItem>) -> Vec<ConformanceItem> {
+    items.sort_by_key(|item| item.uuid);
+    items
+}
+
+pub async fn check_create_get<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN an empty repository
+    let repo = factory.repository().await?;
+    let item = gen_item(1);
+
+    // WHEN we create an item
+    repo.create(&item).await?;
+
+    // THEN it can be read back, and unknown keys are not found
+    assert_eq!(repo.get(&item.uuid).await?, Some(item));
+    assert_eq!(repo.get(&Uuid::new_v4()).await?, None);
+    Ok(())
+}
+
+pub async fn check_create_duplicate<F: RepositoryFactory>(
+    factory: &F,
+) -> Result<(), InterfaceError> {
+    // GIVEN a repository with an item
+    let repo = factory.repository().await?;
+    let item = gen_item(1);
+    repo.create(&item).await?;
+
+    // WHEN we create another item with the same key
+    let duplicate = ConformanceItem {
+        field1: 2,
+        ..item.clone()
+    };
+    let result = repo.create(&duplicate).await;
+
+    // THEN it conflicts and the stored item is kept
+    assert!(
+        matches!(result, Err(InterfaceError::Conflict(_))),
+        "{result:?}"
+    );
+    assert_eq!(repo.get(&item.uuid).await?, Some(item));
+    Ok(())
+}
+
+pub async fn check_create_many<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with an item
+    let repo = factory.repository().await?;
+    let existing = gen_item(1);
+    repo.create(&existing).await?;
+
+    // WHEN we create a batch holding an existing key, or the same key twice
+    let new = gen_item(2);
+    let result = repo.create_many(&[new.clone(), existing.clone()]).await;
+    let twice = repo.create_many(&[new.clone(), new.clone()]).await;
+
+    // THEN both conflict and none of their items are created
+    assert!(
+        matches!(result, Err(InterfaceError::Conflict(_))),
+        "{result:?}"
+    );
+    assert!(
+        matches!(twice, Err(InterfaceError::Conflict(_))),
+        "{twice:?}"
+    );
+    assert_eq!(sorted(repo.list().await?), vec![existing.clone()]);
+
+    // AND a batch of new keys is created
+    let other = gen_item(3);
+    repo.create_many(&[new.clone(), other.clone()]).await?;
+    assert_eq!(
+        sorted(repo.list().await?),
+        sorted(vec![existing, new, other])
+    );
+    Ok(())
+}
+
+pub async fn check_update<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with an item
+    let repo = factory.repository().await?;
+    let mut item = gen_item(1);
+    repo.create(&item).await?;
+
+    // WHEN we update it
+    item.field1 = 2;
+    item.name = "it's updated".to_string();
+    repo.update(&item).await?;
+
+    // THEN the update is stored
+    assert_eq!(repo.get(&item.uuid).await?, Some(item));
+    Ok(())
+}
+
+pub async fn check_update_missing<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN an empty repository
+    let repo = factory.repository().await?;
+    let item = gen_item(1);
+
+    // WHEN we update an item that was never created
+    let result = repo.update(&item).await;
+
+    // THEN it is missing and is not created
+    assert!(
+        matches!(result, Err(InterfaceError::MissingItem(_))),
+        "{result:?}"
+    );
+    assert_eq!(repo.get(&item.uuid).await?, None);
+    Ok(())
+}
+
+pub async fn check_update_many<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with two items
+    let repo = factory.repository().await?;
+    let (first, second) = (gen_item(1), gen_item(2));
+    repo.create_many(&[first.clone(), second.clone()]).await?;
+
+    // WHEN we update a batch holding a missing item
+    let updated = [
+        ConformanceItem {
+            field1: 10,
+            ..first.clone()
+        },
+        ConformanceItem {
+            field1: 20,
+            ..second.clone()
+        },
+    ];
+    let result = repo.update_many(&[updated[0].clone(), gen_item(3)]).await;
+
+    // THEN it is missing and none of the items are updated
+    assert!(
+        matches!(result, Err(InterfaceError::MissingItem(_))),
+        "{result:?}"
+    );
+    assert_eq!(sorted(repo.list().await?), sorted(vec![first, second]));
+
+    // AND a batch of existing items is updated
+    repo.update_many(&updated).await?;
+    assert_eq!(sorted(repo.list().await?), sorted(updated.to_vec()));
+    Ok(())
+}
+
+pub async fn check_delete<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with an item
+    let repo = factory.repository().await?;
+    let item = gen_item(1);
+    repo.create(&item).await?;
+
+    // WHEN we delete it, twice
+    repo.delete(&item.uuid).await?;
+    repo.delete(&item.uuid).await?;
+
+    // THEN it is gone
+    assert_eq!(repo.get(&item.uuid).await?, None);
+    Ok(())
+}
+
+pub async fn check_delete_many<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with three items
+    let repo = factory.repository().await?;
+    let items: Vec<_> = (1..=3).map(gen_item).collect();
+    repo.create_many(&items).await?;
+
+    // WHEN we delete two of them and a missing key
+    repo.delete_many(&[items[0].uuid, Uuid::new_v4(), items[2].uuid])
+        .await?;
+
+    // THEN only the other one is left
+    assert_eq!(repo.list().await?, vec![items[1].clone()]);
+    Ok(())
+}
+
+pub async fn check_list<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with five items
+    let repo = factory.repository().await?;
+    assert_eq!(repo.list().await?, vec![]);
+    let items: Vec<_> = (1..=5).map(gen_item).collect();
+    repo.create_many(&items).await?;
+
+    // WHEN we list all of them, or the ones matching a filter
+    let all = repo.list().await?;
+    let filtered = repo
+        .list_where(&Filter::ge("field1", 2).and(Filter::ne("field1", 4)))
+        .await?;
+
+    // THEN we get the expected items
+    assert_eq!(sorted(all), sorted(items.clone()));
+    let expected = vec![items[1].clone(), items[2].clone(), items[4].clone()];
+    assert_eq!(sorted(filtered), sorted(expected));
+
+    // AND unknown fields are rejected
+    let result = repo.list_where(&Filter::eq("unknown", 1)).await;
+    assert!(
+        matches!(result, Err(InterfaceError::InvalidQuery(_))),
+        "{result:?}"
+    );
+    Ok(())
+}
+
+pub async fn check_list_page<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with five items
+    let repo = factory.repository().await?;
+    let items: Vec<_> = [3, 1, 2, 5, 4].into_iter().map(gen_item).collect();
+    repo.create_many(&items).await?;
+
+    // WHEN we list pages of two items until there is no next page
+    let mut pages = Vec::new();
+    let mut request = PageRequest::new("field1", 2);
+    loop {
+        let page = repo.list_page(&request).await?;
+        pages.push(page.items.iter().map(|i| i.field1).collect::<Vec<i32>>());
+        match page.next {
+            Some(cursor) => request = request.after(Some(cursor)),
+            None => break,
+        }
+    }
+
+    // THEN we get every item once, in order
+    assert_eq!(pages, vec![vec![1, 2], vec![3, 4], vec![5]]);
+    Ok(())
+}
+
+pub async fn check_versioned_update<F: RepositoryFactory>(
+    factory: &F,
+) -> Result<(), InterfaceError> {
+    // GIVEN a versioned repository with an item
+    let repo = factory.versioned_repository().await?;
+    let item = gen_versioned_item(1);
+    repo.create(&item).await?;
+
+    // WHEN we update it from the version we read
+    let read = repo
+        .get(&item.uuid)
+        .await?
+        .ok_or(InterfaceError::MissingItem("Created item".to_string()))?;
+    repo.update(&VersionedConformanceItem { field1: 2, ..read })
+        .await?;
+
+    // THEN its version is incremented
+    let stored = repo.get(&item.uuid).await?;
+    assert_eq!(stored.as_ref().map(|i| (i.field1, i.version)), Some((2, 1)));
+
+    // AND updates from the stale version conflict
+    let result = repo
+        .update(&VersionedConformanceItem { field1: 3, ..item })
+        .await;
+    assert!(
+        matches!(result, Err(InterfaceError::Conflict(_))),
+        "{result:?}"
+    );
+    assert_eq!(repo.get(&item.uuid).await?, stored);
+
+    // AND updates of a missing item are missing
+    let result = repo.update(&gen_versioned_item(4)).await;
+    assert!(
+        matches!(result, Err(InterfaceError::MissingItem(_))),
+        "{result:?}"
+    );
+    Ok(())
+}
+
+pub async fn check_upsert<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN an empty repository
+    let repo = factory.repository().await?;
+    let item = gen_item(1);
+
+    // WHEN we upsert a new item, then a modified one
+    repo.upsert(&item).await?;
+    let created = repo.get(&item.uuid).await?;
+    let modified = ConformanceItem {
+        field1: 2,
+        ..item.clone()
+    };
+    repo.upsert(&modified).await?;
+
+    // THEN the item is created, then updated
+    assert_eq!(created, Some(item));
+    assert_eq!(repo.get(&modified.uuid).await?, Some(modified));
+    assert_eq!(repo.count(None).await?, 1);
+    Ok(())
+}
+
+pub async fn check_versioned_upsert<F: RepositoryFactory>(
+    factory: &F,
+) -> Result<(), InterfaceError> {
+    // GIVEN an empty versioned repository
+    let repo = factory.versioned_repository().await?;
+    let item = gen_versioned_item(1);
+
+    // WHEN we upsert a new item, then from the version we read
+    repo.upsert(&item).await?;
+    repo.upsert(&VersionedConformanceItem {
+        field1: 2,
+        ..item.clone()
+    })
+    .await?;
+
+    // THEN it is created, then updated with its version incremented
+    let stored = repo.get(&item.uuid).await?;
+    assert_eq!(stored.as_ref().map(|i| (i.field1, i.version)), Some((2, 1)));
+
+    // AND upserts from the stale version conflict
+    let result = repo
+        .upsert(&VersionedConformanceItem { field1: 3, ..item })
+        .await;
+    assert!(
+        matches!(result, Err(InterfaceError::Conflict(_))),
+        "{result:?}"
+    );
+    assert_eq!(repo.get(&item.uuid).await?, stored);
+    Ok(())
+}
+
+pub async fn check_exists<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with an item
+    let repo = factory.repository().await?;
+    let item = gen_item(1);
+    repo.create(&item).await?;
+
+    // WHEN we check whether it exists, and a missing one
+    let found = repo.exists(&item.uuid).await?;
+    let missing = repo.exists(&Uuid::new_v4()).await?;
+
+    // THEN only the item exists, until it is deleted
+    assert!(found);
+    assert!(!missing);
+    repo.delete(&item.uuid).await?;
+    assert!(!repo.exists(&item.uuid).await?);
+    Ok(())
+}
+
+pub async fn check_count<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with five items
+    let repo = factory.repository().await?;
+    assert_eq!(repo.count(None).await?, 0);
+    let items: Vec<_> = (1..=5).map(gen_item).collect();
+    repo.create_many(&items).await?;
+
+    // WHEN we count all of them, or the ones matching a filter
+    let all = repo.count(None).await?;
+    let filter = Filter::ge("field1", 2).and(Filter::ne("field1", 4));
+    let filtered = repo.count(Some(&filter)).await?;
+
+    // THEN we get the number of items listed
+    assert_eq!(all, 5);
+    assert_eq!(filtered, 3);
+
+    // AND unknown fields are rejected
+    let result = repo.count(Some(&Filter::eq("unknown", 1))).await;
+    assert!(
+        matches!(result, Err(InterfaceError::InvalidQuery(_))),
+        "{result:?}"
+    );
+    Ok(())
+}
+
+pub async fn check_typed_queries<F: RepositoryFactory>(factory: &F) -> Result<(), InterfaceError> {
+    // GIVEN a repository with five items
+    let repo = factory.repository().await?;
+    let items: Vec<_> = (1..=5).map(gen_item).collect();
+    repo.create_many(&items).await?;
+    let columns = ConformanceItem::columns();
+    let condition = columns.field1.ge(2).and(columns.field1.ne(4));
+
+    // WHEN we list, count and select the items matching a typed condition
+    let listed = repo.list_matching(&condition).await?;
+    let counted = repo.count_matching(&condition).await?;
+    let mut selected = repo
+        .select(&(columns.field1, columns.name), &condition)
+        .await?;
+    selected.sort();
+
+    // THEN we get the matching items, or only the selected fields
+    let expected = vec![items[1].clone(), items[2].clone(), items[4].clone()];
+    assert_eq!(sorted(listed), sorted(expected));
+    assert_eq!(counted, 3);
+    assert_eq!(
+        selected,
+        vec![
+            (2, "item 2".to_string()),
+            (3, "item 3".to_string()),
+            (5, "item 5".to_string())
+        ]
+    );
+
+    // AND a page is ordered by a typed column
+    let page = repo
+        .list_page(&PageRequest::ordered(columns.field1.desc(), 2).filter(condition))
+        .await?;
+    let fields: Vec<i32> = page.items.iter().map(|item| item.field1).collect();
+    assert_eq!(fields, vec![5, 3]);
+
+    // AND dates, timestamps, decimals, enums and JSON values are compared
+    let counts = [
+        repo.count_matching(&columns.day.lt(items[2].day)).await?,
+        repo.count_matching(&columns.at.ge(items[3].at)).await?,
+        repo.count_matching(&columns.amount.eq(items[0].amount))
+            .await?,
+        repo.count_matching(&columns.status.eq(ConformanceStatus::Active))
+            .await?,
+        repo.count_matching(&columns.tags.eq(items[4].tags.clone()))
+            .await?,
+    ];
+    assert_eq!(counts, [2, 2, 1, 2, 1]);
+
+    // AND an unknown field is rejected
+    let result = repo.select_where(&["unknown"], &Filter::And(vec![])).await;
+    assert!(
+        matches!(result, Err(InterfaceError::InvalidQuery(_))),
+        "{result:?}"
+    );
+    Ok(())
+}
+
+pub async fn check_concurrent_creates<F: RepositoryFactory>(
+    factory: &F,
+) -> Result<(), InterfaceError> {
+    // GIVEN an empty repository
+    let repo = Arc::new(factory.repository().await?);
+
+    // WHEN tasks create distinct items concurrently
+    let items: Vec<_> = (0..TASKS as i32).map(gen_item).collect();
+    let mut tasks = JoinSet::new();
+    for item in items.clone() {
+        let repo = Arc::clone(&repo);
+        tasks.spawn(async move { repo.create(&item).await });
+    }
+
+    // THEN all of them are created
+    for result in tasks.join_all().await {
+        result?;
+    }
+    assert_eq!(sorted(repo.list().await?), sorted(items));
+    Ok(())
+}
+
+pub async fn check_concurrent_duplicates<F: RepositoryFactory>(
+    factory: &F,
+) -> Result<(), InterfaceError> {
+    // GIVEN an empty repository
+    let repo = Arc::new(factory.repository().await?);
+
+    // WHEN tasks create items with the same key concurrently
+    let uuid = Uuid::new_v4();
+    let mut tasks = JoinSet::new();
+    for field1 in 0..TASKS as i32 {
+        let repo = Arc::clone(&repo);
+        let item = ConformanceItem {
+            uuid,
+            ..gen_item(field1)
+        };
+        tasks.spawn(async move { repo.create(&item).await.map(|_| item) });
+    }
+
+    // THEN exactly one of them is created, the others conflict
+    let mut created = Vec::new();
+    for result in tasks.join_all().await {
+        match result {
+            Ok(item) => created.push(item),
+            Err(InterfaceError::Conflict(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    assert_eq!(created.len(), 1);
+    assert_eq!(repo.list().await?, created);
+    Ok(())
+}
+
+pub async fn check_concurrent_versioned_updates<F: RepositoryFactory>(
+    factory: &F,
+) -> Result<(), InterfaceError> {
+    // GIVEN a versioned repository with an item
+    let repo = Arc::new(factory.versioned_repository().await?);
+    let item = gen_versioned_item(0);
+    repo.create(&item).await?;
+
+    // WHEN tasks update the same version of the item concurrently
+    let mut tasks = JoinSet::new();
+    for field1 in 1..=TASKS as i32 {
+        let repo = Arc::clone(&repo);
+        let item = VersionedConformanceItem {
+            field1,
+            ..item.clone()
+        };
+        tasks.spawn(async move { repo.update(&item).await.map(|_| item.field1) });
+    }
+
+    // THEN exactly one of them is applied, the others conflict
+    let mut applied = Vec::new();
+    for result in tasks.join_all().await {
+        match result {
+            Ok(field1) => applied.push(field1),
+            Err(InterfaceError::Conflict(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    assert_eq!(applied.len(), 1);
+    let stored = repo.get(&item.uuid).await?;
+    assert_eq!(stored.map(|i| (i.field1, i.version)), Some((applied[0], 1)));
+    Ok(())
+}
+
+/// Generate a test per conformance check of the repositories built by a `RepositoryFactory`
+///
+/// The factory expression is evaluated once per test. The calling crate depends on `tokio`.
+#[macro_export]
+macro_rules! repository_conformance_tests {
+    ($factory:expr $(, #[$meta:meta])*) => {
+        mod conformance {
+            use super::*;
+
+            $crate::repository_conformance_tests!(@tests ($(#[$meta])*) $factory;
+                check_create_get,
+                check_create_duplicate,
+                check_create_many,
+                check_update,
+                check_update_missing,
+                check_update_many,
+                check_delete,
+                check_delete_many,
+                check_list,
+                check_list_page,
+                check_versioned_update,
+                check_upsert,
+                check_versioned_upsert,
+                check_exists,
+                check_count,
+                check_typed_queries,
+                check_concurrent_creates,
+                check_concurrent_duplicates,
+                check_concurrent_versioned_updates,
+            );
+        }
+    };
+    (@tests $metas:tt $factory:expr; $($check:ident,)*) => {
+        $($crate::repository_conformance_tests!(@test $metas $factory; $check);)*
+    };
+    (@test ($(#[$meta:meta])*) $factory:expr; $check:ident) => {
+        #[tokio::test(flavor = "multi_thread")]
+        $(#[$meta])*
+        async fn $check() -> Result<(), $crate::error::InterfaceError> {
+            $crate::conformance::$check(&$factory).await
+        }
+    };
+}
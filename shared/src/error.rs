@@ -1,3 +1,4 @@
+use serde_json::{json, Value as JsonValue};
 use thiserror::Error;
 
 /// Customer errors
@@ -9,13 +10,271 @@ pub enum InterfaceError {
 
     /// Client error
     #[error("RDS failed: {0}")]
-    RdsError(aws_sdk_rdsdata::Error),
+    RdsError(Box<aws_sdk_rdsdata::Error>),
+
+    /// PostgreSQL client error
+    #[error("PostgreSQL failed: {0}")]
+    PostgresError(Box<tokio_postgres::Error>),
+
+    /// SQLite error
+    #[error("SQLite failed: {0}")]
+    SqliteError(Box<rusqlite::Error>),
 
     /// Parsing error
     #[error("Invalid field: {0}")]
     FromFields(String),
 
+    /// The item already exists, or was modified or deleted since it was read
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    /// Invalid filter or query
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    /// Invalid input, rejected before or by the database
+    #[error("Invalid input: {0}")]
+    Validation(String),
+
+    /// The balance does not cover the amount
+    #[error("Insufficient funds: {0}")]
+    InsufficientFunds(String),
+
+    /// Temporary failure, the operation can be retried
+    #[error("Temporary failure: {0}")]
+    Transient(String),
+
+    /// Missing or refused credentials
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
     /// Unknown
     #[error("Other error: {0}")]
     Other(String),
 }
+
+/// Category of an error, deciding how it is reported to clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    Conflict,
+    Validation,
+    InsufficientFunds,
+    Transient,
+    Unauthorized,
+    Internal,
+}
+
+impl ErrorKind {
+    /// HTTP status of the error
+    pub fn status(self) -> u16 {
+        match self {
+            ErrorKind::NotFound => 404,
+            ErrorKind::Conflict => 409,
+            ErrorKind::Validation => 400,
+            ErrorKind::InsufficientFunds => 422,
+            ErrorKind::Transient => 503,
+            ErrorKind::Unauthorized => 401,
+            ErrorKind::Internal => 500,
+        }
+    }
+
+    /// Short, human readable summary of the error
+    pub fn title(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "Not found",
+            ErrorKind::Conflict => "Conflict",
+            ErrorKind::Validation => "Invalid request",
+            ErrorKind::InsufficientFunds => "Insufficient funds",
+            ErrorKind::Transient => "Service unavailable",
+            ErrorKind::Unauthorized => "Unauthorized",
+            ErrorKind::Internal => "Internal error",
+        }
+    }
+
+    /// Fixed detail of the error, reported to clients instead of messages
+    /// that may reveal the implementation
+    pub fn detail(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "The item does not exist",
+            ErrorKind::Conflict => "The item conflicts with its stored state",
+            ErrorKind::Validation => "The item is not valid",
+            ErrorKind::InsufficientFunds => "The balance does not cover the amount",
+            ErrorKind::Transient => "The service is temporarily unavailable",
+            ErrorKind::Unauthorized => "The credentials are missing or refused",
+            ErrorKind::Internal => "Internal error",
+        }
+    }
+
+    /// ISO 8583 response code of an authorization refused with the error
+    pub fn iso_response_code(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "25",          // Unable to locate record
+            ErrorKind::Conflict => "94",          // Duplicate transmission
+            ErrorKind::Validation => "30",        // Format error
+            ErrorKind::InsufficientFunds => "51", // Not sufficient funds
+            ErrorKind::Transient => "91",         // Issuer or switch inoperative
+            ErrorKind::Unauthorized => "63",      // Security violation
+            ErrorKind::Internal => "96",          // System malfunction
+        }
+    }
+
+    /// Identifier of the kind, e.g. in problem types and metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "not-found",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Validation => "validation",
+            ErrorKind::InsufficientFunds => "insufficient-funds",
+            ErrorKind::Transient => "transient",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::Internal => "internal",
+        }
+    }
+}
+
+impl InterfaceError {
+    /// Error of a failed SQL statement from its SQLSTATE code,
+    /// `None` for the codes that are not specific to the request
+    ///
+    /// The message of the database names its tables, constraints and values: it is
+    /// only logged, and the error holds the fixed detail of its kind.
+    pub fn from_sqlstate(code: &str, message: impl Into<String>) -> Option<Self> {
+        let class = code.get(..2)?;
+        let kind = match (class, code) {
+            (_, "23505") | (_, "23P01") => ErrorKind::Conflict,
+            // Serialization failure and deadlock: the transaction can be replayed
+            (_, "40001") | (_, "40P01") => ErrorKind::Transient,
+            (_, "42501") | ("28", _) => ErrorKind::Unauthorized,
+            ("22", _) | ("23", _) => ErrorKind::Validation,
+            // Connection exception, insufficient resources, operator intervention
+            ("08", _) | ("53", _) | ("57", _) => ErrorKind::Transient,
+            _ => return None,
+        };
+        tracing::debug!("SQLSTATE {code}: {}", message.into());
+        let detail = kind.detail().to_string();
+        Some(match kind {
+            ErrorKind::Conflict => InterfaceError::Conflict(detail),
+            ErrorKind::Transient => InterfaceError::Transient(detail),
+            ErrorKind::Unauthorized => InterfaceError::Unauthorized(detail),
+            _ => InterfaceError::Validation(detail),
+        })
+    }
+
+    /// Category of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            InterfaceError::MissingItem(_) => ErrorKind::NotFound,
+            InterfaceError::Conflict(_) => ErrorKind::Conflict,
+            InterfaceError::InvalidQuery(_) | InterfaceError::Validation(_) => {
+                ErrorKind::Validation
+            }
+            InterfaceError::InsufficientFunds(_) => ErrorKind::InsufficientFunds,
+            InterfaceError::Transient(_) => ErrorKind::Transient,
+            InterfaceError::Unauthorized(_) => ErrorKind::Unauthorized,
+            InterfaceError::RdsError(_)
+            | InterfaceError::PostgresError(_)
+            | InterfaceError::SqliteError(_)
+            | InterfaceError::FromFields(_)
+            | InterfaceError::Other(_) => ErrorKind::Internal,
+        }
+    }
+
+    /// Whether the same operation may succeed if it is retried
+    pub fn is_retryable(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
+
+    /// HTTP status of the error
+    pub fn status(&self) -> u16 {
+        self.kind().status()
+    }
+
+    /// Problem details (RFC 9457) describing the error to a client
+    ///
+    /// Only the messages set by the domain are detailed, the other errors have
+    /// the fixed detail of their kind: they may reveal the implementation.
+    pub fn problem(&self) -> JsonValue {
+        let kind = self.kind();
+        let detail = match self {
+            InterfaceError::MissingItem(_)
+            | InterfaceError::Conflict(_)
+            | InterfaceError::InvalidQuery(_)
+            | InterfaceError::Validation(_)
+            | InterfaceError::InsufficientFunds(_) => self.to_string(),
+            _ => kind.detail().to_string(),
+        };
+        json!({
+            "type": format!("/problems/{}", kind.as_str()),
+            "title": kind.title(),
+            "status": kind.status(),
+            "detail": detail,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_from_sqlstate() {
+        let kind = |code| InterfaceError::from_sqlstate(code, "failed").map(|err| err.kind());
+
+        assert_eq!(kind("23505"), Some(ErrorKind::Conflict));
+        assert_eq!(kind("23502"), Some(ErrorKind::Validation));
+        assert_eq!(kind("22P02"), Some(ErrorKind::Validation));
+        assert_eq!(kind("40001"), Some(ErrorKind::Transient));
+        assert_eq!(kind("08006"), Some(ErrorKind::Transient));
+        assert_eq!(kind("28P01"), Some(ErrorKind::Unauthorized));
+        assert_eq!(kind("42501"), Some(ErrorKind::Unauthorized));
+        assert_eq!(kind("42P01"), None);
+        assert_eq!(kind(""), None);
+    }
+
+    #[test]
+    fn test_problem() {
+        // Client errors are detailed
+        let err = InterfaceError::InsufficientFunds("balance of 10".to_string());
+        assert_eq!(err.status(), 422);
+        assert_eq!(err.kind().iso_response_code(), "51");
+        assert_eq!(
+            err.problem(),
+            json!({
+                "type": "/problems/insufficient-funds",
+                "title": "Insufficient funds",
+                "status": 422,
+                "detail": "Insufficient funds: balance of 10",
+            })
+        );
+
+        // Internal and infrastructure errors are not
+        let err = InterfaceError::Other("connection string".to_string());
+        assert_eq!(err.status(), 500);
+        assert_eq!(err.problem()["detail"], "Internal error");
+        assert!(!err.is_retryable());
+        let err = InterfaceError::Transient("host db-1 is unreachable".to_string());
+        assert_eq!(
+            err.problem()["detail"],
+            "The service is temporarily unavailable"
+        );
+    }
+
+    #[test]
+    fn test_problem_database_error() {
+        // GIVEN a conflict reported by the database
+        let message = "duplicate key value violates unique constraint \"customer_pkey\"";
+        let err = InterfaceError::from_sqlstate("23505", message).unwrap();
+
+        // WHEN it is described to a client
+        let problem = err.problem();
+
+        // THEN the detail does not reveal the message of the database
+        assert_eq!(err.kind(), ErrorKind::Conflict);
+        let detail = problem["detail"].as_str().unwrap();
+        assert!(!detail.contains("customer_pkey"));
+        assert!(!detail.contains("23505"));
+        assert_eq!(detail, "Conflict: The item conflicts with its stored state");
+    }
+}
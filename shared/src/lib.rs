@@ -1,7 +1,11 @@
+pub mod conformance;
+pub mod migration;
 pub mod ports;
 pub mod usecase;
 // pub mod domain;
 pub mod error;
+pub mod query;
+pub mod relation;
 
 pub mod rds_client;
 pub mod settings;
@@ -9,17 +13,33 @@ pub mod utils;
 pub use sql_macros;
 
 // Define requirement for Val
-pub trait Val: Default + Send + Sync + Clone + serde::de::DeserializeOwned {}
-impl<T> Val for T where T: Default + Send + Sync + Clone + serde::de::DeserializeOwned {}
+pub trait Val:
+    Default + Send + Sync + Clone + serde::Serialize + serde::de::DeserializeOwned
+{
+}
+impl<T> Val for T where
+    T: Default + Send + Sync + Clone + serde::Serialize + serde::de::DeserializeOwned
+{
+}
 
 /// Queryset for SQL implementations
 pub trait QuerySet<T> {
-    /// Table name
+    /// Table name, unquoted
     fn table(&self) -> String;
 
     /// SQL query to create a new table
     fn create_table(&self) -> String;
 
+    /// SQL query to create a new table in a SQLite database
+    fn create_table_sqlite(&self) -> String;
+
+    /// SQL queries to create the indexes of the table, after the table
+    fn create_indexes(&self) -> Vec<String>;
+
+    /// Columns of the table with their PostgreSQL type and constraints,
+    /// e.g. `("pan", "VARCHAR(255) NOT NULL UNIQUE")`
+    fn column_definitions(&self) -> Vec<(String, String)>;
+
     /// SQL query to drop a table
     fn drop_table(&self) -> String;
 
@@ -35,6 +55,63 @@ pub trait QuerySet<T> {
     /// SQL query to update an object (prepared)
     fn update(&self) -> String;
 
+    /// SQL query to create an object, or update it if its key exists (prepared)
+    fn upsert(&self) -> String;
+
+    /// Fields of the primary key
+    fn primary_key(&self) -> Vec<String>;
+
+    /// SQL query to get an object by primary key (prepared)
+    fn get_by_key(&self) -> String;
+
+    /// SQL query to delete an object by primary key (prepared)
+    fn delete_by_key(&self) -> String;
+
+    /// SQL query to check whether an object exists by primary key (prepared)
+    fn exists(&self) -> String;
+
+    /// SQL query to count the items matching a condition (prepared)
+    fn count(&self, condition: &str) -> String;
+
+    /// Name of the version field, if the table uses optimistic concurrency control
+    fn version(&self) -> Option<String>;
+
     /// SQL query to list all items
     fn list(&self) -> String;
+
+    /// Names of the stored fields
+    fn columns(&self) -> Vec<String>;
+
+    /// Quoted column of a field, to use in a condition or an ordering
+    fn column(&self, field_name: &str) -> String;
+
+    /// SQL query to list the items matching a condition (prepared)
+    fn list_where(&self, condition: &str) -> String;
+
+    /// SQL query to read some fields of the items matching a condition (prepared)
+    fn select_where(&self, fields: &[&str], condition: &str) -> String;
+
+    /// SQL query to list an ordered page of the items matching a condition (prepared)
+    fn list_page(&self, condition: &str, order_by: &str, limit: u32) -> String;
+    /// Name of the column marking the soft deleted rows, if deletions are soft
+    fn deleted_column(&self) -> Option<String>;
+
+    /// Name of the history table, if the mutations are audited
+    fn history_table(&self) -> Option<String>;
+
+    /// SQL query to create the history table
+    fn create_history_table(&self) -> String;
+
+    /// SQL query to create the history table in a SQLite database
+    fn create_history_table_sqlite(&self) -> String;
+
+    /// SQL query to drop the history table
+    fn drop_history_table(&self) -> String;
+
+    /// SQL query to record the next revision of `count` objects (prepared), the
+    /// parameters of the i-th one suffixed by `_i`
+    fn insert_history(&self, count: usize) -> String;
+
+    /// SQL query to list the revisions of an object, oldest first (prepared)
+    fn list_history(&self) -> String;
 }
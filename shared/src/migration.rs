@@ -0,0 +1,690 @@
+//! Versioned migrations of the schema of a database
+//!
+//! `create_table` only creates the missing tables, a column added to a model never
+//! reaches an existing table. The schema is instead changed by migrations: SQL
+//! scripts named `V<version>__<name>.sql`, applied once each, in the order of
+//! their versions. The applied migrations are recorded in the `schema_history`
+//! table with the checksum of their script, a migration modified after it was
+//! applied is rejected.
+//!
+//! A migration is proposed for a model by diffing the columns of its queryset
+//! against the live `information_schema` of a PostgreSQL database, its statements
+//! are to be reviewed before the migration file is written.
+use std::fs;
+use std::path::Path;
+
+use crate::error::InterfaceError;
+use crate::usecase::history::now_millis;
+use crate::QuerySet;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+/// Table recording the applied migrations
+pub const SCHEMA_HISTORY_TABLE: &str = "schema_history";
+
+/// A database the migrations are applied to
+#[async_trait]
+pub trait MigrationDatabase: Send + Sync {
+    /// Execute the statements in a single transaction: all of them, or none
+    async fn apply(&self, statements: &[String]) -> Result<(), InterfaceError>;
+
+    /// Rows of a query, as JSON objects
+    async fn query(&self, sql: &str) -> Result<Vec<JsonValue>, InterfaceError>;
+}
+
+/// A SQL script changing the schema, identified by its version
+#[derive(Clone, Debug, PartialEq)]
+pub struct Migration {
+    pub version: i64,
+    pub name: String,
+    pub script: String,
+}
+
+impl Migration {
+    pub fn new(version: i64, name: impl Into<String>, script: impl Into<String>) -> Self {
+        Migration {
+            version,
+            name: name.into(),
+            script: script.into(),
+        }
+    }
+
+    /// Read a migration from a file named `V<version>__<name>.sql`
+    pub fn from_file(path: &Path) -> Result<Self, InterfaceError> {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let (version, name) = file_name
+            .strip_prefix('V')
+            .and_then(|name| name.strip_suffix(".sql"))
+            .and_then(|name| name.split_once("__"))
+            .and_then(|(version, name)| Some((version.parse().ok()?, name)))
+            .filter(|(_, name)| !name.is_empty())
+            .ok_or_else(|| {
+                InterfaceError::Validation(format!(
+                    "Invalid migration file name {file_name}, expected V<version>__<name>.sql"
+                ))
+            })?;
+        let script = fs::read_to_string(path).map_err(|err| {
+            InterfaceError::Other(format!("Failed to read {}: {err}", path.display()))
+        })?;
+        Ok(Migration::new(version, name, script))
+    }
+
+    /// Name of the file of the migration
+    pub fn file_name(&self) -> String {
+        format!("V{}__{}.sql", self.version, self.name)
+    }
+
+    /// FNV-1a hash of the script, telling whether it changed since it was applied
+    pub fn checksum(&self) -> String {
+        let hash = self
+            .script
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        format!("{hash:016x}")
+    }
+
+    /// Statements of the script, split on the semicolons outside of the quotes
+    /// and the comments. Dollar-quoted bodies are not supported.
+    pub fn statements(&self) -> Vec<String> {
+        let mut statements = Vec::new();
+        let mut statement = String::new();
+        // Whether the statement holds more than comments
+        let mut code = false;
+        let mut quote: Option<char> = None;
+        let mut chars = self.script.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some(open), c) => {
+                    statement.push(c);
+                    if c == open {
+                        quote = None;
+                    }
+                }
+                (None, '\'' | '"') => {
+                    statement.push(c);
+                    quote = Some(c);
+                    code = true;
+                }
+                (None, '-') if chars.peek() == Some(&'-') => {
+                    statement.push(c);
+                    for c in chars.by_ref() {
+                        statement.push(c);
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                (None, ';') => {
+                    if code {
+                        statements.push(statement.trim().to_string());
+                    }
+                    statement.clear();
+                    code = false;
+                }
+                (None, c) => {
+                    code |= !c.is_whitespace();
+                    statement.push(c);
+                }
+            }
+        }
+        if code {
+            statements.push(statement.trim().to_string());
+        }
+        statements
+    }
+
+    /// Migration following the given ones, with the statements of a proposal
+    pub fn next(migrations: &[Migration], name: &str, statements: &[String]) -> Self {
+        let version = migrations.iter().map(|m| m.version).max().unwrap_or(0) + 1;
+        let script: String = statements
+            .iter()
+            .map(|statement| format!("{statement};\n"))
+            .collect();
+        Migration::new(version, name, script)
+    }
+}
+
+/// Read the migrations of a directory, ordered by version
+pub fn load_migrations(directory: &Path) -> Result<Vec<Migration>, InterfaceError> {
+    let io_error = |err: std::io::Error| {
+        InterfaceError::Other(format!("Failed to read {}: {err}", directory.display()))
+    };
+    let mut migrations = Vec::new();
+    for entry in fs::read_dir(directory).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.extension().is_some_and(|extension| extension == "sql") {
+            migrations.push(Migration::from_file(&path)?);
+        }
+    }
+    migrations.sort_by_key(|migration| migration.version);
+
+    if let Some(pair) = migrations
+        .windows(2)
+        .find(|pair| pair[0].version == pair[1].version)
+    {
+        return Err(InterfaceError::Validation(format!(
+            "Migrations {} and {} have the same version",
+            pair[0].file_name(),
+            pair[1].file_name()
+        )));
+    }
+    Ok(migrations)
+}
+
+/// A migration recorded in the schema history
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub checksum: String,
+    /// Milliseconds since the Unix epoch
+    pub applied_at: i64,
+}
+
+/// Quote an identifier, so that any name can be used, even a reserved word
+pub(crate) fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
+
+/// Migrations recorded in the schema history, creating it if it does not exist
+pub async fn applied_migrations(
+    database: &dyn MigrationDatabase,
+) -> Result<Vec<AppliedMigration>, InterfaceError> {
+    let table = quote_identifier(SCHEMA_HISTORY_TABLE);
+    database
+        .apply(&[format!(
+            "CREATE TABLE IF NOT EXISTS {table} (\"version\" BIGINT, \"name\" VARCHAR(255) NOT NULL, \
+             \"checksum\" VARCHAR(16) NOT NULL, \"applied_at\" BIGINT NOT NULL, PRIMARY KEY (\"version\"))"
+        )])
+        .await?;
+
+    database
+        .query(&format!(
+            "SELECT \"version\", \"name\", \"checksum\", \"applied_at\" FROM {table} ORDER BY \"version\""
+        ))
+        .await?
+        .into_iter()
+        .map(|row| {
+            serde_json::from_value(row).map_err(|e| {
+                InterfaceError::FromFields(format!("Failed to parse the schema history: {e}"))
+            })
+        })
+        .collect()
+}
+
+/// Migrations not applied yet, after checking that the applied ones are unchanged.
+/// A migration older than the last applied one is rejected, rather than applied
+/// out of order.
+pub async fn pending_migrations<'a>(
+    database: &dyn MigrationDatabase,
+    migrations: &'a [Migration],
+) -> Result<Vec<&'a Migration>, InterfaceError> {
+    let applied = applied_migrations(database).await?;
+    for record in &applied {
+        let migration = migrations
+            .iter()
+            .find(|migration| migration.version == record.version)
+            .ok_or_else(|| {
+                InterfaceError::Validation(format!(
+                    "Migration {} was applied, but its file is missing",
+                    record.version
+                ))
+            })?;
+        if migration.checksum() != record.checksum {
+            return Err(InterfaceError::Validation(format!(
+                "Migration {} was modified after it was applied",
+                migration.file_name()
+            )));
+        }
+    }
+
+    let last = applied.iter().map(|record| record.version).max();
+    let pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|migration| !applied.iter().any(|r| r.version == migration.version))
+        .collect();
+    if let Some(migration) = pending
+        .iter()
+        .find(|migration| Some(migration.version) < last)
+    {
+        return Err(InterfaceError::Validation(format!(
+            "Migration {} is older than the last applied migration {}",
+            migration.file_name(),
+            last.unwrap_or_default()
+        )));
+    }
+    Ok(pending)
+}
+
+/// Apply the pending migrations in order, each one in its own transaction with its
+/// record in the schema history, returning the applied versions. A concurrent run
+/// applying the same migration fails on the primary key of the schema history.
+pub async fn migrate(
+    database: &dyn MigrationDatabase,
+    migrations: &[Migration],
+) -> Result<Vec<i64>, InterfaceError> {
+    let mut versions = Vec::new();
+    for migration in pending_migrations(database, migrations).await? {
+        let mut statements = migration.statements();
+        statements.push(format!(
+            "INSERT INTO {} (\"version\", \"name\", \"checksum\", \"applied_at\") VALUES ({}, {}, {}, {})",
+            quote_identifier(SCHEMA_HISTORY_TABLE),
+            migration.version,
+            quote_literal(&migration.name),
+            quote_literal(&migration.checksum()),
+            now_millis()
+        ));
+        tracing::info!("Applying migration {}", migration.file_name());
+        database.apply(&statements).await?;
+        versions.push(migration.version);
+    }
+    Ok(versions)
+}
+
+/// A column of a live table, as described by the `information_schema`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct LiveColumn {
+    pub column_name: String,
+    pub data_type: String,
+    pub character_maximum_length: Option<i64>,
+    /// `YES` or `NO`
+    pub is_nullable: String,
+}
+
+impl LiveColumn {
+    /// Type of the column, spelled like the types of the generated tables
+    fn sql_type(&self) -> String {
+        match (self.data_type.as_str(), self.character_maximum_length) {
+            ("character varying", Some(length)) => format!("VARCHAR({length})"),
+            ("character varying", None) => "VARCHAR".to_string(),
+            ("timestamp without time zone", _) => "TIMESTAMP".to_string(),
+            ("timestamp with time zone", _) => "TIMESTAMPTZ".to_string(),
+            (data_type, _) => data_type.to_uppercase(),
+        }
+    }
+
+    fn nullable(&self) -> bool {
+        self.is_nullable.eq_ignore_ascii_case("YES")
+    }
+}
+
+/// Columns of a table of the current schema, none if the table does not exist
+pub async fn live_columns(
+    database: &dyn MigrationDatabase,
+    table: &str,
+) -> Result<Vec<LiveColumn>, InterfaceError> {
+    database
+        .query(&format!(
+            "SELECT column_name::TEXT AS column_name, data_type::TEXT AS data_type, \
+             character_maximum_length::BIGINT AS character_maximum_length, \
+             is_nullable::TEXT AS is_nullable FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = {} ORDER BY ordinal_position",
+            quote_literal(table)
+        ))
+        .await?
+        .into_iter()
+        .map(|row| {
+            serde_json::from_value(row).map_err(|e| {
+                InterfaceError::FromFields(format!("Failed to parse the columns of {table}: {e}"))
+            })
+        })
+        .collect()
+}
+
+/// Names of the indexes of a table of the current schema
+async fn live_indexes(
+    database: &dyn MigrationDatabase,
+    table: &str,
+) -> Result<Vec<String>, InterfaceError> {
+    let rows = database
+        .query(&format!(
+            "SELECT indexname::TEXT AS indexname FROM pg_indexes \
+             WHERE schemaname = current_schema() AND tablename = {}",
+            quote_literal(table)
+        ))
+        .await?;
+    Ok(rows
+        .iter()
+        .filter_map(|row| row["indexname"].as_str().map(str::to_string))
+        .collect())
+}
+
+/// Type of a column definition, the constraints following it
+fn definition_type(definition: &str) -> &str {
+    match definition.strip_prefix("DOUBLE PRECISION") {
+        Some(_) => "DOUBLE PRECISION",
+        None => definition.split_whitespace().next().unwrap_or_default(),
+    }
+}
+
+/// Statements migrating a live table to the columns and indexes of a queryset,
+/// the table being created if it has no column. The columns missing from the
+/// model are not dropped, their `DROP COLUMN` is proposed as a comment.
+pub fn diff_table<T, Q: QuerySet<T>>(
+    queryset: &Q,
+    live: &[LiveColumn],
+    indexes: &[String],
+) -> Vec<String> {
+    if live.is_empty() {
+        let mut statements = vec![queryset.create_table()];
+        statements.extend(queryset.create_indexes());
+        return statements;
+    }
+
+    let table = quote_identifier(&queryset.table());
+    let primary_key: Vec<String> = queryset
+        .primary_key()
+        .iter()
+        .map(|field| queryset.column(field))
+        .collect();
+    let mut statements = Vec::new();
+    let definitions = queryset.column_definitions();
+
+    for (column, definition) in &definitions {
+        let quoted = quote_identifier(column);
+        let not_null = definition.contains("NOT NULL") || primary_key.contains(&quoted);
+        let sql_type = definition_type(definition);
+        let Some(current) = live.iter().find(|live| live.column_name == *column) else {
+            let mut statement = String::new();
+            if not_null && !definition.contains("DEFAULT") {
+                statement.push_str(
+                    "-- The column has no default, fill it in the existing rows before NOT NULL\n",
+                );
+            }
+            statement.push_str(&format!(
+                "ALTER TABLE {table} ADD COLUMN {quoted} {definition}"
+            ));
+            statements.push(statement);
+            continue;
+        };
+        if !current.sql_type().eq_ignore_ascii_case(sql_type) {
+            statements.push(format!(
+                "ALTER TABLE {table} ALTER COLUMN {quoted} TYPE {sql_type} USING {quoted}::{sql_type}"
+            ));
+        }
+        match (current.nullable(), not_null) {
+            (true, true) => statements.push(format!(
+                "ALTER TABLE {table} ALTER COLUMN {quoted} SET NOT NULL"
+            )),
+            (false, false) => statements.push(format!(
+                "ALTER TABLE {table} ALTER COLUMN {quoted} DROP NOT NULL"
+            )),
+            _ => {}
+        }
+    }
+
+    for current in live {
+        if !definitions
+            .iter()
+            .any(|(column, _)| *column == current.column_name)
+        {
+            statements.push(format!(
+                "-- ALTER TABLE {table} DROP COLUMN {}",
+                quote_identifier(&current.column_name)
+            ));
+        }
+    }
+
+    // The indexes are named after their table and column
+    statements.extend(queryset.create_indexes().into_iter().filter(|statement| {
+        !indexes
+            .iter()
+            .any(|index| statement.contains(&quote_identifier(index)))
+    }));
+    statements
+}
+
+/// Statements migrating the live table of a queryset, and creating its history
+/// table if it is audited, to be reviewed before they are written to a migration
+pub async fn propose_migration<T, Q: QuerySet<T>>(
+    database: &dyn MigrationDatabase,
+    queryset: &Q,
+) -> Result<Vec<String>, InterfaceError> {
+    let table = queryset.table();
+    let live = live_columns(database, &table).await?;
+    let indexes = live_indexes(database, &table).await?;
+    let mut statements = diff_table(queryset, &live, &indexes);
+
+    if let Some(history_table) = queryset.history_table() {
+        if live_columns(database, &history_table).await?.is_empty() {
+            statements.push(queryset.create_history_table());
+        }
+    }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{ConformanceItem, ConformanceItemQuerySet};
+    use crate::usecase::sqlite::SqliteDatabase;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    /// A new directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("migrations-{}", Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn live_column(name: &str, data_type: &str, nullable: bool) -> LiveColumn {
+        LiveColumn {
+            column_name: name.to_string(),
+            data_type: data_type.to_string(),
+            character_maximum_length: (data_type == "character varying").then_some(255),
+            is_nullable: if nullable { "YES" } else { "NO" }.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_statements() {
+        // GIVEN a script with comments, quotes and a trailing comment
+        let migration = Migration::new(
+            1,
+            "init",
+            "-- Create the table;\nCREATE TABLE \"a;b\" (\"c\" TEXT DEFAULT 'x;''y');\n\
+             UPDATE \"a;b\" SET \"c\" = 'z' -- every row;\n;\n-- DROP TABLE \"a;b\";\n",
+        );
+
+        // WHEN it is split
+        let statements = migration.statements();
+
+        // THEN only the semicolons ending the statements split it
+        assert_eq!(
+            statements,
+            vec![
+                "-- Create the table;\nCREATE TABLE \"a;b\" (\"c\" TEXT DEFAULT 'x;''y')",
+                "UPDATE \"a;b\" SET \"c\" = 'z' -- every row;",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_migrations() {
+        // GIVEN a directory of migrations
+        let directory = TempDir::new();
+        fs::write(
+            directory.0.join("V2__add_column.sql"),
+            "ALTER TABLE t ADD c INTEGER;",
+        )
+        .unwrap();
+        fs::write(
+            directory.0.join("V10__index.sql"),
+            "CREATE INDEX i ON t (c);",
+        )
+        .unwrap();
+        fs::write(
+            directory.0.join("V1__create.sql"),
+            "CREATE TABLE t (id INTEGER);",
+        )
+        .unwrap();
+        fs::write(directory.0.join("README.md"), "Migrations").unwrap();
+
+        // WHEN they are loaded
+        let migrations = load_migrations(&directory.0).unwrap();
+
+        // THEN they are ordered by version
+        let names: Vec<String> = migrations.iter().map(Migration::file_name).collect();
+        assert_eq!(
+            names,
+            vec!["V1__create.sql", "V2__add_column.sql", "V10__index.sql"]
+        );
+
+        // GIVEN two migrations with the same version
+        fs::write(directory.0.join("V2__other.sql"), "SELECT 1;").unwrap();
+
+        // THEN they are rejected
+        assert!(matches!(
+            load_migrations(&directory.0),
+            Err(InterfaceError::Validation(_))
+        ));
+
+        // GIVEN a file without a version
+        assert!(matches!(
+            Migration::from_file(&directory.0.join("create.sql")),
+            Err(InterfaceError::Validation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_migrate() -> Result<(), InterfaceError> {
+        // GIVEN a database and two migrations
+        let database = SqliteDatabase::open_in_memory()?;
+        let mut migrations = vec![
+            Migration::new(1, "create", "CREATE TABLE \"t\" (\"id\" INTEGER);"),
+            Migration::new(
+                2,
+                "add_column",
+                "ALTER TABLE \"t\" ADD COLUMN \"c\" TEXT;\nINSERT INTO \"t\" VALUES (1, 'a');",
+            ),
+        ];
+
+        // WHEN they are applied twice
+        let applied = migrate(&database, &migrations).await?;
+        let reapplied = migrate(&database, &migrations).await?;
+
+        // THEN they are applied once, and recorded
+        assert_eq!(applied, vec![1, 2]);
+        assert_eq!(reapplied, Vec::<i64>::new());
+        let history = applied_migrations(&database).await?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].name, "add_column".to_string());
+        assert_eq!(history[1].checksum, migrations[1].checksum());
+        let rows = database.query("SELECT \"id\", \"c\" FROM \"t\"").await?;
+        assert_eq!(rows, vec![serde_json::json!({"id": 1, "c": "a"})]);
+
+        // GIVEN a failing migration
+        migrations.push(Migration::new(
+            3,
+            "broken",
+            "INSERT INTO \"t\" VALUES (2, 'b');\nINSERT INTO \"missing\" VALUES (1);",
+        ));
+
+        // WHEN it is applied
+        let result = migrate(&database, &migrations).await;
+
+        // THEN none of its statements are applied, and it is not recorded
+        assert!(result.is_err());
+        assert_eq!(database.query("SELECT \"id\" FROM \"t\"").await?.len(), 1);
+        assert_eq!(applied_migrations(&database).await?.len(), 2);
+
+        // GIVEN an applied migration that was modified
+        migrations.pop();
+        let script = migrations[0].script.clone();
+        migrations[0].script.push_str("\n-- modified");
+
+        // THEN the migrations are rejected
+        assert!(matches!(
+            migrate(&database, &migrations).await,
+            Err(InterfaceError::Validation(_))
+        ));
+
+        // GIVEN a new migration older than the last applied one
+        migrations[0].script = script;
+        migrations.push(Migration::new(0, "late", "SELECT 1;"));
+
+        // THEN it is rejected
+        assert!(matches!(
+            migrate(&database, &migrations).await,
+            Err(InterfaceError::Validation(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_table() {
+        let queryset: ConformanceItemQuerySet<ConformanceItem> = ConformanceItem::queryset();
+
+        // GIVEN no live table
+        // THEN the table is created
+        assert_eq!(
+            diff_table(&queryset, &[], &[]),
+            vec![queryset.create_table()]
+        );
+
+        // GIVEN a live table with a nullable key, a missing column, a column of
+        // another type, and a column removed from the model
+        let live = vec![
+            live_column("uuid", "uuid", true),
+            live_column("name", "text", true),
+            live_column("legacy", "integer", true),
+        ];
+
+        // WHEN it is diffed with the model
+        let statements = diff_table(&queryset, &live, &[]);
+
+        // THEN the steps migrating it are proposed, the removed column is not dropped
+        assert_eq!(
+            statements,
+            vec![
+                r#"ALTER TABLE "conformance_item" ALTER COLUMN "uuid" SET NOT NULL"#,
+                r#"ALTER TABLE "conformance_item" ADD COLUMN "field1" INTEGER"#,
+                r#"ALTER TABLE "conformance_item" ALTER COLUMN "name" TYPE VARCHAR(255) USING "name"::VARCHAR(255)"#,
+                r#"ALTER TABLE "conformance_item" ADD COLUMN "day" DATE"#,
+                r#"ALTER TABLE "conformance_item" ADD COLUMN "at" TIMESTAMP"#,
+                r#"ALTER TABLE "conformance_item" ADD COLUMN "amount" NUMERIC"#,
+                r#"ALTER TABLE "conformance_item" ADD COLUMN "status" VARCHAR(255)"#,
+                r#"ALTER TABLE "conformance_item" ADD COLUMN "tags" JSONB"#,
+                r#"-- ALTER TABLE "conformance_item" DROP COLUMN "legacy""#,
+            ]
+        );
+
+        // GIVEN a live table matching the model
+        let live = vec![
+            live_column("uuid", "uuid", false),
+            live_column("field1", "integer", true),
+            live_column("name", "character varying", true),
+            live_column("day", "date", true),
+            live_column("at", "timestamp without time zone", true),
+            live_column("amount", "numeric", true),
+            live_column("status", "character varying", true),
+            live_column("tags", "jsonb", true),
+        ];
+
+        // THEN there is nothing to migrate
+        assert_eq!(diff_table(&queryset, &live, &[]), Vec::<String>::new());
+    }
+}
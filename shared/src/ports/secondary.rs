@@ -1,10 +1,70 @@
-use crate::{error::InterfaceError, Val};
+use crate::{
+    error::InterfaceError,
+    query::{project, Condition, FieldValue, Filter, Page, PageRequest, Projection},
+    relation::{BelongsTo, HasMany},
+    Val,
+};
 use async_trait::async_trait;
+use serde_json::Value as JsonValue;
+use std::fmt::Debug;
 use uuid::Uuid;
 
-pub trait Repository<T>: Create<T> + Get<T> + Update<T> + List<T> + Delete<T>
+/// Primary key of an entity, made of one or several field values
+pub trait Key: Clone + Ord + Debug + Send + Sync {
+    /// Values of the key fields, in the order of `HasKey::KEY_FIELDS`
+    fn values(&self) -> Vec<FieldValue>;
+}
+
+impl Key for Uuid {
+    fn values(&self) -> Vec<FieldValue> {
+        vec![FieldValue::Uuid(*self)]
+    }
+}
+
+impl Key for String {
+    fn values(&self) -> Vec<FieldValue> {
+        vec![FieldValue::String(self.clone())]
+    }
+}
+
+impl Key for i32 {
+    fn values(&self) -> Vec<FieldValue> {
+        vec![FieldValue::Integer((*self).into())]
+    }
+}
+
+impl Key for i64 {
+    fn values(&self) -> Vec<FieldValue> {
+        vec![FieldValue::Integer(*self)]
+    }
+}
+
+impl<A: Key, B: Key> Key for (A, B) {
+    fn values(&self) -> Vec<FieldValue> {
+        [self.0.values(), self.1.values()].concat()
+    }
+}
+
+impl<A: Key, B: Key, C: Key> Key for (A, B, C) {
+    fn values(&self) -> Vec<FieldValue> {
+        [self.0.values(), self.1.values(), self.2.values()].concat()
+    }
+}
+
+/// Entity identified by a primary key
+pub trait HasKey {
+    type Key: Key;
+
+    /// Names of the fields forming the primary key
+    const KEY_FIELDS: &'static [&'static str];
+
+    fn key(&self) -> Self::Key;
+}
+
+pub trait Repository<T>:
+    Create<T> + Get<T> + Update<T> + List<T> + Delete<T> + Send + Sync
 where
-    T: Val,
+    T: Val + HasKey,
 {
 }
 
@@ -15,24 +75,45 @@ where
     T: Val,
 {
     async fn create(&self, item: &T) -> Result<(), InterfaceError>;
+
+    /// Create several objects, in as few round trips as the backend allows
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        for item in items {
+            self.create(item).await?;
+        }
+        Ok(())
+    }
 }
 
 /// Get object trait
 #[async_trait]
 pub trait Get<T>
 where
-    T: Val,
+    T: Val + HasKey,
 {
-    async fn get(&self, id: &Uuid) -> Result<Option<T>, InterfaceError>;
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError>;
+
+    /// Whether an object exists, without reading it when the backend allows
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        Ok(self.get(key).await?.is_some())
+    }
 }
 
 /// Delete object trait
 #[async_trait]
 pub trait Delete<T>
 where
-    T: Val,
+    T: Val + HasKey,
 {
-    async fn delete(&self, id: &Uuid) -> Result<(), InterfaceError>;
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError>;
+
+    /// Delete several objects, in as few round trips as the backend allows
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        for key in keys {
+            self.delete(key).await?;
+        }
+        Ok(())
+    }
 }
 
 /// Update object trait
@@ -41,7 +122,23 @@ pub trait Update<T>
 where
     T: Val,
 {
+    /// Update a stored object
+    ///
+    /// An update affecting no row fails: with `InterfaceError::MissingItem` if the object
+    /// does not exist, with `InterfaceError::Conflict` if it is versioned and was modified
+    /// since it was read.
     async fn update(&self, item: &T) -> Result<(), InterfaceError>;
+
+    /// Update several objects, in as few round trips as the backend allows
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        for item in items {
+            self.update(item).await?;
+        }
+        Ok(())
+    }
+
+    /// Create an object, or update it if it already exists
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError>;
 }
 
 /// Get object range trait
@@ -51,4 +148,184 @@ where
     T: Val,
 {
     async fn list(&self) -> Result<Vec<T>, InterfaceError>;
+
+    /// List the items matching a filter
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError>;
+
+    /// List a bounded page of items, the returned cursor leads to the next page
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError>;
+
+    /// Count the items, or the items matching a filter
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        let items = match filter {
+            Some(filter) => self.list_where(filter).await?,
+            None => self.list().await?,
+        };
+        Ok(items.len() as u64)
+    }
+
+    /// Read some fields of the items matching a filter, as JSON objects keyed by field
+    async fn select_where(
+        &self,
+        fields: &[&str],
+        filter: &Filter,
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        self.list_where(filter)
+            .await?
+            .iter()
+            .map(|item| {
+                let item = serde_json::to_value(item).map_err(|e| {
+                    InterfaceError::FromFields(format!("Failed to serialize item: {e}"))
+                })?;
+                project(&item, fields)
+            })
+            .collect()
+    }
+}
+
+/// Typed queries on the columns generated by `struct_to_sql`,
+/// available on every `List`, including a `dyn Repository`
+#[async_trait]
+pub trait TypedList<T: Val>: List<T> {
+    /// List the items matching a condition
+    async fn list_matching(&self, condition: &Condition<T>) -> Result<Vec<T>, InterfaceError> {
+        self.list_where(condition.filter()).await
+    }
+
+    /// Count the items matching a condition
+    async fn count_matching(&self, condition: &Condition<T>) -> Result<u64, InterfaceError> {
+        self.count(Some(condition.filter())).await
+    }
+
+    /// Read the projected columns of the items matching a condition
+    async fn select<P>(
+        &self,
+        projection: &P,
+        condition: &Condition<T>,
+    ) -> Result<Vec<P::Output>, InterfaceError>
+    where
+        P: Projection<T> + Sync,
+        P::Output: Send,
+    {
+        self.select_where(&projection.fields(), condition.filter())
+            .await?
+            .iter()
+            .map(|row| projection.decode(row))
+            .collect()
+    }
+}
+
+impl<T: Val, R: List<T> + Sync + ?Sized> TypedList<T> for R {}
+
+/// Mutation recorded in the history of an entity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    Create,
+    Update,
+    Upsert,
+    Delete,
+}
+
+impl Mutation {
+    /// Name of the mutation, as stored in a history table
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mutation::Create => "create",
+            Mutation::Update => "update",
+            Mutation::Upsert => "upsert",
+            Mutation::Delete => "delete",
+        }
+    }
+
+    /// Mutation of its name in a history table
+    pub fn parse(name: &str) -> Result<Self, InterfaceError> {
+        match name {
+            "create" => Ok(Mutation::Create),
+            "update" => Ok(Mutation::Update),
+            "upsert" => Ok(Mutation::Upsert),
+            "delete" => Ok(Mutation::Delete),
+            _ => Err(InterfaceError::FromFields(format!(
+                "Unknown mutation: {name}"
+            ))),
+        }
+    }
+}
+
+/// Revision of an entity: the images of the entity before and after a mutation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change<T> {
+    /// Number of the revision, from 1 for the first mutation of the entity
+    pub revision: i64,
+    pub operation: Mutation,
+    /// The entity before the mutation, `None` if it did not exist
+    pub before: Option<T>,
+    /// The entity after the mutation, `None` if it was deleted
+    pub after: Option<T>,
+    /// Who made the mutation
+    pub actor: String,
+    /// Time of the mutation, in milliseconds since the Unix epoch
+    pub changed_at: i64,
+}
+
+/// Audit history of the entities
+#[async_trait]
+pub trait History<T>
+where
+    T: Val + HasKey,
+{
+    /// Revisions of an entity, oldest first, including the ones of a deleted entity
+    ///
+    /// Fails with `InterfaceError::InvalidQuery` if the mutations are not audited.
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError>;
+}
+
+/// Loading of the items related to the items of a repository, declared with
+/// `#[sql(belongs_to(...))]` and `#[sql(has_many(...))]`, see [`crate::relation`]
+#[async_trait]
+pub trait Join<T, U>
+where
+    T: Val,
+    U: Val,
+{
+    /// Items matching a filter, ordered by key, each one with the item it belongs to.
+    /// The items without one, or whose one is deleted, are left out.
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError>;
+
+    /// Items matching a filter, ordered by key, each one with the items referencing it,
+    /// ordered by key too
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError>;
+}
+
+/// Transaction spanning the operations of several repositories
+///
+/// Operations are either all applied with `commit` or all discarded with `rollback`.
+/// A transaction must be finished explicitly, it is not rolled back on drop.
+#[async_trait]
+pub trait Transaction: Send + Sync {
+    /// Apply the operations of the transaction
+    async fn commit(&self) -> Result<(), InterfaceError>;
+
+    /// Discard the operations of the transaction
+    async fn rollback(&self) -> Result<(), InterfaceError>;
+
+    /// Commit the transaction if its operations succeeded, roll it back otherwise
+    async fn finish(&self, outcome: Result<(), InterfaceError>) -> Result<(), InterfaceError> {
+        match outcome {
+            Ok(()) => self.commit().await,
+            Err(err) => {
+                if let Err(rollback_err) = self.rollback().await {
+                    tracing::error!("Failed to rollback transaction: {}", rollback_err);
+                }
+                Err(err)
+            }
+        }
+    }
 }
@@ -0,0 +1,1164 @@
+//! Backend agnostic query primitives used by the repository ports
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::error::InterfaceError;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use uuid::Uuid;
+
+/// A value a field can be compared against
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FieldValue {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Double(f64),
+    String(String),
+    Uuid(Uuid),
+    Bytes(Vec<u8>),
+}
+
+impl From<bool> for FieldValue {
+    fn from(value: bool) -> Self {
+        FieldValue::Bool(value)
+    }
+}
+
+impl From<i16> for FieldValue {
+    fn from(value: i16) -> Self {
+        FieldValue::Integer(value.into())
+    }
+}
+
+impl From<i32> for FieldValue {
+    fn from(value: i32) -> Self {
+        FieldValue::Integer(value.into())
+    }
+}
+
+impl From<i64> for FieldValue {
+    fn from(value: i64) -> Self {
+        FieldValue::Integer(value)
+    }
+}
+
+impl From<f32> for FieldValue {
+    fn from(value: f32) -> Self {
+        FieldValue::Double(value.into())
+    }
+}
+
+impl From<f64> for FieldValue {
+    fn from(value: f64) -> Self {
+        FieldValue::Double(value)
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(value: String) -> Self {
+        FieldValue::String(value)
+    }
+}
+
+impl From<&str> for FieldValue {
+    fn from(value: &str) -> Self {
+        FieldValue::String(value.to_string())
+    }
+}
+
+impl From<Uuid> for FieldValue {
+    fn from(value: Uuid) -> Self {
+        FieldValue::Uuid(value)
+    }
+}
+
+impl From<Vec<u8>> for FieldValue {
+    fn from(value: Vec<u8>) -> Self {
+        FieldValue::Bytes(value)
+    }
+}
+
+/// Dates, timestamps, decimals and JSON values are compared as their serialized
+/// strings, the ones the backends store and bind to their columns
+macro_rules! serialized {
+    ($($type:ty),*) => {
+        $(impl From<$type> for FieldValue {
+            fn from(value: $type) -> Self {
+                FieldValue::serialized(&value)
+            }
+        })*
+    };
+}
+
+serialized!(NaiveDate, NaiveDateTime, DateTime<Utc>, Decimal, JsonValue);
+
+impl<V: Into<FieldValue>> From<Option<V>> for FieldValue {
+    fn from(value: Option<V>) -> Self {
+        value.map_or(FieldValue::Null, Into::into)
+    }
+}
+
+impl FieldValue {
+    /// The serialized string of a value, e.g. the variant of a string-backed enum
+    pub fn serialized<V: Serialize>(value: &V) -> Self {
+        match serde_json::to_value(value) {
+            Ok(JsonValue::String(s)) => FieldValue::String(s),
+            Ok(json) => FieldValue::String(json.to_string()),
+            Err(_) => FieldValue::Null,
+        }
+    }
+
+    /// Read the serialized value of a field.
+    /// UUIDs are serialized as strings and read back as such.
+    pub fn from_json(json: &JsonValue) -> Self {
+        match json {
+            JsonValue::Null => FieldValue::Null,
+            JsonValue::Bool(b) => FieldValue::Bool(*b),
+            JsonValue::Number(n) => match n.as_i64() {
+                Some(i) => FieldValue::Integer(i),
+                None => FieldValue::Double(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            JsonValue::String(s) => FieldValue::String(s.clone()),
+            other => FieldValue::String(other.to_string()),
+        }
+    }
+
+    /// Compare the serialized value of a field with `self`.
+    ///
+    /// Follows SQL semantics: any comparison involving a NULL is unknown (`None`)
+    /// and comparing values of different types is an error.
+    pub fn compare_json(
+        &self,
+        field: &str,
+        json: &JsonValue,
+    ) -> Result<Option<Ordering>, InterfaceError> {
+        let ordering = match (json, self) {
+            (JsonValue::Null, _) | (_, FieldValue::Null) => None,
+            (JsonValue::Bool(a), FieldValue::Bool(b)) => Some(a.cmp(b)),
+            (JsonValue::Number(a), FieldValue::Integer(b)) => match a.as_i64() {
+                Some(a) => Some(a.cmp(b)),
+                None => a.as_f64().and_then(|a| a.partial_cmp(&(*b as f64))),
+            },
+            (JsonValue::Number(a), FieldValue::Double(b)) => {
+                a.as_f64().and_then(|a| a.partial_cmp(b))
+            }
+            (JsonValue::String(a), FieldValue::String(b)) => Some(a.as_str().cmp(b.as_str())),
+            (JsonValue::String(a), FieldValue::Uuid(b)) => match Uuid::parse_str(a) {
+                Ok(a) => Some(a.cmp(b)),
+                Err(_) => return Err(type_mismatch(field, json, self)),
+            },
+            // Bytes are serialized as arrays of numbers
+            (JsonValue::Array(a), FieldValue::Bytes(b)) => {
+                let a: Option<Vec<u8>> = a
+                    .iter()
+                    .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                    .collect();
+                match a {
+                    Some(a) => Some(a.cmp(b)),
+                    None => return Err(type_mismatch(field, json, self)),
+                }
+            }
+            // JSON values are compared as their serialized strings, like in `from_json`
+            (JsonValue::Object(_) | JsonValue::Array(_), FieldValue::String(b)) => {
+                Some(json.to_string().as_str().cmp(b.as_str()))
+            }
+            _ => return Err(type_mismatch(field, json, self)),
+        };
+        Ok(ordering)
+    }
+}
+
+fn type_mismatch(field: &str, json: &JsonValue, value: &FieldValue) -> InterfaceError {
+    InterfaceError::InvalidQuery(format!(
+        "Cannot compare field {field} ({json}) with {value:?}"
+    ))
+}
+
+/// Comparison operators available in a [`Filter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Operator {
+    /// SQL syntax of the operator
+    pub fn to_sql(self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Ne => "<>",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+        }
+    }
+
+    /// Does the ordering between a field and a value satisfy the operator
+    pub fn accepts(self, ordering: Ordering) -> bool {
+        match self {
+            Operator::Eq => ordering == Ordering::Equal,
+            Operator::Ne => ordering != Ordering::Equal,
+            Operator::Lt => ordering == Ordering::Less,
+            Operator::Le => ordering != Ordering::Greater,
+            Operator::Gt => ordering == Ordering::Greater,
+            Operator::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// Predicate on the named fields of an entity
+///
+/// SQL backends render it as a prepared `WHERE` clause with [`Filter::to_sql`],
+/// in memory backends evaluate it on the serialized entity with [`Filter::matches`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// `field <op> value`
+    Compare(String, Operator, FieldValue),
+    /// `field IN (values)`
+    In(String, Vec<FieldValue>),
+    /// All the filters match, an empty list always matches
+    And(Vec<Filter>),
+    /// Any of the filters matches, an empty list never matches
+    Or(Vec<Filter>),
+}
+
+impl Filter {
+    pub fn eq(field: &str, value: impl Into<FieldValue>) -> Self {
+        Filter::Compare(field.to_string(), Operator::Eq, value.into())
+    }
+
+    pub fn ne(field: &str, value: impl Into<FieldValue>) -> Self {
+        Filter::Compare(field.to_string(), Operator::Ne, value.into())
+    }
+
+    pub fn lt(field: &str, value: impl Into<FieldValue>) -> Self {
+        Filter::Compare(field.to_string(), Operator::Lt, value.into())
+    }
+
+    pub fn le(field: &str, value: impl Into<FieldValue>) -> Self {
+        Filter::Compare(field.to_string(), Operator::Le, value.into())
+    }
+
+    pub fn gt(field: &str, value: impl Into<FieldValue>) -> Self {
+        Filter::Compare(field.to_string(), Operator::Gt, value.into())
+    }
+
+    pub fn ge(field: &str, value: impl Into<FieldValue>) -> Self {
+        Filter::Compare(field.to_string(), Operator::Ge, value.into())
+    }
+
+    pub fn is_in<V: Into<FieldValue>>(field: &str, values: impl IntoIterator<Item = V>) -> Self {
+        Filter::In(
+            field.to_string(),
+            values.into_iter().map(Into::into).collect(),
+        )
+    }
+
+    /// Combine two filters, both must match
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Filter::And(mut filters) => {
+                filters.push(other);
+                Filter::And(filters)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    /// Combine two filters, either must match
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Filter::Or(mut filters) => {
+                filters.push(other);
+                Filter::Or(filters)
+            }
+            filter => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    /// Names of the fields used by the filter
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            Filter::Compare(field, _, _) | Filter::In(field, _) => vec![field.as_str()],
+            Filter::And(filters) | Filter::Or(filters) => {
+                filters.iter().flat_map(Filter::fields).collect()
+            }
+        }
+    }
+
+    /// Ensure that the filter only uses known fields.
+    /// Field names are written verbatim in the SQL query, this must be called
+    /// before rendering a filter built from untrusted input.
+    pub fn validate<S: AsRef<str>>(&self, columns: &[S]) -> Result<(), InterfaceError> {
+        self.fields()
+            .into_iter()
+            .try_for_each(|field| validate_field(field, columns))
+    }
+
+    /// Render the filter as a SQL condition with named parameters `:p0`, `:p1`, ...
+    pub fn to_sql(&self) -> (String, Vec<(String, FieldValue)>) {
+        self.to_sql_with(|field| field.to_string())
+    }
+
+    /// Render the filter like `to_sql`, writing each field as the given column,
+    /// e.g. the quoted column of a renamed field
+    pub fn to_sql_with(
+        &self,
+        column: impl Fn(&str) -> String,
+    ) -> (String, Vec<(String, FieldValue)>) {
+        let mut params = Vec::new();
+        let condition = self.render(&column, &mut params);
+        (condition, params)
+    }
+
+    fn render(
+        &self,
+        column: &dyn Fn(&str) -> String,
+        params: &mut Vec<(String, FieldValue)>,
+    ) -> String {
+        let mut bind = |value: &FieldValue| {
+            let name = format!("p{}", params.len());
+            params.push((name.clone(), value.clone()));
+            format!(":{name}")
+        };
+        match self {
+            Filter::Compare(field, op, value) => {
+                format!("{} {} {}", column(field), op.to_sql(), bind(value))
+            }
+            Filter::In(_, values) if values.is_empty() => "FALSE".to_string(),
+            Filter::In(field, values) => {
+                let placeholders: Vec<String> = values.iter().map(bind).collect();
+                format!("{} IN ({})", column(field), placeholders.join(", "))
+            }
+            Filter::And(filters) if filters.is_empty() => "TRUE".to_string(),
+            Filter::Or(filters) if filters.is_empty() => "FALSE".to_string(),
+            Filter::And(filters) => Self::render_all(filters, " AND ", column, params),
+            Filter::Or(filters) => Self::render_all(filters, " OR ", column, params),
+        }
+    }
+
+    fn render_all(
+        filters: &[Filter],
+        separator: &str,
+        column: &dyn Fn(&str) -> String,
+        params: &mut Vec<(String, FieldValue)>,
+    ) -> String {
+        let conditions: Vec<String> = filters
+            .iter()
+            .map(|filter| format!("({})", filter.render(column, params)))
+            .collect();
+        conditions.join(separator)
+    }
+
+    /// Evaluate the filter on a serialized entity.
+    pub fn matches(&self, item: &JsonValue) -> Result<bool, InterfaceError> {
+        match self {
+            Filter::Compare(field, op, value) => Ok(value
+                .compare_json(field, field_of(item, field)?)?
+                .is_some_and(|ordering| op.accepts(ordering))),
+            Filter::In(field, values) => {
+                let json = field_of(item, field)?;
+                for value in values {
+                    if value.compare_json(field, json)? == Some(Ordering::Equal) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Filter::And(filters) => {
+                for filter in filters {
+                    if !filter.matches(item)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Filter::Or(filters) => {
+                for filter in filters {
+                    if filter.matches(item)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Ensure that a field is one of the columns
+pub fn validate_field<S: AsRef<str>>(field: &str, columns: &[S]) -> Result<(), InterfaceError> {
+    match columns.iter().any(|column| column.as_ref() == field) {
+        true => Ok(()),
+        false => Err(InterfaceError::InvalidQuery(format!(
+            "Unknown field: {field}"
+        ))),
+    }
+}
+
+/// Ensure that a selection reads at least one field, and only columns
+pub fn validate_selection<S: AsRef<str>>(
+    fields: &[&str],
+    columns: &[S],
+) -> Result<(), InterfaceError> {
+    if fields.is_empty() {
+        return Err(InterfaceError::InvalidQuery("Empty selection".to_string()));
+    }
+    fields
+        .iter()
+        .try_for_each(|field| validate_field(field, columns))
+}
+
+/// Get a field of a serialized entity
+pub(crate) fn field_of<'a>(
+    item: &'a JsonValue,
+    field: &str,
+) -> Result<&'a JsonValue, InterfaceError> {
+    item.get(field)
+        .ok_or_else(|| InterfaceError::InvalidQuery(format!("Unknown field: {field}")))
+}
+
+/// Maximum number of items in a page
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+/// Sort direction of a listing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl Direction {
+    /// SQL syntax of the direction
+    pub fn to_sql(self) -> &'static str {
+        match self {
+            Direction::Asc => "ASC",
+            Direction::Desc => "DESC",
+        }
+    }
+}
+
+/// Opaque position in a listing, returned with a page to fetch the next one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor(String);
+
+impl Cursor {
+    /// Encode the sort values of the last item of a page
+    pub fn new(values: &[FieldValue]) -> Self {
+        let json = serde_json::to_string(values).unwrap_or_default();
+        Cursor(json.bytes().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Decode the sort values of the last item of the previous page
+    pub fn values(&self) -> Result<Vec<FieldValue>, InterfaceError> {
+        let invalid = || InterfaceError::InvalidQuery(format!("Invalid cursor: {}", self.0));
+        if !self.0.len().is_multiple_of(2) || !self.0.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..self.0.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&self.0[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        serde_json::from_slice(&bytes).map_err(|_| invalid())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Cursor {
+    fn from(value: String) -> Self {
+        Cursor(value)
+    }
+}
+
+/// Request for a bounded, ordered page of items
+///
+/// Pages are keyset paginated: items are sorted by `order_by` then by the
+/// key fields of the repository so that the order is total, and the cursor
+/// holds these values for the last item of the previous page. `order_by` should
+/// name a non nullable field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageRequest {
+    pub size: u32,
+    pub order_by: String,
+    pub direction: Direction,
+    pub cursor: Option<Cursor>,
+    pub filter: Option<Filter>,
+}
+
+impl PageRequest {
+    /// First page of `size` items ordered by `order_by`
+    pub fn new(order_by: &str, size: u32) -> Self {
+        PageRequest {
+            size,
+            order_by: order_by.to_string(),
+            direction: Direction::Asc,
+            cursor: None,
+            filter: None,
+        }
+    }
+
+    /// First page of `size` items in the order of a column
+    pub fn ordered<T>(order: OrderBy<T>, size: u32) -> Self {
+        PageRequest::new(order.field, size).direction(order.direction)
+    }
+
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Continue after the last item of a previous page
+    pub fn after(mut self, cursor: Option<Cursor>) -> Self {
+        self.cursor = cursor;
+        self
+    }
+
+    /// Only list the items matching a filter, or a condition
+    pub fn filter(mut self, filter: impl Into<Filter>) -> Self {
+        self.filter = Some(filter.into());
+        self
+    }
+
+    /// Ensure the page is bounded and only uses known fields
+    pub fn validate<S: AsRef<str>>(&self, columns: &[S]) -> Result<(), InterfaceError> {
+        if self.size == 0 || self.size > MAX_PAGE_SIZE {
+            return Err(InterfaceError::InvalidQuery(format!(
+                "Page size must be between 1 and {MAX_PAGE_SIZE}, got {}",
+                self.size
+            )));
+        }
+        if let Some(filter) = &self.filter {
+            filter.validate(columns)?;
+        }
+        validate_field(&self.order_by, columns)
+    }
+
+    /// Sort values of an item, stored in the cursor
+    fn sort_fields<'a, S: AsRef<str>>(&'a self, key: &'a [S]) -> Vec<&'a str> {
+        let mut fields = vec![self.order_by.as_str()];
+        fields.extend(
+            key.iter()
+                .map(AsRef::as_ref)
+                .filter(|field| *field != self.order_by),
+        );
+        fields
+    }
+
+    /// Condition selecting the items of the page: the request's filter,
+    /// restricted to the items after the cursor
+    pub fn condition<S: AsRef<str>>(&self, key: &[S]) -> Result<Filter, InterfaceError> {
+        let mut condition = Filter::And(self.filter.iter().cloned().collect());
+        if let Some(cursor) = &self.cursor {
+            let fields = self.sort_fields(key);
+            let values = cursor.values()?;
+            if values.len() != fields.len() {
+                return Err(InterfaceError::InvalidQuery(format!(
+                    "Invalid cursor: {}",
+                    cursor.as_str()
+                )));
+            }
+            let op = match self.direction {
+                Direction::Asc => Operator::Gt,
+                Direction::Desc => Operator::Lt,
+            };
+            // (a, b) > (x, y) <=> a > x OR (a = x AND b > y)
+            let mut after = Filter::Or(vec![]);
+            for (i, (field, value)) in fields.iter().zip(&values).enumerate() {
+                let mut clause = Filter::And(vec![]);
+                for (previous, previous_value) in fields.iter().zip(&values).take(i) {
+                    clause = clause.and(Filter::eq(previous, previous_value.clone()));
+                }
+                clause = clause.and(Filter::Compare(field.to_string(), op, value.clone()));
+                after = after.or(clause);
+            }
+            condition = condition.and(after);
+        }
+        Ok(condition)
+    }
+
+    /// SQL `ORDER BY` expression of the page
+    pub fn order_sql<S: AsRef<str>>(&self, key: &[S]) -> String {
+        self.order_sql_with(key, |field| field.to_string())
+    }
+
+    /// SQL `ORDER BY` expression of the page, writing each field as the given column
+    pub fn order_sql_with<S: AsRef<str>>(
+        &self,
+        key: &[S],
+        column: impl Fn(&str) -> String,
+    ) -> String {
+        self.sort_fields(key)
+            .iter()
+            .map(|field| format!("{} {}", column(field), self.direction.to_sql()))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Compare two serialized items in the order of the page,
+    /// NULLs are sorted after any value as in PostgreSQL
+    pub fn compare<S: AsRef<str>>(&self, key: &[S], a: &JsonValue, b: &JsonValue) -> Ordering {
+        let ordering = self
+            .sort_fields(key)
+            .into_iter()
+            .map(|field| {
+                let (a, b) = (a.get(field), b.get(field));
+                match (a, b) {
+                    (Some(JsonValue::Null) | None, Some(JsonValue::Null) | None) => Ordering::Equal,
+                    (Some(JsonValue::Null) | None, _) => Ordering::Greater,
+                    (_, Some(JsonValue::Null) | None) => Ordering::Less,
+                    (Some(a), Some(b)) => FieldValue::from_json(b)
+                        .compare_json(field, a)
+                        .ok()
+                        .flatten()
+                        .unwrap_or(Ordering::Equal),
+                }
+            })
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal);
+        match self.direction {
+            Direction::Asc => ordering,
+            Direction::Desc => ordering.reverse(),
+        }
+    }
+
+    /// Build a page from up to `size + 1` ordered items,
+    /// the extra item signals that there is a next page
+    pub fn page<T, S: AsRef<str>>(
+        &self,
+        key: &[S],
+        mut items: Vec<T>,
+        value_of: impl Fn(&T, &str) -> Result<FieldValue, InterfaceError>,
+    ) -> Result<Page<T>, InterfaceError> {
+        let size = self.size as usize;
+        let next = if items.len() > size {
+            items.truncate(size);
+            let last = &items[size - 1];
+            let values = self
+                .sort_fields(key)
+                .into_iter()
+                .map(|field| value_of(last, field))
+                .collect::<Result<Vec<FieldValue>, InterfaceError>>()?;
+            Some(Cursor::new(&values))
+        } else {
+            None
+        };
+        Ok(Page { items, next })
+    }
+}
+
+/// A page of items and the cursor to the next page, if any
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<Cursor>,
+}
+
+/// Type of a field that can be compared in a [`Filter`], with the values it is
+/// compared against: the type itself, or the type in an `Option`
+pub trait Comparable {
+    type Value: Into<FieldValue>;
+}
+
+macro_rules! comparable {
+    ($($type:ty),*) => {
+        $(impl Comparable for $type {
+            type Value = $type;
+        })*
+    };
+}
+
+comparable!(
+    bool,
+    i16,
+    i32,
+    i64,
+    f32,
+    f64,
+    String,
+    Uuid,
+    Vec<u8>,
+    NaiveDate,
+    NaiveDateTime,
+    DateTime<Utc>,
+    Decimal,
+    JsonValue
+);
+
+/// Make string-backed enums comparable in typed conditions, by their serialized variant
+///
+/// ```
+/// use serde::Serialize;
+///
+/// #[derive(Serialize)]
+/// enum Status {
+///     Active,
+/// }
+/// shared::comparable_enum!(Status);
+///
+/// let value: shared::query::FieldValue = Status::Active.into();
+/// assert_eq!(value, shared::query::FieldValue::String("Active".to_string()));
+/// ```
+#[macro_export]
+macro_rules! comparable_enum {
+    ($($type:ty),*) => {
+        $(impl $crate::query::Comparable for $type {
+            type Value = $type;
+        }
+
+        impl From<$type> for $crate::query::FieldValue {
+            fn from(value: $type) -> Self {
+                $crate::query::FieldValue::serialized(&value)
+            }
+        })*
+    };
+}
+
+impl<V: Comparable> Comparable for Option<V> {
+    type Value = V::Value;
+}
+
+/// Typed handle on the field of type `V` of an entity `T`, generated by
+/// `struct_to_sql` as `T::columns()`. The conditions built from it only compare
+/// the field with values of its type, and only apply to the entity.
+///
+/// ```compile_fail
+/// use shared::query::Column;
+///
+/// struct Customer;
+/// let balance: Column<Customer, i64> = Column::new("balance");
+/// let _ = balance.gt("100");
+/// ```
+pub struct Column<T, V> {
+    field: &'static str,
+    _marker: PhantomData<fn() -> (T, V)>,
+}
+
+impl<T, V> Column<T, V> {
+    pub const fn new(field: &'static str) -> Self {
+        Column {
+            field,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Name of the field
+    pub fn name(&self) -> &'static str {
+        self.field
+    }
+
+    pub fn asc(&self) -> OrderBy<T> {
+        OrderBy::new(self.field, Direction::Asc)
+    }
+
+    pub fn desc(&self) -> OrderBy<T> {
+        OrderBy::new(self.field, Direction::Desc)
+    }
+}
+
+impl<T, V: Comparable> Column<T, V> {
+    fn compare(&self, op: Operator, value: impl Into<V::Value>) -> Condition<T> {
+        Condition::new(Filter::Compare(
+            self.field.to_string(),
+            op,
+            value.into().into(),
+        ))
+    }
+
+    pub fn eq(&self, value: impl Into<V::Value>) -> Condition<T> {
+        self.compare(Operator::Eq, value)
+    }
+
+    pub fn ne(&self, value: impl Into<V::Value>) -> Condition<T> {
+        self.compare(Operator::Ne, value)
+    }
+
+    pub fn lt(&self, value: impl Into<V::Value>) -> Condition<T> {
+        self.compare(Operator::Lt, value)
+    }
+
+    pub fn le(&self, value: impl Into<V::Value>) -> Condition<T> {
+        self.compare(Operator::Le, value)
+    }
+
+    pub fn gt(&self, value: impl Into<V::Value>) -> Condition<T> {
+        self.compare(Operator::Gt, value)
+    }
+
+    pub fn ge(&self, value: impl Into<V::Value>) -> Condition<T> {
+        self.compare(Operator::Ge, value)
+    }
+
+    pub fn is_in<I: Into<V::Value>>(&self, values: impl IntoIterator<Item = I>) -> Condition<T> {
+        Condition::new(Filter::In(
+            self.field.to_string(),
+            values
+                .into_iter()
+                .map(|value| value.into().into())
+                .collect(),
+        ))
+    }
+}
+
+impl<T, V> Clone for Column<T, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, V> Copy for Column<T, V> {}
+
+impl<T, V> fmt::Debug for Column<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Column").field(&self.field).finish()
+    }
+}
+
+/// A [`Filter`] on the columns of an entity `T`
+pub struct Condition<T> {
+    filter: Filter,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Condition<T> {
+    fn new(filter: Filter) -> Self {
+        Condition {
+            filter,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Combine two conditions, both must match
+    pub fn and(self, other: Condition<T>) -> Self {
+        Condition::new(self.filter.and(other.filter))
+    }
+
+    /// Combine two conditions, either must match
+    pub fn or(self, other: Condition<T>) -> Self {
+        Condition::new(self.filter.or(other.filter))
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+}
+
+impl<T> From<Condition<T>> for Filter {
+    fn from(condition: Condition<T>) -> Self {
+        condition.filter
+    }
+}
+
+impl<T> Clone for Condition<T> {
+    fn clone(&self) -> Self {
+        Condition::new(self.filter.clone())
+    }
+}
+
+impl<T> fmt::Debug for Condition<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Condition").field(&self.filter).finish()
+    }
+}
+
+/// Ordering of a listing by a column of an entity `T`
+pub struct OrderBy<T> {
+    field: &'static str,
+    direction: Direction,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> OrderBy<T> {
+    fn new(field: &'static str, direction: Direction) -> Self {
+        OrderBy {
+            field,
+            direction,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Columns of an entity `T` read by a query instead of the whole entity: a column,
+/// or a tuple of columns, read as the value or the tuple of values of their fields
+pub trait Projection<T> {
+    type Output;
+
+    /// Names of the fields read
+    fn fields(&self) -> Vec<&'static str>;
+
+    /// Read the values of the fields in a row, keyed by field name
+    fn decode(&self, row: &JsonValue) -> Result<Self::Output, InterfaceError>;
+}
+
+impl<T, V: DeserializeOwned> Projection<T> for Column<T, V> {
+    type Output = V;
+
+    fn fields(&self) -> Vec<&'static str> {
+        vec![self.field]
+    }
+
+    fn decode(&self, row: &JsonValue) -> Result<V, InterfaceError> {
+        serde_json::from_value(field_of(row, self.field)?.clone())
+            .map_err(|e| InterfaceError::FromFields(format!("{}: {e}", self.field)))
+    }
+}
+
+macro_rules! projection {
+    ($($column:ident),*) => {
+        impl<T, $($column: Projection<T>),*> Projection<T> for ($($column,)*) {
+            type Output = ($($column::Output,)*);
+
+            fn fields(&self) -> Vec<&'static str> {
+                #[allow(non_snake_case)]
+                let ($($column,)*) = self;
+                let mut fields = Vec::new();
+                $(fields.extend($column.fields());)*
+                fields
+            }
+
+            fn decode(&self, row: &JsonValue) -> Result<Self::Output, InterfaceError> {
+                #[allow(non_snake_case)]
+                let ($($column,)*) = self;
+                Ok(($($column.decode(row)?,)*))
+            }
+        }
+    };
+}
+
+projection!(A);
+projection!(A, B);
+projection!(A, B, C);
+projection!(A, B, C, D);
+
+/// Keep the given fields of a serialized entity
+pub(crate) fn project(item: &JsonValue, fields: &[&str]) -> Result<JsonValue, InterfaceError> {
+    let mut row = serde_json::Map::new();
+    for field in fields {
+        row.insert(field.to_string(), field_of(item, field)?.clone());
+    }
+    Ok(JsonValue::Object(row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_sql() {
+        // GIVEN a nested filter
+        let filter = Filter::eq("name", "abc")
+            .and(Filter::gt("balance", 10).or(Filter::is_in("id", [1, 2])));
+
+        // WHEN we render it
+        let (condition, params) = filter.to_sql();
+
+        // THEN we get a prepared condition and its parameters
+        assert_eq!(
+            condition,
+            "(name = :p0) AND ((balance > :p1) OR (id IN (:p2, :p3)))"
+        );
+        assert_eq!(
+            params,
+            vec![
+                ("p0".to_string(), FieldValue::from("abc")),
+                ("p1".to_string(), FieldValue::Integer(10)),
+                ("p2".to_string(), FieldValue::Integer(1)),
+                ("p3".to_string(), FieldValue::Integer(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_sql_with() {
+        // GIVEN a filter and a page on renamed fields
+        let filter = Filter::eq("name", "abc").or(Filter::is_in("id", [1]));
+        let request = PageRequest::new("name", 10);
+        let column = |field: &str| match field {
+            "name" => "\"full_name\"".to_string(),
+            field => format!("\"{}\"", field),
+        };
+
+        // WHEN we render them with the columns of the fields
+        let (condition, _) = filter.to_sql_with(column);
+
+        // THEN the columns are written instead of the fields
+        assert_eq!(condition, "(\"full_name\" = :p0) OR (\"id\" IN (:p1))");
+        assert_eq!(
+            request.order_sql_with(&["id"], column),
+            "\"full_name\" ASC, \"id\" ASC"
+        );
+    }
+
+    #[test]
+    fn test_to_sql_empty() {
+        assert_eq!(Filter::And(vec![]).to_sql().0, "TRUE");
+        assert_eq!(Filter::Or(vec![]).to_sql().0, "FALSE");
+        assert_eq!(Filter::is_in::<i32>("id", []).to_sql().0, "FALSE");
+    }
+
+    #[test]
+    fn test_matches() -> Result<(), InterfaceError> {
+        // GIVEN a serialized item
+        let uuid = Uuid::new_v4();
+        let item =
+            json!({"uuid": uuid, "name": "abc", "balance": 10, "logo": [0, 255], "missing": null});
+
+        // WHEN we evaluate filters
+        // THEN they follow the SQL semantics
+        assert!(Filter::eq("uuid", uuid).matches(&item)?);
+        assert!(Filter::eq("name", "abc").matches(&item)?);
+        assert!(Filter::ge("balance", 10).matches(&item)?);
+        assert!(!Filter::gt("balance", 10).matches(&item)?);
+        assert!(Filter::is_in("balance", [1, 10]).matches(&item)?);
+        assert!(Filter::eq("logo", vec![0u8, 255]).matches(&item)?);
+        assert!(Filter::lt("logo", vec![1u8]).matches(&item)?);
+        assert!(!Filter::eq("missing", 1).matches(&item)?);
+        assert!(!Filter::ne("missing", 1).matches(&item)?);
+        assert!(Filter::lt("balance", 5)
+            .or(Filter::eq("name", "abc"))
+            .matches(&item)?);
+        assert!(!Filter::lt("balance", 5)
+            .and(Filter::eq("name", "abc"))
+            .matches(&item)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches_invalid() {
+        let item = json!({"name": "abc"});
+
+        assert!(matches!(
+            Filter::eq("unknown", 1).matches(&item),
+            Err(InterfaceError::InvalidQuery(_))
+        ));
+        assert!(matches!(
+            Filter::eq("name", 1).matches(&item),
+            Err(InterfaceError::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn test_cursor() -> Result<(), InterfaceError> {
+        // GIVEN the sort values of an item
+        let values = vec![FieldValue::Integer(3), FieldValue::Uuid(Uuid::new_v4())];
+
+        // WHEN we encode and decode them in a cursor
+        let cursor = Cursor::new(&values);
+
+        // THEN we get the same values back
+        assert_eq!(cursor.values()?, values);
+        // AND tampered cursors are rejected
+        let tampered = Cursor::from(format!("{}0", cursor.as_str()));
+        assert!(matches!(
+            tampered.values(),
+            Err(InterfaceError::InvalidQuery(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_condition() -> Result<(), InterfaceError> {
+        // GIVEN a filtered request after a cursor
+        let uuid = Uuid::new_v4();
+        let cursor = Cursor::new(&[FieldValue::Integer(3), FieldValue::Uuid(uuid)]);
+        let request = PageRequest::new("balance", 10)
+            .direction(Direction::Desc)
+            .filter(Filter::eq("name", "abc"))
+            .after(Some(cursor));
+
+        // WHEN we render the page condition and order
+        let (condition, params) = request.condition(&["uuid"])?.to_sql();
+
+        // THEN the items are restricted to the ones after the cursor
+        assert_eq!(
+            condition,
+            "(name = :p0) AND (((balance < :p1)) OR ((balance = :p2) AND (uuid < :p3)))"
+        );
+        assert_eq!(params[3], ("p3".to_string(), FieldValue::Uuid(uuid)));
+        assert_eq!(request.order_sql(&["uuid"]), "balance DESC, uuid DESC");
+        assert_eq!(request.order_sql(&["balance"]), "balance DESC");
+        assert_eq!(
+            request.order_sql(&["stan", "rrn"]),
+            "balance DESC, stan DESC, rrn DESC"
+        );
+        Ok(())
+    }
+
+    struct Account;
+
+    const BALANCE: Column<Account, i64> = Column::new("balance");
+    const NAME: Column<Account, String> = Column::new("name");
+    const BRANCH: Column<Account, Option<String>> = Column::new("branch");
+
+    #[test]
+    fn test_typed_condition() {
+        // GIVEN a condition built from typed columns
+        let condition = NAME
+            .eq("abc")
+            .and(BALANCE.gt(10).or(BRANCH.is_in(["Paris", "Lyon"])));
+
+        // WHEN we get its filter
+        let filter = Filter::from(condition);
+
+        // THEN it is the filter built from the field names
+        assert_eq!(
+            filter,
+            Filter::eq("name", "abc")
+                .and(Filter::gt("balance", 10_i64).or(Filter::is_in("branch", ["Paris", "Lyon"])))
+        );
+
+        // AND an ordering gives the order of a page
+        let request = PageRequest::ordered(BALANCE.desc(), 10).filter(BALANCE.ge(0));
+        assert_eq!(request.order_by, "balance");
+        assert_eq!(request.direction, Direction::Desc);
+        assert_eq!(request.filter, Some(Filter::ge("balance", 0_i64)));
+    }
+
+    #[test]
+    fn test_projection() -> Result<(), InterfaceError> {
+        // GIVEN a serialized entity
+        let item = json!({"name": "abc", "balance": 10, "branch": null});
+
+        // WHEN we project it on a tuple of columns
+        let projection = (NAME, BALANCE, BRANCH);
+        let row = project(&item, &projection.fields())?;
+
+        // THEN the values of the fields are decoded to their types
+        assert_eq!(
+            projection.decode(&row)?,
+            ("abc".to_string(), 10, None::<String>)
+        );
+
+        // AND an unknown field is rejected
+        assert!(project(&item, &["iban"]).is_err());
+        assert!(BALANCE.decode(&json!({"balance": "ten"})).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        let columns = ["uuid", "name"];
+        assert!(Filter::eq("name", "abc").validate(&columns).is_ok());
+        assert!(Filter::eq("name; DROP TABLE x", "abc")
+            .validate(&columns)
+            .is_err());
+        assert!(validate_selection(&["name"], &columns).is_ok());
+        assert!(validate_selection(&[], &columns).is_err());
+        assert!(validate_selection(&["name", "balance"], &columns).is_err());
+    }
+}
@@ -1,7 +1,13 @@
 //! RdsClient is use to communicate with an AWS Aurora DB
 use crate::settings::RdsSettings;
 use aws_config::SdkConfig;
-use aws_sdk_rdsdata::operation::execute_statement::builders::ExecuteStatementFluentBuilder;
+use aws_sdk_rdsdata::operation::{
+    batch_execute_statement::builders::BatchExecuteStatementFluentBuilder,
+    begin_transaction::builders::BeginTransactionFluentBuilder,
+    commit_transaction::builders::CommitTransactionFluentBuilder,
+    execute_statement::builders::ExecuteStatementFluentBuilder,
+    rollback_transaction::builders::RollbackTransactionFluentBuilder,
+};
 use secrecy::{ExposeSecret, Secret};
 
 #[derive(Clone)]
@@ -10,6 +16,7 @@ pub struct RdsClient {
     secret_arn: Secret<String>,
     cluster_arn: String,
     db_instance: String,
+    transaction_id: Option<String>,
 }
 
 impl RdsClient {
@@ -19,14 +26,62 @@ impl RdsClient {
             secret_arn: settings.secretarn.clone(),
             cluster_arn: settings.clusterarn.clone(),
             db_instance: settings.dbinstance.clone(),
+            transaction_id: None,
         }
     }
 
+    /// A client whose statements are executed in the given transaction
+    pub fn with_transaction(&self, transaction_id: &str) -> Self {
+        RdsClient {
+            transaction_id: Some(transaction_id.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Transaction the statements are executed in, if any
+    pub fn transaction_id(&self) -> Option<&str> {
+        self.transaction_id.as_deref()
+    }
+
     pub fn execute_statement(&self) -> ExecuteStatementFluentBuilder {
         self.client
             .execute_statement()
             .secret_arn(self.secret_arn.expose_secret())
             .resource_arn(self.cluster_arn.as_str())
             .database(self.db_instance.as_str())
+            .set_transaction_id(self.transaction_id.clone())
+    }
+
+    pub fn batch_execute_statement(&self) -> BatchExecuteStatementFluentBuilder {
+        self.client
+            .batch_execute_statement()
+            .secret_arn(self.secret_arn.expose_secret())
+            .resource_arn(self.cluster_arn.as_str())
+            .database(self.db_instance.as_str())
+            .set_transaction_id(self.transaction_id.clone())
+    }
+
+    pub fn begin_transaction(&self) -> BeginTransactionFluentBuilder {
+        self.client
+            .begin_transaction()
+            .secret_arn(self.secret_arn.expose_secret())
+            .resource_arn(self.cluster_arn.as_str())
+            .database(self.db_instance.as_str())
+    }
+
+    pub fn commit_transaction(&self) -> CommitTransactionFluentBuilder {
+        self.client
+            .commit_transaction()
+            .secret_arn(self.secret_arn.expose_secret())
+            .resource_arn(self.cluster_arn.as_str())
+            .set_transaction_id(self.transaction_id.clone())
+    }
+
+    pub fn rollback_transaction(&self) -> RollbackTransactionFluentBuilder {
+        self.client
+            .rollback_transaction()
+            .secret_arn(self.secret_arn.expose_secret())
+            .resource_arn(self.cluster_arn.as_str())
+            .set_transaction_id(self.transaction_id.clone())
     }
 }
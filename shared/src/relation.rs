@@ -0,0 +1,389 @@
+//! Relations between the entities of the repositories
+//!
+//! `struct_to_sql` declares them on the models: `#[sql(belongs_to(customer = "Customer"))]`
+//! on the foreign key of a card generates `Card::customer()`, a [`BelongsTo`], and
+//! `#[sql(has_many(cards = "Card(customer_uuid)"))]` on the customer generates
+//! `Customer::cards()`, a [`HasMany`]. The repositories load the related items with
+//! [`Join`](crate::ports::secondary::Join): the SQL ones join the tables in one query.
+use std::marker::PhantomData;
+
+use crate::error::InterfaceError;
+use crate::migration::quote_identifier;
+use crate::query::{validate_field, FieldValue, Filter};
+use crate::{QuerySet, Val};
+use serde_json::{Map, Value as JsonValue};
+
+/// Alias of the table of the items in the join queries
+const ITEM: &str = "item";
+
+/// Alias of the table of the related items in the join queries
+const RELATED: &str = "related";
+
+/// Relation of an entity `T` to the entity `U` referenced by a foreign key of `T`
+pub struct BelongsTo<T, U> {
+    foreign_key: &'static str,
+    parent: Box<dyn QuerySet<U> + Send + Sync>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, U> BelongsTo<T, U> {
+    pub fn new(foreign_key: &'static str, parent: Box<dyn QuerySet<U> + Send + Sync>) -> Self {
+        BelongsTo {
+            foreign_key,
+            parent,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Field of `T` holding the key of its `U`
+    pub fn foreign_key(&self) -> &'static str {
+        self.foreign_key
+    }
+
+    /// SQL query listing the items matching a filter, ordered by key, each one
+    /// with the item it belongs to (prepared)
+    pub fn join_sql<Q>(
+        &self,
+        queryset: &Q,
+        filter: &Filter,
+    ) -> Result<(String, Vec<(String, FieldValue)>), InterfaceError>
+    where
+        Q: QuerySet<T> + ?Sized,
+    {
+        validate_field(self.foreign_key, &queryset.columns())?;
+        let on = format!(
+            "{} = {}",
+            qualified(ITEM, &queryset.column(self.foreign_key)),
+            qualified(RELATED, &self.parent.column(&single_key(&*self.parent)?))
+        );
+        join_sql(queryset, &*self.parent, "JOIN", &on, filter)
+    }
+
+    /// Read a joined row as an item and the item it belongs to
+    pub fn decode(&self, row: &JsonValue) -> Result<(T, U), InterfaceError>
+    where
+        T: Val,
+        U: Val,
+    {
+        Ok((decode(row, ITEM)?, decode(row, RELATED)?))
+    }
+}
+
+/// Relation of an entity `T` to the entities `U` referencing it by a foreign key of `U`
+pub struct HasMany<T, U> {
+    foreign_key: &'static str,
+    children: Box<dyn QuerySet<U> + Send + Sync>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, U> HasMany<T, U> {
+    pub fn new(foreign_key: &'static str, children: Box<dyn QuerySet<U> + Send + Sync>) -> Self {
+        HasMany {
+            foreign_key,
+            children,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Field of `U` holding the key of its `T`
+    pub fn foreign_key(&self) -> &'static str {
+        self.foreign_key
+    }
+
+    /// SQL query listing the items matching a filter, ordered by key, each one
+    /// with the items referencing it, ordered by key too (prepared)
+    pub fn join_sql<Q>(
+        &self,
+        queryset: &Q,
+        filter: &Filter,
+    ) -> Result<(String, Vec<(String, FieldValue)>), InterfaceError>
+    where
+        Q: QuerySet<T> + ?Sized,
+    {
+        validate_field(self.foreign_key, &self.children.columns())?;
+        let on = format!(
+            "{} = {}",
+            qualified(RELATED, &self.children.column(self.foreign_key)),
+            qualified(ITEM, &queryset.column(&single_key(queryset)?))
+        );
+        join_sql(queryset, &*self.children, "LEFT JOIN", &on, filter)
+    }
+
+    /// Read the joined rows, ordered by item, as the items and the items referencing them
+    pub fn decode(&self, rows: &[JsonValue]) -> Result<Vec<(T, Vec<U>)>, InterfaceError>
+    where
+        T: Val,
+        U: Val,
+    {
+        let mut items: Vec<(JsonValue, T, Vec<U>)> = Vec::new();
+        for row in rows {
+            // The rows of an item follow each other, repeating its fields
+            let fields = fields_of(row, ITEM);
+            let json = JsonValue::Object(fields.clone());
+            if items.last().map(|(last, _, _)| last) != Some(&json) {
+                items.push((json, parse(fields)?, Vec::new()));
+            }
+            // The item has no related item when the left join found none
+            let foreign_key = row.get(format!("{RELATED}.{}", self.foreign_key));
+            if let (Some((_, _, children)), Some(foreign_key)) = (items.last_mut(), foreign_key) {
+                if !foreign_key.is_null() {
+                    children.push(decode(row, RELATED)?);
+                }
+            }
+        }
+        Ok(items
+            .into_iter()
+            .map(|(_, item, children)| (item, children))
+            .collect())
+    }
+}
+
+/// Join query of the items matching a filter and their related items, ordered by key.
+/// The columns are aliased `item.field` and `related.field`, and the soft deleted rows
+/// are left out of both tables.
+fn join_sql<T, U>(
+    queryset: &(impl QuerySet<T> + ?Sized),
+    related: &(impl QuerySet<U> + ?Sized),
+    join: &str,
+    on: &str,
+    filter: &Filter,
+) -> Result<(String, Vec<(String, FieldValue)>), InterfaceError> {
+    filter.validate(&queryset.columns())?;
+    let (condition, params) = filter.to_sql_with(|field| qualified(ITEM, &queryset.column(field)));
+
+    let mut columns = selected(ITEM, queryset);
+    columns.extend(selected(RELATED, related));
+    let on = match related.deleted_column() {
+        Some(deleted) => format!(
+            "{on} AND {} IS NULL",
+            qualified(RELATED, &quote_identifier(&deleted))
+        ),
+        None => on.to_string(),
+    };
+    let condition = match queryset.deleted_column() {
+        Some(deleted) => format!(
+            "{} IS NULL AND ({condition})",
+            qualified(ITEM, &quote_identifier(&deleted))
+        ),
+        None => condition,
+    };
+    let mut order_by: Vec<String> = queryset
+        .primary_key()
+        .iter()
+        .map(|field| qualified(ITEM, &queryset.column(field)))
+        .collect();
+    order_by.extend(
+        related
+            .primary_key()
+            .iter()
+            .map(|field| qualified(RELATED, &related.column(field))),
+    );
+
+    let sql = format!(
+        "SELECT {} FROM {} AS {} {join} {} AS {} ON {on} WHERE {condition} ORDER BY {}",
+        columns.join(", "),
+        quote_identifier(&queryset.table()),
+        quote_identifier(ITEM),
+        quote_identifier(&related.table()),
+        quote_identifier(RELATED),
+        order_by.join(", ")
+    );
+    Ok((sql, params))
+}
+
+/// Single field of the primary key of a table, the one a foreign key references
+fn single_key<T>(queryset: &(impl QuerySet<T> + ?Sized)) -> Result<String, InterfaceError> {
+    match &queryset.primary_key()[..] {
+        [key] => Ok(key.clone()),
+        _ => Err(InterfaceError::InvalidQuery(format!(
+            "{} has a composite key, it cannot be referenced",
+            queryset.table()
+        ))),
+    }
+}
+
+/// Column of a table alias, e.g. `"item"."uuid"`
+fn qualified(alias: &str, column: &str) -> String {
+    format!("{}.{}", quote_identifier(alias), column)
+}
+
+/// Columns of a table alias, named after their alias and field, e.g. `"item"."uuid" AS "item.uuid"`
+fn selected<T>(alias: &str, queryset: &(impl QuerySet<T> + ?Sized)) -> Vec<String> {
+    queryset
+        .columns()
+        .iter()
+        .map(|field| {
+            format!(
+                "{} AS {}",
+                qualified(alias, &queryset.column(field)),
+                quote_identifier(&format!("{alias}.{field}"))
+            )
+        })
+        .collect()
+}
+
+/// Fields of a table alias in a joined row, without their prefix
+fn fields_of(row: &JsonValue, alias: &str) -> Map<String, JsonValue> {
+    let prefix = format!("{alias}.");
+    row.as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(column, value)| {
+            column
+                .strip_prefix(&prefix)
+                .map(|field| (field.to_string(), value.clone()))
+        })
+        .collect()
+}
+
+fn parse<V: Val>(fields: Map<String, JsonValue>) -> Result<V, InterfaceError> {
+    serde_json::from_value(JsonValue::Object(fields))
+        .map_err(|e| InterfaceError::FromFields(format!("Failed to parse row: {e}")))
+}
+
+/// Read the item of a table alias in a joined row
+fn decode<V: Val>(row: &JsonValue, alias: &str) -> Result<V, InterfaceError> {
+    parse(fields_of(row, alias))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ports::secondary::HasKey;
+    use crate::query::Column;
+    use crate::usecase::rds::{FromRow, GetFieldsAsParams, RdsRow};
+    use pretty_assertions::assert_eq;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use sql_macros::struct_to_sql;
+    use uuid::Uuid;
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    #[sql(has_many(pets = "Pet(owner_uuid)"))]
+    pub(crate) struct Owner {
+        pub(crate) uuid: Uuid,
+        pub(crate) name: String,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql(soft_delete)]
+    pub(crate) struct Pet {
+        pub(crate) uuid: Uuid,
+        #[sql(belongs_to(owner = "Owner"))]
+        pub(crate) owner_uuid: Option<Uuid>,
+        pub(crate) name: String,
+    }
+
+    pub(crate) fn gen_owner(name: &str) -> Owner {
+        Owner {
+            uuid: Uuid::new_v4(),
+            name: name.to_string(),
+        }
+    }
+
+    pub(crate) fn gen_pet(owner: Option<&Owner>, name: &str) -> Pet {
+        Pet {
+            uuid: Uuid::new_v4(),
+            owner_uuid: owner.map(|owner| owner.uuid),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_belongs_to_join_sql() {
+        // GIVEN the relation of the pets to their owner
+        let relation = Pet::owner();
+
+        // WHEN the join query of the pets is built
+        let (sql, params) = relation
+            .join_sql(&Pet::queryset(), &Filter::eq("name", "Rex"))
+            .unwrap();
+
+        // THEN the owners are inner joined on the foreign key, deleted pets left out
+        assert_eq!(
+            sql,
+            r#"SELECT "item"."uuid" AS "item.uuid", "item"."owner_uuid" AS "item.owner_uuid", "item"."name" AS "item.name", "related"."uuid" AS "related.uuid", "related"."name" AS "related.name" FROM "pet" AS "item" JOIN "owner" AS "related" ON "item"."owner_uuid" = "related"."uuid" WHERE "item"."deleted_at" IS NULL AND ("item"."name" = :p0) ORDER BY "item"."uuid", "related"."uuid""#
+        );
+        assert_eq!(params.len(), 1);
+    }
+
+    #[test]
+    fn test_has_many_join_sql() {
+        // GIVEN the relation of the owners to their pets
+        let relation = Owner::pets();
+
+        // WHEN the join query of the owners is built
+        let (sql, params) = relation
+            .join_sql(&Owner::queryset(), &Filter::And(vec![]))
+            .unwrap();
+
+        // THEN the pets are left joined on the foreign key, deleted pets left out
+        assert_eq!(
+            sql,
+            r#"SELECT "item"."uuid" AS "item.uuid", "item"."name" AS "item.name", "related"."uuid" AS "related.uuid", "related"."owner_uuid" AS "related.owner_uuid", "related"."name" AS "related.name" FROM "owner" AS "item" LEFT JOIN "pet" AS "related" ON "related"."owner_uuid" = "item"."uuid" AND "related"."deleted_at" IS NULL WHERE TRUE ORDER BY "item"."uuid", "related"."uuid""#
+        );
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn test_join_sql_unknown_field() {
+        // GIVEN the relation of the pets to their owner
+        let relation = Pet::owner();
+
+        // WHEN the join query filters on a field pets don't have
+        let result = relation.join_sql(&Pet::queryset(), &Filter::eq("age", 3));
+
+        // THEN the query is invalid
+        assert!(matches!(result, Err(InterfaceError::InvalidQuery(_))));
+    }
+
+    #[test]
+    fn test_belongs_to_decode() {
+        // GIVEN a joined row of a pet and its owner
+        let owner = gen_owner("Alice");
+        let pet = gen_pet(Some(&owner), "Rex");
+        let row = json!({
+            "item.uuid": pet.uuid,
+            "item.owner_uuid": owner.uuid,
+            "item.name": "Rex",
+            "related.uuid": owner.uuid,
+            "related.name": "Alice",
+        });
+
+        // WHEN the row is decoded
+        let decoded = Pet::owner().decode(&row).unwrap();
+
+        // THEN it is the pet and its owner
+        assert_eq!(decoded, (pet, owner));
+    }
+
+    #[test]
+    fn test_has_many_decode() {
+        // GIVEN the joined rows of an owner with two pets and of an owner without pets
+        let alice = gen_owner("Alice");
+        let bob = gen_owner("Bob");
+        let rex = gen_pet(Some(&alice), "Rex");
+        let tom = gen_pet(Some(&alice), "Tom");
+        let row = |owner: &Owner, pet: Option<&Pet>| {
+            json!({
+                "item.uuid": owner.uuid,
+                "item.name": owner.name,
+                "related.uuid": pet.map(|pet| pet.uuid),
+                "related.owner_uuid": pet.and_then(|pet| pet.owner_uuid),
+                "related.name": pet.map(|pet| pet.name.clone()),
+            })
+        };
+        let rows = vec![
+            row(&alice, Some(&rex)),
+            row(&alice, Some(&tom)),
+            row(&bob, None),
+        ];
+
+        // WHEN the rows are decoded
+        let decoded = Owner::pets().decode(&rows).unwrap();
+
+        // THEN each owner comes once, with its pets
+        assert_eq!(decoded, vec![(alice, vec![rex, tom]), (bob, vec![])]);
+    }
+}
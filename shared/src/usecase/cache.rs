@@ -0,0 +1,549 @@
+//! Caching decorator for any Repository
+//!
+//! Items read with `get` are kept for a bounded time, up to a bounded number of
+//! items evicting the least recently used. Created items are written through to the
+//! cache, updated and deleted ones are invalidated: the inner repository may change
+//! them on write, e.g. by incrementing their version. Lists are not cached.
+use crate::ports::secondary::{
+    Change, Create, Delete, Get, HasKey, History, Join, List, Repository, Update,
+};
+use crate::query::{Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::{error::InterfaceError, Val};
+use async_trait::async_trait;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Default time an item is kept
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// Default number of cached items
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+struct Entry<T> {
+    item: T,
+    expires: Instant,
+    used: u64,
+}
+
+/// Items cached by key, shared by a repository and the ones bound to its transactions
+struct Cache<T>
+where
+    T: Val + HasKey,
+{
+    entries: BTreeMap<T::Key, Entry<T>>,
+    /// Keys by last use, the first one is evicted when the cache is full
+    recency: BTreeMap<u64, T::Key>,
+    clock: u64,
+    /// Incremented by each invalidation: a read started before must not fill the cache
+    generation: u64,
+}
+
+impl<T> Cache<T>
+where
+    T: Val + HasKey,
+{
+    fn get(&mut self, key: &T::Key) -> Option<T> {
+        let entry = self.entries.get(key)?;
+        if entry.expires <= Instant::now() {
+            self.remove(key);
+            return None;
+        }
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.used);
+        entry.used = self.clock;
+        self.recency.insert(self.clock, key.clone());
+        Some(entry.item.clone())
+    }
+
+    fn insert(&mut self, item: &T, ttl: Duration, capacity: usize) {
+        let key = item.key();
+        self.remove(&key);
+        while self.entries.len() >= capacity {
+            match self.recency.pop_first() {
+                Some((_, evicted)) => self.entries.remove(&evicted),
+                None => return,
+            };
+        }
+
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                item: item.clone(),
+                expires: Instant::now() + ttl,
+                used: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &T::Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+        }
+    }
+
+    fn invalidate(&mut self, keys: &[T::Key]) {
+        for key in keys {
+            self.remove(key);
+        }
+        self.generation += 1;
+    }
+}
+
+/// Repository caching the items of an inner repository
+pub struct CachedRepository<T, R>
+where
+    T: Val + HasKey,
+{
+    inner: R,
+    cache: Arc<Mutex<Cache<T>>>,
+    ttl: Duration,
+    capacity: usize,
+    /// Keys written through a repository bound to a transaction,
+    /// invalidated again once the transaction is over
+    written: Option<Mutex<Vec<T::Key>>>,
+}
+
+impl<T, R> CachedRepository<T, R>
+where
+    T: Val + HasKey,
+{
+    pub fn new(inner: R) -> Self {
+        CachedRepository {
+            inner,
+            cache: Arc::new(Mutex::new(Cache {
+                entries: BTreeMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                generation: 0,
+            })),
+            ttl: DEFAULT_TTL,
+            capacity: DEFAULT_CAPACITY,
+            written: None,
+        }
+    }
+
+    /// Keep the items for `ttl` after they are read
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.set_ttl(ttl);
+        self
+    }
+
+    /// Keep at most `capacity` items, no item is cached with a capacity of 0
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.set_capacity(capacity);
+        self
+    }
+
+    /// Same as `with_ttl`, once the repository is decorated
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Same as `with_capacity`, once the repository is decorated
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// The decorated repository
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// The decorated repository, e.g. to configure it
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Repository over `inner` bound to a transaction, sharing this cache
+    ///
+    /// It reads from `inner` without filling the cache, which must not hold
+    /// uncommitted items. The keys it writes are invalidated when it is dropped,
+    /// so it should be dropped once the transaction is committed or rolled back.
+    pub fn in_transaction<S>(&self, inner: S) -> CachedRepository<T, S> {
+        CachedRepository {
+            inner,
+            cache: Arc::clone(&self.cache),
+            ttl: self.ttl,
+            capacity: self.capacity,
+            written: Some(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Forget the cached item of a key, e.g. after it was written by another process
+    pub fn invalidate(&self, key: &T::Key) {
+        self.lock().invalidate(std::slice::from_ref(key));
+    }
+
+    /// Forget all the cached items
+    pub fn invalidate_all(&self) {
+        let mut cache = self.lock();
+        let keys: Vec<T::Key> = cache.entries.keys().cloned().collect();
+        cache.invalidate(&keys);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cache<T>> {
+        // The cache is consistent after each call, even if a thread panicked
+        self.cache.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Invalidate written keys, whether the write succeeded or not
+    fn written(&self, keys: Vec<T::Key>) {
+        self.lock().invalidate(&keys);
+        if let Some(written) = &self.written {
+            written.lock().unwrap().extend(keys);
+        }
+    }
+
+    /// Cache created items, unless another write happened during their creation
+    fn created(&self, items: &[T], generation: u64) {
+        let mut cache = self.lock();
+        if self.written.is_none() && cache.generation == generation {
+            for item in items {
+                cache.insert(item, self.ttl, self.capacity);
+            }
+        }
+    }
+}
+
+impl<T, R> Drop for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+{
+    fn drop(&mut self) {
+        if let Some(written) = &self.written {
+            let keys = std::mem::take(&mut *written.lock().unwrap());
+            if !keys.is_empty() {
+                self.lock().invalidate(&keys);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T, R> Create<T> for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        self.create_many(std::slice::from_ref(item)).await
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let generation = self.lock().generation;
+        let result = match items {
+            [item] => self.inner.create(item).await,
+            items => self.inner.create_many(items).await,
+        };
+        match result {
+            Ok(()) => self.created(items, generation),
+            Err(_) => self.written(items.iter().map(HasKey::key).collect()),
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl<T, R> Get<T> for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        if self.written.is_some() {
+            return self.inner.get(key).await;
+        }
+
+        let generation = {
+            let mut cache = self.lock();
+            if let Some(item) = cache.get(key) {
+                return Ok(Some(item));
+            }
+            cache.generation
+        };
+
+        let item = self.inner.get(key).await?;
+        if let Some(item) = &item {
+            let mut cache = self.lock();
+            if cache.generation == generation {
+                cache.insert(item, self.ttl, self.capacity);
+            }
+        }
+        Ok(item)
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        if self.written.is_none() && self.lock().get(key).is_some() {
+            return Ok(true);
+        }
+        self.inner.exists(key).await
+    }
+}
+
+#[async_trait]
+impl<T, R> Update<T> for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        let result = self.inner.update(item).await;
+        self.written(vec![item.key()]);
+        result
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let result = self.inner.update_many(items).await;
+        self.written(items.iter().map(HasKey::key).collect());
+        result
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        let result = self.inner.upsert(item).await;
+        self.written(vec![item.key()]);
+        result
+    }
+}
+
+#[async_trait]
+impl<T, R> Delete<T> for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        let result = self.inner.delete(key).await;
+        self.written(vec![key.clone()]);
+        result
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        let result = self.inner.delete_many(keys).await;
+        self.written(keys.to_vec());
+        result
+    }
+}
+
+#[async_trait]
+impl<T, R> List<T> for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        self.inner.list().await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        self.inner.list_where(filter).await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        self.inner.list_page(request).await
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        self.inner.count(filter).await
+    }
+
+    async fn select_where(
+        &self,
+        fields: &[&str],
+        filter: &Filter,
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        self.inner.select_where(fields, filter).await
+    }
+}
+
+impl<T, R> Repository<T> for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+}
+
+#[async_trait]
+impl<T, R> History<T> for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + History<T> + Send + Sync,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        self.inner.history(key).await
+    }
+}
+
+#[async_trait]
+impl<T, U, R> Join<T, U> for CachedRepository<T, R>
+where
+    T: Val + HasKey,
+    U: Val,
+    R: Repository<T> + Join<T, U> + Send + Sync,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        self.inner.list_with_parent(relation, filter).await
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        self.inner.list_with_children(relation, filter).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{
+        gen_item, ConformanceItem, RepositoryFactory, VersionedConformanceItem,
+    };
+    use crate::usecase::memory::InMemoryRepository;
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    type Repo = CachedRepository<ConformanceItem, InMemoryRepository<ConformanceItem>>;
+
+    #[tokio::test]
+    async fn test_read_through() -> Result<(), InterfaceError> {
+        // GIVEN a cached repository with an item read once
+        let repo: Repo = CachedRepository::new(InMemoryRepository::new());
+        let mut item = gen_item(3);
+        repo.inner().create(&item).await?;
+        repo.get(&item.uuid).await?;
+
+        // WHEN the item is changed behind the cache
+        item.field1 = 4;
+        repo.inner().update(&item).await?;
+
+        // THEN the cached item is read until it is invalidated
+        assert_eq!(repo.get(&item.uuid).await?.unwrap().field1, 3);
+        repo.invalidate(&item.uuid);
+        assert_eq!(repo.get(&item.uuid).await?.unwrap().field1, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write() -> Result<(), InterfaceError> {
+        // GIVEN a cached repository with a created item
+        let repo: Repo = CachedRepository::new(InMemoryRepository::new());
+        let mut item = gen_item(3);
+        repo.create(&item).await?;
+        assert_eq!(repo.lock().entries.len(), 1);
+
+        // WHEN the item is updated, then deleted
+        item.field1 = 4;
+        repo.update(&item).await?;
+        let updated = repo.get(&item.uuid).await?;
+        repo.delete(&item.uuid).await?;
+
+        // THEN the cache never returns a stale item
+        assert_eq!(updated, Some(item.clone()));
+        assert_eq!(repo.get(&item.uuid).await?, None);
+        assert!(repo.lock().entries.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ttl() -> Result<(), InterfaceError> {
+        // GIVEN a cached repository keeping items for 10ms
+        let repo: Repo =
+            CachedRepository::new(InMemoryRepository::new()).with_ttl(Duration::from_millis(10));
+        let mut item = gen_item(3);
+        repo.create(&item).await?;
+
+        // WHEN the item is changed behind the cache and the TTL elapses
+        item.field1 = 4;
+        repo.inner().update(&item).await?;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // THEN the item is read again
+        assert_eq!(repo.get(&item.uuid).await?, Some(item));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_capacity() -> Result<(), InterfaceError> {
+        // GIVEN a cached repository keeping two items
+        let repo: Repo = CachedRepository::new(InMemoryRepository::new()).with_capacity(2);
+        let items = [gen_item(3), gen_item(3), gen_item(3)];
+
+        // WHEN three items are created, the first one being read before the third one
+        repo.create(&items[0]).await?;
+        repo.create(&items[1]).await?;
+        repo.get(&items[0].uuid).await?;
+        repo.create(&items[2]).await?;
+
+        // THEN the least recently used one is evicted
+        let cached: Vec<Uuid> = repo.lock().entries.keys().cloned().collect();
+        let mut expected = vec![items[0].uuid, items[2].uuid];
+        expected.sort();
+        assert_eq!(cached, expected);
+
+        // AND nothing is cached without capacity
+        let repo: Repo = CachedRepository::new(InMemoryRepository::new()).with_capacity(0);
+        repo.create(&items[0]).await?;
+        assert!(repo.lock().entries.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_transaction() -> Result<(), InterfaceError> {
+        // GIVEN a cached item
+        let repo: Repo = CachedRepository::new(InMemoryRepository::new());
+        let mut item = gen_item(3);
+        repo.create(&item).await?;
+
+        // WHEN it is updated through a repository bound to a transaction
+        let transaction = repo.in_transaction(InMemoryRepository::new());
+        item.field1 = 4;
+        transaction.inner().create(&item).await?;
+        transaction.update(&item).await?;
+        let read = transaction.get(&item.uuid).await?;
+
+        // THEN the transaction reads its own item without caching it
+        assert_eq!(read, Some(item.clone()));
+        assert!(repo.lock().entries.is_empty());
+
+        // AND the written keys are invalidated once the transaction is dropped
+        repo.lock().insert(&item, DEFAULT_TTL, DEFAULT_CAPACITY);
+        drop(transaction);
+        assert!(repo.lock().entries.is_empty());
+        Ok(())
+    }
+
+    struct CacheFactory;
+
+    #[async_trait]
+    impl RepositoryFactory for CacheFactory {
+        type Repo = CachedRepository<ConformanceItem, InMemoryRepository<ConformanceItem>>;
+        type VersionedRepo = CachedRepository<
+            VersionedConformanceItem,
+            InMemoryRepository<VersionedConformanceItem>,
+        >;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            Ok(CachedRepository::new(InMemoryRepository::new()))
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            Ok(CachedRepository::new(
+                InMemoryRepository::new().with_version("version"),
+            ))
+        }
+    }
+
+    crate::repository_conformance_tests!(CacheFactory);
+}
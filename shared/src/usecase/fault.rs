@@ -0,0 +1,513 @@
+//! Fault injection decorator for any Repository, to test resilience
+//!
+//! The calls can be delayed, and fail at random, for some operations or keys, or on
+//! demand. The randomness is drawn from a seeded generator, so that a test replays the
+//! same faults. A fault either prevents the call, or loses its reply once it was applied,
+//! like a timeout after the database committed.
+use crate::ports::secondary::{
+    Change, Create, Delete, Get, HasKey, History, Join, List, Repository, Update,
+};
+use crate::query::{Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::{error::InterfaceError, Val};
+use async_trait::async_trait;
+use serde_json::Value as JsonValue;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::debug;
+
+/// Operation of a repository, batches included: `exists` and `history` are a `Get`,
+/// `upsert` an `Update` and `count` a `List`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    Create,
+    Get,
+    Update,
+    Delete,
+    List,
+}
+
+type ErrorFactory = Arc<dyn Fn(Operation) -> InterfaceError + Send + Sync>;
+
+/// Repository injecting faults in the calls to an inner repository
+pub struct FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+{
+    inner: R,
+    latency: Duration,
+    jitter: Duration,
+    failure_rate: f64,
+    operations: BTreeSet<Operation>,
+    keys: BTreeSet<T::Key>,
+    lost_replies: bool,
+    error: ErrorFactory,
+    state: Mutex<State>,
+}
+
+/// Generator and counters shared by the calls
+struct State {
+    random: u64,
+    fail_next: usize,
+    injected: usize,
+}
+
+impl State {
+    /// Next number of the SplitMix64 sequence
+    fn next(&mut self) -> u64 {
+        self.random = self.random.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.random;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl<T, R> FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    /// Wrap `inner` without any fault, until they are configured
+    pub fn new(inner: R) -> Self {
+        FaultyRepository {
+            inner,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            failure_rate: 0.0,
+            operations: BTreeSet::new(),
+            keys: BTreeSet::new(),
+            lost_replies: false,
+            error: Arc::new(|operation| {
+                InterfaceError::Transient(format!("Injected fault in {:?}", operation))
+            }),
+            state: Mutex::new(State {
+                random: RandomState::new().build_hasher().finish(),
+                fail_next: 0,
+                injected: 0,
+            }),
+        }
+    }
+
+    /// Draw the random faults from `seed`, to replay them
+    pub fn with_seed(self, seed: u64) -> Self {
+        self.lock().random = seed;
+        self
+    }
+
+    /// Delay every call by `latency`, plus a random delay up to `jitter`
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    /// Fail a fraction of the calls, between 0 and 1
+    pub fn with_failure_rate(mut self, rate: f64) -> Self {
+        self.failure_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Fail every call of an operation
+    pub fn fail_operation(mut self, operation: Operation) -> Self {
+        self.operations.insert(operation);
+        self
+    }
+
+    /// Fail every call reading or writing the item of a key
+    pub fn fail_key(mut self, key: T::Key) -> Self {
+        self.keys.insert(key);
+        self
+    }
+
+    /// Apply the failing calls and lose their replies, instead of preventing them
+    pub fn with_lost_replies(mut self) -> Self {
+        self.lost_replies = true;
+        self
+    }
+
+    /// Error of the failing calls, `InterfaceError::Transient` by default
+    pub fn with_error(
+        mut self,
+        error: impl Fn(Operation) -> InterfaceError + Send + Sync + 'static,
+    ) -> Self {
+        self.error = Arc::new(error);
+        self
+    }
+
+    /// Fail the next `calls`, whatever their operation
+    pub fn fail_next(&self, calls: usize) {
+        self.lock().fail_next += calls;
+    }
+
+    /// Number of faults injected so far
+    pub fn injected(&self) -> usize {
+        self.lock().injected
+    }
+
+    /// The decorated repository
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Delay of a call, and whether it fails
+    fn draw(&self, operation: Operation, keys: &[T::Key]) -> (Duration, bool) {
+        let mut state = self.lock();
+        // Draw the same numbers for every call, so that the sequence of faults
+        // only depends on the seed and the number of calls
+        let delay = self.latency + self.jitter.mul_f64(state.unit());
+        let random = state.unit() < self.failure_rate;
+
+        let failing = if state.fail_next > 0 {
+            state.fail_next -= 1;
+            true
+        } else {
+            random
+                || self.operations.contains(&operation)
+                || keys.iter().any(|key| self.keys.contains(key))
+        };
+        if failing {
+            state.injected += 1;
+        }
+        (delay, failing)
+    }
+
+    /// Run a call with its faults
+    async fn inject<V>(
+        &self,
+        operation: Operation,
+        keys: &[T::Key],
+        call: impl Future<Output = Result<V, InterfaceError>>,
+    ) -> Result<V, InterfaceError> {
+        let (delay, failing) = self.draw(operation, keys);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        if !failing {
+            return call.await;
+        }
+
+        debug!(
+            ?operation,
+            lost_reply = self.lost_replies,
+            "Injecting a fault"
+        );
+        if self.lost_replies {
+            call.await?;
+        }
+        Err((self.error)(operation))
+    }
+}
+
+#[async_trait]
+impl<T, R> Create<T> for FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        let keys = [item.key()];
+        self.inject(Operation::Create, &keys, self.inner.create(item))
+            .await
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let keys: Vec<T::Key> = items.iter().map(HasKey::key).collect();
+        self.inject(Operation::Create, &keys, self.inner.create_many(items))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, R> Get<T> for FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        let keys = std::slice::from_ref(key);
+        self.inject(Operation::Get, keys, self.inner.get(key)).await
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        let keys = std::slice::from_ref(key);
+        self.inject(Operation::Get, keys, self.inner.exists(key))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, R> Update<T> for FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        let keys = [item.key()];
+        self.inject(Operation::Update, &keys, self.inner.update(item))
+            .await
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let keys: Vec<T::Key> = items.iter().map(HasKey::key).collect();
+        self.inject(Operation::Update, &keys, self.inner.update_many(items))
+            .await
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        let keys = [item.key()];
+        self.inject(Operation::Update, &keys, self.inner.upsert(item))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, R> Delete<T> for FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        let keys = std::slice::from_ref(key);
+        self.inject(Operation::Delete, keys, self.inner.delete(key))
+            .await
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        self.inject(Operation::Delete, keys, self.inner.delete_many(keys))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, R> List<T> for FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        self.inject(Operation::List, &[], self.inner.list()).await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        self.inject(Operation::List, &[], self.inner.list_where(filter))
+            .await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        self.inject(Operation::List, &[], self.inner.list_page(request))
+            .await
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        self.inject(Operation::List, &[], self.inner.count(filter))
+            .await
+    }
+
+    async fn select_where(
+        &self,
+        fields: &[&str],
+        filter: &Filter,
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        self.inject(
+            Operation::List,
+            &[],
+            self.inner.select_where(fields, filter),
+        )
+        .await
+    }
+}
+
+impl<T, R> Repository<T> for FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+}
+
+#[async_trait]
+impl<T, R> History<T> for FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + History<T> + Send + Sync,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        let keys = std::slice::from_ref(key);
+        self.inject(Operation::Get, keys, self.inner.history(key))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, U, R> Join<T, U> for FaultyRepository<T, R>
+where
+    T: Val + HasKey,
+    U: Val,
+    R: Repository<T> + Join<T, U> + Send + Sync,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        self.inject(
+            Operation::List,
+            &[],
+            self.inner.list_with_parent(relation, filter),
+        )
+        .await
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        self.inject(
+            Operation::List,
+            &[],
+            self.inner.list_with_children(relation, filter),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{
+        gen_item, ConformanceItem, RepositoryFactory, VersionedConformanceItem,
+    };
+    use crate::error::ErrorKind;
+    use crate::usecase::memory::InMemoryRepository;
+    use pretty_assertions::assert_eq;
+    use std::time::Instant;
+
+    type Repo = FaultyRepository<ConformanceItem, InMemoryRepository<ConformanceItem>>;
+
+    async fn outcomes(repo: &Repo, calls: usize) -> Vec<bool> {
+        let mut outcomes = Vec::new();
+        for _ in 0..calls {
+            outcomes.push(repo.list().await.is_ok());
+        }
+        outcomes
+    }
+
+    #[tokio::test]
+    async fn test_seeded_failure_rate() {
+        // GIVEN two repositories failing half of the calls, with the same seed
+        let repo = |seed| {
+            FaultyRepository::new(InMemoryRepository::<ConformanceItem>::new())
+                .with_failure_rate(0.5)
+                .with_seed(seed)
+        };
+        let (repo1, repo2, repo3) = (repo(42), repo(42), repo(7));
+
+        // WHEN they are called
+        let outcomes1 = outcomes(&repo1, 64).await;
+        let outcomes2 = outcomes(&repo2, 64).await;
+        let outcomes3 = outcomes(&repo3, 64).await;
+
+        // THEN the same calls fail with the same seed
+        assert_eq!(outcomes1, outcomes2);
+        assert_ne!(outcomes1, outcomes3);
+        let failed = outcomes1.iter().filter(|ok| !**ok).count();
+        assert!((16..48).contains(&failed), "{} failures", failed);
+        assert_eq!(repo1.injected(), failed);
+    }
+
+    #[tokio::test]
+    async fn test_fail_operation_and_key() -> Result<(), InterfaceError> {
+        // GIVEN a repository failing the updates, and the calls on an item
+        let (item1, item2) = (gen_item(3), gen_item(3));
+        let repo = FaultyRepository::new(InMemoryRepository::<ConformanceItem>::new())
+            .fail_operation(Operation::Update)
+            .fail_key(item2.uuid)
+            .with_error(|_| InterfaceError::Other("down".to_string()));
+
+        // WHEN the items are created, read and updated
+        repo.create(&item1).await?;
+        let create2 = repo.create(&item2).await;
+        let get1 = repo.get(&item1.uuid).await?;
+        let update1 = repo.update(&item1).await;
+        let batch = repo.delete_many(&[item1.uuid, item2.uuid]).await;
+
+        // THEN only these calls fail, with the configured error
+        assert_eq!(get1, Some(item1.clone()));
+        assert_eq!(create2.unwrap_err().kind(), ErrorKind::Internal);
+        assert!(update1.is_err());
+        assert!(batch.is_err());
+        assert_eq!(repo.inner().list().await?, vec![item1]);
+        assert_eq!(repo.injected(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lost_reply() -> Result<(), InterfaceError> {
+        // GIVEN a repository losing the replies of the failing calls
+        let repo =
+            FaultyRepository::new(InMemoryRepository::<ConformanceItem>::new()).with_lost_replies();
+        let item = gen_item(3);
+
+        // WHEN the next create fails
+        repo.fail_next(1);
+        let created = repo.create(&item).await;
+
+        // THEN it failed with a transient error, but the item was created
+        assert!(created.unwrap_err().is_retryable());
+        assert_eq!(repo.get(&item.uuid).await?, Some(item));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_latency() -> Result<(), InterfaceError> {
+        // GIVEN a slow repository
+        let repo = FaultyRepository::new(InMemoryRepository::<ConformanceItem>::new())
+            .with_latency(Duration::from_millis(20), Duration::from_millis(10));
+
+        // WHEN it is called
+        let start = Instant::now();
+        repo.list().await?;
+
+        // THEN the call is delayed
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(20), "{:?}", elapsed);
+        Ok(())
+    }
+
+    struct FaultyFactory;
+
+    #[async_trait]
+    impl RepositoryFactory for FaultyFactory {
+        type Repo = FaultyRepository<ConformanceItem, InMemoryRepository<ConformanceItem>>;
+        type VersionedRepo = FaultyRepository<
+            VersionedConformanceItem,
+            InMemoryRepository<VersionedConformanceItem>,
+        >;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            let repo = FaultyRepository::new(InMemoryRepository::new());
+            Ok(repo.with_latency(Duration::ZERO, Duration::from_millis(2)))
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            let repo = FaultyRepository::new(InMemoryRepository::new().with_version("version"));
+            Ok(repo.with_latency(Duration::ZERO, Duration::from_millis(2)))
+        }
+    }
+
+    crate::repository_conformance_tests!(FaultyFactory);
+}
@@ -0,0 +1,1200 @@
+//! File backed implementation of a Repository
+//!
+//! The items are held in memory. Every write appends the new state of the
+//! modified items, and their new revisions when the history is recorded, to a
+//! journal, which is periodically compacted into a snapshot.
+//! On startup the snapshot is loaded and the journal replayed. A record torn by a
+//! crash in the middle of a write is the journal's last line, without a newline,
+//! and is truncated. Any other bad record fails the opening, so that the records
+//! after it are never lost.
+use crate::ports::secondary::{
+    Change, Create, Delete, Get, HasKey, History, Join, List, Mutation, Repository, Transaction,
+    Update,
+};
+use crate::query::{Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::usecase::memory::{
+    Entry, InMemoryRepository, MemoryTransaction, MemoryTransactionRepository,
+};
+use crate::{error::InterfaceError, Val};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const SNAPSHOT: &str = "snapshot.json";
+const SNAPSHOT_TMP: &str = "snapshot.json.tmp";
+const JOURNAL: &str = "journal.log";
+
+/// Number of journal records after which the journal is compacted
+pub const DEFAULT_COMPACTION: usize = 1000;
+
+fn io_error(err: io::Error) -> InterfaceError {
+    InterfaceError::Other(format!("File repository failed: {err}"))
+}
+
+/// A journal record: the state of an item after a write, or a revision of its history
+#[derive(Serialize, Deserialize)]
+enum Record<T> {
+    Put(T),
+    Delete(T),
+    SoftDelete(T),
+    Change(Revision<T>),
+}
+
+/// A `Change` of the history of an item, as stored in the journal and the snapshot
+#[derive(Serialize, Deserialize)]
+struct Revision<T> {
+    revision: i64,
+    operation: String,
+    before: Option<T>,
+    after: Option<T>,
+    actor: String,
+    changed_at: i64,
+}
+
+impl<T> From<Change<T>> for Revision<T> {
+    fn from(change: Change<T>) -> Self {
+        Revision {
+            revision: change.revision,
+            operation: change.operation.as_str().to_string(),
+            before: change.before,
+            after: change.after,
+            actor: change.actor,
+            changed_at: change.changed_at,
+        }
+    }
+}
+
+impl<T: HasKey> Revision<T> {
+    /// Key of the item and the change it records
+    fn into_change(self) -> Result<(T::Key, Change<T>), InterfaceError> {
+        let key = match self.after.as_ref().or(self.before.as_ref()) {
+            Some(item) => item.key(),
+            None => {
+                return Err(InterfaceError::FromFields(format!(
+                    "Revision {} has no item",
+                    self.revision
+                )))
+            }
+        };
+        let change = Change {
+            revision: self.revision,
+            operation: Mutation::parse(&self.operation)?,
+            before: self.before,
+            after: self.after,
+            actor: self.actor,
+            changed_at: self.changed_at,
+        };
+        Ok((key, change))
+    }
+}
+
+/// The content of a snapshot
+#[derive(Serialize, Deserialize)]
+struct Snapshot<T> {
+    items: Vec<T>,
+    deleted: Vec<T>,
+    history: Vec<Revision<T>>,
+}
+
+impl<T: Val + HasKey> Snapshot<T> {
+    fn of(memory: &InMemoryRepository<T>) -> Self {
+        let (items, deleted, history) = memory.backup();
+        Snapshot {
+            items,
+            deleted,
+            history: history.into_iter().map(Revision::from).collect(),
+        }
+    }
+}
+
+/// Recover a record of the journal or the snapshot
+fn recover<T: Val + HasKey>(
+    memory: &InMemoryRepository<T>,
+    record: Record<T>,
+) -> Result<(), InterfaceError> {
+    match record {
+        Record::Put(item) => memory.recover(item.key(), Some(item)),
+        Record::Delete(item) => memory.recover(item.key(), None),
+        Record::SoftDelete(item) => memory.recover_deleted(item),
+        Record::Change(revision) => {
+            let (key, change) = revision.into_change()?;
+            memory.recover_change(key, change);
+        }
+    }
+    Ok(())
+}
+
+/// CRC-32 (IEEE) of a journal record, to detect torn writes
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Encode a record as a `<checksum> <json>` line
+fn encode<T: Serialize>(record: &Record<T>) -> Result<Vec<u8>, InterfaceError> {
+    let json = serde_json::to_vec(record)
+        .map_err(|e| InterfaceError::FromFields(format!("Failed to serialize record: {e}")))?;
+    let mut line = format!("{:08x} ", checksum(&json)).into_bytes();
+    line.extend(json);
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Decode a complete line of the journal, found at `offset`
+fn decode<T: Val>(line: &[u8], offset: usize) -> Result<Record<T>, InterfaceError> {
+    let corrupted = || InterfaceError::Other(format!("Corrupted journal record at byte {offset}"));
+    let (crc, json) = match (line.get(..9), line.get(9..)) {
+        (Some([crc @ .., b' ']), Some(json)) => (crc, json),
+        _ => return Err(corrupted()),
+    };
+    let crc = std::str::from_utf8(crc)
+        .ok()
+        .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+        .ok_or_else(corrupted)?;
+    if crc != checksum(json) {
+        return Err(corrupted());
+    }
+    serde_json::from_slice(json).map_err(|e| {
+        InterfaceError::FromFields(format!(
+            "Failed to parse journal record at byte {offset}: {e}"
+        ))
+    })
+}
+
+/// The append-only journal of a repository
+struct Journal {
+    directory: PathBuf,
+    file: File,
+    /// Length of the valid records, where the next record is written
+    len: u64,
+    records: usize,
+    compaction: usize,
+}
+
+impl Journal {
+    /// Append records durably, all of them or none
+    fn append(&mut self, lines: &[u8]) -> Result<(), InterfaceError> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let written = self
+            .file
+            .write_all(lines)
+            .and_then(|_| self.file.sync_data());
+
+        if let Err(err) = written {
+            // Drop a partial write so that the next records are not written after it
+            self.file.set_len(self.len).map_err(io_error)?;
+            return Err(io_error(err));
+        }
+        self.len += lines.len() as u64;
+        self.records += lines.iter().filter(|byte| **byte == b'\n').count();
+        Ok(())
+    }
+
+    /// Write the items to a new snapshot and empty the journal
+    fn compact<T: Val>(&mut self, snapshot: &Snapshot<T>) -> Result<(), InterfaceError> {
+        let json = serde_json::to_vec(snapshot)
+            .map_err(|e| InterfaceError::FromFields(format!("Failed to serialize items: {e}")))?;
+
+        // The snapshot is replaced atomically. If we crash before the journal is
+        // emptied, replaying it again is harmless: records hold the final states,
+        // and the revisions already in the history are skipped
+        let tmp = self.directory.join(SNAPSHOT_TMP);
+        let mut file = File::create(&tmp).map_err(io_error)?;
+        file.write_all(&json)
+            .and_then(|_| file.sync_all())
+            .map_err(io_error)?;
+        fs::rename(&tmp, self.directory.join(SNAPSHOT)).map_err(io_error)?;
+        sync_directory(&self.directory)?;
+
+        self.file.set_len(0).map_err(io_error)?;
+        self.file.sync_all().map_err(io_error)?;
+        self.len = 0;
+        self.records = 0;
+        Ok(())
+    }
+}
+
+/// Persist the renaming of files in a directory
+fn sync_directory(directory: &Path) -> Result<(), InterfaceError> {
+    #[cfg(unix)]
+    File::open(directory)
+        .and_then(|dir| dir.sync_all())
+        .map_err(io_error)?;
+    #[cfg(not(unix))]
+    let _ = directory;
+    Ok(())
+}
+
+/// Repository persisted in a directory, as a snapshot and a journal
+pub struct FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    memory: Arc<InMemoryRepository<T>>,
+    journal: Mutex<Journal>,
+    /// Open transactions bound to the repository, the journal is not compacted
+    /// meanwhile so that the snapshot never holds uncommitted writes
+    transactions: AtomicUsize,
+}
+
+impl<T> FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    /// Open the repository stored in a directory, creating it if needed,
+    /// and recover its items
+    pub fn open(directory: impl AsRef<Path>) -> Result<Self, InterfaceError> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory).map_err(io_error)?;
+        let memory = InMemoryRepository::new();
+
+        // Load the last snapshot, always complete as it is renamed into place
+        match fs::read(directory.join(SNAPSHOT)) {
+            Ok(json) => {
+                let snapshot: Snapshot<T> = serde_json::from_slice(&json).map_err(|e| {
+                    InterfaceError::FromFields(format!("Failed to parse snapshot: {e}"))
+                })?;
+                let records = (snapshot.items.into_iter().map(Record::Put))
+                    .chain(snapshot.deleted.into_iter().map(Record::SoftDelete))
+                    .chain(snapshot.history.into_iter().map(Record::Change));
+                for record in records {
+                    recover(&memory, record)?;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(io_error(err)),
+        }
+
+        // Replay the journal up to its torn end, if any
+        let path = directory.join(JOURNAL);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(io_error(err)),
+        };
+        let (mut len, mut records) = (0, 0);
+        for line in bytes.split_inclusive(|byte| *byte == b'\n') {
+            let record = match line.strip_suffix(b"\n") {
+                Some(line) => decode::<T>(line, len)?,
+                None => break,
+            };
+            recover(&memory, record)?;
+            len += line.len();
+            records += 1;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(io_error)?;
+        if len < bytes.len() {
+            tracing::warn!(
+                "Truncating {} bytes of a torn record from {}",
+                bytes.len() - len,
+                path.display()
+            );
+            file.set_len(len as u64).map_err(io_error)?;
+        }
+
+        Ok(FileRepository {
+            memory: Arc::new(memory),
+            journal: Mutex::new(Journal {
+                directory,
+                file,
+                len: len as u64,
+                records,
+                compaction: DEFAULT_COMPACTION,
+            }),
+            transactions: AtomicUsize::new(0),
+        })
+    }
+
+    /// Use an integer field of the items for optimistic concurrency control,
+    /// see `InMemoryRepository::with_version`. It is set before `belongs_to`.
+    pub fn with_version(mut self, field: &str) -> Self {
+        let memory = Arc::get_mut(&mut self.memory)
+            .expect("The version is set before the repository is linked");
+        *memory = std::mem::take(memory).with_version(field);
+        self
+    }
+
+    /// Keep the deleted items, see `InMemoryRepository::with_soft_delete`
+    pub fn with_soft_delete(mut self) -> Self {
+        let memory = Arc::get_mut(&mut self.memory)
+            .expect("The soft delete is set before the repository is linked");
+        *memory = std::mem::take(memory).with_soft_delete();
+        self
+    }
+
+    /// Record the history of the items, see `InMemoryRepository::with_history`
+    pub fn with_history(mut self) -> Self {
+        let memory = Arc::get_mut(&mut self.memory)
+            .expect("The history is set before the repository is linked");
+        *memory = std::mem::take(memory).with_history();
+        self
+    }
+
+    /// Compact the journal into a snapshot every `records` records
+    pub fn with_compaction(mut self, records: usize) -> Self {
+        self.journal.get_mut().compaction = records.max(1);
+        self
+    }
+
+    /// Compact the journal into a snapshot now, unless a transaction is open
+    pub async fn compact(&self) -> Result<(), InterfaceError> {
+        let mut journal = self.journal.lock().await;
+        if self.transactions.load(Ordering::SeqCst) > 0 {
+            return Err(InterfaceError::Other(
+                "The repository cannot be compacted during a transaction".to_string(),
+            ));
+        }
+        journal.compact(&Snapshot::of(&self.memory))
+    }
+
+    /// The same repository, writing in a transaction: its writes are journaled on commit
+    pub fn in_transaction<'a>(
+        &'a self,
+        transaction: &FileTransaction<'a>,
+    ) -> FileTransactionRepository<'a, T> {
+        let journals = Arc::clone(&transaction.journals);
+        let index = journals.lock().unwrap().as_mut().map(|journals| {
+            self.transactions.fetch_add(1, Ordering::SeqCst);
+            journals.push(Pending {
+                repo: self,
+                lines: Vec::new(),
+            });
+            journals.len() - 1
+        });
+        FileTransactionRepository {
+            repo: self,
+            memory: self.memory.in_transaction(&transaction.memory),
+            journals,
+            index,
+        }
+    }
+
+    /// Journal records of the new state of the keys written since `previous`
+    fn records(&self, previous: &[Entry<T>]) -> Result<Vec<u8>, InterfaceError> {
+        let keys: Vec<_> = previous.iter().map(|entry| entry.key.clone()).collect();
+        let mut lines = Vec::new();
+        for (before, after) in previous.iter().zip(self.memory.entries(&keys)) {
+            let record = match (&before.item, after.item, after.deleted) {
+                (_, Some(item), _) => Some(Record::Put(item)),
+                (Some(_), None, Some(item)) => Some(Record::SoftDelete(item)),
+                (Some(item), None, None) => Some(Record::Delete(item.clone())),
+                (None, None, _) => None,
+            };
+            let changes = self.memory.changes_since(&before.key, before.revisions);
+            let changes = changes
+                .into_iter()
+                .map(|change| Record::Change(change.into()));
+            for record in record.into_iter().chain(changes) {
+                lines.extend(encode(&record)?);
+            }
+        }
+        Ok(lines)
+    }
+
+    /// Apply a write to the items in memory, then journal the new state of the
+    /// written keys. The items are restored if the journal cannot be written.
+    async fn write(
+        &self,
+        keys: Vec<T::Key>,
+        operation: impl Future<Output = Result<(), InterfaceError>> + Send,
+    ) -> Result<(), InterfaceError> {
+        let mut journal = self.journal.lock().await;
+        let previous = self.memory.entries(&keys);
+        operation.await?;
+
+        let appended = self
+            .records(&previous)
+            .and_then(|lines| journal.append(&lines));
+        if let Err(err) = appended {
+            self.memory.restore(previous);
+            return Err(err);
+        }
+        self.compact_if_due(&mut journal).await
+    }
+
+    /// Compact the journal once it holds enough records and no transaction is open.
+    /// The writes are durable, a failed compaction is retried on the next write.
+    async fn compact_if_due(&self, journal: &mut Journal) -> Result<(), InterfaceError> {
+        if journal.records >= journal.compaction && self.transactions.load(Ordering::SeqCst) == 0 {
+            if let Err(err) = journal.compact(&Snapshot::of(&self.memory)) {
+                tracing::warn!("Failed to compact {}: {err}", journal.directory.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> FileRepository<T>
+where
+    T: Val + HasKey + 'static,
+{
+    /// Enforce the foreign key of a relation and join the related items,
+    /// see `InMemoryRepository::belongs_to`
+    pub fn belongs_to<U>(&self, relation: &BelongsTo<T, U>, parents: &FileRepository<U>)
+    where
+        U: Val + HasKey + 'static,
+    {
+        self.memory.belongs_to(relation, &parents.memory);
+    }
+}
+
+#[async_trait]
+impl<T> Create<T> for FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.memory.create(item)).await
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let keys = items.iter().map(|item| item.key()).collect();
+        self.write(keys, self.memory.create_many(items)).await
+    }
+}
+
+#[async_trait]
+impl<T> Get<T> for FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        self.memory.get(key).await
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        self.memory.exists(key).await
+    }
+}
+
+#[async_trait]
+impl<T> Delete<T> for FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        self.write(vec![key.clone()], self.memory.delete(key)).await
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        self.write(keys.to_vec(), self.memory.delete_many(keys))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T> Update<T> for FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.memory.update(item)).await
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let keys = items.iter().map(|item| item.key()).collect();
+        self.write(keys, self.memory.update_many(items)).await
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.memory.upsert(item)).await
+    }
+}
+
+#[async_trait]
+impl<T> List<T> for FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        self.memory.list().await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        self.memory.list_where(filter).await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        self.memory.list_page(request).await
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        self.memory.count(filter).await
+    }
+}
+
+#[async_trait]
+impl<T> Repository<T> for FileRepository<T> where T: Val + HasKey {}
+
+#[async_trait]
+impl<T> History<T> for FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        self.memory.history(key).await
+    }
+}
+
+/// Joins of the items of repositories linked with `belongs_to`
+#[async_trait]
+impl<T, U> Join<T, U> for FileRepository<T>
+where
+    T: Val + HasKey + 'static,
+    U: Val + HasKey + 'static,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        self.memory.list_with_parent(relation, filter).await
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        self.memory.list_with_children(relation, filter).await
+    }
+}
+
+/// A file repository bound to a transaction
+#[async_trait]
+trait Journaled: Send + Sync {
+    /// Journal the writes of a committed transaction
+    async fn append(&self, lines: &[u8]) -> Result<(), InterfaceError>;
+
+    /// Unbind a finished transaction
+    fn release(&self);
+}
+
+#[async_trait]
+impl<T> Journaled for FileRepository<T>
+where
+    T: Val + HasKey,
+{
+    async fn append(&self, lines: &[u8]) -> Result<(), InterfaceError> {
+        let mut journal = self.journal.lock().await;
+        let appended = journal.append(lines);
+        self.release();
+        appended?;
+        self.compact_if_due(&mut journal).await
+    }
+
+    fn release(&self) {
+        self.transactions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Journal records written in a transaction to a repository, appended on commit
+struct Pending<'a> {
+    repo: &'a dyn Journaled,
+    lines: Vec<u8>,
+}
+
+/// The repositories bound to a transaction, `None` once it is finished
+type Journals<'a> = Arc<std::sync::Mutex<Option<Vec<Pending<'a>>>>>;
+
+/// Transaction over file repositories, see `MemoryTransaction`
+///
+/// The writes are applied in memory right away and journaled on commit, a crash
+/// before the commit loses all of them. Each journal is appended atomically, a crash
+/// between the journals of two repositories keeps the writes to the first one only.
+pub struct FileTransaction<'a> {
+    memory: MemoryTransaction<'a>,
+    journals: Journals<'a>,
+}
+
+impl Default for FileTransaction<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> FileTransaction<'a> {
+    pub fn new() -> Self {
+        FileTransaction {
+            memory: MemoryTransaction::new(),
+            journals: Arc::new(std::sync::Mutex::new(Some(Vec::new()))),
+        }
+    }
+
+    fn take(&self) -> Vec<Pending<'a>> {
+        self.journals.lock().unwrap().take().unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl Transaction for FileTransaction<'_> {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        let journals = self.take();
+        self.memory.commit().await?;
+        let mut journals = journals.into_iter();
+        while let Some(pending) = journals.next() {
+            if let Err(err) = pending.repo.append(&pending.lines).await {
+                journals.for_each(|pending| pending.repo.release());
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        let journals = self.take();
+        let rolled_back = self.memory.rollback().await;
+        journals.iter().for_each(|pending| pending.repo.release());
+        rolled_back
+    }
+}
+
+impl Drop for FileTransaction<'_> {
+    fn drop(&mut self) {
+        // The writes are undone before the repositories can be compacted again
+        self.memory.abort();
+        self.take()
+            .iter()
+            .for_each(|pending| pending.repo.release());
+    }
+}
+
+/// File repository bound to a `FileTransaction`
+pub struct FileTransactionRepository<'a, T>
+where
+    T: Val + HasKey,
+{
+    repo: &'a FileRepository<T>,
+    memory: MemoryTransactionRepository<'a, T>,
+    journals: Journals<'a>,
+    /// Index of the repository in the journals of the transaction
+    index: Option<usize>,
+}
+
+impl<T> FileTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    /// Apply a write in the transaction, and keep the journal records of the
+    /// written keys until the commit
+    async fn write(
+        &self,
+        keys: Vec<T::Key>,
+        operation: impl Future<Output = Result<(), InterfaceError>> + Send,
+    ) -> Result<(), InterfaceError> {
+        // The journal is locked so that it is not compacted during the write
+        let _journal = self.repo.journal.lock().await;
+        let previous = self.repo.memory.entries(&keys);
+        self.memory.write(keys, operation).await?;
+
+        let lines = match self.repo.records(&previous) {
+            Ok(lines) => lines,
+            Err(err) => {
+                self.repo.memory.restore(previous);
+                return Err(err);
+            }
+        };
+        let mut journals = self.journals.lock().unwrap();
+        if let (Some(journals), Some(index)) = (journals.as_mut(), self.index) {
+            journals[index].lines.extend(lines);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T> Create<T> for FileTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.repo.memory.create(item))
+            .await
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let keys = items.iter().map(|item| item.key()).collect();
+        self.write(keys, self.repo.memory.create_many(items)).await
+    }
+}
+
+#[async_trait]
+impl<T> Get<T> for FileTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        self.repo.get(key).await
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        self.repo.exists(key).await
+    }
+}
+
+#[async_trait]
+impl<T> Delete<T> for FileTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        self.write(vec![key.clone()], self.repo.memory.delete(key))
+            .await
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        self.write(keys.to_vec(), self.repo.memory.delete_many(keys))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T> Update<T> for FileTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.repo.memory.update(item))
+            .await
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let keys = items.iter().map(|item| item.key()).collect();
+        self.write(keys, self.repo.memory.update_many(items)).await
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.repo.memory.upsert(item))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T> List<T> for FileTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        self.repo.list().await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        self.repo.list_where(filter).await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        self.repo.list_page(request).await
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        self.repo.count(filter).await
+    }
+}
+
+#[async_trait]
+impl<T> Repository<T> for FileTransactionRepository<'_, T> where T: Val + HasKey {}
+
+#[async_trait]
+impl<T> History<T> for FileTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        self.repo.history(key).await
+    }
+}
+
+#[async_trait]
+impl<T, U> Join<T, U> for FileTransactionRepository<'_, T>
+where
+    T: Val + HasKey + 'static,
+    U: Val + HasKey + 'static,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        self.repo.list_with_parent(relation, filter).await
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        self.repo.list_with_children(relation, filter).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{ConformanceItem, RepositoryFactory, VersionedConformanceItem};
+    use crate::relation::tests::{gen_owner, gen_pet, Owner, Pet};
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    // Define structures
+    #[derive(Default, Debug, Clone, Deserialize, PartialEq, Serialize)]
+    struct Item1 {
+        uuid: Uuid,
+        field1: i32,
+    }
+
+    impl HasKey for Item1 {
+        type Key = Uuid;
+
+        const KEY_FIELDS: &'static [&'static str] = &["uuid"];
+
+        fn key(&self) -> Uuid {
+            self.uuid
+        }
+    }
+
+    // Gen item
+    fn sorted(mut items: Vec<Item1>) -> Vec<Item1> {
+        items.sort_by_key(|item| item.uuid);
+        items
+    }
+
+    fn gen_item() -> Item1 {
+        Item1 {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+        }
+    }
+
+    /// A new directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            TempDir(std::env::temp_dir().join(format!("file-repository-{}", Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[async_trait]
+    impl RepositoryFactory for TempDir {
+        type Repo = FileRepository<ConformanceItem>;
+        type VersionedRepo = FileRepository<VersionedConformanceItem>;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            FileRepository::open(self.0.join("items"))
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            Ok(FileRepository::open(self.0.join("versioned"))?.with_version("version"))
+        }
+    }
+
+    crate::repository_conformance_tests!(TempDir::new());
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[tokio::test]
+    async fn test_recover() -> Result<(), InterfaceError> {
+        // GIVEN a repository where items were created, updated and deleted
+        let dir = TempDir::new();
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        let (mut item1, item2, item3) = (gen_item(), gen_item(), gen_item());
+        repo.create_many(&[item1.clone(), item2.clone(), item3.clone()])
+            .await?;
+        item1.field1 = 5;
+        repo.update(&item1).await?;
+        repo.delete(&item2.uuid).await?;
+        drop(repo);
+
+        // WHEN we open it again
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+
+        // THEN the items are recovered
+        let mut expected = vec![item1, item3];
+        expected.sort_by_key(|item| item.uuid);
+        assert_eq!(repo.list().await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compaction() -> Result<(), InterfaceError> {
+        // GIVEN a repository compacted every 4 records
+        let dir = TempDir::new();
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?.with_compaction(4);
+        let items: Vec<Item1> = (0..5).map(|_| gen_item()).collect();
+
+        // WHEN we write 6 records
+        for item in &items {
+            repo.create(item).await?;
+        }
+        repo.delete(&items[0].uuid).await?;
+        drop(repo);
+
+        // THEN the snapshot holds the first 4 records and the journal the last 2
+        let snapshot: Snapshot<Item1> =
+            serde_json::from_slice(&fs::read(dir.0.join(SNAPSHOT)).unwrap()).unwrap();
+        let journal = fs::read(dir.0.join(JOURNAL)).unwrap();
+        assert_eq!(snapshot.items.len(), 4);
+        assert_eq!(journal.iter().filter(|byte| **byte == b'\n').count(), 2);
+
+        // AND all of them are recovered
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        assert_eq!(repo.list().await?.len(), 4);
+        assert_eq!(repo.get(&items[0].uuid).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_history() -> Result<(), InterfaceError> {
+        // GIVEN a repository keeping its deleted items and their history,
+        // compacted every 3 records
+        let dir = TempDir::new();
+        let open = || -> Result<FileRepository<Item1>, InterfaceError> {
+            Ok(FileRepository::open(&dir.0)?
+                .with_soft_delete()
+                .with_history()
+                .with_compaction(3))
+        };
+        let repo = open()?;
+
+        // WHEN an item is created, updated then deleted
+        let mut item = gen_item();
+        repo.create(&item).await?;
+        item.field1 = 5;
+        repo.update(&item).await?;
+        repo.delete(&item.uuid).await?;
+        drop(repo);
+
+        // THEN once opened again the item is still deleted and cannot be created again
+        let repo = open()?;
+        assert_eq!(repo.get(&item.uuid).await?, None);
+        let created = repo.create(&item).await;
+        assert!(matches!(created, Err(InterfaceError::Conflict(_))));
+        // AND its history is kept, across the snapshot and the journal
+        let operations: Vec<_> = repo
+            .history(&item.uuid)
+            .await?
+            .into_iter()
+            .map(|change| (change.revision, change.operation))
+            .collect();
+        let expected = vec![
+            (1, Mutation::Create),
+            (2, Mutation::Update),
+            (3, Mutation::Delete),
+        ];
+        assert_eq!(operations, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_torn_write() -> Result<(), InterfaceError> {
+        // GIVEN a journal ending with a torn record
+        let dir = TempDir::new();
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        let (item1, item2, item3) = (gen_item(), gen_item(), gen_item());
+        repo.create(&item1).await?;
+        repo.create(&item2).await?;
+        drop(repo);
+
+        let path = dir.0.join(JOURNAL);
+        let journal = fs::read(&path).unwrap();
+        let torn = journal.len() - 10;
+        fs::write(&path, &journal[..torn]).unwrap();
+
+        // WHEN we open the repository and write again
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        let recovered = repo.list().await?;
+        repo.create(&item3).await?;
+        drop(repo);
+
+        // THEN the torn record is dropped and the next records are kept
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        assert_eq!(recovered, vec![item1.clone()]);
+        assert_eq!(repo.get(&item1.uuid).await?, Some(item1));
+        assert_eq!(repo.get(&item2.uuid).await?, None);
+        assert_eq!(repo.get(&item3.uuid).await?, Some(item3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_record() -> Result<(), InterfaceError> {
+        // GIVEN a journal with a corrupted record followed by a valid one
+        let dir = TempDir::new();
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        let (item1, item2) = (gen_item(), gen_item());
+        repo.create(&item1).await?;
+        repo.create(&item2).await?;
+        drop(repo);
+
+        let path = dir.0.join(JOURNAL);
+        let mut journal = fs::read(&path).unwrap();
+        let first = journal.iter().position(|byte| *byte == b'\n').unwrap() - 5;
+        journal[first] = journal[first].wrapping_add(1);
+        fs::write(&path, &journal).unwrap();
+
+        // WHEN we open the repository
+        let result = FileRepository::<Item1>::open(&dir.0);
+
+        // THEN it fails and the journal is kept as is
+        assert!(matches!(result, Err(InterfaceError::Other(_))));
+        assert_eq!(fs::read(&path).unwrap(), journal);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_record() -> Result<(), InterfaceError> {
+        // GIVEN a journal of items lacking a field the model now requires
+        let dir = TempDir::new();
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        repo.create(&gen_item()).await?;
+        repo.create(&gen_item()).await?;
+        drop(repo);
+        let journal = fs::read(dir.0.join(JOURNAL)).unwrap();
+
+        // WHEN we open the repository with the new model
+        let result = FileRepository::<ConformanceItem>::open(&dir.0);
+
+        // THEN it fails and the journal is kept as is
+        assert!(matches!(result, Err(InterfaceError::FromFields(_))));
+        assert_eq!(fs::read(dir.0.join(JOURNAL)).unwrap(), journal);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rollback() -> Result<(), InterfaceError> {
+        // GIVEN a repository with two items
+        let dir = TempDir::new();
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?.with_compaction(3);
+        let (mut item1, mut item2, item3) = (gen_item(), gen_item(), gen_item());
+        repo.create_many(&[item1.clone(), item2.clone()]).await?;
+        let stored = item1.clone();
+
+        // WHEN a transaction writes
+        let transaction = FileTransaction::new();
+        let bound = repo.in_transaction(&transaction);
+        bound.create(&item3).await?;
+        item1.field1 = 5;
+        bound.update(&item1).await?;
+        // AND another item is updated outside of the transaction
+        item2.field1 = 7;
+        repo.update(&item2).await?;
+        let journal = fs::read(dir.0.join(JOURNAL)).unwrap();
+        // AND the transaction rolls back
+        transaction.rollback().await?;
+
+        // THEN nothing of the transaction was journaled nor compacted
+        assert!(!dir.0.join(SNAPSHOT).exists());
+        assert_eq!(journal.split(|byte| *byte == b'\n').count(), 4);
+        // AND only its writes are undone, also once the repository is opened again
+        let expected = vec![stored, item2];
+        assert_eq!(sorted(repo.list().await?), sorted(expected.clone()));
+        drop(bound);
+        drop(transaction);
+        drop(repo);
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        assert_eq!(sorted(repo.list().await?), sorted(expected));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_commit() -> Result<(), InterfaceError> {
+        // GIVEN a transaction over a repository
+        let dir = TempDir::new();
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        let transaction = FileTransaction::new();
+        let bound = repo.in_transaction(&transaction);
+
+        // WHEN it writes an item then commits
+        let item = gen_item();
+        bound.create(&item).await?;
+        let compacted = repo.compact().await;
+        let journal = fs::read(dir.0.join(JOURNAL)).unwrap();
+        transaction.commit().await?;
+
+        // THEN the item is journaled on commit only
+        assert!(journal.is_empty());
+        assert!(compacted.is_err());
+        drop(bound);
+        drop(transaction);
+        drop(repo);
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        assert_eq!(repo.list().await?, vec![item]);
+        // AND the repository can be compacted again
+        repo.compact().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_belongs_to() -> Result<(), InterfaceError> {
+        // GIVEN owners and pets linked by the foreign key of the pets
+        let dir = TempDir::new();
+        let owners: FileRepository<Owner> = FileRepository::open(dir.0.join("owner"))?;
+        let pets: FileRepository<Pet> = FileRepository::open(dir.0.join("pet"))?;
+        pets.belongs_to(&Pet::owner(), &owners);
+        let alice = gen_owner("Alice");
+        owners.create(&alice).await?;
+        let rex = gen_pet(Some(&alice), "Rex");
+        pets.create(&rex).await?;
+
+        // WHEN a pet of a missing owner is created
+        let result = pets.create(&gen_pet(Some(&gen_owner("Bob")), "Tom")).await;
+
+        // THEN it is rejected
+        assert!(matches!(result, Err(InterfaceError::Validation(_))));
+        // AND the owner is joined to its pets
+        let with_pets = owners
+            .list_with_children(&Owner::pets(), &Filter::And(vec![]))
+            .await?;
+        assert_eq!(with_pets, vec![(alice, vec![rex])]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_version() -> Result<(), InterfaceError> {
+        // GIVEN a versioned repository with an item
+        let dir = TempDir::new();
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?.with_version("field1");
+        let item = gen_item();
+        repo.create(&item).await?;
+
+        // WHEN the item is updated twice from the same version
+        repo.update(&item).await?;
+        let result = repo.update(&item).await;
+        drop(repo);
+
+        // THEN the conflicting update is not journaled
+        let repo: FileRepository<Item1> = FileRepository::open(&dir.0)?;
+        assert!(matches!(result, Err(InterfaceError::Conflict(_))));
+        assert_eq!(repo.get(&item.uuid).await?.unwrap().field1, 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+//! Audit history of the SQL repositories
+//!
+//! An audited mutation runs in a transaction: the entities are read before and
+//! after it, and its changes are appended to the history table of the entity in
+//! one statement, each with the next revision of its entity. Entities are stored
+//! as JSON images in the history.
+use crate::ports::secondary::{Change, HasKey, Key, Mutation, Repository};
+use crate::query::{FieldValue, Filter};
+use crate::{error::InterfaceError, Val};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Actor recorded with the changes, unless the repository is given one
+pub const DEFAULT_ACTOR: &str = "system";
+
+tokio::task_local! {
+    static ACTOR: String;
+}
+
+/// Run `operation` with the changes it makes recorded as made by `actor`,
+/// e.g. the identity of a request, instead of the actor of the repositories
+pub async fn as_actor<F: Future>(actor: impl Into<String>, operation: F) -> F::Output {
+    ACTOR.scope(actor.into(), operation).await
+}
+
+/// Actor of the running operation, or the one of the repository outside of `as_actor`
+pub(crate) fn current_actor(repository_actor: &str) -> String {
+    ACTOR
+        .try_with(String::clone)
+        .unwrap_or_else(|_| repository_actor.to_string())
+}
+
+/// Mutation of the entities of an audited repository
+pub(crate) enum Changes<'a, T: HasKey> {
+    Create(&'a [T]),
+    Update(&'a [T]),
+    Upsert(&'a T),
+    Delete(&'a [T::Key]),
+}
+
+impl<T: HasKey> Changes<'_, T> {
+    fn mutation(&self) -> Mutation {
+        match self {
+            Changes::Create(_) => Mutation::Create,
+            Changes::Update(_) => Mutation::Update,
+            Changes::Upsert(_) => Mutation::Upsert,
+            Changes::Delete(_) => Mutation::Delete,
+        }
+    }
+
+    /// Keys of the changed entities
+    fn keys(&self) -> Vec<T::Key> {
+        match self {
+            Changes::Create(items) | Changes::Update(items) => {
+                items.iter().map(HasKey::key).collect()
+            }
+            Changes::Upsert(item) => vec![item.key()],
+            Changes::Delete(keys) => keys.to_vec(),
+        }
+    }
+}
+
+/// Repository appending the changes of its entities to their history
+#[async_trait]
+pub(crate) trait AppendHistory<T>
+where
+    T: Val + HasKey,
+{
+    /// Record the changes in one statement, each with the revision following
+    /// the last one of its entity
+    async fn append(
+        &self,
+        mutation: Mutation,
+        changes: Vec<(T::Key, Option<T>, Option<T>)>,
+    ) -> Result<(), InterfaceError>;
+}
+
+/// Apply a mutation with a repository that does not audit it, then append its changes
+///
+/// The repository must be bound to a transaction, for the history to match the entities.
+/// The entities are read in one query before and one after the mutation, an entity
+/// mutated twice by it has one change. Deleting a missing entity changes nothing,
+/// and is not recorded.
+pub(crate) async fn apply<T, R>(repo: &R, changes: Changes<'_, T>) -> Result<(), InterfaceError>
+where
+    T: Val + HasKey,
+    R: Repository<T> + AppendHistory<T> + Sync,
+{
+    let mut keys = changes.keys();
+    keys.sort();
+    keys.dedup();
+    let filter = keys_filter::<T>(&keys);
+    let mut before = images(repo.list_where(&filter).await?);
+
+    let mutation = changes.mutation();
+    match changes {
+        Changes::Create(items) => repo.create_many(items).await?,
+        Changes::Update(items) => repo.update_many(items).await?,
+        Changes::Upsert(item) => repo.upsert(item).await?,
+        Changes::Delete(keys) => repo.delete_many(keys).await?,
+    }
+
+    let mut after = images(repo.list_where(&filter).await?);
+    let changes: Vec<_> = keys
+        .into_iter()
+        .filter_map(|key| match (before.remove(&key), after.remove(&key)) {
+            (None, None) => None,
+            (before, after) => Some((key, before, after)),
+        })
+        .collect();
+    if changes.is_empty() {
+        return Ok(());
+    }
+    repo.append(mutation, changes).await
+}
+
+/// Filter matching the entities of the keys: `key IN (...)` for a single key field
+fn keys_filter<T: HasKey>(keys: &[T::Key]) -> Filter {
+    match T::KEY_FIELDS {
+        [field] => Filter::In(
+            field.to_string(),
+            keys.iter().flat_map(Key::values).collect(),
+        ),
+        fields => Filter::Or(
+            keys.iter()
+                .map(|key| {
+                    let values = fields.iter().zip(key.values());
+                    Filter::And(
+                        values
+                            .map(|(field, value)| Filter::eq(field, value))
+                            .collect(),
+                    )
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn images<T: HasKey>(items: Vec<T>) -> BTreeMap<T::Key, T> {
+    items.into_iter().map(|item| (item.key(), item)).collect()
+}
+
+/// Row of a history table
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub(crate) struct HistoryRow {
+    revision: i64,
+    operation: String,
+    before_image: Option<String>,
+    after_image: Option<String>,
+    actor: String,
+    changed_at: i64,
+}
+
+impl HistoryRow {
+    pub(crate) fn into_change<T: Val>(self) -> Result<Change<T>, InterfaceError> {
+        Ok(Change {
+            revision: self.revision,
+            operation: Mutation::parse(&self.operation)?,
+            before: self.before_image.as_deref().map(from_image).transpose()?,
+            after: self.after_image.as_deref().map(from_image).transpose()?,
+            actor: self.actor,
+            changed_at: self.changed_at,
+        })
+    }
+}
+
+fn to_image<T: Val>(item: &T) -> Result<FieldValue, InterfaceError> {
+    serde_json::to_string(item)
+        .map(FieldValue::String)
+        .map_err(|e| InterfaceError::FromFields(format!("Failed to serialize image: {e}")))
+}
+
+fn from_image<T: Val>(image: &str) -> Result<T, InterfaceError> {
+    serde_json::from_str(image)
+        .map_err(|e| InterfaceError::FromFields(format!("Failed to parse image: {e}")))
+}
+
+/// Named parameters of the history rows of changes, see `QuerySet::insert_history`:
+/// the parameters of the i-th change are suffixed by `_i`
+pub(crate) fn history_parameters<T: Val + HasKey>(
+    key_columns: Vec<String>,
+    mutation: Mutation,
+    actor: &str,
+    changes: &[(T::Key, Option<T>, Option<T>)],
+) -> Result<Vec<(String, FieldValue)>, InterfaceError> {
+    let image = |item: &Option<T>| match item {
+        Some(item) => to_image(item),
+        None => Ok(FieldValue::Null),
+    };
+    let changed_at = now_millis();
+    let mut params = Vec::new();
+    for (index, (key, before, after)) in changes.iter().enumerate() {
+        let row = key_columns.iter().cloned().zip(key.values()).chain([
+            (
+                "operation".to_string(),
+                FieldValue::String(mutation.as_str().to_string()),
+            ),
+            ("before_image".to_string(), image(before)?),
+            ("after_image".to_string(), image(after)?),
+            ("actor".to_string(), FieldValue::String(actor.to_string())),
+            ("changed_at".to_string(), FieldValue::Integer(changed_at)),
+        ]);
+        params.extend(row.map(|(name, value)| (format!("{name}_{index}"), value)));
+    }
+    Ok(params)
+}
+
+/// Error of a history read from a repository that does not audit its mutations
+pub(crate) fn not_audited(table: &str) -> InterfaceError {
+    InterfaceError::InvalidQuery(format!("The mutations of {table} are not audited"))
+}
+
+/// Current time, in milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{gen_item, ConformanceItem};
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value as JsonValue};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_history_row() -> Result<(), InterfaceError> {
+        // GIVEN the deletion of an item
+        let item = gen_item(3);
+        let changes = vec![(item.uuid, Some(item.clone()), None)];
+
+        // WHEN it is stored in a row with its revision, then read back
+        let params = history_parameters(
+            vec!["uuid".to_string()],
+            Mutation::Delete,
+            "teller",
+            &changes,
+        )?;
+        let mut row = serde_json::Map::new();
+        row.insert("revision".to_string(), json!(2));
+        for (name, value) in &params {
+            let value = match value {
+                FieldValue::Uuid(uuid) => json!(uuid),
+                FieldValue::String(s) => json!(s),
+                FieldValue::Integer(i) => json!(i),
+                _ => JsonValue::Null,
+            };
+            row.insert(name.strip_suffix("_0").unwrap().to_string(), value);
+        }
+        let row: HistoryRow = serde_json::from_value(JsonValue::Object(row)).unwrap();
+        let change = row.into_change::<ConformanceItem>()?;
+
+        // THEN the change is unchanged
+        assert_eq!(
+            params[0],
+            ("uuid_0".to_string(), FieldValue::Uuid(item.uuid))
+        );
+        assert_eq!(
+            change,
+            Change {
+                revision: 2,
+                operation: Mutation::Delete,
+                before: Some(item),
+                after: None,
+                actor: "teller".to_string(),
+                changed_at: change.changed_at,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_filter() {
+        let keys = [Uuid::new_v4(), Uuid::new_v4()];
+        assert_eq!(
+            keys_filter::<ConformanceItem>(&keys),
+            Filter::is_in("uuid", keys)
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Tracing and metrics decorator for the secondary ports
+//!
+//! Each operation runs in a `repository` span recording the table, the operation,
+//! the number of rows and the outcome, and is counted with its latency in metrics
+//! that can be shared by all the repositories of an application.
+use crate::ports::secondary::{
+    Change, Create, Delete, Get, HasKey, History, Join, List, Repository, Transaction, Update,
+};
+use crate::query::{Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::{error::InterfaceError, Val};
+use async_trait::async_trait;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, field, info_span, Instrument};
+
+/// Counters of an operation on a table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OperationMetrics {
+    pub calls: u64,
+    pub errors: u64,
+    /// Rows read or written by the successful calls
+    pub rows: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+/// Metrics of the operations, by table and operation
+#[derive(Debug, Default)]
+pub struct RepositoryMetrics {
+    operations: Mutex<BTreeMap<(String, &'static str), OperationMetrics>>,
+}
+
+impl RepositoryMetrics {
+    fn record(&self, table: &str, operation: &'static str, outcome: &Outcome) {
+        let mut operations = self.operations.lock().unwrap();
+        let metrics = operations
+            .entry((table.to_string(), operation))
+            .or_default();
+        metrics.calls += 1;
+        metrics.total_latency += outcome.latency;
+        metrics.max_latency = metrics.max_latency.max(outcome.latency);
+        match outcome.rows {
+            Some(rows) => metrics.rows += rows as u64,
+            None => metrics.errors += 1,
+        }
+    }
+
+    /// Counters of an operation on a table
+    pub fn get(&self, table: &str, operation: &str) -> OperationMetrics {
+        let operations = self.operations.lock().unwrap();
+        operations
+            .iter()
+            .find(|((t, o), _)| t == table && *o == operation)
+            .map(|(_, metrics)| metrics.clone())
+            .unwrap_or_default()
+    }
+
+    /// Counters of all the operations called, by table and operation
+    pub fn snapshot(&self) -> Vec<(String, &'static str, OperationMetrics)> {
+        let operations = self.operations.lock().unwrap();
+        operations
+            .iter()
+            .map(|((table, operation), metrics)| (table.clone(), *operation, metrics.clone()))
+            .collect()
+    }
+}
+
+/// Result of a call: the rows of a success, and its latency
+struct Outcome {
+    rows: Option<usize>,
+    latency: Duration,
+}
+
+/// Repository, or transaction, observed with tracing spans and metrics
+pub struct Instrumented<R> {
+    inner: R,
+    table: String,
+    metrics: Arc<RepositoryMetrics>,
+}
+
+impl<R> Instrumented<R> {
+    /// Observe `inner`, named by its table, e.g. `QuerySet::table`, or `transaction`
+    pub fn new(inner: R, table: impl Into<String>) -> Self {
+        Instrumented {
+            inner,
+            table: table.into(),
+            metrics: Default::default(),
+        }
+    }
+
+    /// Record the metrics with the ones of other repositories
+    pub fn with_metrics(mut self, metrics: Arc<RepositoryMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> &Arc<RepositoryMetrics> {
+        &self.metrics
+    }
+
+    /// The decorated repository
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Run an operation in its span, then record its outcome
+    async fn observe<V>(
+        &self,
+        operation: &'static str,
+        future: impl Future<Output = Result<V, InterfaceError>>,
+        rows: impl FnOnce(&V) -> usize,
+    ) -> Result<V, InterfaceError> {
+        let span = info_span!(
+            "repository",
+            table = %self.table,
+            operation,
+            rows = field::Empty,
+            outcome = field::Empty,
+        );
+        let start = Instant::now();
+        let result = future.instrument(span.clone()).await;
+
+        let outcome = Outcome {
+            rows: result.as_ref().ok().map(rows),
+            latency: start.elapsed(),
+        };
+        let status = match &result {
+            Ok(_) => "ok",
+            Err(err) => err.kind().as_str(),
+        };
+        if let Some(rows) = outcome.rows {
+            span.record("rows", rows);
+        }
+        span.record("outcome", status);
+        span.in_scope(
+            || debug!(latency = ?outcome.latency, "{} on {}: {}", operation, self.table, status),
+        );
+
+        self.metrics.record(&self.table, operation, &outcome);
+        result
+    }
+}
+
+#[async_trait]
+impl<T, R> Create<T> for Instrumented<R>
+where
+    T: Val,
+    R: Create<T> + Send + Sync,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        self.observe("create", self.inner.create(item), |_| 1).await
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let future = self.inner.create_many(items);
+        self.observe("create_many", future, |_| items.len()).await
+    }
+}
+
+#[async_trait]
+impl<T, R> Get<T> for Instrumented<R>
+where
+    T: Val + HasKey,
+    R: Get<T> + Send + Sync,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        let future = self.inner.get(key);
+        self.observe("get", future, |item| item.iter().count())
+            .await
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        let future = self.inner.exists(key);
+        self.observe("exists", future, |found| *found as usize)
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, R> Update<T> for Instrumented<R>
+where
+    T: Val,
+    R: Update<T> + Send + Sync,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        self.observe("update", self.inner.update(item), |_| 1).await
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let future = self.inner.update_many(items);
+        self.observe("update_many", future, |_| items.len()).await
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        self.observe("upsert", self.inner.upsert(item), |_| 1).await
+    }
+}
+
+#[async_trait]
+impl<T, R> Delete<T> for Instrumented<R>
+where
+    T: Val + HasKey,
+    R: Delete<T> + Send + Sync,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        self.observe("delete", self.inner.delete(key), |_| 1).await
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        let future = self.inner.delete_many(keys);
+        self.observe("delete_many", future, |_| keys.len()).await
+    }
+}
+
+#[async_trait]
+impl<T, R> List<T> for Instrumented<R>
+where
+    T: Val,
+    R: List<T> + Send + Sync,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        self.observe("list", self.inner.list(), Vec::len).await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        let future = self.inner.list_where(filter);
+        self.observe("list_where", future, Vec::len).await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        let future = self.inner.list_page(request);
+        self.observe("list_page", future, |page| page.items.len())
+            .await
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        let future = self.inner.count(filter);
+        self.observe("count", future, |_| 1).await
+    }
+
+    async fn select_where(
+        &self,
+        fields: &[&str],
+        filter: &Filter,
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        let future = self.inner.select_where(fields, filter);
+        self.observe("select_where", future, Vec::len).await
+    }
+}
+
+impl<T, R> Repository<T> for Instrumented<R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+}
+
+#[async_trait]
+impl<T, R> History<T> for Instrumented<R>
+where
+    T: Val + HasKey,
+    R: History<T> + Send + Sync,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        let future = self.inner.history(key);
+        self.observe("history", future, Vec::len).await
+    }
+}
+
+#[async_trait]
+impl<T, U, R> Join<T, U> for Instrumented<R>
+where
+    T: Val + HasKey,
+    U: Val,
+    R: Join<T, U> + Send + Sync,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        let future = self.inner.list_with_parent(relation, filter);
+        self.observe("list_with_parent", future, Vec::len).await
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        let future = self.inner.list_with_children(relation, filter);
+        self.observe("list_with_children", future, Vec::len).await
+    }
+}
+
+#[async_trait]
+impl<R> Transaction for Instrumented<R>
+where
+    R: Transaction,
+{
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.observe("commit", self.inner.commit(), |_| 0).await
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.observe("rollback", self.inner.rollback(), |_| 0).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{
+        gen_item, ConformanceItem, RepositoryFactory, VersionedConformanceItem,
+    };
+    use crate::usecase::memory::{InMemoryRepository, MemoryTransaction};
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<(), InterfaceError> {
+        // GIVEN an instrumented repository
+        let repo = Instrumented::new(
+            InMemoryRepository::<ConformanceItem>::new(),
+            "ConformanceItem",
+        );
+
+        // WHEN we create two items, list them, and update a missing one
+        repo.create_many(&[gen_item(3), gen_item(3)]).await?;
+        repo.list().await?;
+        repo.list().await?;
+        let missing = repo.update(&gen_item(3)).await;
+
+        // THEN the calls, rows and errors are counted by operation
+        assert!(missing.is_err());
+        let metrics = repo.metrics();
+        assert_eq!(metrics.get("ConformanceItem", "create_many").rows, 2);
+        let list = metrics.get("ConformanceItem", "list");
+        assert_eq!((list.calls, list.rows, list.errors), (2, 4, 0));
+        assert!(list.max_latency <= list.total_latency);
+        let update = metrics.get("ConformanceItem", "update");
+        assert_eq!((update.calls, update.rows, update.errors), (1, 0, 1));
+        assert_eq!(metrics.snapshot().len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_metrics() -> Result<(), InterfaceError> {
+        // GIVEN a repository and a transaction sharing their metrics
+        let repo = Instrumented::new(
+            InMemoryRepository::<ConformanceItem>::new(),
+            "ConformanceItem",
+        );
+        let transaction = Instrumented::new(MemoryTransaction::new(), "transaction")
+            .with_metrics(Arc::clone(repo.metrics()));
+
+        // WHEN an item is created, then the transaction committed
+        repo.create(&gen_item(3)).await?;
+        transaction.finish(Ok(())).await?;
+
+        // THEN both are observed
+        let metrics = repo.metrics();
+        assert_eq!(metrics.get("ConformanceItem", "create").calls, 1);
+        assert_eq!(metrics.get("transaction", "commit").calls, 1);
+        assert_eq!(metrics.get("transaction", "rollback").calls, 0);
+        Ok(())
+    }
+
+    struct InstrumentedFactory;
+
+    #[async_trait]
+    impl RepositoryFactory for InstrumentedFactory {
+        type Repo = Instrumented<InMemoryRepository<ConformanceItem>>;
+        type VersionedRepo = Instrumented<InMemoryRepository<VersionedConformanceItem>>;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            Ok(Instrumented::new(
+                InMemoryRepository::new(),
+                "ConformanceItem",
+            ))
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            let inner = InMemoryRepository::new().with_version("version");
+            Ok(Instrumented::new(inner, "VersionedConformanceItem"))
+        }
+    }
+
+    crate::repository_conformance_tests!(InstrumentedFactory);
+}
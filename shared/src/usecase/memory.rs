@@ -1,102 +1,980 @@
 //! In Memory implementation of a Repository
-use crate::ports::secondary::{Create, Delete, Get, List, Repository, Update};
+use crate::ports::secondary::{
+    Change, Create, Delete, Get, HasKey, History, Join, List, Mutation, Repository, Transaction,
+    Update,
+};
+use crate::query::{field_of, FieldValue, Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::usecase::history::{current_actor, not_audited, now_millis, DEFAULT_ACTOR};
 use crate::{error::InterfaceError, Val};
 use async_trait::async_trait;
-use std::{collections::HashMap, sync::RwLock};
-use uuid::Uuid;
+use serde_json::Value as JsonValue;
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex, RwLock, Weak},
+};
 
-pub trait HasUuid {
-    fn get_uuid(&self) -> Uuid;
+pub struct InMemoryRepository<T>
+where
+    T: Val + HasKey,
+{
+    data: RwLock<BTreeMap<T::Key, T>>,
+    version: Option<String>,
+    soft_delete: bool,
+    /// Soft deleted items, hidden from the reads
+    deleted: RwLock<BTreeMap<T::Key, T>>,
+    audit: bool,
+    history: RwLock<BTreeMap<T::Key, Vec<Change<T>>>>,
+    actor: String,
+    /// Foreign keys between the items and the items of other repositories
+    links: RwLock<Vec<Link>>,
 }
 
-#[derive(Default)]
-pub struct InMemoryRepository<T>
+/// Foreign key between the items of a repository and the items of another one
+struct Link {
+    /// Field of the referencing items holding the key of the referenced item
+    foreign_key: String,
+    /// Whether the items of the repository are the referencing ones
+    referencing: bool,
+    /// The other repository, a `Weak<InMemoryRepository<U>>`, to join its items
+    other: Box<dyn Any + Send + Sync>,
+    /// Whether the other repository holds an item, even soft deleted,
+    /// with the given value on its side of the foreign key
+    holds: Holds,
+    /// Lock of the foreign key, shared by both sides: held shared by the writes of
+    /// referencing items, exclusively by the deletions of referenced items, from
+    /// their check to their write, before any lock of the items
+    lock: Arc<RwLock<()>>,
+}
+
+/// Check of a value on the other side of a foreign key
+type Holds = Box<dyn Fn(&JsonValue) -> Result<bool, InterfaceError> + Send + Sync>;
+
+impl<T> Default for InMemoryRepository<T>
 where
-    T: Val + HasUuid,
+    T: Val + HasKey,
 {
-    data: RwLock<HashMap<Uuid, T>>,
+    fn default() -> Self {
+        InMemoryRepository {
+            data: RwLock::new(BTreeMap::new()),
+            version: None,
+            soft_delete: false,
+            deleted: RwLock::new(BTreeMap::new()),
+            audit: false,
+            history: RwLock::new(BTreeMap::new()),
+            actor: DEFAULT_ACTOR.to_string(),
+            links: RwLock::new(Vec::new()),
+        }
+    }
 }
 
 impl<T> InMemoryRepository<T>
 where
-    T: Val + HasUuid,
+    T: Val + HasKey,
 {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Use an integer field of the items for optimistic concurrency control:
+    /// an update is rejected unless the item has the stored version,
+    /// and the stored version is incremented
+    pub fn with_version(mut self, field: &str) -> Self {
+        self.version = Some(field.to_string());
+        self
+    }
+
+    /// Keep the deleted items, hidden from the reads: their keys cannot be created again
+    pub fn with_soft_delete(mut self) -> Self {
+        self.soft_delete = true;
+        self
+    }
+
+    /// Record every mutation in the history of the items
+    pub fn with_history(mut self) -> Self {
+        self.audit = true;
+        self
+    }
+
+    /// Record the changes as made by `actor`, outside of `as_actor`
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = actor.into();
+        self
+    }
+
+    /// Append the changes of a mutation to the history of the items, if it is recorded
+    fn record(&self, mutation: Mutation, changes: Vec<(T::Key, Option<T>, Option<T>)>) {
+        if !self.audit {
+            return;
+        }
+        let mut history = self.history.write().unwrap();
+        let (actor, changed_at) = (current_actor(&self.actor), now_millis());
+        for (key, before, after) in changes {
+            let revisions = history.entry(key).or_default();
+            revisions.push(Change {
+                revision: revisions.len() as i64 + 1,
+                operation: mutation,
+                before,
+                after,
+                actor: actor.clone(),
+                changed_at,
+            });
+        }
+    }
+
+    /// Whether a key is held by a soft deleted item
+    fn is_deleted(&self, key: &T::Key) -> bool {
+        self.deleted.read().unwrap().contains_key(key)
+    }
+
+    /// Check the version of an updated item against the stored one,
+    /// and return the item with its version incremented
+    fn next_version(&self, field: &str, stored: &T, item: &T) -> Result<T, InterfaceError> {
+        let mut json = to_json(item)?;
+        let version = field_of(&json, field)?.as_i64();
+        let stored_version = field_of(&to_json(stored)?, field)?.as_i64();
+
+        match (version, stored_version) {
+            (Some(version), Some(stored_version)) if version == stored_version => {
+                json[field] = (version + 1).into();
+                serde_json::from_value(json).map_err(|e| {
+                    InterfaceError::FromFields(format!("Failed to increment {field}: {e}"))
+                })
+            }
+            _ => Err(InterfaceError::Conflict(format!(
+                "Item {:?} was modified since {} {:?}",
+                item.key(),
+                field,
+                version
+            ))),
+        }
+    }
+
+    /// State currently stored under the keys
+    pub(crate) fn entries(&self, keys: &[T::Key]) -> Vec<Entry<T>> {
+        let data = self.data.read().unwrap();
+        let deleted = self.deleted.read().unwrap();
+        let history = self.history.read().unwrap();
+        keys.iter()
+            .map(|key| Entry {
+                key: key.clone(),
+                item: data.get(key).cloned(),
+                deleted: deleted.get(key).cloned(),
+                revisions: history.get(key).map_or(0, Vec::len),
+            })
+            .collect()
+    }
+
+    /// Store the entries back, dropping the revisions recorded after them
+    pub(crate) fn restore(&self, entries: Vec<Entry<T>>) {
+        let mut data = self.data.write().unwrap();
+        let mut deleted = self.deleted.write().unwrap();
+        let mut history = self.history.write().unwrap();
+        for entry in entries {
+            set_entry(&mut data, entry.key.clone(), entry.item);
+            set_entry(&mut deleted, entry.key.clone(), entry.deleted);
+            if let Some(revisions) = history.get_mut(&entry.key) {
+                revisions.truncate(entry.revisions);
+            }
+        }
+    }
+
+    /// Revisions of a key after the first `revisions` ones
+    pub(crate) fn changes_since(&self, key: &T::Key, revisions: usize) -> Vec<Change<T>> {
+        let history = self.history.read().unwrap();
+        history
+            .get(key)
+            .and_then(|changes| changes.get(revisions..))
+            .map(<[_]>::to_vec)
+            .unwrap_or_default()
+    }
+
+    /// The items, the soft deleted items and the history, to back them up
+    pub(crate) fn backup(&self) -> (Vec<T>, Vec<T>, Vec<Change<T>>) {
+        let data = self.data.read().unwrap();
+        let deleted = self.deleted.read().unwrap();
+        let history = self.history.read().unwrap();
+        (
+            data.values().cloned().collect(),
+            deleted.values().cloned().collect(),
+            history.values().flatten().cloned().collect(),
+        )
+    }
+
+    /// Store an item recovered from a backup, or remove the key without an item
+    pub(crate) fn recover(&self, key: T::Key, item: Option<T>) {
+        set_entry(&mut self.data.write().unwrap(), key, item);
+    }
+
+    /// Soft delete an item recovered from a backup
+    pub(crate) fn recover_deleted(&self, item: T) {
+        self.data.write().unwrap().remove(&item.key());
+        self.deleted.write().unwrap().insert(item.key(), item);
+    }
+
+    /// Append a revision recovered from a backup to the history of a key,
+    /// unless the history already holds it
+    pub(crate) fn recover_change(&self, key: T::Key, change: Change<T>) {
+        let mut history = self.history.write().unwrap();
+        let revisions = history.entry(key).or_default();
+        if change.revision as usize > revisions.len() {
+            revisions.push(change);
+        }
+    }
+
+    /// Whether an item, even soft deleted, has a field of the given value
+    fn holds(&self, field: &str, value: &JsonValue) -> Result<bool, InterfaceError> {
+        let data = self.data.read().unwrap();
+        let deleted = self.deleted.read().unwrap();
+        for item in data.values().chain(deleted.values()) {
+            if field_of(&to_json(item)?, field)? == value {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Apply a write with the foreign keys of the items locked, the ones of the side
+    /// of the items that reference others, or else that are referenced. No item can
+    /// then be orphaned by a concurrent write between the check and the write.
+    fn with_relations<R>(
+        &self,
+        referencing: bool,
+        write: impl FnOnce() -> Result<R, InterfaceError>,
+    ) -> Result<R, InterfaceError> {
+        let locks: Vec<_> = self
+            .links
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|link| link.referencing == referencing)
+            .map(|link| Arc::clone(&link.lock))
+            .collect();
+        let _shared: Vec<_> = match referencing {
+            true => locks.iter().map(|lock| lock.read().unwrap()).collect(),
+            false => Vec::new(),
+        };
+        let _exclusive: Vec<_> = match referencing {
+            true => Vec::new(),
+            false => locks.iter().map(|lock| lock.write().unwrap()).collect(),
+        };
+        write()
+    }
+
+    /// Check that the items reference existing items, like foreign keys
+    fn check_references(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let links = self.links.read().unwrap();
+        for link in links.iter().filter(|link| link.referencing) {
+            for item in items {
+                let json = to_json(item)?;
+                let value = field_of(&json, &link.foreign_key)?;
+                if !value.is_null() && !(link.holds)(value)? {
+                    return Err(InterfaceError::Validation(format!(
+                        "Item {:?} references a missing item: {} = {}",
+                        item.key(),
+                        link.foreign_key,
+                        value
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that no item references the deleted items, like foreign keys.
+    /// The soft deleted items are kept, they can still be referenced.
+    fn check_referenced(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        let links = self.links.read().unwrap();
+        if self.soft_delete || links.iter().all(|link| link.referencing) {
+            return Ok(());
+        }
+        let field = single_key::<T>()?;
+        let mut values = Vec::new();
+        for entry in self.entries(keys) {
+            if let Some(item) = entry.item {
+                values.push((entry.key, field_of(&to_json(&item)?, field)?.clone()));
+            }
+        }
+        for link in links.iter().filter(|link| !link.referencing) {
+            for (key, value) in &values {
+                if (link.holds)(value)? {
+                    return Err(InterfaceError::Validation(format!(
+                        "Item {:?} is referenced by {}",
+                        key, link.foreign_key
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The repository linked to the items by a foreign key
+    fn linked<U>(
+        &self,
+        foreign_key: &str,
+        referencing: bool,
+    ) -> Result<Arc<InMemoryRepository<U>>, InterfaceError>
+    where
+        U: Val + HasKey + 'static,
+    {
+        self.links
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|link| link.referencing == referencing && link.foreign_key == foreign_key)
+            .find_map(|link| link.other.downcast_ref::<Weak<InMemoryRepository<U>>>())
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| InterfaceError::InvalidQuery(format!("No relation on {foreign_key}")))
+    }
+
+    /// Field names of the stored items, read from a serialized default item
+    fn columns() -> Result<Vec<String>, InterfaceError> {
+        match to_json(&T::default())? {
+            serde_json::Value::Object(fields) => Ok(fields.keys().cloned().collect()),
+            _ => Err(InterfaceError::FromFields(
+                "Items must serialize to an object".to_string(),
+            )),
+        }
+    }
+}
+
+impl<T> InMemoryRepository<T>
+where
+    T: Val + HasKey + 'static,
+{
+    /// Enforce the foreign key of a relation like a SQL database does: the items must
+    /// reference an item of `parents`, which cannot be deleted while items reference it.
+    /// The items can then be joined to their parents, and the parents to their items.
+    pub fn belongs_to<U>(
+        self: &Arc<Self>,
+        relation: &BelongsTo<T, U>,
+        parents: &Arc<InMemoryRepository<U>>,
+    ) where
+        U: Val + HasKey + 'static,
+    {
+        let foreign_key = relation.foreign_key().to_string();
+        let lock = Arc::new(RwLock::new(()));
+        let referenced = Arc::downgrade(parents);
+        self.links.write().unwrap().push(Link {
+            foreign_key: foreign_key.clone(),
+            referencing: true,
+            other: Box::new(Arc::downgrade(parents)),
+            holds: Box::new(move |value| match referenced.upgrade() {
+                Some(parents) => parents.holds(single_key::<U>()?, value),
+                None => Ok(false),
+            }),
+            lock: Arc::clone(&lock),
+        });
+
+        let referencing = Arc::downgrade(self);
+        let field = foreign_key.clone();
+        parents.links.write().unwrap().push(Link {
+            foreign_key,
+            referencing: false,
+            other: Box::new(Arc::downgrade(self)),
+            holds: Box::new(move |value| match referencing.upgrade() {
+                Some(items) => items.holds(&field, value),
+                None => Ok(false),
+            }),
+            lock,
+        });
+    }
+}
+
+/// State stored under a key, to undo or journal the writes to the key
+pub(crate) struct Entry<T: HasKey> {
+    pub(crate) key: T::Key,
+    pub(crate) item: Option<T>,
+    /// The soft deleted item
+    pub(crate) deleted: Option<T>,
+    /// Number of revisions in the history of the key
+    pub(crate) revisions: usize,
+}
+
+/// Insert an item under a key, or remove the key without an item
+fn set_entry<K: Ord, T>(map: &mut BTreeMap<K, T>, key: K, item: Option<T>) {
+    match item {
+        Some(item) => map.insert(key, item),
+        None => map.remove(&key),
+    };
+}
+
+/// Single key field of the items, the one a foreign key references
+fn single_key<T: HasKey>() -> Result<&'static str, InterfaceError> {
+    match T::KEY_FIELDS {
+        [key] => Ok(key),
+        _ => Err(InterfaceError::InvalidQuery(format!(
+            "{} has a composite key, it cannot be referenced",
+            std::any::type_name::<T>()
+        ))),
+    }
+}
+
+fn duplicate_error<T: HasKey>(item: &T) -> InterfaceError {
+    InterfaceError::Conflict(format!("Item {:?} already exists", item.key()))
+}
+
+fn missing_error<T: HasKey>(item: &T) -> InterfaceError {
+    InterfaceError::MissingItem(format!("{:?}", item.key()))
+}
+
+/// Serialize an item to evaluate filters on its fields
+fn to_json<T: Val>(item: &T) -> Result<serde_json::Value, InterfaceError> {
+    serde_json::to_value(item)
+        .map_err(|e| InterfaceError::FromFields(format!("Failed to serialize item: {e}")))
 }
 
 #[async_trait]
 impl<T> Create<T> for InMemoryRepository<T>
 where
-    T: Val + HasUuid,
+    T: Val + HasKey,
 {
     async fn create(&self, item: &T) -> Result<(), InterfaceError> {
-        self.data
-            .write()
-            .unwrap()
-            .insert(item.get_uuid(), item.clone());
-        Ok(())
+        self.with_relations(true, || {
+            self.check_references(std::slice::from_ref(item))?;
+            let mut data = self.data.write().unwrap();
+            if data.contains_key(&item.key()) || self.is_deleted(&item.key()) {
+                return Err(duplicate_error(item));
+            }
+            data.insert(item.key(), item.clone());
+            self.record(
+                Mutation::Create,
+                vec![(item.key(), None, Some(item.clone()))],
+            );
+            Ok(())
+        })
+    }
+
+    /// All the items are created or, if a key already exists, none of them
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        self.with_relations(true, || {
+            self.check_references(items)?;
+            let mut data = self.data.write().unwrap();
+            let mut staged: BTreeMap<T::Key, T> = BTreeMap::new();
+            for item in items {
+                let key = item.key();
+                if data.contains_key(&key) || staged.contains_key(&key) || self.is_deleted(&key) {
+                    return Err(duplicate_error(item));
+                }
+                staged.insert(key, item.clone());
+            }
+            data.extend(staged);
+            self.record(
+                Mutation::Create,
+                items
+                    .iter()
+                    .map(|item| (item.key(), None, Some(item.clone())))
+                    .collect(),
+            );
+            Ok(())
+        })
     }
 }
 
 #[async_trait]
 impl<T> Get<T> for InMemoryRepository<T>
 where
-    T: Val + HasUuid,
+    T: Val + HasKey,
 {
-    async fn get(&self, id: &Uuid) -> Result<Option<T>, InterfaceError> {
-        Ok(self.data.read().unwrap().get(id).cloned())
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        Ok(self.data.read().unwrap().get(key).cloned())
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        Ok(self.data.read().unwrap().contains_key(key))
     }
 }
 
 #[async_trait]
 impl<T> Delete<T> for InMemoryRepository<T>
 where
-    T: Val + HasUuid,
+    T: Val + HasKey,
 {
-    async fn delete(&self, id: &Uuid) -> Result<(), InterfaceError> {
-        self.data.write().unwrap().remove(id);
-        Ok(())
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        self.delete_many(std::slice::from_ref(key)).await
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        self.with_relations(false, || {
+            self.check_referenced(keys)?;
+            let mut data = self.data.write().unwrap();
+            let mut changes = Vec::new();
+            for key in keys {
+                // Deleting a missing item changes nothing
+                if let Some(item) = data.remove(key) {
+                    if self.soft_delete {
+                        self.deleted
+                            .write()
+                            .unwrap()
+                            .insert(key.clone(), item.clone());
+                    }
+                    changes.push((key.clone(), Some(item), None));
+                }
+            }
+            self.record(Mutation::Delete, changes);
+            Ok(())
+        })
     }
 }
 
 #[async_trait]
 impl<T> Update<T> for InMemoryRepository<T>
 where
-    T: Val + HasUuid,
+    T: Val + HasKey,
 {
     async fn update(&self, item: &T) -> Result<(), InterfaceError> {
-        self.data
-            .write()
-            .unwrap()
-            .insert(item.get_uuid(), item.clone());
-        Ok(())
+        self.with_relations(true, || {
+            self.check_references(std::slice::from_ref(item))?;
+            let mut data = self.data.write().unwrap();
+            let stored = data.get(&item.key()).ok_or_else(|| missing_error(item))?;
+            let item = match &self.version {
+                Some(field) => self.next_version(field, stored, item)?,
+                None => item.clone(),
+            };
+            let before = data.insert(item.key(), item.clone());
+            self.record(Mutation::Update, vec![(item.key(), before, Some(item))]);
+            Ok(())
+        })
+    }
+
+    /// All the items are updated or, on a missing item or a version conflict, none of them
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        self.with_relations(true, || {
+            self.check_references(items)?;
+            let mut data = self.data.write().unwrap();
+            let mut staged: BTreeMap<T::Key, T> = BTreeMap::new();
+            let mut changes = Vec::new();
+            for item in items {
+                let stored = staged
+                    .get(&item.key())
+                    .or_else(|| data.get(&item.key()))
+                    .ok_or_else(|| missing_error(item))?;
+                let item = match &self.version {
+                    Some(field) => self.next_version(field, stored, item)?,
+                    None => item.clone(),
+                };
+                changes.push((item.key(), Some(stored.clone()), Some(item.clone())));
+                staged.insert(item.key(), item);
+            }
+            data.extend(staged);
+            self.record(Mutation::Update, changes);
+            Ok(())
+        })
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        self.with_relations(true, || {
+            self.check_references(std::slice::from_ref(item))?;
+            let mut data = self.data.write().unwrap();
+            if self.is_deleted(&item.key()) {
+                return Err(InterfaceError::Conflict(format!(
+                    "Item {:?} was deleted",
+                    item.key()
+                )));
+            }
+            let item = match (data.get(&item.key()), &self.version) {
+                (Some(stored), Some(field)) => self.next_version(field, stored, item)?,
+                _ => item.clone(),
+            };
+            let before = data.insert(item.key(), item.clone());
+            self.record(Mutation::Upsert, vec![(item.key(), before, Some(item))]);
+            Ok(())
+        })
     }
 }
 
 #[async_trait]
 impl<T> List<T> for InMemoryRepository<T>
 where
-    T: Val + HasUuid,
+    T: Val + HasKey,
 {
     async fn list(&self) -> Result<Vec<T>, InterfaceError> {
-        Ok(self
-            .data
-            .read()
-            .unwrap()
-            .iter()
-            .map(|(_, v)| v.clone())
-            .collect())
+        Ok(self.data.read().unwrap().values().cloned().collect())
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        filter.validate(&Self::columns()?)?;
+
+        let data = self.data.read().unwrap();
+        let mut items = Vec::new();
+        for item in data.values() {
+            if filter.matches(&to_json(item)?)? {
+                items.push(item.clone());
+            }
+        }
+        Ok(items)
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        request.validate(&Self::columns()?)?;
+        let condition = request.condition(T::KEY_FIELDS)?;
+
+        let mut rows = Vec::new();
+        for item in self.data.read().unwrap().values() {
+            let json = to_json(item)?;
+            if condition.matches(&json)? {
+                rows.push((json, item.clone()));
+            }
+        }
+        rows.sort_by(|(a, _), (b, _)| request.compare(T::KEY_FIELDS, a, b));
+        rows.truncate(request.size as usize + 1);
+
+        let page = request.page(T::KEY_FIELDS, rows, |(json, _), field| {
+            Ok(FieldValue::from_json(field_of(json, field)?))
+        })?;
+        Ok(Page {
+            items: page.items.into_iter().map(|(_, item)| item).collect(),
+            next: page.next,
+        })
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        let Some(filter) = filter else {
+            return Ok(self.data.read().unwrap().len() as u64);
+        };
+        filter.validate(&Self::columns()?)?;
+
+        let mut count = 0;
+        for item in self.data.read().unwrap().values() {
+            if filter.matches(&to_json(item)?)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[async_trait]
+impl<T> Repository<T> for InMemoryRepository<T> where T: Val + HasKey {}
+
+/// Joins of the items of repositories linked with `belongs_to`
+#[async_trait]
+impl<T, U> Join<T, U> for InMemoryRepository<T>
+where
+    T: Val + HasKey + 'static,
+    U: Val + HasKey + 'static,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        let key = single_key::<U>()?;
+        let mut parents = Vec::new();
+        for parent in self
+            .linked::<U>(relation.foreign_key(), true)?
+            .list()
+            .await?
+        {
+            parents.push((field_of(&to_json(&parent)?, key)?.clone(), parent));
+        }
+
+        let mut items = Vec::new();
+        for item in self.list_where(filter).await? {
+            let value = field_of(&to_json(&item)?, relation.foreign_key())?.clone();
+            if let Some((_, parent)) = parents.iter().find(|(key, _)| *key == value) {
+                items.push((item, parent.clone()));
+            }
+        }
+        Ok(items)
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        let key = single_key::<T>()?;
+        let mut children = Vec::new();
+        for child in self
+            .linked::<U>(relation.foreign_key(), false)?
+            .list()
+            .await?
+        {
+            children.push((
+                field_of(&to_json(&child)?, relation.foreign_key())?.clone(),
+                child,
+            ));
+        }
+
+        let mut items = Vec::new();
+        for item in self.list_where(filter).await? {
+            let value = field_of(&to_json(&item)?, key)?.clone();
+            let related = children
+                .iter()
+                .filter(|(foreign_key, _)| *foreign_key == value)
+                .map(|(_, child)| child.clone())
+                .collect();
+            items.push((item, related));
+        }
+        Ok(items)
+    }
+}
+
+#[async_trait]
+impl<T> History<T> for InMemoryRepository<T>
+where
+    T: Val + HasKey,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        if !self.audit {
+            return Err(not_audited(std::any::type_name::<T>()));
+        }
+        let history = self.history.read().unwrap();
+        Ok(history.get(key).cloned().unwrap_or_default())
+    }
+}
+
+/// Undo of a write, restoring the entries it wrote
+type Undo<'a> = Box<dyn FnOnce() + Send + 'a>;
+
+/// Undo log of a transaction, `None` once it is finished
+type UndoLog<'a> = Arc<Mutex<Option<Vec<Undo<'a>>>>>;
+
+/// Undo log transaction over in memory repositories
+///
+/// The writes made through the repositories bound to the transaction with
+/// `InMemoryRepository::in_transaction` are applied right away and logged. A rollback,
+/// or dropping the transaction unfinished, restores the keys they wrote and leaves the
+/// other keys as they are. There is no isolation: the writes are visible before the
+/// commit, and a concurrent write to a key written by the transaction is lost on rollback.
+pub struct MemoryTransaction<'a> {
+    undo: UndoLog<'a>,
+}
+
+impl Default for MemoryTransaction<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> MemoryTransaction<'a> {
+    pub fn new() -> Self {
+        MemoryTransaction {
+            undo: Arc::new(Mutex::new(Some(Vec::new()))),
+        }
+    }
+
+    /// Roll back the transaction unless it is finished
+    pub(crate) fn abort(&self) {
+        if let Some(undo) = self.undo.lock().unwrap().take() {
+            undo_all(undo);
+        }
+    }
+
+    fn take(&self) -> Result<Vec<Undo<'a>>, InterfaceError> {
+        self.undo.lock().unwrap().take().ok_or_else(finished_error)
+    }
+}
+
+fn finished_error() -> InterfaceError {
+    InterfaceError::Other("Transaction already finished".to_string())
+}
+
+/// Undo the writes, the last one first
+fn undo_all(undo: Vec<Undo<'_>>) {
+    for undo in undo.into_iter().rev() {
+        undo();
+    }
+}
+
+#[async_trait]
+impl Transaction for MemoryTransaction<'_> {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.take().map(|_| ())
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        undo_all(self.take()?);
+        Ok(())
+    }
+}
+
+impl Drop for MemoryTransaction<'_> {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+impl<T> InMemoryRepository<T>
+where
+    T: Val + HasKey,
+{
+    /// The same repository, logging its writes in a transaction to undo them on rollback
+    pub fn in_transaction<'a>(
+        &'a self,
+        transaction: &MemoryTransaction<'a>,
+    ) -> MemoryTransactionRepository<'a, T> {
+        MemoryTransactionRepository {
+            repo: self,
+            undo: Arc::clone(&transaction.undo),
+        }
+    }
+}
+
+/// In memory repository bound to a `MemoryTransaction`
+pub struct MemoryTransactionRepository<'a, T>
+where
+    T: Val + HasKey,
+{
+    repo: &'a InMemoryRepository<T>,
+    undo: UndoLog<'a>,
+}
+
+impl<'a, T> MemoryTransactionRepository<'a, T>
+where
+    T: Val + HasKey,
+{
+    /// Apply a write to the repository, then log the undo of the written keys
+    pub(crate) async fn write(
+        &self,
+        keys: Vec<T::Key>,
+        operation: impl Future<Output = Result<(), InterfaceError>> + Send,
+    ) -> Result<(), InterfaceError> {
+        if self.undo.lock().unwrap().is_none() {
+            return Err(finished_error());
+        }
+        let previous = self.repo.entries(&keys);
+        operation.await?;
+
+        // A transaction finished concurrently keeps the write, like a committed one
+        let repo = self.repo;
+        if let Some(undo) = self.undo.lock().unwrap().as_mut() {
+            undo.push(Box::new(move || repo.restore(previous)));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T> Create<T> for MemoryTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.repo.create(item)).await
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let keys = items.iter().map(|item| item.key()).collect();
+        self.write(keys, self.repo.create_many(items)).await
+    }
+}
+
+#[async_trait]
+impl<T> Get<T> for MemoryTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        self.repo.get(key).await
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        self.repo.exists(key).await
+    }
+}
+
+#[async_trait]
+impl<T> Delete<T> for MemoryTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        self.write(vec![key.clone()], self.repo.delete(key)).await
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        self.write(keys.to_vec(), self.repo.delete_many(keys)).await
+    }
+}
+
+#[async_trait]
+impl<T> Update<T> for MemoryTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.repo.update(item)).await
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        let keys = items.iter().map(|item| item.key()).collect();
+        self.write(keys, self.repo.update_many(items)).await
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        self.write(vec![item.key()], self.repo.upsert(item)).await
+    }
+}
+
+#[async_trait]
+impl<T> List<T> for MemoryTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        self.repo.list().await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        self.repo.list_where(filter).await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        self.repo.list_page(request).await
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        self.repo.count(filter).await
     }
 }
 
 #[async_trait]
-impl<T> Repository<T> for InMemoryRepository<T> where T: Val + HasUuid {}
+impl<T> Repository<T> for MemoryTransactionRepository<'_, T> where T: Val + HasKey {}
+
+#[async_trait]
+impl<T, U> Join<T, U> for MemoryTransactionRepository<'_, T>
+where
+    T: Val + HasKey + 'static,
+    U: Val + HasKey + 'static,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        self.repo.list_with_parent(relation, filter).await
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        self.repo.list_with_children(relation, filter).await
+    }
+}
+
+#[async_trait]
+impl<T> History<T> for MemoryTransactionRepository<'_, T>
+where
+    T: Val + HasKey,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        self.repo.history(key).await
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::conformance::{ConformanceItem, RepositoryFactory, VersionedConformanceItem};
+    use crate::query::{Direction, MAX_PAGE_SIZE};
+    use crate::relation::tests::{gen_owner, gen_pet, Owner, Pet};
+    use crate::usecase::history::as_actor;
     use serde::{Deserialize, Serialize};
     use uuid::Uuid;
 
@@ -107,12 +985,34 @@ mod tests {
         field1: i32,
     }
 
-    impl HasUuid for Item1 {
-        fn get_uuid(&self) -> Uuid {
+    impl HasKey for Item1 {
+        type Key = Uuid;
+
+        const KEY_FIELDS: &'static [&'static str] = &["uuid"];
+
+        fn key(&self) -> Uuid {
             self.uuid
         }
     }
 
+    /// Item identified by a composite key
+    #[derive(Default, Debug, Clone, Deserialize, PartialEq, Serialize)]
+    struct Item2 {
+        stan: String,
+        rrn: String,
+        field1: i32,
+    }
+
+    impl HasKey for Item2 {
+        type Key = (String, String);
+
+        const KEY_FIELDS: &'static [&'static str] = &["stan", "rrn"];
+
+        fn key(&self) -> (String, String) {
+            (self.stan.clone(), self.rrn.clone())
+        }
+    }
+
     // Gen item
     fn gen_item() -> Item1 {
         Item1 {
@@ -154,8 +1054,8 @@ mod tests {
         let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
         {
             let mut data = repo.data.write().unwrap();
-            data.insert(item1.uuid.clone(), item1.clone());
-            data.insert(item2.uuid.clone(), item2.clone());
+            data.insert(item1.uuid, item1.clone());
+            data.insert(item2.uuid, item2.clone());
         }
 
         // WHEN we get all ITEM1_s
@@ -176,7 +1076,7 @@ mod tests {
         let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
         {
             let mut data = repo.data.write().unwrap();
-            data.insert(item.uuid.clone(), item.clone());
+            data.insert(item.uuid, item.clone());
         }
 
         // WHEN deleting the item
@@ -197,7 +1097,7 @@ mod tests {
         let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
         {
             let mut data = repo.data.write().unwrap();
-            data.insert(item.uuid.clone(), item.clone());
+            data.insert(item.uuid, item.clone());
         }
 
         // WHEN getting the product
@@ -211,21 +1111,405 @@ mod tests {
 
     #[tokio::test]
     async fn test_update() -> Result<(), InterfaceError> {
-        // GIVEN an empty repo and an item
-        let item = gen_item();
+        // GIVEN a repo with an item
+        let mut item = gen_item();
         let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
+        repo.create(&item).await?;
 
-        // WHEN inserting a product
+        // WHEN updating the item and a missing one
+        item.field1 += 1;
         repo.update(&item).await?;
+        let missing = repo.update(&gen_item()).await;
 
         // THEN the length of the repo is 1
         assert_eq!(repo.data.read().unwrap().len(), 1);
-        // AND the item is returned
+        // AND the updated item is returned
         assert_eq!(repo.get(&item.uuid).await?, Some(item));
+        // AND the missing item is not created
+        assert!(matches!(missing, Err(InterfaceError::MissingItem(_))));
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_list_where() -> Result<(), InterfaceError> {
+        // GIVEN a repo with five items
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
+        let items: Vec<Item1> = (1..=5)
+            .map(|field1| Item1 {
+                uuid: Uuid::new_v4(),
+                field1,
+            })
+            .collect();
+        for item in &items {
+            repo.create(item).await?;
+        }
+
+        // WHEN we list the items matching filters
+        // THEN we only get the matching items
+        let field1s = |mut found: Vec<Item1>| {
+            found.sort_by_key(|item| item.field1);
+            found.iter().map(|item| item.field1).collect::<Vec<i32>>()
+        };
+        let filter = Filter::eq("uuid", items[1].uuid);
+        assert_eq!(field1s(repo.list_where(&filter).await?), vec![2]);
+        let filter = Filter::gt("field1", 3);
+        assert_eq!(field1s(repo.list_where(&filter).await?), vec![4, 5]);
+        let filter = Filter::is_in("field1", [1, 3, 7]);
+        assert_eq!(field1s(repo.list_where(&filter).await?), vec![1, 3]);
+        let filter = Filter::ge("field1", 2)
+            .and(Filter::le("field1", 4))
+            .and(Filter::ne("field1", 3).or(Filter::eq("uuid", items[2].uuid)));
+        assert_eq!(field1s(repo.list_where(&filter).await?), vec![2, 3, 4]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_where_unknown_field() {
+        // GIVEN an empty repo
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
+
+        // WHEN we filter on an unknown field
+        let result = repo.list_where(&Filter::eq("field2", 1)).await;
+
+        // THEN the query is rejected
+        assert!(matches!(result, Err(InterfaceError::InvalidQuery(_))));
+    }
+
+    #[tokio::test]
+    async fn test_list_page() -> Result<(), InterfaceError> {
+        // GIVEN a repo with five items, two of them sharing the same field1
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
+        for field1 in [3, 1, 2, 2, 5] {
+            repo.create(&Item1 {
+                uuid: Uuid::new_v4(),
+                field1,
+            })
+            .await?;
+        }
+
+        for direction in [Direction::Asc, Direction::Desc] {
+            // WHEN we list pages of two items until there is no next page
+            let mut pages = Vec::new();
+            let mut request = PageRequest::new("field1", 2).direction(direction);
+            loop {
+                let page = repo.list_page(&request).await?;
+                pages.push(page.items.iter().map(|i| i.field1).collect::<Vec<i32>>());
+                match page.next {
+                    Some(cursor) => request = request.after(Some(cursor)),
+                    None => break,
+                }
+            }
+
+            // THEN we get every item once, in order
+            let expected = match direction {
+                Direction::Asc => vec![vec![1, 2], vec![2, 3], vec![5]],
+                Direction::Desc => vec![vec![5, 3], vec![2, 2], vec![1]],
+            };
+            assert_eq!(pages, expected);
+        }
+
+        // AND pages can be filtered
+        let request = PageRequest::new("field1", 10).filter(Filter::ge("field1", 3));
+        let page = repo.list_page(&request).await?;
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.next, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_page_unbounded() {
+        // GIVEN an empty repo
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
+
+        // WHEN we ask for an empty or too large page
+        // THEN the request is rejected
+        for size in [0, MAX_PAGE_SIZE + 1] {
+            let result = repo.list_page(&PageRequest::new("field1", size)).await;
+            assert!(matches!(result, Err(InterfaceError::InvalidQuery(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_composite_key() -> Result<(), InterfaceError> {
+        // GIVEN a repo with items sharing part of their key
+        let repo: InMemoryRepository<Item2> = InMemoryRepository::new();
+        let items: Vec<Item2> = ["1", "2", "3"]
+            .into_iter()
+            .map(|rrn| Item2 {
+                stan: "000001".to_string(),
+                rrn: rrn.to_string(),
+                field1: 3,
+            })
+            .collect();
+        for item in &items {
+            repo.create(item).await?;
+        }
+
+        // WHEN we get and delete items by their key
+        let key = ("000001".to_string(), "2".to_string());
+        let found = repo.get(&key).await?;
+        repo.delete(&key).await?;
+
+        // THEN only the matching item is affected
+        assert_eq!(found, Some(items[1].clone()));
+        assert_eq!(repo.get(&key).await?, None);
+        assert_eq!(repo.list().await?, vec![items[0].clone(), items[2].clone()]);
+
+        // AND pages are ordered by every key field
+        let request = PageRequest::new("field1", 1).direction(Direction::Desc);
+        let page = repo.list_page(&request).await?;
+        assert_eq!(page.items, vec![items[2].clone()]);
+        let page = repo.list_page(&request.after(page.next)).await?;
+        assert_eq!(page.items, vec![items[0].clone()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch() -> Result<(), InterfaceError> {
+        // GIVEN an empty repo and items
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new();
+        let mut items: Vec<Item1> = (0..10).map(|_| gen_item()).collect();
+
+        // WHEN we create, update and delete them in batches
+        repo.create_many(&items).await?;
+        assert_eq!(repo.data.read().unwrap().len(), 10);
+
+        items.iter_mut().for_each(|item| item.field1 = 7);
+        repo.update_many(&items).await?;
+        let updated = repo.list_where(&Filter::eq("field1", 7)).await?;
+        assert_eq!(updated.len(), 10);
+
+        let keys: Vec<Uuid> = items.iter().take(4).map(|item| item.uuid).collect();
+        repo.delete_many(&keys).await?;
+
+        // THEN the remaining items are the ones that were not deleted
+        assert_eq!(repo.data.read().unwrap().len(), 6);
+        assert_eq!(repo.get(&items[0].uuid).await?, None);
+        assert_eq!(repo.get(&items[9].uuid).await?, Some(items[9].clone()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_many_version() -> Result<(), InterfaceError> {
+        // GIVEN a versioned repo with two items
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new().with_version("field1");
+        let (item1, item2) = (gen_item(), gen_item());
+        repo.create_many(&[item1.clone(), item2.clone()]).await?;
+
+        // WHEN a batch contains a stale item
+        let mut stale = item2.clone();
+        stale.field1 -= 1;
+        let result = repo.update_many(&[item1.clone(), stale]).await;
+
+        // THEN none of the items are updated
+        assert!(matches!(result, Err(InterfaceError::Conflict(_))));
+        assert_eq!(repo.get(&item1.uuid).await?, Some(item1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_version() -> Result<(), InterfaceError> {
+        // GIVEN a versioned repo with an item
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new().with_version("field1");
+        let item = gen_item();
+        repo.create(&item).await?;
+
+        // WHEN two clients update the item they read
+        let mut first = repo.get(&item.uuid).await?.unwrap();
+        let second = repo.get(&item.uuid).await?.unwrap();
+        repo.update(&first).await?;
+        let result = repo.update(&second).await;
+
+        // THEN the second update is rejected and the version was incremented once
+        assert!(matches!(result, Err(InterfaceError::Conflict(_))));
+        first.field1 += 1;
+        assert_eq!(repo.get(&item.uuid).await?, Some(first));
+
+        // AND updating a missing item is an error
+        let result = repo.update(&gen_item()).await;
+        assert!(matches!(result, Err(InterfaceError::MissingItem(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction() -> Result<(), InterfaceError> {
+        // GIVEN two repos, one of them with an item
+        let (item1, item2) = (gen_item(), gen_item());
+        let repo1: InMemoryRepository<Item1> = InMemoryRepository::new();
+        let repo2: InMemoryRepository<Item1> = InMemoryRepository::new();
+        repo1.create(&item1).await?;
+
+        // WHEN we modify both repos in a rolled back transaction
+        let transaction = MemoryTransaction::new();
+        let (bound1, bound2) = (
+            repo1.in_transaction(&transaction),
+            repo2.in_transaction(&transaction),
+        );
+        bound1.delete(&item1.uuid).await?;
+        bound2.create(&item2).await?;
+        let outcome = Err(InterfaceError::Other("abort".to_string()));
+        assert!(transaction.finish(outcome).await.is_err());
+
+        // THEN both repos are restored
+        assert_eq!(repo1.list().await?, vec![item1.clone()]);
+        assert_eq!(repo2.list().await?, vec![]);
+
+        // AND changes of a committed transaction are kept
+        let transaction = MemoryTransaction::new();
+        repo2.in_transaction(&transaction).create(&item2).await?;
+        transaction.finish(Ok(())).await?;
+        assert_eq!(repo2.list().await?, vec![item2.clone()]);
+
+        // AND a transaction cannot be finished twice, nor written to once finished
+        assert!(transaction.rollback().await.is_err());
+        let result = repo2.in_transaction(&transaction).delete(&item2.uuid).await;
+        assert!(result.is_err());
+
+        // AND the changes of a transaction dropped unfinished are rolled back
+        let transaction = MemoryTransaction::new();
+        repo1
+            .in_transaction(&transaction)
+            .delete(&item1.uuid)
+            .await?;
+        drop(transaction);
+        assert_eq!(repo1.list().await?, vec![item1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_concurrent_writes() -> Result<(), InterfaceError> {
+        // GIVEN a repository with two items, both of them recording their history
+        let (item1, item2) = (gen_item(), gen_item());
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new().with_history();
+        repo.create_many(&[item1.clone(), item2.clone()]).await?;
+
+        // WHEN a transaction updates the first item while the second one is
+        // updated outside of it, then the transaction is rolled back
+        let transaction = MemoryTransaction::new();
+        let updated1 = Item1 {
+            field1: 10,
+            ..item1.clone()
+        };
+        let updated2 = Item1 {
+            field1: 20,
+            ..item2.clone()
+        };
+        repo.in_transaction(&transaction).update(&updated1).await?;
+        repo.update(&updated2).await?;
+        transaction.rollback().await?;
+
+        // THEN only the write of the transaction is undone, with its revision
+        assert_eq!(repo.get(&item1.uuid).await?, Some(item1.clone()));
+        assert_eq!(repo.get(&item2.uuid).await?, Some(updated2));
+        assert_eq!(repo.history(&item1.uuid).await?.len(), 1);
+        assert_eq!(repo.history(&item2.uuid).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete() -> Result<(), InterfaceError> {
+        // GIVEN a repository keeping its deleted items, with a deleted item
+        let item = gen_item();
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new().with_soft_delete();
+        repo.create(&item).await?;
+        repo.delete(&item.uuid).await?;
+
+        // WHEN we write it again
+        let created = repo.create(&item).await;
+        let updated = repo.update(&item).await;
+        let upserted = repo.upsert(&item).await;
+
+        // THEN it is hidden from the reads and its key cannot be reused
+        assert_eq!(repo.get(&item.uuid).await?, None);
+        assert_eq!(repo.count(None).await?, 0);
+        assert!(matches!(created, Err(InterfaceError::Conflict(_))));
+        assert!(matches!(updated, Err(InterfaceError::MissingItem(_))));
+        assert!(matches!(upserted, Err(InterfaceError::Conflict(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history() -> Result<(), InterfaceError> {
+        // GIVEN a repository recording its history
+        let item = gen_item();
+        let updated = Item1 {
+            field1: 4,
+            ..item.clone()
+        };
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new()
+            .with_history()
+            .with_actor("teller");
+
+        // WHEN an item is created, updated, upserted in a rolled back transaction,
+        // then deleted twice
+        repo.create(&item).await?;
+        repo.update(&updated).await?;
+        let transaction = MemoryTransaction::new();
+        repo.in_transaction(&transaction).upsert(&item).await?;
+        transaction.rollback().await?;
+        repo.delete(&item.uuid).await?;
+        repo.delete(&item.uuid).await?;
+
+        // THEN the applied mutations are recorded with the images of the item
+        let history = repo.history(&item.uuid).await?;
+        let changes: Vec<_> = history
+            .iter()
+            .map(|change| {
+                (
+                    change.revision,
+                    change.operation,
+                    change.before.clone(),
+                    change.after.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (1, Mutation::Create, None, Some(item.clone())),
+                (
+                    2,
+                    Mutation::Update,
+                    Some(item.clone()),
+                    Some(updated.clone())
+                ),
+                (3, Mutation::Delete, Some(updated), None),
+            ]
+        );
+        assert!(history.iter().all(|change| change.actor == "teller"));
+
+        // AND the history of a repository that does not record it cannot be read
+        let result = InMemoryRepository::<Item1>::new().history(&item.uuid).await;
+        assert!(matches!(result, Err(InterfaceError::InvalidQuery(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history_actor() -> Result<(), InterfaceError> {
+        // GIVEN a repository recording its history as made by a teller
+        let item = gen_item();
+        let repo: InMemoryRepository<Item1> = InMemoryRepository::new()
+            .with_history()
+            .with_actor("teller");
+
+        // WHEN an item is created by a customer, then deleted
+        as_actor("customer-1", repo.create(&item)).await?;
+        repo.delete(&item.uuid).await?;
+
+        // THEN each change is recorded with the actor of its operation
+        let actors: Vec<_> = repo
+            .history(&item.uuid)
+            .await?
+            .into_iter()
+            .map(|change| change.actor)
+            .collect();
+        assert_eq!(actors, vec!["customer-1", "teller"]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_create() -> Result<(), InterfaceError> {
         // GIVEN an empty repo and an item
@@ -242,4 +1526,151 @@ mod tests {
 
         Ok(())
     }
+
+    /// Owners and their pets, linked by the foreign key of the pets
+    fn get_relation_repositories() -> (Arc<InMemoryRepository<Owner>>, Arc<InMemoryRepository<Pet>>)
+    {
+        let owners = Arc::new(InMemoryRepository::new());
+        let pets = Arc::new(InMemoryRepository::new().with_soft_delete());
+        pets.belongs_to(&Pet::owner(), &owners);
+        (owners, pets)
+    }
+
+    #[tokio::test]
+    async fn test_join() -> Result<(), InterfaceError> {
+        // GIVEN two owners, the pets of one of them, a stray pet and a deleted pet
+        let (owners, pets) = get_relation_repositories();
+        let alice = gen_owner("Alice");
+        let bob = gen_owner("Bob");
+        owners.create_many(&[alice.clone(), bob.clone()]).await?;
+        let rex = gen_pet(Some(&alice), "Rex");
+        let stray = gen_pet(None, "Stray");
+        let tom = gen_pet(Some(&alice), "Tom");
+        pets.create_many(&[rex.clone(), stray, tom.clone()]).await?;
+        let deleted = gen_pet(Some(&bob), "Old");
+        pets.create(&deleted).await?;
+        pets.delete(&deleted.uuid).await?;
+
+        // WHEN the pets are listed with their owner
+        let with_owner = pets
+            .list_with_parent(&Pet::owner(), &Filter::And(vec![]))
+            .await?;
+
+        // THEN only the pets with an owner are listed, ordered by key
+        let mut expected = vec![(rex.clone(), alice.clone()), (tom.clone(), alice.clone())];
+        expected.sort_by_key(|(pet, _)| pet.uuid);
+        assert_eq!(with_owner, expected);
+
+        // WHEN the owners are listed with their pets
+        let with_pets = owners
+            .list_with_children(&Owner::pets(), &Filter::eq("name", "Bob"))
+            .await?;
+
+        // THEN the filtered owner is listed without its deleted pet
+        assert_eq!(with_pets, vec![(bob, vec![])]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_join_not_linked() {
+        // GIVEN repositories not linked by the relation
+        let pets: InMemoryRepository<Pet> = InMemoryRepository::new();
+
+        // WHEN the pets are listed with their owner
+        let result = pets
+            .list_with_parent(&Pet::owner(), &Filter::And(vec![]))
+            .await;
+
+        // THEN the query is invalid
+        assert!(matches!(result, Err(InterfaceError::InvalidQuery(_))));
+    }
+
+    #[tokio::test]
+    async fn test_foreign_key() -> Result<(), InterfaceError> {
+        // GIVEN an owner with a pet
+        let (owners, pets) = get_relation_repositories();
+        let alice = gen_owner("Alice");
+        owners.create(&alice).await?;
+        let mut rex = gen_pet(Some(&alice), "Rex");
+        pets.create(&rex).await?;
+
+        // WHEN a pet of a missing owner is created
+        let result = pets.create(&gen_pet(Some(&gen_owner("Bob")), "Tom")).await;
+
+        // THEN it is rejected
+        assert!(matches!(result, Err(InterfaceError::Validation(_))));
+
+        // WHEN the pet is moved to a missing owner
+        rex.owner_uuid = Some(Uuid::new_v4());
+        let result = pets.update(&rex).await;
+
+        // THEN it is rejected
+        assert!(matches!(result, Err(InterfaceError::Validation(_))));
+
+        // WHEN the owner of the pet is deleted
+        let result = owners.delete(&alice.uuid).await;
+
+        // THEN it is rejected, even once the pet is soft deleted
+        assert!(matches!(result, Err(InterfaceError::Validation(_))));
+        pets.delete(&rex.uuid).await?;
+        let result = owners.delete(&alice.uuid).await;
+        assert!(matches!(result, Err(InterfaceError::Validation(_))));
+        assert_eq!(owners.get(&alice.uuid).await?, Some(alice));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_foreign_key_concurrent() -> Result<(), InterfaceError> {
+        // GIVEN owners without pets
+        let (owners, pets) = get_relation_repositories();
+        for _ in 0..100 {
+            let alice = gen_owner("Alice");
+            owners.create(&alice).await?;
+
+            // WHEN pets of the owner are created while the owner is deleted
+            let litter: Vec<_> = (0..100).map(|_| gen_pet(Some(&alice), "Rex")).collect();
+            let barrier = Arc::new(tokio::sync::Barrier::new(2));
+            let created = tokio::spawn({
+                let (pets, barrier) = (Arc::clone(&pets), Arc::clone(&barrier));
+                async move {
+                    barrier.wait().await;
+                    pets.create_many(&litter).await
+                }
+            });
+            let deleted = tokio::spawn({
+                let (owners, barrier) = (Arc::clone(&owners), Arc::clone(&barrier));
+                async move {
+                    barrier.wait().await;
+                    owners.delete(&alice.uuid).await
+                }
+            });
+            let (created, deleted) = (created.await.unwrap(), deleted.await.unwrap());
+
+            // THEN exactly one of them succeeds, and no pet is left without its owner
+            assert!(created.is_ok() != deleted.is_ok());
+            let orphans = pets
+                .list_where(&Filter::eq("owner_uuid", alice.uuid))
+                .await?;
+            assert_eq!(orphans.is_empty(), owners.get(&alice.uuid).await?.is_none());
+        }
+        Ok(())
+    }
+
+    struct MemoryFactory;
+
+    #[async_trait]
+    impl RepositoryFactory for MemoryFactory {
+        type Repo = InMemoryRepository<ConformanceItem>;
+        type VersionedRepo = InMemoryRepository<VersionedConformanceItem>;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            Ok(InMemoryRepository::new())
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            Ok(InMemoryRepository::new().with_version("version"))
+        }
+    }
+
+    crate::repository_conformance_tests!(MemoryFactory);
 }
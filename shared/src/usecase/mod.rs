@@ -1,3 +1,11 @@
 // pub mod handler;
+pub mod cache;
+pub mod fault;
+pub mod file;
+pub mod history;
+pub mod instrument;
 pub mod memory;
+pub mod postgres;
 pub mod rds;
+pub mod retry;
+pub mod sqlite;
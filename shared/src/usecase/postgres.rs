@@ -0,0 +1,1270 @@
+//! Implementation of a Repository over a PostgreSQL connection pool
+//!
+//! The statements are the ones generated by `struct_to_sql` for the RDS Data API:
+//! their named `:param` placeholders are rewritten to the positional `$n`
+//! placeholders of the PostgreSQL protocol.
+use std::error::Error;
+use std::marker::PhantomData;
+use std::slice;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::migration::MigrationDatabase;
+use crate::ports::secondary::{
+    Change, Create, Delete, Get, HasKey, History, Join, Key, List, Mutation, Repository,
+    Transaction, Update,
+};
+use crate::query::{validate_selection, FieldValue, Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::settings::PostgresSettings;
+use crate::usecase::history::{
+    self, current_actor, history_parameters, not_audited, now_millis, AppendHistory, Changes,
+    HistoryRow, DEFAULT_ACTOR,
+};
+use crate::usecase::rds::{
+    count_value, field_value, found_value, item_parameters, not_updated, not_upserted,
+    timestamp_value, GetFieldsAsParams,
+};
+use crate::{error::InterfaceError, QuerySet, Val};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use deadpool_postgres::{Manager, ManagerConfig, Object, PoolError, RecyclingMethod};
+use rust_decimal::Decimal;
+use secrecy::ExposeSecret;
+use serde_json::{Map, Value as JsonValue};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use tokio_postgres::{NoTls, Row};
+use uuid::Uuid;
+
+pub use deadpool_postgres::Pool;
+
+/// Create a pool of connections to the database of the settings
+pub fn create_pool(settings: &PostgresSettings) -> Result<Pool, InterfaceError> {
+    let config =
+        tokio_postgres::Config::from_str(settings.url.expose_secret()).map_err(postgres_error)?;
+    let manager = Manager::from_config(
+        config,
+        NoTls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+
+    Pool::builder(manager)
+        .max_size(settings.pool_size)
+        .build()
+        .map_err(|err| InterfaceError::Other(format!("Failed to create the pool: {err}")))
+}
+
+/// Error of the database, typed from the SQLSTATE of the failed statement
+fn postgres_error(err: tokio_postgres::Error) -> InterfaceError {
+    if err.is_closed() {
+        return InterfaceError::Transient(err.to_string());
+    }
+    err.code()
+        .and_then(|code| InterfaceError::from_sqlstate(code.code(), err.to_string()))
+        .unwrap_or_else(|| InterfaceError::PostgresError(Box::new(err)))
+}
+
+fn pool_error(err: PoolError) -> InterfaceError {
+    match err {
+        PoolError::Backend(err) => postgres_error(err),
+        PoolError::Timeout(_) => {
+            InterfaceError::Transient(format!("No connection available: {err}"))
+        }
+        err => InterfaceError::Other(format!("Failed to get a connection: {err}")),
+    }
+}
+
+fn finished_error() -> InterfaceError {
+    InterfaceError::Other("Transaction already finished".to_string())
+}
+
+/// Rewrite the named `:param` placeholders of a statement to positional `$n`
+/// placeholders, returning the parameter names in position order
+fn positional(sql: &str) -> (String, Vec<String>) {
+    let mut statement = String::with_capacity(sql.len());
+    let mut names: Vec<String> = Vec::new();
+    let mut chars = sql.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                statement.push(c);
+            }
+            // A type cast, e.g. `value::TEXT`
+            ':' if !quoted && chars.peek() == Some(&':') => {
+                chars.next();
+                statement.push_str("::");
+            }
+            ':' if !quoted
+                && chars
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || *c == '_') =>
+            {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    name.push(c);
+                }
+                let position = match names.iter().position(|known| *known == name) {
+                    Some(position) => position,
+                    None => {
+                        names.push(name);
+                        names.len() - 1
+                    }
+                };
+                statement.push_str(&format!("${}", position + 1));
+            }
+            _ => statement.push(c),
+        }
+    }
+    (statement, names)
+}
+
+/// Values of the named parameters, in position order
+fn bind(
+    names: &[String],
+    params: &[(String, FieldValue)],
+) -> Result<Vec<FieldValue>, InterfaceError> {
+    names
+        .iter()
+        .map(|name| {
+            params
+                .iter()
+                .find(|(param, _)| param == name)
+                .map(|(_, value)| value.clone())
+                .ok_or_else(|| InterfaceError::InvalidQuery(format!("Missing parameter: {name}")))
+        })
+        .collect()
+}
+
+impl ToSql for FieldValue {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        // Integers and doubles are widened by the filters, narrow them to the column type
+        match self {
+            FieldValue::Null => Ok(IsNull::Yes),
+            FieldValue::Bool(b) => b.to_sql_checked(ty, out),
+            FieldValue::Integer(i) => match *ty {
+                Type::INT2 => i16::try_from(*i)?.to_sql_checked(ty, out),
+                Type::INT4 => i32::try_from(*i)?.to_sql_checked(ty, out),
+                Type::FLOAT8 => (*i as f64).to_sql_checked(ty, out),
+                Type::NUMERIC => Decimal::from(*i).to_sql_checked(ty, out),
+                _ => i.to_sql_checked(ty, out),
+            },
+            FieldValue::Double(d) => match *ty {
+                Type::FLOAT4 => (*d as f32).to_sql_checked(ty, out),
+                Type::NUMERIC => Decimal::try_from(*d)?.to_sql_checked(ty, out),
+                _ => d.to_sql_checked(ty, out),
+            },
+            // Dates, decimals and JSON values are bound as their serialized strings
+            FieldValue::String(s) => match *ty {
+                Type::UUID => Uuid::parse_str(s)?.to_sql_checked(ty, out),
+                Type::DATE => s.parse::<NaiveDate>()?.to_sql_checked(ty, out),
+                Type::TIMESTAMP => timestamp(s)?.to_sql_checked(ty, out),
+                Type::TIMESTAMPTZ => timestamp(s)?.and_utc().to_sql_checked(ty, out),
+                Type::NUMERIC => s.parse::<Decimal>()?.to_sql_checked(ty, out),
+                Type::JSON | Type::JSONB => {
+                    serde_json::from_str::<JsonValue>(s)?.to_sql_checked(ty, out)
+                }
+                _ => s.to_sql_checked(ty, out),
+            },
+            FieldValue::Uuid(uuid) => uuid.to_sql_checked(ty, out),
+            FieldValue::Bytes(bytes) => bytes.to_sql_checked(ty, out),
+        }
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+/// UTC time of a serialized timestamp, to bind to a TIMESTAMP(TZ) column
+fn timestamp(s: &str) -> Result<NaiveDateTime, Box<dyn Error + Sync + Send>> {
+    timestamp_value(s).ok_or_else(|| format!("Invalid timestamp: {s}").into())
+}
+
+/// Read a column as a JSON value, to deserialize rows like the RDS JSON records.
+/// Dates and timestamps are read in their ISO 8601 form, decimals as strings
+/// and binary values as arrays of bytes, like their serialized fields.
+fn column_value(row: &Row, index: usize) -> Result<JsonValue, tokio_postgres::Error> {
+    Ok(match *row.columns()[index].type_() {
+        Type::BOOL => row.try_get::<_, Option<bool>>(index)?.into(),
+        Type::INT2 => row.try_get::<_, Option<i16>>(index)?.into(),
+        Type::INT4 => row.try_get::<_, Option<i32>>(index)?.into(),
+        Type::INT8 => row.try_get::<_, Option<i64>>(index)?.into(),
+        Type::FLOAT4 => row.try_get::<_, Option<f32>>(index)?.into(),
+        Type::FLOAT8 => row.try_get::<_, Option<f64>>(index)?.into(),
+        Type::UUID => row
+            .try_get::<_, Option<Uuid>>(index)?
+            .map(|uuid| uuid.to_string())
+            .into(),
+        Type::JSON | Type::JSONB => row
+            .try_get::<_, Option<JsonValue>>(index)?
+            .unwrap_or(JsonValue::Null),
+        Type::DATE => row
+            .try_get::<_, Option<NaiveDate>>(index)?
+            .map(|date| date.to_string())
+            .into(),
+        Type::TIMESTAMP => row
+            .try_get::<_, Option<NaiveDateTime>>(index)?
+            .map(|timestamp| timestamp.format("%Y-%m-%dT%H:%M:%S%.f").to_string())
+            .into(),
+        Type::TIMESTAMPTZ => row
+            .try_get::<_, Option<DateTime<Utc>>>(index)?
+            .map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            .into(),
+        Type::NUMERIC => row
+            .try_get::<_, Option<Decimal>>(index)?
+            .map(|decimal| decimal.to_string())
+            .into(),
+        Type::BYTEA => row.try_get::<_, Option<Vec<u8>>>(index)?.into(),
+        _ => row.try_get::<_, Option<String>>(index)?.into(),
+    })
+}
+
+fn parse_row<T: Val>(row: &Row) -> Result<T, InterfaceError> {
+    let mut object = Map::new();
+    for (index, column) in row.columns().iter().enumerate() {
+        let value = column_value(row, index)
+            .map_err(|err| InterfaceError::FromFields(format!("{}: {err}", column.name())))?;
+        object.insert(column.name().to_string(), value);
+    }
+
+    serde_json::from_value(JsonValue::Object(object))
+        .map_err(|e| InterfaceError::FromFields(format!("Failed to parse row: {e}")))
+}
+
+/// Where the statements are executed: any connection of the pool,
+/// or the connection of a transaction
+#[derive(Clone)]
+enum Connection {
+    Pool(Pool),
+    Transaction(Arc<Mutex<Option<Object>>>),
+}
+
+/// Connection borrowed to execute a statement
+enum Client {
+    Pooled(Box<Object>),
+    Transaction(OwnedMutexGuard<Option<Object>>),
+}
+
+impl Connection {
+    async fn client(&self) -> Result<Client, InterfaceError> {
+        match self {
+            Connection::Pool(pool) => Ok(Client::Pooled(Box::new(
+                pool.get().await.map_err(pool_error)?,
+            ))),
+            Connection::Transaction(connection) => Ok(Client::Transaction(
+                Arc::clone(connection).lock_owned().await,
+            )),
+        }
+    }
+}
+
+impl Client {
+    fn object(&self) -> Result<&Object, InterfaceError> {
+        match self {
+            Client::Pooled(object) => Ok(object),
+            Client::Transaction(guard) => guard.as_ref().ok_or_else(finished_error),
+        }
+    }
+}
+
+pub struct PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    connection: Connection,
+    queryset: Arc<Q>,
+    actor: String,
+    /// Off in the transactions recording the history
+    auditing: bool,
+
+    _marker_val: PhantomData<T>,
+}
+
+impl<T, Q> PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    pub fn new(pool: Pool, queryset: Box<Q>) -> Self {
+        PostgresRepository {
+            connection: Connection::Pool(pool),
+            queryset: Arc::from(queryset),
+            actor: DEFAULT_ACTOR.to_string(),
+            auditing: true,
+            _marker_val: PhantomData,
+        }
+    }
+
+    /// The same repository, executing its statements in a transaction
+    pub fn in_transaction(&self, transaction: &PostgresTransaction) -> Self {
+        self.with_connection(Connection::Transaction(Arc::clone(&transaction.connection)))
+    }
+
+    fn with_connection(&self, connection: Connection) -> Self {
+        PostgresRepository {
+            connection,
+            queryset: Arc::clone(&self.queryset),
+            actor: self.actor.clone(),
+            auditing: self.auditing,
+            _marker_val: PhantomData,
+        }
+    }
+
+    /// Record the changes of an audited table as made by `actor`, outside of `as_actor`
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = actor.into();
+        self
+    }
+
+    /// Named parameters matching the primary key columns
+    fn key_parameters(&self, key: &T::Key) -> Vec<(String, FieldValue)> {
+        self.queryset
+            .primary_key()
+            .into_iter()
+            .zip(key.values())
+            .collect()
+    }
+
+    /// Named parameters of a deletion: the primary key, and the deletion time if it is soft
+    fn delete_parameters(&self, key: &T::Key) -> Vec<(String, FieldValue)> {
+        let mut params = self.key_parameters(key);
+        if let Some(column) = self.queryset.deleted_column() {
+            params.push((column, FieldValue::Integer(now_millis())));
+        }
+        params
+    }
+
+    /// Create the table and its indexes, and its history table if it is audited
+    pub async fn create_table(&self) -> Result<(), InterfaceError> {
+        self.execute(&self.queryset.create_table(), &[]).await?;
+        for sql in self.queryset.create_indexes() {
+            self.execute(&sql, &[]).await?;
+        }
+        if self.queryset.history_table().is_some() {
+            self.execute(&self.queryset.create_history_table(), &[])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// !!! DROP THE TABLE, AND ITS HISTORY !!!
+    pub async fn drop_table(&self) -> Result<(), InterfaceError> {
+        self.execute(&self.queryset.drop_table(), &[]).await?;
+        if self.queryset.history_table().is_some() {
+            self.execute(&self.queryset.drop_history_table(), &[])
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn audits(&self) -> bool {
+        self.auditing && self.queryset.history_table().is_some()
+    }
+
+    /// Apply a mutation and record its changes in the history,
+    /// in a transaction unless the repository already is in one
+    async fn audit(&self, changes: Changes<'_, T>) -> Result<(), InterfaceError> {
+        let transaction = match &self.connection {
+            Connection::Pool(pool) => Some(PostgresTransaction::begin(pool).await?),
+            Connection::Transaction(_) => None,
+        };
+        let connection = match &transaction {
+            Some(transaction) => Connection::Transaction(Arc::clone(&transaction.connection)),
+            None => self.connection.clone(),
+        };
+        let repo = PostgresRepository {
+            auditing: false,
+            ..self.with_connection(connection)
+        };
+
+        let outcome = history::apply(&repo, changes).await;
+        match transaction {
+            Some(transaction) => transaction.finish(outcome).await,
+            None => outcome,
+        }
+    }
+
+    async fn query<R: Val>(
+        &self,
+        sql: &str,
+        params: &[(String, FieldValue)],
+    ) -> Result<Vec<R>, InterfaceError> {
+        let (sql, names) = positional(sql);
+        let values = bind(&names, params)?;
+
+        let client = self.connection.client().await?;
+        let client = client.object()?;
+        let statement = client.prepare_cached(&sql).await.map_err(postgres_error)?;
+        let rows = client
+            .query(&statement, &references(&values))
+            .await
+            .map_err(postgres_error)?;
+
+        rows.iter().map(parse_row).collect()
+    }
+
+    /// Execute a statement, returning the number of rows modified
+    async fn execute(
+        &self,
+        sql: &str,
+        params: &[(String, FieldValue)],
+    ) -> Result<u64, InterfaceError> {
+        let (sql, names) = positional(sql);
+        let values = bind(&names, params)?;
+
+        let client = self.connection.client().await?;
+        let client = client.object()?;
+        let statement = client.prepare_cached(&sql).await.map_err(postgres_error)?;
+        client
+            .execute(&statement, &references(&values))
+            .await
+            .map_err(postgres_error)
+    }
+
+    /// Execute a statement once per parameter set, all of them or none
+    async fn execute_many(
+        &self,
+        sql: &str,
+        parameter_sets: Vec<Vec<(String, FieldValue)>>,
+    ) -> Result<(), InterfaceError> {
+        if parameter_sets.is_empty() {
+            return Ok(());
+        }
+
+        match &self.connection {
+            Connection::Pool(pool) => {
+                let transaction = PostgresTransaction::begin(pool).await?;
+                let outcome = self
+                    .in_transaction(&transaction)
+                    .execute_each(sql, parameter_sets)
+                    .await;
+                transaction.finish(outcome).await
+            }
+            Connection::Transaction(_) => self.execute_each(sql, parameter_sets).await,
+        }
+    }
+
+    async fn execute_each(
+        &self,
+        sql: &str,
+        parameter_sets: Vec<Vec<(String, FieldValue)>>,
+    ) -> Result<(), InterfaceError> {
+        let (sql, names) = positional(sql);
+
+        let client = self.connection.client().await?;
+        let client = client.object()?;
+        let statement = client.prepare_cached(&sql).await.map_err(postgres_error)?;
+        for params in parameter_sets {
+            let values = bind(&names, &params)?;
+            client
+                .execute(&statement, &references(&values))
+                .await
+                .map_err(postgres_error)?;
+        }
+        Ok(())
+    }
+}
+
+fn references(values: &[FieldValue]) -> Vec<&(dyn ToSql + Sync)> {
+    values
+        .iter()
+        .map(|value| value as &(dyn ToSql + Sync))
+        .collect()
+}
+
+#[async_trait]
+impl<T, Q> Create<T> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Create(slice::from_ref(item))).await;
+        }
+        self.execute(&self.queryset.create(), &item_parameters(item)?)
+            .await?;
+        Ok(())
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Create(items)).await;
+        }
+        let parameter_sets = items
+            .iter()
+            .map(item_parameters)
+            .collect::<Result<_, _>>()?;
+        self.execute_many(&self.queryset.create(), parameter_sets)
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, Q> Get<T> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        let mut items = self
+            .query(&self.queryset.get_by_key(), &self.key_parameters(key))
+            .await?;
+
+        if items.len() > 1 {
+            // There should only be one record
+            return Err(InterfaceError::Other(format!(
+                "Received multiple results for key: {:?}",
+                key
+            )));
+        }
+        Ok(items.pop())
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        let rows: Vec<JsonValue> = self
+            .query(&self.queryset.exists(), &self.key_parameters(key))
+            .await?;
+        found_value(&rows)
+    }
+}
+
+#[async_trait]
+impl<T, Q> Delete<T> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Delete(slice::from_ref(key))).await;
+        }
+        self.execute(&self.queryset.delete_by_key(), &self.delete_parameters(key))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Delete(keys)).await;
+        }
+        let parameter_sets = keys.iter().map(|key| self.delete_parameters(key)).collect();
+        self.execute_many(&self.queryset.delete_by_key(), parameter_sets)
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, Q> Update<T> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Update(slice::from_ref(item))).await;
+        }
+        let updated = self
+            .execute(&self.queryset.update(), &item_parameters(item)?)
+            .await?;
+
+        // A versioned update only matches the row if nobody updated it in between
+        if updated == 0 {
+            let version = self.queryset.version();
+            let exists = version.is_some() && self.get(&item.key()).await?.is_some();
+            return Err(not_updated(item, version, exists));
+        }
+        Ok(())
+    }
+
+    /// Updated one by one to check each of them, all of them or none
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Update(items)).await;
+        }
+        if let (Connection::Pool(pool), false) = (&self.connection, items.is_empty()) {
+            let transaction = PostgresTransaction::begin(pool).await?;
+            let outcome = self.in_transaction(&transaction).update_many(items).await;
+            return transaction.finish(outcome).await;
+        }
+        for item in items {
+            self.update(item).await?;
+        }
+        Ok(())
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Upsert(item)).await;
+        }
+        let upserted = self
+            .execute(&self.queryset.upsert(), &item_parameters(item)?)
+            .await?;
+
+        // The existing row of a versioned item is only updated if it has its version,
+        // and a soft deleted row is not updated
+        let version = self.queryset.version();
+        let guarded = version.is_some() || self.queryset.deleted_column().is_some();
+        if upserted == 0 && guarded {
+            return Err(not_upserted(item, version));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T, Q> List<T> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        self.query(&self.queryset.list(), &[]).await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        self.query(&self.queryset.list_where(&condition), &params)
+            .await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        request.validate(&self.queryset.columns())?;
+        let key = self.queryset.primary_key();
+        let (condition, params) = request
+            .condition(&key)?
+            .to_sql_with(|field| self.queryset.column(field));
+
+        // Fetch one more item to know whether there is a next page
+        let items = self
+            .query(
+                &self.queryset.list_page(
+                    &condition,
+                    &request.order_sql_with(&key, |field| self.queryset.column(field)),
+                    request.size + 1,
+                ),
+                &params,
+            )
+            .await?;
+        request.page(&key, items, field_value)
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        let all = Filter::And(vec![]);
+        let filter = filter.unwrap_or(&all);
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        let rows: Vec<JsonValue> = self
+            .query(&self.queryset.count(&condition), &params)
+            .await?;
+        count_value(&rows)
+    }
+
+    async fn select_where(
+        &self,
+        fields: &[&str],
+        filter: &Filter,
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        validate_selection(fields, &self.queryset.columns())?;
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        self.query(&self.queryset.select_where(fields, &condition), &params)
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, Q, U> Join<T, U> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+    U: Val,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        let (sql, params) = relation.join_sql(&*self.queryset, filter)?;
+        let rows: Vec<JsonValue> = self.query(&sql, &params).await?;
+        rows.iter().map(|row| relation.decode(row)).collect()
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        let (sql, params) = relation.join_sql(&*self.queryset, filter)?;
+        let rows: Vec<JsonValue> = self.query(&sql, &params).await?;
+        relation.decode(&rows)
+    }
+}
+
+#[async_trait]
+impl<T, Q> Repository<T> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+}
+
+#[async_trait]
+impl<T, Q> History<T> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        if self.queryset.history_table().is_none() {
+            return Err(not_audited(&self.queryset.table()));
+        }
+        let rows: Vec<HistoryRow> = self
+            .query(&self.queryset.list_history(), &self.key_parameters(key))
+            .await?;
+        rows.into_iter().map(HistoryRow::into_change).collect()
+    }
+}
+
+#[async_trait]
+impl<T, Q> AppendHistory<T> for PostgresRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn append(
+        &self,
+        mutation: Mutation,
+        changes: Vec<(T::Key, Option<T>, Option<T>)>,
+    ) -> Result<(), InterfaceError> {
+        let actor = current_actor(&self.actor);
+        let params = history_parameters(self.queryset.primary_key(), mutation, &actor, &changes)?;
+        self.execute(&self.queryset.insert_history(changes.len()), &params)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Transaction holding a connection of the pool, shared by the repositories
+/// built with `PostgresRepository::in_transaction`
+pub struct PostgresTransaction {
+    connection: Arc<Mutex<Option<Object>>>,
+}
+
+impl PostgresTransaction {
+    /// Begin a transaction on a connection of the pool
+    pub async fn begin(pool: &Pool) -> Result<Self, InterfaceError> {
+        let client = pool.get().await.map_err(pool_error)?;
+        client
+            .batch_execute("BEGIN")
+            .await
+            .map_err(postgres_error)?;
+
+        Ok(PostgresTransaction {
+            connection: Arc::new(Mutex::new(Some(client))),
+        })
+    }
+
+    /// Run the closing statement and give the connection back to the pool
+    async fn end(&self, statement: &str) -> Result<(), InterfaceError> {
+        let client = self
+            .connection
+            .lock()
+            .await
+            .take()
+            .ok_or_else(finished_error)?;
+
+        match client.batch_execute(statement).await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                // The connection is in an unknown state, close it
+                drop(Object::take(client));
+                Err(postgres_error(err))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transaction for PostgresTransaction {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.end("COMMIT").await
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.end("ROLLBACK").await
+    }
+}
+
+impl Drop for PostgresTransaction {
+    /// A transaction that was neither committed nor rolled back must not
+    /// go back to the pool: its connection is closed instead
+    fn drop(&mut self) {
+        if let Ok(mut connection) = self.connection.try_lock() {
+            if let Some(client) = connection.take() {
+                drop(Object::take(client));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MigrationDatabase for Pool {
+    async fn apply(&self, statements: &[String]) -> Result<(), InterfaceError> {
+        let mut client = self.get().await.map_err(pool_error)?;
+        let transaction = client.transaction().await.map_err(postgres_error)?;
+        for sql in statements {
+            transaction
+                .batch_execute(sql)
+                .await
+                .map_err(postgres_error)?;
+        }
+        transaction.commit().await.map_err(postgres_error)
+    }
+
+    async fn query(&self, sql: &str) -> Result<Vec<JsonValue>, InterfaceError> {
+        let client = self.get().await.map_err(pool_error)?;
+        let rows = client.query(sql, &[]).await.map_err(postgres_error)?;
+        rows.iter().map(parse_row).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{
+        ConformanceItem, ConformanceItemQuerySet, RepositoryFactory, VersionedConformanceItem,
+        VersionedConformanceItemQuerySet,
+    };
+    use crate::query::{Column, Direction};
+    use crate::settings::get_settings;
+    use crate::usecase::rds::{FromRow, RdsRow};
+    use pretty_assertions::assert_eq;
+    use secrecy::Secret;
+    use serde::{Deserialize, Serialize};
+    use sql_macros::struct_to_sql;
+    use uuid::Uuid;
+
+    // Define structures
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    struct Item1 {
+        uuid: Uuid,
+        field1: i32,
+    }
+
+    // Gen item
+    fn gen_item() -> Item1 {
+        Item1 {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    struct VersionedItem {
+        uuid: Uuid,
+        field1: i32,
+        #[sql(version)]
+        version: i32,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql(soft_delete, audit)]
+    struct AuditedItem {
+        uuid: Uuid,
+        field1: i32,
+        #[sql(version)]
+        version: i32,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    struct TypedItem {
+        uuid: Uuid,
+        active: bool,
+        amount: i64,
+        rate: f64,
+        balance: Decimal,
+        opened_on: NaiveDate,
+        opened_at: DateTime<Utc>,
+        closed_at: Option<NaiveDateTime>,
+        logo: Vec<u8>,
+        #[sql(json)]
+        settings: JsonValue,
+    }
+
+    /// Pool of the throwaway database of `POSTGRES_URL`, e.g. the CI service,
+    /// or else of the local settings
+    async fn get_pool() -> Pool {
+        let settings = match std::env::var("POSTGRES_URL") {
+            Ok(url) => PostgresSettings {
+                url: Secret::new(url),
+                pool_size: 4,
+            },
+            Err(_) => {
+                let settings = get_settings().await.expect("Failed to load configuration");
+                settings.postgres.expect("Missing postgres settings")
+            }
+        };
+        create_pool(&settings).expect("Failed to create the pool")
+    }
+
+    async fn get_item1_repository() -> PostgresRepository<Item1, Item1QuerySet<Item1>> {
+        let queryset: Box<Item1QuerySet<Item1>> = Box::new(Item1::queryset());
+        PostgresRepository::new(get_pool().await, queryset)
+    }
+
+    #[test]
+    fn test_positional() {
+        // GIVEN a statement with named parameters, a cast and a literal
+        let sql = "UPDATE t SET a = :a, b = :b::TEXT WHERE c = ':c' AND uuid = :uuid AND a > :a";
+
+        // WHEN we rewrite the placeholders
+        let (statement, names) = positional(sql);
+
+        // THEN each name gets a position and the cast and literal are untouched
+        assert_eq!(
+            statement,
+            "UPDATE t SET a = $1, b = $2::TEXT WHERE c = ':c' AND uuid = $3 AND a > $1"
+        );
+        assert_eq!(names, vec!["a", "b", "uuid"]);
+    }
+
+    #[test]
+    fn test_bind() -> Result<(), InterfaceError> {
+        // GIVEN parameters in any order
+        let params = vec![
+            ("b".to_string(), FieldValue::from(2)),
+            ("a".to_string(), FieldValue::from("x")),
+        ];
+
+        // WHEN we bind them to the positions
+        let values = bind(&["a".to_string(), "b".to_string()], &params)?;
+        let missing = bind(&["c".to_string()], &params);
+
+        // THEN they are in position order and a missing one is rejected
+        assert_eq!(values, vec![FieldValue::from("x"), FieldValue::from(2)]);
+        assert!(matches!(missing, Err(InterfaceError::InvalidQuery(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let mut out = BytesMut::new();
+
+        // Integers are narrowed to the column type
+        FieldValue::Integer(3)
+            .to_sql_checked(&Type::INT4, &mut out)
+            .unwrap();
+        assert_eq!(out.len(), 4);
+        assert!(FieldValue::Integer(i64::MAX)
+            .to_sql_checked(&Type::INT4, &mut out)
+            .is_err());
+
+        // Integers and doubles are converted for NUMERIC columns
+        for value in [FieldValue::Integer(12), FieldValue::Double(12.5)] {
+            out.clear();
+            value.to_sql_checked(&Type::NUMERIC, &mut out).unwrap();
+            assert!(!out.is_empty());
+        }
+        assert!(FieldValue::Double(f64::NAN)
+            .to_sql_checked(&Type::NUMERIC, &mut out)
+            .is_err());
+
+        // Strings are accepted for UUID columns, but not other types
+        out.clear();
+        FieldValue::from(Uuid::new_v4().to_string())
+            .to_sql_checked(&Type::UUID, &mut out)
+            .unwrap();
+        assert_eq!(out.len(), 16);
+        assert!(FieldValue::Bool(true)
+            .to_sql_checked(&Type::INT4, &mut out)
+            .is_err());
+
+        // Serialized dates, decimals and JSON values are parsed for their column
+        for (value, ty) in [
+            ("2024-01-02", Type::DATE),
+            ("2024-01-02T03:04:05.250", Type::TIMESTAMP),
+            ("2024-01-02T03:04:05Z", Type::TIMESTAMPTZ),
+            ("12.50", Type::NUMERIC),
+            (r#"{"theme":"dark"}"#, Type::JSONB),
+        ] {
+            out.clear();
+            FieldValue::from(value)
+                .to_sql_checked(&ty, &mut out)
+                .unwrap();
+            assert!(!out.is_empty());
+        }
+        assert!(FieldValue::from("2024-01-02")
+            .to_sql_checked(&Type::TIMESTAMP, &mut out)
+            .is_err());
+    }
+
+    /// Accessing the database configured in `postgres.url`
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_create_and_delete_entry() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an empty table
+        let repo = get_item1_repository().await;
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let item = gen_item();
+
+        // WHEN we create an entry and delete it
+        repo.create(&item).await?;
+        let created = repo.get(&item.uuid).await?;
+        repo.delete(&item.uuid).await?;
+
+        // THEN it was only there until it was deleted
+        let all = repo.list().await?;
+        repo.drop_table().await?;
+        assert_eq!(created, Some(item));
+        assert_eq!(all.len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_list_page() -> Result<(), InterfaceError> {
+        // GIVEN a repository with items
+        let repo = get_item1_repository().await;
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let items: Vec<Item1> = (0..5)
+            .map(|field1| Item1 {
+                uuid: Uuid::new_v4(),
+                field1,
+            })
+            .collect();
+        repo.create_many(&items).await?;
+
+        // WHEN we read them two by two in descending order
+        let request = PageRequest::new("field1", 2).direction(Direction::Desc);
+        let first = repo.list_page(&request).await?;
+        let second = repo.list_page(&request.clone().after(first.next)).await?;
+
+        // THEN the pages follow each other
+        repo.drop_table().await?;
+        let fields: Vec<i32> = first
+            .items
+            .iter()
+            .chain(second.items.iter())
+            .map(|item| item.field1)
+            .collect();
+        assert_eq!(fields, vec![4, 3, 2, 1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_update_version() -> Result<(), InterfaceError> {
+        // GIVEN a versioned repository with an item
+        let queryset: Box<VersionedItemQuerySet<VersionedItem>> =
+            Box::new(VersionedItem::queryset());
+        let repo = PostgresRepository::new(get_pool().await, queryset);
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let item = VersionedItem {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+            version: 0,
+        };
+        repo.create(&item).await?;
+
+        // WHEN two clients update the item they read
+        let first = repo.get(&item.uuid).await?.unwrap();
+        let second = repo.get(&item.uuid).await?.unwrap();
+        repo.update(&first).await?;
+        let result = repo.update(&second).await;
+
+        // THEN the second update is rejected and the version was incremented once
+        let stored = repo.get(&item.uuid).await?.unwrap();
+        repo.drop_table().await?;
+        assert!(matches!(result, Err(InterfaceError::Conflict(_))));
+        assert_eq!(stored.version, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_types() -> Result<(), InterfaceError> {
+        // GIVEN a repository of items with all the supported types
+        let repo = PostgresRepository::new(get_pool().await, Box::new(TypedItem::queryset()));
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let item = TypedItem {
+            uuid: Uuid::new_v4(),
+            active: true,
+            amount: 1 << 40,
+            rate: 0.25,
+            balance: "12.50".parse().unwrap(),
+            opened_on: "2024-01-02".parse().unwrap(),
+            opened_at: "2024-01-02T03:04:05.250Z".parse().unwrap(),
+            closed_at: "2024-02-03T04:05:06".parse().ok(),
+            logo: vec![0, 255],
+            settings: serde_json::json!({"theme": "dark"}),
+        };
+
+        // WHEN we create it and read it back
+        repo.create(&item).await?;
+        let found = repo.get(&item.uuid).await?;
+        // AND we filter the NUMERIC column with an integer and a double
+        let above = repo.count(Some(&Filter::gt("balance", 12))).await?;
+        let below = repo.count(Some(&Filter::lt("balance", 12.25))).await?;
+
+        // THEN it is unchanged
+        repo.drop_table().await?;
+        assert_eq!(found, Some(item));
+        // AND the numbers are compared as decimals
+        assert_eq!((above, below), (1, 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_soft_delete_and_history() -> Result<(), InterfaceError> {
+        // GIVEN an audited repository keeping its deleted items
+        let queryset = Box::new(AuditedItem::queryset());
+        let repo = PostgresRepository::new(get_pool().await, queryset).with_actor("teller");
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let item = AuditedItem {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+            version: 0,
+        };
+
+        // WHEN an item is created, updated, then deleted
+        repo.create(&item).await?;
+        repo.update(&item).await?;
+        repo.delete(&item.uuid).await?;
+
+        // THEN it is hidden, its key cannot be reused, and its changes are recorded
+        let found = repo.get(&item.uuid).await?;
+        let created = repo.create(&item).await;
+        let history = repo.history(&item.uuid).await?;
+        repo.drop_table().await?;
+        assert_eq!(found, None);
+        assert!(matches!(created, Err(InterfaceError::Conflict(_))));
+        let operations: Vec<Mutation> = history.iter().map(|change| change.operation).collect();
+        assert_eq!(
+            operations,
+            vec![Mutation::Create, Mutation::Update, Mutation::Delete]
+        );
+        assert_eq!(history[2].before.as_ref().map(|item| item.version), Some(1));
+        assert!(history.iter().all(|change| change.actor == "teller"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_transaction() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an empty table
+        let pool = get_pool().await;
+        let repo = get_item1_repository().await;
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let (item1, item2, item3) = (gen_item(), gen_item(), gen_item());
+
+        // WHEN we create an item in a rolled back transaction, another one
+        // in a committed transaction and a last one in a dropped transaction
+        let transaction = PostgresTransaction::begin(&pool).await?;
+        repo.in_transaction(&transaction).create(&item1).await?;
+        transaction.rollback().await?;
+
+        let transaction = PostgresTransaction::begin(&pool).await?;
+        let outcome = repo.in_transaction(&transaction).create(&item2).await;
+        transaction.finish(outcome).await?;
+
+        let transaction = PostgresTransaction::begin(&pool).await?;
+        repo.in_transaction(&transaction).create(&item3).await?;
+        drop(transaction);
+
+        // THEN only the committed item is in the table
+        let all = repo.list().await?;
+        repo.drop_table().await?;
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].uuid, item2.uuid);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_batch() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an empty table
+        let repo = get_item1_repository().await;
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let mut items: Vec<Item1> = (0..10).map(|_| gen_item()).collect();
+
+        // WHEN we create, update and delete the items in batches
+        repo.create_many(&items).await?;
+        items.iter_mut().for_each(|item| item.field1 = 7);
+        repo.update_many(&items).await?;
+        let keys: Vec<Uuid> = items.iter().take(4).map(|item| item.uuid).collect();
+        repo.delete_many(&keys).await?;
+
+        // THEN the remaining items were all updated
+        let all = repo.list_where(&Filter::eq("field1", 7)).await?;
+        repo.drop_table().await?;
+        assert_eq!(all.len(), 6);
+        Ok(())
+    }
+
+    /// Tables of the conformance suite, recreated for each check
+    struct PostgresFactory;
+
+    #[async_trait]
+    impl RepositoryFactory for PostgresFactory {
+        type Repo = PostgresRepository<ConformanceItem, ConformanceItemQuerySet<ConformanceItem>>;
+        type VersionedRepo = PostgresRepository<
+            VersionedConformanceItem,
+            VersionedConformanceItemQuerySet<VersionedConformanceItem>,
+        >;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            let queryset = Box::new(ConformanceItem::queryset());
+            let repo = PostgresRepository::new(get_pool().await, queryset);
+            repo.drop_table().await?;
+            repo.create_table().await?;
+            Ok(repo)
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            let queryset = Box::new(VersionedConformanceItem::queryset());
+            let repo = PostgresRepository::new(get_pool().await, queryset);
+            repo.drop_table().await?;
+            repo.create_table().await?;
+            Ok(repo)
+        }
+    }
+
+    crate::repository_conformance_tests!(PostgresFactory, #[serial_test::serial], #[ignore]);
+}
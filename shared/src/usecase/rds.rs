@@ -1,153 +1,670 @@
 //! Implementation of a Repository for a Rds Client
 use std::marker::PhantomData;
+use std::slice;
 use std::sync::Arc;
 
+use crate::migration::MigrationDatabase;
+use crate::query::{validate_selection, FieldValue, Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::usecase::history::{
+    self, current_actor, history_parameters, not_audited, now_millis, AppendHistory, Changes,
+    HistoryRow, DEFAULT_ACTOR,
+};
 use crate::{error::InterfaceError, QuerySet, Val};
 use crate::{
-    ports::secondary::{Create, Delete, Get, List, Repository, Update},
+    ports::secondary::{
+        Change, Create, Delete, Get, HasKey, History, Join, Key, List, Mutation, Repository,
+        Transaction, Update,
+    },
     rds_client::RdsClient,
 };
 use async_trait::async_trait;
-use aws_sdk_rdsdata::types::SqlParameter;
+use aws_sdk_rdsdata::primitives::Blob;
+use aws_sdk_rdsdata::types::{ColumnMetadata, Field, SqlParameter, TypeHint};
 use aws_sdk_rdsdata::{
-    error::SdkError,
+    error::{ProvideErrorMetadata, SdkError},
     operation::execute_statement::{ExecuteStatementError, ExecuteStatementOutput},
     types::RecordsFormatType,
 };
+use chrono::{DateTime, NaiveDateTime, SecondsFormat};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number, Value as JsonValue};
 use uuid::Uuid;
 
+/// Maximum number of parameter sets sent in one BatchExecuteStatement call.
+/// The Data API also caps a request at 4 MiB, so wide rows may need smaller batches.
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+/// Error code of the requests throttled by the RDS Data API
+const THROTTLING_EXCEPTION: &str = "ThrottlingException";
+
+/// Error of the RDS Data API, typed from the SQLSTATE of the failed statement
+/// reported in the message, or from the kind of failure
+fn rds_error<E, R>(err: SdkError<E, R>) -> InterfaceError
+where
+    SdkError<E, R>: Into<aws_sdk_rdsdata::Error>,
+{
+    let unreachable = matches!(
+        err,
+        SdkError::DispatchFailure(_) | SdkError::TimeoutError(_)
+    );
+    let err: aws_sdk_rdsdata::Error = err.into();
+    let message = err.to_string();
+    if unreachable {
+        return InterfaceError::Transient(format!("RDS is unreachable: {message}"));
+    }
+    match err {
+        // The credentials of the service, not of its client: an internal error
+        err @ (aws_sdk_rdsdata::Error::AccessDeniedException(_)
+        | aws_sdk_rdsdata::Error::ForbiddenException(_)) => InterfaceError::RdsError(Box::new(err)),
+        aws_sdk_rdsdata::Error::DatabaseResumingException(_)
+        | aws_sdk_rdsdata::Error::DatabaseUnavailableException(_)
+        | aws_sdk_rdsdata::Error::ServiceUnavailableError(_)
+        | aws_sdk_rdsdata::Error::StatementTimeoutException(_) => {
+            InterfaceError::Transient(message)
+        }
+        err if matches!(err.code(), Some(THROTTLING_EXCEPTION)) => {
+            InterfaceError::Transient(message)
+        }
+        err => sqlstate(&message)
+            .and_then(|code| InterfaceError::from_sqlstate(code, message.as_str()))
+            .unwrap_or_else(|| InterfaceError::RdsError(Box::new(err))),
+    }
+}
+
+/// SQLSTATE code in the message of a failed statement: `...; SQLState: 23505`
+fn sqlstate(message: &str) -> Option<&str> {
+    let (_, code) = message.rsplit_once("SQLState: ")?;
+    code.get(..5)
+}
+
 /// Build a `Vec<SqlParameter>` to use in ExecuteStatementBuilder::set_parameters.
 /// from an items fields
 pub trait GetFieldsAsParams {
     fn get_fields_as_params(&self) -> Option<Vec<SqlParameter>>;
 }
 
+/// Decode an item from a record of the Data API, field by field
+pub trait FromRow: Sized {
+    fn from_row(row: &RdsRow) -> Result<Self, InterfaceError>;
+}
+
+/// A record of the Data API, with the metadata of its columns
+pub struct RdsRow<'a> {
+    columns: &'a [ColumnMetadata],
+    fields: &'a [Field],
+}
+
+impl<'a> RdsRow<'a> {
+    pub fn new(columns: &'a [ColumnMetadata], fields: &'a [Field]) -> Self {
+        RdsRow { columns, fields }
+    }
+
+    /// Index of a column, by label, ignoring the case if no label matches exactly
+    fn position(&self, column: &str) -> Option<usize> {
+        let label = |metadata: &ColumnMetadata| {
+            metadata
+                .label()
+                .or(metadata.name())
+                .unwrap_or_default()
+                .to_string()
+        };
+        self.columns
+            .iter()
+            .position(|metadata| label(metadata) == column)
+            .or_else(|| {
+                self.columns
+                    .iter()
+                    .position(|metadata| label(metadata).eq_ignore_ascii_case(column))
+            })
+    }
+
+    /// Decode the value of a column to the type of a field
+    pub fn get<V: DeserializeOwned>(&self, column: &str) -> Result<V, InterfaceError> {
+        let error = |message: String| InterfaceError::FromFields(format!("{column}: {message}"));
+        let index = self
+            .position(column)
+            .ok_or_else(|| InterfaceError::FromFields(format!("Missing column: {column}")))?;
+        let field = self
+            .fields
+            .get(index)
+            .ok_or_else(|| error("missing value in the record".to_string()))?;
+        let type_name = self.columns[index].type_name().unwrap_or_default();
+        let value = field_json(field, type_name).map_err(error)?;
+        serde_json::from_value(value).map_err(|e| error(e.to_string()))
+    }
+
+    /// The record as a JSON object, keyed by column label
+    pub fn to_json(&self) -> Result<JsonValue, InterfaceError> {
+        self.columns
+            .iter()
+            .zip(self.fields)
+            .map(|(metadata, field)| {
+                let column = metadata.label().or(metadata.name()).unwrap_or_default();
+                let value = field_json(field, metadata.type_name().unwrap_or_default())
+                    .map_err(|e| InterfaceError::FromFields(format!("{column}: {e}")))?;
+                Ok((column.to_string(), value))
+            })
+            .collect::<Result<Map<String, JsonValue>, InterfaceError>>()
+            .map(JsonValue::Object)
+    }
+}
+
+/// JSON value of a field, given the type of its column: JSON columns are parsed,
+/// and timestamps are written in the format of their serialized `chrono` types.
+/// Integers, decimals and binaries are kept exact.
+fn field_json(field: &Field, type_name: &str) -> Result<JsonValue, String> {
+    Ok(match field {
+        Field::IsNull(_) => JsonValue::Null,
+        Field::BooleanValue(b) => JsonValue::Bool(*b),
+        Field::LongValue(i) => JsonValue::from(*i),
+        Field::DoubleValue(d) => Number::from_f64(*d)
+            .map(JsonValue::Number)
+            .ok_or_else(|| format!("unsupported double {d}"))?,
+        Field::BlobValue(blob) => blob.as_ref().iter().copied().collect(),
+        Field::StringValue(s) => match type_name.to_ascii_lowercase().as_str() {
+            "json" | "jsonb" => serde_json::from_str(s).map_err(|e| e.to_string())?,
+            "timestamp" => match timestamp_value(s) {
+                Some(timestamp) => timestamp.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+                None => s.clone(),
+            }
+            .into(),
+            "timestamptz" => match timestamp_value(s) {
+                Some(timestamp) => timestamp
+                    .and_utc()
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                None => s.clone(),
+            }
+            .into(),
+            _ => JsonValue::String(s.clone()),
+        },
+        other => return Err(format!("unsupported value {other:?}")),
+    })
+}
+
+/// Build a named `SqlParameter` from a filter value
+pub fn sql_parameter(name: &str, value: &FieldValue) -> SqlParameter {
+    let (value, type_hint) = match value {
+        FieldValue::Null => (Field::IsNull(true), None),
+        FieldValue::Bool(b) => (Field::BooleanValue(*b), None),
+        FieldValue::Integer(i) => (Field::LongValue(*i), None),
+        FieldValue::Double(d) => (Field::DoubleValue(*d), None),
+        FieldValue::String(s) => (Field::StringValue(s.clone()), None),
+        FieldValue::Uuid(u) => (Field::StringValue(u.to_string()), Some(TypeHint::Uuid)),
+        FieldValue::Bytes(b) => (Field::BlobValue(Blob::new(b.clone())), None),
+    };
+    SqlParameter::builder()
+        .name(name)
+        .value(value)
+        .set_type_hint(type_hint)
+        .build()
+}
+
+/// Build the named `SqlParameter`s of a rendered filter
+fn sql_parameters(params: &[(String, FieldValue)]) -> Option<Vec<SqlParameter>> {
+    Some(
+        params
+            .iter()
+            .map(|(name, value)| sql_parameter(name, value))
+            .collect(),
+    )
+}
+
+/// Read a field of an item as a filter value, from its `SqlParameter`
+pub(crate) fn field_value<T: GetFieldsAsParams>(
+    item: &T,
+    name: &str,
+) -> Result<FieldValue, InterfaceError> {
+    let param = item
+        .get_fields_as_params()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|param| param.name() == Some(name))
+        .ok_or_else(|| InterfaceError::FromFields(format!("Missing field: {name}")))?;
+
+    parameter_value(&param)
+}
+
+/// Convert a `SqlParameter` back to a filter value
+fn parameter_value(param: &SqlParameter) -> Result<FieldValue, InterfaceError> {
+    let name = param.name().unwrap_or_default();
+    Ok(match (param.value(), param.type_hint()) {
+        (Some(Field::StringValue(s)), Some(TypeHint::Uuid)) => FieldValue::Uuid(
+            Uuid::parse_str(s).map_err(|e| InterfaceError::FromFields(format!("{name}: {e}")))?,
+        ),
+        (Some(Field::StringValue(s)), _) => FieldValue::String(s.clone()),
+        (Some(Field::LongValue(i)), _) => FieldValue::Integer(*i),
+        (Some(Field::DoubleValue(d)), _) => FieldValue::Double(*d),
+        (Some(Field::BooleanValue(b)), _) => FieldValue::Bool(*b),
+        (Some(Field::BlobValue(b)), _) => FieldValue::Bytes(b.as_ref().to_vec()),
+        (Some(Field::IsNull(_)) | None, _) => FieldValue::Null,
+        (Some(other), _) => {
+            return Err(InterfaceError::FromFields(format!(
+                "Unsupported value for {name}: {other:?}"
+            )))
+        }
+    })
+}
+
+/// UTC time of a serialized timestamp: RFC 3339, the PostgreSQL text format
+/// with a time zone, or ISO 8601 without a time zone
+pub(crate) fn timestamp_value(timestamp: &str) -> Option<NaiveDateTime> {
+    match DateTime::parse_from_rfc3339(timestamp)
+        .or_else(|_| DateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f%#z"))
+    {
+        Ok(timestamp) => Some(timestamp.naive_utc()),
+        Err(_) => NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f")
+            .or_else(|_| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f"))
+            .ok(),
+    }
+}
+
+/// `SqlParameter`s of all the fields of an item, with the timestamps in the
+/// `YYYY-MM-DD HH:MM:SS[.FFF]` format of the Data API
+fn rds_parameters<T: GetFieldsAsParams>(item: &T) -> Option<Vec<SqlParameter>> {
+    let params = item.get_fields_as_params()?;
+    Some(
+        params
+            .into_iter()
+            .map(|param| match (param.value(), param.type_hint()) {
+                (Some(Field::StringValue(s)), Some(TypeHint::Timestamp)) => {
+                    match timestamp_value(s) {
+                        Some(timestamp) => SqlParameter::builder()
+                            .set_name(param.name().map(String::from))
+                            .value(Field::StringValue(
+                                timestamp.format("%Y-%m-%d %H:%M:%S%.f").to_string(),
+                            ))
+                            .type_hint(TypeHint::Timestamp)
+                            .build(),
+                        None => param,
+                    }
+                }
+                _ => param,
+            })
+            .collect(),
+    )
+}
+
+/// Named parameters of all the fields of an item
+pub(crate) fn item_parameters<T: GetFieldsAsParams>(
+    item: &T,
+) -> Result<Vec<(String, FieldValue)>, InterfaceError> {
+    item.get_fields_as_params()
+        .unwrap_or_default()
+        .iter()
+        .map(|param| {
+            Ok((
+                param.name().unwrap_or_default().to_string(),
+                parameter_value(param)?,
+            ))
+        })
+        .collect()
+}
+
+/// Error of an update that matched no row: the item is missing or,
+/// for a versioned item that still exists, it was modified since it was read
+pub(crate) fn not_updated<T: GetFieldsAsParams + HasKey>(
+    item: &T,
+    version: Option<String>,
+    exists: bool,
+) -> InterfaceError {
+    match version {
+        Some(version) if exists => match field_value(item, &version) {
+            Ok(value) => InterfaceError::Conflict(format!(
+                "Item {:?} was modified since {} {:?}",
+                item.key(),
+                version,
+                value
+            )),
+            Err(err) => err,
+        },
+        _ => InterfaceError::MissingItem(format!("{:?}", item.key())),
+    }
+}
+
+/// Error of an upsert that matched no row: a versioned item modified since it was read,
+/// or a soft deleted item
+pub(crate) fn not_upserted<T: GetFieldsAsParams + HasKey>(
+    item: &T,
+    version: Option<String>,
+) -> InterfaceError {
+    match version {
+        Some(_) => not_updated(item, version, true),
+        None => InterfaceError::Conflict(format!("Item {:?} was deleted", item.key())),
+    }
+}
+
+/// Column of the single row returned by an `exists` or `count` query
+pub(crate) fn scalar<'a>(
+    rows: &'a [JsonValue],
+    column: &str,
+) -> Result<&'a JsonValue, InterfaceError> {
+    rows.first()
+        .and_then(|row| row.get(column))
+        .ok_or_else(|| InterfaceError::FromFields(format!("Missing column: {column}")))
+}
+
+/// Result of an `exists` query, a boolean or, in SQLite, an integer
+pub(crate) fn found_value(rows: &[JsonValue]) -> Result<bool, InterfaceError> {
+    let found = scalar(rows, "found")?;
+    found
+        .as_bool()
+        .or_else(|| found.as_i64().map(|found| found != 0))
+        .ok_or_else(|| InterfaceError::FromFields(format!("Invalid found: {found}")))
+}
+
+/// Result of a `count` query
+pub(crate) fn count_value(rows: &[JsonValue]) -> Result<u64, InterfaceError> {
+    let count = scalar(rows, "count")?;
+    count
+        .as_u64()
+        .ok_or_else(|| InterfaceError::FromFields(format!("Invalid count: {count}")))
+}
+
+/// Parse the records of a statement formatted as JSON
+#[allow(clippy::result_large_err)]
+fn parse_rds_output<R>(
+    statement: Result<ExecuteStatementOutput, SdkError<ExecuteStatementError>>,
+) -> Result<Vec<R>, InterfaceError>
+where
+    R: serde::de::DeserializeOwned,
+{
+    // Did the request succeed?
+    let data = match statement {
+        Ok(data) => Ok(data),
+        Err(err) => Err(rds_error(err)),
+    }?;
+
+    // Are there records?
+    let records = match data.formatted_records() {
+        Some(records) => Ok(records),
+        None => Err(InterfaceError::Other(
+            "Amazon RDS Data did not include records in their response.".to_string(),
+        )),
+    }?;
+
+    // Can we parse the records?
+    match serde_json::from_str::<Vec<R>>(records.to_string().as_str()) {
+        Ok(items) => Ok(items),
+        Err(e) => Err(InterfaceError::FromFields(format!(
+            "Failed to parse formatted records: {e}"
+        ))),
+    }
+}
+
 pub struct RdsRepository<T, Q>
 where
-    T: Val + GetFieldsAsParams,
-    Q: QuerySet<T> + std::marker::Sync,
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
 {
     client: Arc<RdsClient>,
-    queryset: Box<Q>,
+    queryset: Arc<Q>,
+    batch_size: usize,
+    actor: String,
+    /// Off in the transactions recording the history
+    auditing: bool,
 
     _marker_val: PhantomData<T>,
 }
 
 impl<T, Q> RdsRepository<T, Q>
 where
-    T: Val + GetFieldsAsParams,
-    Q: QuerySet<T> + std::marker::Sync,
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
 {
     /// Create a table with name {table} in the remote database
     pub fn new(client: Arc<RdsClient>, queryset: Box<Q>) -> Self {
         RdsRepository {
             client,
-            queryset,
+            queryset: Arc::from(queryset),
+            batch_size: MAX_BATCH_SIZE,
+            actor: DEFAULT_ACTOR.to_string(),
+            auditing: true,
+            _marker_val: PhantomData,
+        }
+    }
+
+    /// The same repository, executing its statements in a transaction
+    pub fn in_transaction(&self, transaction: &RdsTransaction) -> Self {
+        self.with_client(transaction.client())
+    }
+
+    fn with_client(&self, client: Arc<RdsClient>) -> Self {
+        RdsRepository {
+            client,
+            queryset: Arc::clone(&self.queryset),
+            batch_size: self.batch_size,
+            actor: self.actor.clone(),
+            auditing: self.auditing,
             _marker_val: PhantomData,
         }
     }
 
-    /// Create the remote table
+    /// Record the changes of an audited table as made by `actor`, outside of `as_actor`
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = actor.into();
+        self
+    }
+
+    /// Send at most `batch_size` parameter sets per batch statement
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.clamp(1, MAX_BATCH_SIZE);
+        self
+    }
+
+    /// Run `sql` once per parameter set, in chunks of `batch_size`,
+    /// in a transaction unless the repository already is in one
+    async fn batch_execute(
+        &self,
+        sql: String,
+        parameter_sets: Vec<Vec<SqlParameter>>,
+    ) -> Result<(), InterfaceError> {
+        if parameter_sets.is_empty() {
+            return Ok(());
+        }
+        if self.client.transaction_id().is_none() {
+            let transaction = RdsTransaction::begin(&self.client).await?;
+            let outcome = self
+                .in_transaction(&transaction)
+                .send_batches(&sql, &parameter_sets)
+                .await;
+            return transaction.finish(outcome).await;
+        }
+        self.send_batches(&sql, &parameter_sets).await
+    }
+
+    async fn send_batches(
+        &self,
+        sql: &str,
+        parameter_sets: &[Vec<SqlParameter>],
+    ) -> Result<(), InterfaceError> {
+        for chunk in parameter_sets.chunks(self.batch_size) {
+            self.client
+                .batch_execute_statement()
+                .sql(sql)
+                .set_parameter_sets(Some(chunk.to_vec()))
+                .send()
+                .await
+                .map_err(rds_error)?;
+        }
+        Ok(())
+    }
+
+    /// Named parameters matching the primary key columns
+    fn key_parameters(&self, key: &T::Key) -> Option<Vec<SqlParameter>> {
+        Some(
+            self.queryset
+                .primary_key()
+                .iter()
+                .zip(key.values())
+                .map(|(name, value)| sql_parameter(name, &value))
+                .collect(),
+        )
+    }
+
+    /// Named parameters of a deletion: the primary key, and the deletion time if it is soft
+    fn delete_parameters(&self, key: &T::Key) -> Option<Vec<SqlParameter>> {
+        let mut params = self.key_parameters(key)?;
+        if let Some(column) = self.queryset.deleted_column() {
+            params.push(sql_parameter(&column, &FieldValue::Integer(now_millis())));
+        }
+        Some(params)
+    }
+
+    /// Create the remote table and its indexes, and its history table if it is audited
     pub async fn create_table(&self) -> Result<(), InterfaceError> {
-        self.client
-            .execute_statement()
-            .sql(self.queryset.create_table())
-            .send()
-            .await
-            .map_err(|err| InterfaceError::RdsError(err.into()))?;
+        let mut statements = vec![self.queryset.create_table()];
+        statements.extend(self.queryset.create_indexes());
+        if self.queryset.history_table().is_some() {
+            statements.push(self.queryset.create_history_table());
+        }
+        for sql in statements {
+            self.client
+                .execute_statement()
+                .sql(sql)
+                .send()
+                .await
+                .map_err(rds_error)?;
+        }
         Ok(())
     }
 
-    /// !!! DROP THE REMOTE TABLE !!!
+    /// !!! DROP THE REMOTE TABLE, AND ITS HISTORY !!!
     pub async fn drop_table(&self) -> Result<(), InterfaceError> {
-        self.client
+        let mut statements = vec![self.queryset.drop_table()];
+        if self.queryset.history_table().is_some() {
+            statements.push(self.queryset.drop_history_table());
+        }
+        for sql in statements {
+            self.client
+                .execute_statement()
+                .sql(sql)
+                .send()
+                .await
+                .map_err(rds_error)?;
+        }
+        Ok(())
+    }
+
+    fn audits(&self) -> bool {
+        self.auditing && self.queryset.history_table().is_some()
+    }
+
+    /// Apply a mutation and record its changes in the history,
+    /// in a transaction unless the repository already is in one
+    async fn audit(&self, changes: Changes<'_, T>) -> Result<(), InterfaceError> {
+        let transaction = match self.client.transaction_id() {
+            Some(_) => None,
+            None => Some(RdsTransaction::begin(&self.client).await?),
+        };
+        let client = transaction
+            .as_ref()
+            .map_or_else(|| Arc::clone(&self.client), RdsTransaction::client);
+        let repo = RdsRepository {
+            auditing: false,
+            ..self.with_client(client)
+        };
+
+        let outcome = history::apply(&repo, changes).await;
+        match transaction {
+            Some(transaction) => transaction.finish(outcome).await,
+            None => outcome,
+        }
+    }
+
+    /// Run a query, reading its rows as JSON objects keyed by column label
+    async fn query_json(
+        &self,
+        sql: &str,
+        params: &[(String, FieldValue)],
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        let data = self
+            .client
             .execute_statement()
-            .sql(self.queryset.drop_table())
+            .sql(sql)
+            .set_parameters(sql_parameters(params))
+            .include_result_metadata(true)
             .send()
             .await
-            .map_err(|err| InterfaceError::RdsError(err.into()))?;
-        Ok(())
+            .map_err(rds_error)?;
+        let columns = data.column_metadata();
+        data.records()
+            .iter()
+            .map(|record| RdsRow::new(columns, record).to_json())
+            .collect()
     }
 
+    /// Decode the items of the records, read with the metadata of their columns
     #[allow(clippy::result_large_err)]
-    fn parse_rds_output(
+    fn parse_rds_rows(
         &self,
         statement: Result<ExecuteStatementOutput, SdkError<ExecuteStatementError>>,
-    ) -> Result<Vec<T>, InterfaceError>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        // Did the request succeed?
-        let data = match statement {
-            Ok(data) => Ok(data),
-            Err(err) => Err(InterfaceError::RdsError(err.into())),
-        }?;
-
-        // Are there records?
-        let records = match data.formatted_records() {
-            Some(records) => Ok(records),
-            None => Err(InterfaceError::Other(
-                "Amazon RDS Data did not include records in their response.".to_string(),
-            )),
-        }?;
-
-        // Can we parse the records?
-        match serde_json::from_str::<Vec<T>>(records.to_string().as_str()) {
-            Ok(items) => Ok(items),
-            Err(e) => Err(InterfaceError::FromFields(format!(
-                "Failed to parse formatted records: {e}"
-            ))),
-        }
+    ) -> Result<Vec<T>, InterfaceError> {
+        let data = statement.map_err(rds_error)?;
+        let columns = data.column_metadata();
+        data.records()
+            .iter()
+            .map(|record| T::from_row(&RdsRow::new(columns, record)))
+            .collect()
     }
 }
 
 #[async_trait]
 impl<T, Q> Create<T> for RdsRepository<T, Q>
 where
-    T: Val + GetFieldsAsParams,
-    Q: QuerySet<T> + std::marker::Sync,
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
 {
     async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Create(slice::from_ref(item))).await;
+        }
         self.client
             .execute_statement()
             .sql(self.queryset.create())
-            .set_parameters(item.get_fields_as_params())
+            .set_parameters(rds_parameters(item))
             .send()
             .await
-            .map_err(|err| InterfaceError::RdsError(err.into()))?;
+            .map_err(rds_error)?;
         Ok(())
     }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Create(items)).await;
+        }
+        let parameter_sets = items
+            .iter()
+            .map(|item| rds_parameters(item).unwrap_or_default())
+            .collect();
+        self.batch_execute(self.queryset.create(), parameter_sets)
+            .await
+    }
 }
 
 #[async_trait]
 impl<T, Q> Get<T> for RdsRepository<T, Q>
 where
-    T: Val + GetFieldsAsParams,
-    Q: QuerySet<T> + std::marker::Sync,
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
 {
-    async fn get(&self, id: &Uuid) -> Result<Option<T>, InterfaceError> {
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
         let statement = self
             .client
             .execute_statement()
-            .sql(self.queryset.get("uuid"))
-            .set_parameters(Some(vec![aws_sdk_rdsdata::types::SqlParameter::builder()
-                .name("uuid".to_string())
-                .value(aws_sdk_rdsdata::types::Field::StringValue(
-                    id.to_string().clone(),
-                ))
-                .type_hint(aws_sdk_rdsdata::types::TypeHint::Uuid)
-                .build()]))
-            .format_records_as(RecordsFormatType::Json)
+            .sql(self.queryset.get_by_key())
+            .set_parameters(self.key_parameters(key))
+            .include_result_metadata(true)
             .send()
             .await;
 
-        let items: Vec<T> = self.parse_rds_output(statement)?;
+        let items = self.parse_rds_rows(statement)?;
 
         if items.len() > 1 {
             // There should only be one record
             return Err(InterfaceError::Other(format!(
-                "Received multiple results for id: {:}",
-                id
+                "Received multiple results for key: {:?}",
+                key
             )));
         }
 
@@ -163,46 +680,118 @@ where
         }?;
         Ok(Some(item.to_owned()))
     }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        let statement = self
+            .client
+            .execute_statement()
+            .sql(self.queryset.exists())
+            .set_parameters(self.key_parameters(key))
+            .format_records_as(RecordsFormatType::Json)
+            .send()
+            .await;
+
+        found_value(&parse_rds_output(statement)?)
+    }
 }
 
 #[async_trait]
 impl<T, Q> Delete<T> for RdsRepository<T, Q>
 where
-    T: Val + GetFieldsAsParams,
-    Q: QuerySet<T> + std::marker::Sync,
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
 {
-    async fn delete(&self, id: &Uuid) -> Result<(), InterfaceError> {
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Delete(slice::from_ref(key))).await;
+        }
         self.client
             .execute_statement()
-            .sql(self.queryset.delete("uuid"))
-            .set_parameters(Some(vec![aws_sdk_rdsdata::types::SqlParameter::builder()
-                .name("uuid".to_string())
-                .value(aws_sdk_rdsdata::types::Field::StringValue(
-                    id.to_string().clone(),
-                ))
-                .type_hint(aws_sdk_rdsdata::types::TypeHint::Uuid)
-                .build()]))
+            .sql(self.queryset.delete_by_key())
+            .set_parameters(self.delete_parameters(key))
             .send()
             .await
-            .map(|_| Ok(()))
-            .map_err(|err| InterfaceError::RdsError(err.into()))?
+            .map_err(rds_error)?;
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Delete(keys)).await;
+        }
+        let parameter_sets = keys
+            .iter()
+            .map(|key| self.delete_parameters(key).unwrap_or_default())
+            .collect();
+        self.batch_execute(self.queryset.delete_by_key(), parameter_sets)
+            .await
     }
 }
 
 #[async_trait]
 impl<T, Q> Update<T> for RdsRepository<T, Q>
 where
-    T: Val + GetFieldsAsParams,
-    Q: QuerySet<T> + std::marker::Sync,
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
 {
     async fn update(&self, item: &T) -> Result<(), InterfaceError> {
-        self.client
+        if self.audits() {
+            return self.audit(Changes::Update(slice::from_ref(item))).await;
+        }
+        let output = self
+            .client
             .execute_statement()
             .sql(self.queryset.update())
-            .set_parameters(item.get_fields_as_params())
+            .set_parameters(rds_parameters(item))
+            .send()
+            .await
+            .map_err(rds_error)?;
+
+        // A versioned update only matches the row if nobody updated it in between
+        if output.number_of_records_updated() == 0 {
+            let version = self.queryset.version();
+            let exists = version.is_some() && self.get(&item.key()).await?.is_some();
+            return Err(not_updated(item, version, exists));
+        }
+        Ok(())
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Update(items)).await;
+        }
+        // Batch results carry no row counts, so the items are updated one by one
+        if self.client.transaction_id().is_none() && !items.is_empty() {
+            let transaction = RdsTransaction::begin(&self.client).await?;
+            let outcome = self.in_transaction(&transaction).update_many(items).await;
+            return transaction.finish(outcome).await;
+        }
+        for item in items {
+            self.update(item).await?;
+        }
+        Ok(())
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Upsert(item)).await;
+        }
+        let output = self
+            .client
+            .execute_statement()
+            .sql(self.queryset.upsert())
+            .set_parameters(rds_parameters(item))
             .send()
             .await
-            .map_err(|err| InterfaceError::RdsError(err.into()))?;
+            .map_err(rds_error)?;
+
+        // The existing row of a versioned item is only updated if it has its version,
+        // and a soft deleted row is not updated
+        let version = self.queryset.version();
+        let guarded = version.is_some() || self.queryset.deleted_column().is_some();
+        if output.number_of_records_updated() == 0 && guarded {
+            return Err(not_upserted(item, version));
+        }
         Ok(())
     }
 }
@@ -210,33 +799,272 @@ where
 #[async_trait]
 impl<T, Q> List<T> for RdsRepository<T, Q>
 where
-    T: Val + GetFieldsAsParams,
-    Q: QuerySet<T> + std::marker::Sync,
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
 {
     async fn list(&self) -> Result<Vec<T>, InterfaceError> {
         let statement = self
             .client
             .execute_statement()
             .sql(self.queryset.list())
+            .include_result_metadata(true)
+            .send()
+            .await;
+
+        self.parse_rds_rows(statement)
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        let statement = self
+            .client
+            .execute_statement()
+            .sql(self.queryset.list_where(&condition))
+            .set_parameters(sql_parameters(&params))
+            .include_result_metadata(true)
+            .send()
+            .await;
+
+        self.parse_rds_rows(statement)
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        request.validate(&self.queryset.columns())?;
+        let key = self.queryset.primary_key();
+        let (condition, params) = request
+            .condition(&key)?
+            .to_sql_with(|field| self.queryset.column(field));
+
+        // Fetch one more item to know whether there is a next page
+        let statement = self
+            .client
+            .execute_statement()
+            .sql(self.queryset.list_page(
+                &condition,
+                &request.order_sql_with(&key, |field| self.queryset.column(field)),
+                request.size + 1,
+            ))
+            .set_parameters(sql_parameters(&params))
+            .include_result_metadata(true)
+            .send()
+            .await;
+
+        let items = self.parse_rds_rows(statement)?;
+        request.page(&key, items, field_value)
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        let all = Filter::And(vec![]);
+        let filter = filter.unwrap_or(&all);
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        let statement = self
+            .client
+            .execute_statement()
+            .sql(self.queryset.count(&condition))
+            .set_parameters(sql_parameters(&params))
             .format_records_as(RecordsFormatType::Json)
             .send()
             .await;
 
-        self.parse_rds_output(statement)
+        count_value(&parse_rds_output(statement)?)
+    }
+
+    async fn select_where(
+        &self,
+        fields: &[&str],
+        filter: &Filter,
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        validate_selection(fields, &self.queryset.columns())?;
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        self.query_json(&self.queryset.select_where(fields, &condition), &params)
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, Q, U> Join<T, U> for RdsRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+    U: Val,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        let (sql, params) = relation.join_sql(&*self.queryset, filter)?;
+        let rows = self.query_json(&sql, &params).await?;
+        rows.iter().map(|row| relation.decode(row)).collect()
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        let (sql, params) = relation.join_sql(&*self.queryset, filter)?;
+        let rows = self.query_json(&sql, &params).await?;
+        relation.decode(&rows)
     }
 }
 
 #[async_trait]
 impl<T, Q> Repository<T> for RdsRepository<T, Q>
 where
-    T: Val + GetFieldsAsParams,
-    Q: QuerySet<T> + std::marker::Sync,
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
 {
 }
 
+#[async_trait]
+impl<T, Q> History<T> for RdsRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        if self.queryset.history_table().is_none() {
+            return Err(not_audited(&self.queryset.table()));
+        }
+        let statement = self
+            .client
+            .execute_statement()
+            .sql(self.queryset.list_history())
+            .set_parameters(self.key_parameters(key))
+            .format_records_as(RecordsFormatType::Json)
+            .send()
+            .await;
+
+        let rows: Vec<HistoryRow> = parse_rds_output(statement)?;
+        rows.into_iter().map(HistoryRow::into_change).collect()
+    }
+}
+
+#[async_trait]
+impl<T, Q> AppendHistory<T> for RdsRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + FromRow + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn append(
+        &self,
+        mutation: Mutation,
+        changes: Vec<(T::Key, Option<T>, Option<T>)>,
+    ) -> Result<(), InterfaceError> {
+        let actor = current_actor(&self.actor);
+        let params = history_parameters(self.queryset.primary_key(), mutation, &actor, &changes)?;
+        self.client
+            .execute_statement()
+            .sql(self.queryset.insert_history(changes.len()))
+            .set_parameters(sql_parameters(&params))
+            .send()
+            .await
+            .map_err(rds_error)?;
+        Ok(())
+    }
+}
+
+/// Transaction of the RDS Data API, shared by the repositories built
+/// with `RdsRepository::in_transaction`
+pub struct RdsTransaction {
+    client: Arc<RdsClient>,
+}
+
+impl RdsTransaction {
+    /// Begin a transaction
+    pub async fn begin(client: &RdsClient) -> Result<Self, InterfaceError> {
+        if client.transaction_id().is_some() {
+            return Err(InterfaceError::Other(
+                "Nested transactions are not supported".to_string(),
+            ));
+        }
+
+        let output = client.begin_transaction().send().await.map_err(rds_error)?;
+
+        let transaction_id = output.transaction_id().ok_or_else(|| {
+            InterfaceError::Other(
+                "Amazon RDS Data did not include a transaction id in their response.".to_string(),
+            )
+        })?;
+
+        Ok(RdsTransaction {
+            client: Arc::new(client.with_transaction(transaction_id)),
+        })
+    }
+
+    /// Client executing its statements in the transaction
+    pub fn client(&self) -> Arc<RdsClient> {
+        Arc::clone(&self.client)
+    }
+}
+
+#[async_trait]
+impl Transaction for RdsTransaction {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.client
+            .commit_transaction()
+            .send()
+            .await
+            .map_err(rds_error)?;
+        Ok(())
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.client
+            .rollback_transaction()
+            .send()
+            .await
+            .map_err(rds_error)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MigrationDatabase for RdsClient {
+    async fn apply(&self, statements: &[String]) -> Result<(), InterfaceError> {
+        let transaction = RdsTransaction::begin(self).await?;
+        let client = transaction.client();
+        let outcome = async {
+            for sql in statements {
+                client
+                    .execute_statement()
+                    .sql(sql)
+                    .send()
+                    .await
+                    .map_err(rds_error)?;
+            }
+            Ok(())
+        }
+        .await;
+        transaction.finish(outcome).await
+    }
+
+    async fn query(&self, sql: &str) -> Result<Vec<JsonValue>, InterfaceError> {
+        let statement = self
+            .execute_statement()
+            .sql(sql)
+            .format_records_as(RecordsFormatType::Json)
+            .send()
+            .await;
+        parse_rds_output(statement)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::conformance::{
+        ConformanceItem, ConformanceItemQuerySet, RepositoryFactory, VersionedConformanceItem,
+        VersionedConformanceItemQuerySet,
+    };
+    use crate::query::{Column, Direction};
     use crate::settings::get_settings;
     use pretty_assertions::assert_eq;
     use serde::{Deserialize, Serialize};
@@ -244,7 +1072,7 @@ mod tests {
     use uuid::Uuid;
 
     // Define structures
-    #[derive(Deserialize, Serialize, PartialEq)]
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
     #[struct_to_sql]
     struct Item1 {
         uuid: Uuid,
@@ -259,7 +1087,33 @@ mod tests {
         }
     }
 
-    async fn get_item1_repository() -> RdsRepository<Item1, Item1QuerySet<Item1>> {
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    struct VersionedItem {
+        uuid: Uuid,
+        field1: i32,
+        #[sql(version)]
+        version: i32,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql(soft_delete, audit)]
+    struct AuditedItem {
+        uuid: Uuid,
+        field1: i32,
+        #[sql(version)]
+        version: i32,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    struct TimestampedItem {
+        uuid: Uuid,
+        opened_at: NaiveDateTime,
+        closed_at: Option<DateTime<chrono::Utc>>,
+    }
+
+    async fn get_client() -> Arc<RdsClient> {
         // Get AWS Config
         let sdk_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
 
@@ -267,7 +1121,11 @@ mod tests {
         let settings = get_settings().await.expect("Failed to load configuration");
 
         // Get client
-        let client = Arc::new(RdsClient::new(&settings.rds, &sdk_config));
+        Arc::new(RdsClient::new(&settings.rds, &sdk_config))
+    }
+
+    async fn get_item1_repository() -> RdsRepository<Item1, Item1QuerySet<Item1>> {
+        let client = get_client().await;
 
         // Initialize the Item1 queryset
         let queryset: Box<Item1QuerySet<Item1>> = Box::new(Item1::queryset());
@@ -277,19 +1135,137 @@ mod tests {
         repo
     }
 
+    #[test]
+    fn test_sqlstate() {
+        let message = "DatabaseErrorException: ERROR: duplicate key value violates unique \
+                       constraint \"item1_pkey\"; SQLState: 23505";
+        assert_eq!(sqlstate(message), Some("23505"));
+        assert_eq!(sqlstate("SQLState: 23"), None);
+        assert_eq!(sqlstate("Communications link failure"), None);
+    }
+
+    #[test]
+    fn test_scalar_values() {
+        use serde_json::json;
+
+        assert!(found_value(&[json!({"found": true})]).unwrap());
+        assert!(!found_value(&[json!({"found": 0})]).unwrap());
+        assert_eq!(count_value(&[json!({"count": 3})]).unwrap(), 3);
+        assert!(count_value(&[]).is_err());
+        assert!(count_value(&[json!({"count": -1})]).is_err());
+    }
+
+    #[test]
+    fn test_rds_parameters() {
+        // GIVEN an item with timestamps
+        let item = TimestampedItem {
+            uuid: Uuid::new_v4(),
+            opened_at: "2024-01-02T03:04:05.250".parse().unwrap(),
+            closed_at: DateTime::parse_from_rfc3339("2024-01-02T05:04:05+02:00")
+                .ok()
+                .map(|closed_at| closed_at.to_utc()),
+        };
+
+        // WHEN we build its parameters
+        let values: Vec<Option<Field>> = rds_parameters(&item)
+            .unwrap()
+            .iter()
+            .map(|param| param.value().cloned())
+            .collect();
+
+        // THEN the timestamps are in UTC, in the format of the Data API
+        let timestamp = |s: &str| Some(Field::StringValue(s.to_string()));
+        assert_eq!(
+            values[1..],
+            [
+                timestamp("2024-01-02 03:04:05.250"),
+                timestamp("2024-01-02 03:04:05")
+            ]
+        );
+        assert_eq!(timestamp_value("2024-01-02"), None);
+    }
+
+    #[test]
+    fn test_from_row() {
+        let column = |name: &str, type_name: &str| {
+            ColumnMetadata::builder()
+                .name(name)
+                .label(name)
+                .type_name(type_name)
+                .build()
+        };
+        let string = |s: &str| Field::StringValue(s.to_string());
+
+        // GIVEN a record of the Data API, with timestamps in its text format
+        let uuid = Uuid::new_v4();
+        let columns = [
+            column("uuid", "uuid"),
+            column("opened_at", "timestamp"),
+            column("closed_at", "timestamptz"),
+        ];
+        let fields = [
+            string(&uuid.to_string()),
+            string("2024-01-02 03:04:05.25"),
+            string("2024-01-02 03:04:05"),
+        ];
+
+        // WHEN it is decoded
+        let item = TimestampedItem::from_row(&RdsRow::new(&columns, &fields)).unwrap();
+
+        // THEN each field has the value of its column
+        assert_eq!(item.uuid, uuid);
+        assert_eq!(
+            item.opened_at,
+            "2024-01-02T03:04:05.250".parse::<NaiveDateTime>().unwrap()
+        );
+        assert_eq!(
+            item.closed_at.map(|closed_at| closed_at.to_rfc3339()),
+            Some("2024-01-02T03:04:05+00:00".to_string())
+        );
+
+        // GIVEN a null timestamp
+        let fields = [fields[0].clone(), fields[1].clone(), Field::IsNull(true)];
+
+        // WHEN it is decoded
+        let item = TimestampedItem::from_row(&RdsRow::new(&columns, &fields)).unwrap();
+
+        // THEN the field is empty
+        assert_eq!(item.closed_at, None);
+
+        // GIVEN a column of the wrong type
+        let columns = [column("uuid", "uuid"), column("field1", "varchar")];
+        let fields = [string(&uuid.to_string()), string("three")];
+
+        // WHEN it is decoded
+        let error = Item1::from_row(&RdsRow::new(&columns, &fields)).unwrap_err();
+
+        // THEN the error names the column
+        assert_eq!(
+            error.to_string(),
+            r#"Invalid field: field1: invalid type: string "three", expected i32"#
+        );
+
+        // GIVEN a missing column
+        let error = Item1::from_row(&RdsRow::new(&columns[..1], &fields[..1])).unwrap_err();
+
+        // THEN the error names the column
+        assert_eq!(error.to_string(), "Invalid field: Missing column: field1");
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_queryset() -> Result<(), InterfaceError> {
         let repo: RdsRepository<Item1, Item1QuerySet<Item1>> = get_item1_repository().await;
 
-        assert_eq!(repo.queryset.table(), "Item1".to_string());
+        assert_eq!(repo.queryset.table(), "item1".to_string());
         assert_eq!(
             repo.queryset.drop_table(),
-            "DROP TABLE IF EXISTS Item1".to_string()
+            r#"DROP TABLE IF EXISTS "item1""#.to_string()
         );
         assert_eq!(
             repo.queryset.create_table(),
-            "CREATE TABLE IF NOT EXISTS Item1 (uuid UUID, field1 INTEGER)".to_string()
+            r#"CREATE TABLE IF NOT EXISTS "item1" ("uuid" UUID, "field1" INTEGER, PRIMARY KEY ("uuid"))"#
+                .to_string()
         );
         Ok(())
     }
@@ -304,8 +1280,8 @@ mod tests {
 
         // WHEN we create and delete a table
         // THEN we get no error
-        let _ = repo.create_table().await?;
-        let _ = repo.drop_table().await?;
+        repo.create_table().await?;
+        repo.drop_table().await?;
         Ok(())
     }
 
@@ -316,7 +1292,7 @@ mod tests {
     async fn test_all_empty() -> Result<(), InterfaceError> {
         // GIVEN a repository with an empty table
         let repo: RdsRepository<Item1, Item1QuerySet<Item1>> = get_item1_repository().await;
-        let _ = repo.create_table().await?;
+        repo.create_table().await?;
 
         // WHEN we list all items
         let all = repo.list().await?;
@@ -324,7 +1300,7 @@ mod tests {
         // THEN we get an empty list
         assert_eq!(all.len(), 0);
 
-        let _ = repo.drop_table().await?;
+        repo.drop_table().await?;
         Ok(())
     }
 
@@ -334,16 +1310,16 @@ mod tests {
     async fn test_create_entry() -> Result<(), InterfaceError> {
         // GIVEN a repository with an empty table and an item
         let repo: RdsRepository<Item1, Item1QuerySet<Item1>> = get_item1_repository().await;
-        let _ = repo.drop_table().await?;
-        let _ = repo.create_table().await?;
+        repo.drop_table().await?;
+        repo.create_table().await?;
         let item = gen_item();
 
         // WHEN we create an entry
-        let _ = repo.create(&item).await?;
+        repo.create(&item).await?;
 
         // THEN we get no errors and there is one item in the table
         let all = repo.list().await?;
-        let _ = repo.drop_table().await?;
+        repo.drop_table().await?;
         assert_eq!(all.len(), 1);
         Ok(())
     }
@@ -354,43 +1330,278 @@ mod tests {
     async fn test_delete_entry() -> Result<(), InterfaceError> {
         // GIVEN a repository with an empty table and an item
         let repo: RdsRepository<Item1, Item1QuerySet<Item1>> = get_item1_repository().await;
-        let _ = repo.drop_table().await?;
-        let _ = repo.create_table().await?;
+        repo.drop_table().await?;
+        repo.create_table().await?;
         let item = gen_item();
 
         // WHEN we create and delete an entry
-        let _ = repo.create(&item).await?;
-        let _ = repo.delete(&item.uuid).await?;
+        repo.create(&item).await?;
+        repo.delete(&item.uuid).await?;
 
         // THEN we get no errors and there is no item in the table
         let all = repo.list().await?;
-        let _ = repo.drop_table().await?;
+        repo.drop_table().await?;
         assert_eq!(all.len(), 0);
         Ok(())
     }
 
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_list_where() -> Result<(), InterfaceError> {
+        // GIVEN a repository with five items
+        let repo: RdsRepository<Item1, Item1QuerySet<Item1>> = get_item1_repository().await;
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let items: Vec<Item1> = (1..=5)
+            .map(|field1| Item1 {
+                uuid: Uuid::new_v4(),
+                field1,
+            })
+            .collect();
+        for item in &items {
+            repo.create(item).await?;
+        }
+
+        // WHEN we list the items matching filters
+        // THEN we only get the matching items
+        let field1s = |mut found: Vec<Item1>| {
+            found.sort_by_key(|item| item.field1);
+            found.iter().map(|item| item.field1).collect::<Vec<i32>>()
+        };
+        let filter = Filter::eq("uuid", items[1].uuid);
+        assert_eq!(field1s(repo.list_where(&filter).await?), vec![2]);
+        let filter = Filter::gt("field1", 3);
+        assert_eq!(field1s(repo.list_where(&filter).await?), vec![4, 5]);
+        let filter = Filter::is_in("field1", [1, 3, 7]);
+        assert_eq!(field1s(repo.list_where(&filter).await?), vec![1, 3]);
+        let filter = Filter::ge("field1", 2)
+            .and(Filter::le("field1", 4))
+            .and(Filter::ne("field1", 3).or(Filter::eq("uuid", items[2].uuid)));
+        assert_eq!(field1s(repo.list_where(&filter).await?), vec![2, 3, 4]);
+
+        // AND unknown fields are rejected before reaching the database
+        let result = repo.list_where(&Filter::eq("field2", 1)).await;
+        assert!(matches!(result, Err(InterfaceError::InvalidQuery(_))));
+
+        repo.drop_table().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_list_page() -> Result<(), InterfaceError> {
+        // GIVEN a repository with five items, two of them sharing the same field1
+        let repo: RdsRepository<Item1, Item1QuerySet<Item1>> = get_item1_repository().await;
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        for field1 in [3, 1, 2, 2, 5] {
+            repo.create(&Item1 {
+                uuid: Uuid::new_v4(),
+                field1,
+            })
+            .await?;
+        }
+
+        for direction in [Direction::Asc, Direction::Desc] {
+            // WHEN we list pages of two items until there is no next page
+            let mut pages = Vec::new();
+            let mut request = PageRequest::new("field1", 2).direction(direction);
+            loop {
+                let page = repo.list_page(&request).await?;
+                pages.push(page.items.iter().map(|i| i.field1).collect::<Vec<i32>>());
+                match page.next {
+                    Some(cursor) => request = request.after(Some(cursor)),
+                    None => break,
+                }
+            }
+
+            // THEN we get every item once, in order
+            let expected = match direction {
+                Direction::Asc => vec![vec![1, 2], vec![2, 3], vec![5]],
+                Direction::Desc => vec![vec![5, 3], vec![2, 2], vec![1]],
+            };
+            assert_eq!(pages, expected);
+        }
+
+        repo.drop_table().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_batch() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an empty table and small batches
+        let repo: RdsRepository<Item1, Item1QuerySet<Item1>> =
+            get_item1_repository().await.with_batch_size(3);
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let mut items: Vec<Item1> = (0..10).map(|_| gen_item()).collect();
+
+        // WHEN we create, update and delete the items in batches
+        repo.create_many(&items).await?;
+        items.iter_mut().for_each(|item| item.field1 = 7);
+        repo.update_many(&items).await?;
+        let keys: Vec<Uuid> = items.iter().take(4).map(|item| item.uuid).collect();
+        repo.delete_many(&keys).await?;
+
+        // THEN the remaining items were all updated
+        let all = repo.list_where(&Filter::eq("field1", 7)).await?;
+        repo.drop_table().await?;
+        assert_eq!(all.len(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_update_version() -> Result<(), InterfaceError> {
+        // GIVEN a versioned repository with an item
+        let queryset: Box<VersionedItemQuerySet<VersionedItem>> =
+            Box::new(VersionedItem::queryset());
+        let repo = RdsRepository::new(get_client().await, queryset);
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let item = VersionedItem {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+            version: 0,
+        };
+        repo.create(&item).await?;
+
+        // WHEN two clients update the item they read
+        let first = repo.get(&item.uuid).await?.unwrap();
+        let second = repo.get(&item.uuid).await?.unwrap();
+        repo.update(&first).await?;
+        let result = repo.update(&second).await;
+
+        // THEN the second update is rejected and the version was incremented once
+        let stored = repo.get(&item.uuid).await?.unwrap();
+        repo.drop_table().await?;
+        assert!(matches!(result, Err(InterfaceError::Conflict(_))));
+        assert_eq!(stored.version, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_soft_delete_and_history() -> Result<(), InterfaceError> {
+        // GIVEN an audited repository keeping its deleted items
+        let queryset = Box::new(AuditedItem::queryset());
+        let repo = RdsRepository::new(get_client().await, queryset).with_actor("teller");
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let item = AuditedItem {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+            version: 0,
+        };
+
+        // WHEN an item is created, updated, then deleted
+        repo.create(&item).await?;
+        repo.update(&item).await?;
+        repo.delete(&item.uuid).await?;
+
+        // THEN it is hidden, its key cannot be reused, and its changes are recorded
+        let found = repo.get(&item.uuid).await?;
+        let created = repo.create(&item).await;
+        let history = repo.history(&item.uuid).await?;
+        repo.drop_table().await?;
+        assert_eq!(found, None);
+        assert!(matches!(created, Err(InterfaceError::Conflict(_))));
+        let operations: Vec<Mutation> = history.iter().map(|change| change.operation).collect();
+        assert_eq!(
+            operations,
+            vec![Mutation::Create, Mutation::Update, Mutation::Delete]
+        );
+        assert_eq!(history[2].before.as_ref().map(|item| item.version), Some(1));
+        assert!(history.iter().all(|change| change.actor == "teller"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    #[ignore]
+    async fn test_transaction() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an empty table
+        let repo: RdsRepository<Item1, Item1QuerySet<Item1>> = get_item1_repository().await;
+        repo.drop_table().await?;
+        repo.create_table().await?;
+        let (item1, item2) = (gen_item(), gen_item());
+
+        // WHEN we create an item in a rolled back transaction
+        // and another one in a committed transaction
+        let transaction = RdsTransaction::begin(&repo.client).await?;
+        repo.in_transaction(&transaction).create(&item1).await?;
+        transaction.rollback().await?;
+
+        let transaction = RdsTransaction::begin(&repo.client).await?;
+        let outcome = repo.in_transaction(&transaction).create(&item2).await;
+        transaction.finish(outcome).await?;
+
+        // THEN only the committed item is in the table
+        let all = repo.list().await?;
+        repo.drop_table().await?;
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].uuid, item2.uuid);
+        Ok(())
+    }
+
     #[tokio::test]
     #[serial_test::serial]
     #[ignore]
     async fn test_update_entry() -> Result<(), InterfaceError> {
         // GIVEN a repository with an empty table and an item
         let repo: RdsRepository<Item1, Item1QuerySet<Item1>> = get_item1_repository().await;
-        let _ = repo.drop_table().await?;
-        let _ = repo.create_table().await?;
+        repo.drop_table().await?;
+        repo.create_table().await?;
         let mut item = gen_item();
 
         // WHEN we create and update an entry
-        let _ = repo.create(&item).await?;
+        repo.create(&item).await?;
         item.field1 += 1;
-        let _ = repo.update(&item).await?;
+        repo.update(&item).await?;
 
         // THEN we get no errors, there is one entry in the table
         // and the modifications where applied
         let resp_item: Item1 = repo.get(&item.uuid).await?.unwrap();
         assert_eq!(item.field1, resp_item.field1);
         let all = repo.list().await?;
-        let _ = repo.drop_table().await?;
+        repo.drop_table().await?;
         assert_eq!(all.len(), 1);
         Ok(())
     }
+
+    /// Tables of the conformance suite, recreated for each check
+    struct RdsFactory;
+
+    #[async_trait]
+    impl RepositoryFactory for RdsFactory {
+        type Repo = RdsRepository<ConformanceItem, ConformanceItemQuerySet<ConformanceItem>>;
+        type VersionedRepo = RdsRepository<
+            VersionedConformanceItem,
+            VersionedConformanceItemQuerySet<VersionedConformanceItem>,
+        >;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            let queryset = Box::new(ConformanceItem::queryset());
+            let repo = RdsRepository::new(get_client().await, queryset);
+            repo.drop_table().await?;
+            repo.create_table().await?;
+            Ok(repo)
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            let queryset = Box::new(VersionedConformanceItem::queryset());
+            let repo = RdsRepository::new(get_client().await, queryset);
+            repo.drop_table().await?;
+            repo.create_table().await?;
+            Ok(repo)
+        }
+    }
+
+    crate::repository_conformance_tests!(RdsFactory, #[serial_test::serial], #[ignore]);
 }
@@ -0,0 +1,619 @@
+//! Retry decorator for any Repository
+//!
+//! Operations failing with a transient error are retried after a jittered exponential
+//! backoff, until they succeed, fail with another error, or the attempts or the deadline
+//! of the policy are exhausted. Only idempotent operations are retried: reads, deletes,
+//! updates of unversioned items, and creates of items keyed by an idempotency key.
+use crate::ports::secondary::{
+    Change, Create, Delete, Get, HasKey, History, Join, List, Repository, Update,
+};
+use crate::query::{Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::settings::RetrySettings;
+use crate::{error::InterfaceError, Val};
+use async_trait::async_trait;
+use serde_json::Value as JsonValue;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// When and how long to wait before retrying a failed operation
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts of an operation, including the first one
+    pub max_attempts: u32,
+    /// Backoff ceiling after the first attempt, doubled after each attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// No attempt starts after this time since the first one
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+impl From<&RetrySettings> for RetryPolicy {
+    fn from(settings: &RetrySettings) -> Self {
+        RetryPolicy {
+            max_attempts: settings.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(settings.initial_backoff_ms),
+            max_backoff: Duration::from_millis(settings.max_backoff_ms),
+            deadline: Duration::from_millis(settings.deadline_ms),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Random delay after a failed attempt, up to the exponential backoff ("full jitter")
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        ceiling.mul_f64(jitter())
+    }
+
+    /// Run `operation` until it succeeds, fails with an error that is not retryable,
+    /// or the policy gives up and returns the last error
+    pub async fn run<R, F, Fut>(&self, mut operation: F) -> Result<R, InterfaceError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, InterfaceError>>,
+    {
+        let deadline = Instant::now() + self.deadline;
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(err) if err.is_retryable() && attempt < self.max_attempts => {
+                    let backoff = self.backoff(attempt);
+                    if Instant::now() + backoff > deadline {
+                        return Err(err);
+                    }
+                    warn!(
+                        attempt,
+                        ?backoff,
+                        "Retrying after a transient error: {}",
+                        err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Random number in [0, 1)
+fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Repository retrying the idempotent operations of an inner repository
+///
+/// It should not be bound to a transaction: a failed statement aborts the transaction,
+/// which has to be retried as a whole.
+pub struct RetryingRepository<T, R> {
+    inner: R,
+    policy: RetryPolicy,
+    versioned: bool,
+    idempotency_key: bool,
+    _marker_val: PhantomData<T>,
+}
+
+impl<T, R> RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    pub fn new(inner: R, policy: RetryPolicy) -> Self {
+        RetryingRepository {
+            inner,
+            policy,
+            versioned: false,
+            idempotency_key: false,
+            _marker_val: PhantomData,
+        }
+    }
+
+    /// The updates check the version of the items: an update that failed may have
+    /// been applied and would conflict, they are not retried
+    pub fn versioned(mut self) -> Self {
+        self.versioned = true;
+        self
+    }
+
+    /// The items are keyed by an idempotency key, e.g. a UUID generated by the client:
+    /// creates are retried, and a retry conflicting with the very same stored item
+    /// means that a failed attempt was applied
+    pub fn with_idempotency_key(mut self) -> Self {
+        self.idempotency_key = true;
+        self
+    }
+
+    /// The decorated repository
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+
+    /// Retry creating items keyed by an idempotency key
+    async fn create_idempotent<'a, F, Fut>(
+        &'a self,
+        items: &'a [T],
+        create: F,
+    ) -> Result<(), InterfaceError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<(), InterfaceError>>,
+    {
+        let attempts = AtomicU32::new(0);
+        let result = self
+            .policy
+            .run(|| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                create()
+            })
+            .await;
+
+        match result {
+            Err(InterfaceError::Conflict(_)) if attempts.load(Ordering::Relaxed) > 1 => {
+                for item in items {
+                    let stored = self.inner.get(&item.key()).await?;
+                    if !same(stored.as_ref(), item)? {
+                        return result;
+                    }
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether the stored item is the given one
+fn same<T: Val>(stored: Option<&T>, item: &T) -> Result<bool, InterfaceError> {
+    let json =
+        |item| serde_json::to_value(item).map_err(|e| InterfaceError::FromFields(e.to_string()));
+    match stored {
+        Some(stored) => Ok(json(stored)? == json(item)?),
+        None => Ok(false),
+    }
+}
+
+#[async_trait]
+impl<T, R> Create<T> for RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        if !self.idempotency_key {
+            return self.inner.create(item).await;
+        }
+        self.create_idempotent(std::slice::from_ref(item), || self.inner.create(item))
+            .await
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        if !self.idempotency_key {
+            return self.inner.create_many(items).await;
+        }
+        self.create_idempotent(items, || self.inner.create_many(items))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, R> Get<T> for RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        self.policy.run(|| self.inner.get(key)).await
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        self.policy.run(|| self.inner.exists(key)).await
+    }
+}
+
+#[async_trait]
+impl<T, R> Update<T> for RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.versioned {
+            return self.inner.update(item).await;
+        }
+        self.policy.run(|| self.inner.update(item)).await
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        if self.versioned {
+            return self.inner.update_many(items).await;
+        }
+        self.policy.run(|| self.inner.update_many(items)).await
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.versioned {
+            return self.inner.upsert(item).await;
+        }
+        self.policy.run(|| self.inner.upsert(item)).await
+    }
+}
+
+#[async_trait]
+impl<T, R> Delete<T> for RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        self.policy.run(|| self.inner.delete(key)).await
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        self.policy.run(|| self.inner.delete_many(keys)).await
+    }
+}
+
+#[async_trait]
+impl<T, R> List<T> for RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        self.policy.run(|| self.inner.list()).await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        self.policy.run(|| self.inner.list_where(filter)).await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        self.policy.run(|| self.inner.list_page(request)).await
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        self.policy.run(|| self.inner.count(filter)).await
+    }
+
+    async fn select_where(
+        &self,
+        fields: &[&str],
+        filter: &Filter,
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        self.policy
+            .run(|| self.inner.select_where(fields, filter))
+            .await
+    }
+}
+
+impl<T, R> Repository<T> for RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + Send + Sync,
+{
+}
+
+#[async_trait]
+impl<T, R> History<T> for RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    R: Repository<T> + History<T> + Send + Sync,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        self.policy.run(|| self.inner.history(key)).await
+    }
+}
+
+#[async_trait]
+impl<T, U, R> Join<T, U> for RetryingRepository<T, R>
+where
+    T: Val + HasKey,
+    U: Val,
+    R: Repository<T> + Join<T, U> + Send + Sync,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        self.policy
+            .run(|| self.inner.list_with_parent(relation, filter))
+            .await
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        self.policy
+            .run(|| self.inner.list_with_children(relation, filter))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{
+        gen_item, ConformanceItem, RepositoryFactory, VersionedConformanceItem,
+    };
+    use crate::usecase::memory::InMemoryRepository;
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    /// Repository failing its next calls with a transient error,
+    /// before or after applying them
+    #[derive(Default)]
+    struct Flaky {
+        inner: InMemoryRepository<ConformanceItem>,
+        failures: AtomicU32,
+        applied: bool,
+        calls: AtomicU32,
+    }
+
+    impl Flaky {
+        fn failing(failures: u32) -> Self {
+            Flaky {
+                failures: AtomicU32::new(failures),
+                ..Default::default()
+            }
+        }
+
+        async fn call<R>(
+            &self,
+            operation: impl Future<Output = Result<R, InterfaceError>>,
+        ) -> Result<R, InterfaceError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let failing = self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok();
+            match (failing, self.applied) {
+                (false, _) => operation.await,
+                (true, false) => Err(InterfaceError::Transient("flaky".to_string())),
+                (true, true) => operation
+                    .await
+                    .and(Err(InterfaceError::Transient("flaky".to_string()))),
+            }
+        }
+
+        fn calls(&self) -> u32 {
+            self.calls.load(Ordering::Relaxed)
+        }
+    }
+
+    #[async_trait]
+    impl Create<ConformanceItem> for Flaky {
+        async fn create(&self, item: &ConformanceItem) -> Result<(), InterfaceError> {
+            self.call(self.inner.create(item)).await
+        }
+    }
+
+    #[async_trait]
+    impl Get<ConformanceItem> for Flaky {
+        async fn get(&self, key: &Uuid) -> Result<Option<ConformanceItem>, InterfaceError> {
+            self.call(self.inner.get(key)).await
+        }
+    }
+
+    #[async_trait]
+    impl Update<ConformanceItem> for Flaky {
+        async fn update(&self, item: &ConformanceItem) -> Result<(), InterfaceError> {
+            self.call(self.inner.update(item)).await
+        }
+
+        async fn upsert(&self, item: &ConformanceItem) -> Result<(), InterfaceError> {
+            self.call(self.inner.upsert(item)).await
+        }
+    }
+
+    #[async_trait]
+    impl Delete<ConformanceItem> for Flaky {
+        async fn delete(&self, key: &Uuid) -> Result<(), InterfaceError> {
+            self.call(self.inner.delete(key)).await
+        }
+    }
+
+    #[async_trait]
+    impl List<ConformanceItem> for Flaky {
+        async fn list(&self) -> Result<Vec<ConformanceItem>, InterfaceError> {
+            self.call(self.inner.list()).await
+        }
+
+        async fn list_where(
+            &self,
+            filter: &Filter,
+        ) -> Result<Vec<ConformanceItem>, InterfaceError> {
+            self.call(self.inner.list_where(filter)).await
+        }
+
+        async fn list_page(
+            &self,
+            request: &PageRequest,
+        ) -> Result<Page<ConformanceItem>, InterfaceError> {
+            self.call(self.inner.list_page(request)).await
+        }
+    }
+
+    impl Repository<ConformanceItem> for Flaky {}
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            deadline: Duration::from_secs(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() -> Result<(), InterfaceError> {
+        // GIVEN a repository failing twice, then three times
+        let repo = RetryingRepository::new(Flaky::failing(2), fast_policy());
+        let item = gen_item(3);
+        repo.inner().inner.create(&item).await?;
+
+        // WHEN we read an item
+        let read = repo.get(&item.uuid).await?;
+        repo.inner().failures.store(3, Ordering::Relaxed);
+        let failed = repo.list().await;
+
+        // THEN the reads are retried up to the maximum attempts
+        assert_eq!(read, Some(item));
+        assert!(matches!(failed, Err(InterfaceError::Transient(_))));
+        assert_eq!(repo.inner().calls(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_retry() -> Result<(), InterfaceError> {
+        // GIVEN a repository failing once, that can't retry for long
+        let policy = RetryPolicy {
+            deadline: Duration::ZERO,
+            ..fast_policy()
+        };
+        let repo = RetryingRepository::new(Flaky::failing(1), policy);
+
+        // WHEN the deadline is exceeded, or the error is not transient
+        let late = repo.get(&Uuid::new_v4()).await;
+        let missing = repo.update(&gen_item(3)).await;
+
+        // THEN the operations are not retried
+        assert!(matches!(late, Err(InterfaceError::Transient(_))));
+        assert!(matches!(missing, Err(InterfaceError::MissingItem(_))));
+        assert_eq!(repo.inner().calls(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_idempotent() -> Result<(), InterfaceError> {
+        // GIVEN a repository failing once, with versioned items
+        let repo = RetryingRepository::new(Flaky::failing(1), fast_policy()).versioned();
+
+        // WHEN we create an item, then update it
+        let item = gen_item(3);
+        let created = repo.create(&item).await;
+        repo.inner().failures.store(1, Ordering::Relaxed);
+        repo.inner().inner.create(&item).await?;
+        let updated = repo.update(&item).await;
+
+        // THEN neither are retried
+        assert!(matches!(created, Err(InterfaceError::Transient(_))));
+        assert!(matches!(updated, Err(InterfaceError::Transient(_))));
+        assert_eq!(repo.inner().calls(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key() -> Result<(), InterfaceError> {
+        // GIVEN a repository failing once after creating an item
+        let flaky = Flaky {
+            applied: true,
+            ..Flaky::failing(1)
+        };
+        let repo = RetryingRepository::new(flaky, fast_policy()).with_idempotency_key();
+
+        // WHEN we create an item keyed by an idempotency key
+        let item = gen_item(3);
+        repo.create(&item).await?;
+
+        // THEN the retry conflicting with the created item succeeds
+        assert_eq!(repo.inner().inner.list().await?, vec![item.clone()]);
+
+        // AND a retry conflicting with another item fails
+        let repo = RetryingRepository::new(Flaky::failing(1), fast_policy()).with_idempotency_key();
+        repo.inner().inner.create(&item).await?;
+        let other = ConformanceItem {
+            field1: 4,
+            ..item.clone()
+        };
+        let result = repo.create(&other).await;
+        assert!(matches!(result, Err(InterfaceError::Conflict(_))));
+        assert_eq!(repo.inner().calls(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..40 {
+            let ceiling = Duration::from_millis(50)
+                .saturating_mul(1 << (attempt - 1).min(31))
+                .min(Duration::from_secs(2));
+            assert!(policy.backoff(attempt) <= ceiling);
+        }
+    }
+
+    #[test]
+    fn test_default_settings() {
+        // GIVEN retry settings with every field left out
+        let settings: RetrySettings = serde_json::from_str("{}").unwrap();
+
+        // THEN they build the default policy
+        assert_eq!(RetryPolicy::from(&settings), RetryPolicy::default());
+    }
+
+    struct RetryFactory;
+
+    #[async_trait]
+    impl RepositoryFactory for RetryFactory {
+        type Repo = RetryingRepository<ConformanceItem, InMemoryRepository<ConformanceItem>>;
+        type VersionedRepo = RetryingRepository<
+            VersionedConformanceItem,
+            InMemoryRepository<VersionedConformanceItem>,
+        >;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            Ok(
+                RetryingRepository::new(InMemoryRepository::new(), RetryPolicy::default())
+                    .with_idempotency_key(),
+            )
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            let inner = InMemoryRepository::new().with_version("version");
+            Ok(RetryingRepository::new(inner, RetryPolicy::default()).versioned())
+        }
+    }
+
+    crate::repository_conformance_tests!(RetryFactory);
+}
@@ -0,0 +1,1299 @@
+//! Implementation of a Repository over an embedded SQLite database
+//!
+//! The statements are the ones generated by `struct_to_sql`: SQLite binds
+//! their named `:param` placeholders as they are. UUIDs are stored as text.
+use std::marker::PhantomData;
+use std::slice;
+use std::sync::Arc;
+
+use crate::migration::MigrationDatabase;
+use crate::ports::secondary::{
+    Change, Create, Delete, Get, HasKey, History, Join, Key, List, Mutation, Repository,
+    Transaction, Update,
+};
+use crate::query::{validate_selection, FieldValue, Filter, Page, PageRequest};
+use crate::relation::{BelongsTo, HasMany};
+use crate::settings::SqliteSettings;
+use crate::usecase::history::{
+    self, current_actor, history_parameters, not_audited, now_millis, AppendHistory, Changes,
+    HistoryRow, DEFAULT_ACTOR,
+};
+use crate::usecase::rds::{
+    count_value, field_value, found_value, item_parameters, not_updated, not_upserted,
+    GetFieldsAsParams,
+};
+use crate::{error::InterfaceError, QuerySet, Val};
+use async_trait::async_trait;
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+use rusqlite::{ffi, ErrorCode, ToSql};
+use serde_json::{Map, Value as JsonValue};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Error of the database, typed from the result code of the failed statement
+/// like the SQLSTATE of the same failure in PostgreSQL
+fn sqlite_error(err: rusqlite::Error) -> InterfaceError {
+    if let rusqlite::Error::SqliteFailure(failure, _) = &err {
+        let sqlstate = match failure.extended_code {
+            ffi::SQLITE_CONSTRAINT_PRIMARYKEY | ffi::SQLITE_CONSTRAINT_UNIQUE => Some("23505"),
+            ffi::SQLITE_CONSTRAINT_NOTNULL => Some("23502"),
+            ffi::SQLITE_CONSTRAINT_CHECK => Some("23514"),
+            ffi::SQLITE_CONSTRAINT_FOREIGNKEY => Some("23503"),
+            _ => None,
+        };
+        if let Some(err) =
+            sqlstate.and_then(|code| InterfaceError::from_sqlstate(code, err.to_string()))
+        {
+            return err;
+        }
+        if let ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked = failure.code {
+            return InterfaceError::Transient(err.to_string());
+        }
+    }
+    InterfaceError::SqliteError(Box::new(err))
+}
+
+fn finished_error() -> InterfaceError {
+    InterfaceError::Other("Transaction already finished".to_string())
+}
+
+/// An embedded database: a single connection, shared by the repositories
+#[derive(Clone)]
+pub struct SqliteDatabase {
+    connection: Arc<Mutex<rusqlite::Connection>>,
+}
+
+impl SqliteDatabase {
+    /// Open, or create, the database of the settings
+    pub fn open(settings: &SqliteSettings) -> Result<Self, InterfaceError> {
+        Self::from_connection(rusqlite::Connection::open(&settings.path).map_err(sqlite_error)?)
+    }
+
+    /// Open a new database living in memory
+    pub fn open_in_memory() -> Result<Self, InterfaceError> {
+        Self::from_connection(rusqlite::Connection::open_in_memory().map_err(sqlite_error)?)
+    }
+
+    /// SQLite only enforces the foreign keys of a connection once they are turned on
+    fn from_connection(connection: rusqlite::Connection) -> Result<Self, InterfaceError> {
+        connection
+            .pragma_update(None, "foreign_keys", true)
+            .map_err(sqlite_error)?;
+        Ok(SqliteDatabase {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+}
+
+impl ToSql for FieldValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            FieldValue::Null => ToSqlOutput::Owned(Value::Null),
+            FieldValue::Bool(b) => ToSqlOutput::from(*b),
+            FieldValue::Integer(i) => ToSqlOutput::from(*i),
+            FieldValue::Double(d) => ToSqlOutput::from(*d),
+            FieldValue::String(s) => ToSqlOutput::from(s.as_str()),
+            FieldValue::Uuid(uuid) => ToSqlOutput::Owned(Value::Text(uuid.to_string())),
+            FieldValue::Bytes(bytes) => ToSqlOutput::Borrowed(ValueRef::Blob(bytes)),
+        })
+    }
+}
+
+/// Bind the named parameters of a statement
+fn bind(
+    statement: &mut rusqlite::Statement,
+    params: &[(String, FieldValue)],
+) -> Result<(), InterfaceError> {
+    for index in 1..=statement.parameter_count() {
+        let name = statement
+            .parameter_name(index)
+            .unwrap_or_default()
+            .trim_start_matches(':')
+            .to_string();
+        let value = params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| InterfaceError::InvalidQuery(format!("Missing parameter: {name}")))?;
+        statement
+            .raw_bind_parameter(index, value)
+            .map_err(sqlite_error)?;
+    }
+    Ok(())
+}
+
+/// Execute a statement, returning the number of rows modified
+fn execute(
+    connection: &rusqlite::Connection,
+    sql: &str,
+    params: &[(String, FieldValue)],
+) -> Result<usize, InterfaceError> {
+    let mut statement = connection.prepare_cached(sql).map_err(sqlite_error)?;
+    bind(&mut statement, params)?;
+    statement.raw_execute().map_err(sqlite_error)
+}
+
+fn query<T: Val>(
+    connection: &rusqlite::Connection,
+    sql: &str,
+    params: &[(String, FieldValue)],
+) -> Result<Vec<T>, InterfaceError> {
+    let mut statement = connection.prepare_cached(sql).map_err(sqlite_error)?;
+    let columns: Vec<(String, Option<String>)> = statement
+        .columns()
+        .into_iter()
+        .map(|column| {
+            (
+                column.name().to_string(),
+                column.decl_type().map(str::to_uppercase),
+            )
+        })
+        .collect();
+    bind(&mut statement, params)?;
+
+    let mut rows = statement.raw_query();
+    let mut items = Vec::new();
+    while let Some(row) = rows.next().map_err(sqlite_error)? {
+        items.push(parse_row(row, &columns)?);
+    }
+    Ok(items)
+}
+
+/// Read a row as a JSON object, to deserialize rows like the RDS JSON records.
+/// The declared type of a column tells the booleans from the integers, and the
+/// JSON values from the strings.
+fn parse_row<T: Val>(
+    row: &rusqlite::Row,
+    columns: &[(String, Option<String>)],
+) -> Result<T, InterfaceError> {
+    let mut object = Map::new();
+    for (index, (column, decl_type)) in columns.iter().enumerate() {
+        let value = match (
+            row.get_ref(index).map_err(sqlite_error)?,
+            decl_type.as_deref(),
+        ) {
+            (ValueRef::Null, _) => JsonValue::Null,
+            (ValueRef::Integer(i), Some("BOOLEAN")) => (i != 0).into(),
+            (ValueRef::Integer(i), _) => i.into(),
+            (ValueRef::Real(d), _) => d.into(),
+            (ValueRef::Text(text), Some("JSON")) => serde_json::from_slice(text)
+                .map_err(|e| InterfaceError::FromFields(format!("{column}: {e}")))?,
+            (ValueRef::Text(text), _) => String::from_utf8_lossy(text).into(),
+            (ValueRef::Blob(bytes), _) => bytes.to_vec().into(),
+        };
+        object.insert(column.clone(), value);
+    }
+
+    serde_json::from_value(JsonValue::Object(object))
+        .map_err(|e| InterfaceError::FromFields(format!("Failed to parse row: {e}")))
+}
+
+/// Run `f` in a transaction, unless the connection already is in one
+fn atomically<R>(
+    connection: &mut rusqlite::Connection,
+    f: impl FnOnce(&rusqlite::Connection) -> Result<R, InterfaceError>,
+) -> Result<R, InterfaceError> {
+    if !connection.is_autocommit() {
+        return f(connection);
+    }
+    let transaction = connection.transaction().map_err(sqlite_error)?;
+    let result = f(&transaction)?;
+    transaction.commit().map_err(sqlite_error)?;
+    Ok(result)
+}
+
+#[async_trait]
+impl MigrationDatabase for SqliteDatabase {
+    async fn apply(&self, statements: &[String]) -> Result<(), InterfaceError> {
+        let mut connection = self.connection.lock().await;
+        atomically(&mut connection, |connection| {
+            for sql in statements {
+                connection.execute_batch(sql).map_err(sqlite_error)?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn query(&self, sql: &str) -> Result<Vec<JsonValue>, InterfaceError> {
+        query(&*self.connection.lock().await, sql, &[])
+    }
+}
+
+/// Where the statements are executed: the shared connection,
+/// or the connection held by a transaction
+#[derive(Clone)]
+enum Connection {
+    Database(SqliteDatabase),
+    Transaction(Arc<Mutex<Option<OwnedMutexGuard<rusqlite::Connection>>>>),
+}
+
+pub struct SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    connection: Connection,
+    queryset: Arc<Q>,
+    actor: String,
+    /// Off in the transactions recording the history
+    auditing: bool,
+
+    _marker_val: PhantomData<T>,
+}
+
+impl<T, Q> SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    pub fn new(database: SqliteDatabase, queryset: Box<Q>) -> Self {
+        SqliteRepository {
+            connection: Connection::Database(database),
+            queryset: Arc::from(queryset),
+            actor: DEFAULT_ACTOR.to_string(),
+            auditing: true,
+            _marker_val: PhantomData,
+        }
+    }
+
+    /// The same repository, executing its statements in a transaction
+    pub fn in_transaction(&self, transaction: &SqliteTransaction) -> Self {
+        self.with_connection_of(Connection::Transaction(Arc::clone(&transaction.connection)))
+    }
+
+    fn with_connection_of(&self, connection: Connection) -> Self {
+        SqliteRepository {
+            connection,
+            queryset: Arc::clone(&self.queryset),
+            actor: self.actor.clone(),
+            auditing: self.auditing,
+            _marker_val: PhantomData,
+        }
+    }
+
+    /// Record the changes of an audited table as made by `actor`, outside of `as_actor`
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = actor.into();
+        self
+    }
+
+    /// Named parameters matching the primary key columns
+    fn key_parameters(&self, key: &T::Key) -> Vec<(String, FieldValue)> {
+        self.queryset
+            .primary_key()
+            .into_iter()
+            .zip(key.values())
+            .collect()
+    }
+
+    /// Named parameters of a deletion: the primary key, and the deletion time if it is soft
+    fn delete_parameters(&self, key: &T::Key) -> Vec<(String, FieldValue)> {
+        let mut params = self.key_parameters(key);
+        if let Some(column) = self.queryset.deleted_column() {
+            params.push((column, FieldValue::Integer(now_millis())));
+        }
+        params
+    }
+
+    /// Create the table and its indexes, and its history table if it is audited
+    pub async fn create_table(&self) -> Result<(), InterfaceError> {
+        let mut statements = vec![self.queryset.create_table_sqlite()];
+        statements.extend(self.queryset.create_indexes());
+        if self.queryset.history_table().is_some() {
+            statements.push(self.queryset.create_history_table_sqlite());
+        }
+        self.with_connection(|connection| {
+            for sql in &statements {
+                execute(connection, sql, &[])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// !!! DROP THE TABLE, AND ITS HISTORY !!!
+    pub async fn drop_table(&self) -> Result<(), InterfaceError> {
+        let mut statements = vec![self.queryset.drop_table()];
+        if self.queryset.history_table().is_some() {
+            statements.push(self.queryset.drop_history_table());
+        }
+        self.with_connection(|connection| {
+            for sql in &statements {
+                execute(connection, sql, &[])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    fn audits(&self) -> bool {
+        self.auditing && self.queryset.history_table().is_some()
+    }
+
+    /// Apply a mutation and record its changes in the history,
+    /// in a transaction unless the repository already is in one
+    async fn audit(&self, changes: Changes<'_, T>) -> Result<(), InterfaceError> {
+        let transaction = match &self.connection {
+            Connection::Database(database) => Some(SqliteTransaction::begin(database).await?),
+            Connection::Transaction(_) => None,
+        };
+        let connection = match &transaction {
+            Some(transaction) => Connection::Transaction(Arc::clone(&transaction.connection)),
+            None => self.connection.clone(),
+        };
+        let repo = SqliteRepository {
+            auditing: false,
+            ..self.with_connection_of(connection)
+        };
+
+        let outcome = history::apply(&repo, changes).await;
+        match transaction {
+            Some(transaction) => transaction.finish(outcome).await,
+            None => outcome,
+        }
+    }
+
+    /// Run `f` with the connection, waiting for any transaction
+    /// of another repository to finish
+    async fn with_connection<R>(
+        &self,
+        f: impl FnOnce(&mut rusqlite::Connection) -> Result<R, InterfaceError> + Send,
+    ) -> Result<R, InterfaceError> {
+        match &self.connection {
+            Connection::Database(database) => f(&mut *database.connection.lock().await),
+            Connection::Transaction(transaction) => {
+                let mut guard = transaction.lock().await;
+                f(guard.as_mut().ok_or_else(finished_error)?)
+            }
+        }
+    }
+
+    /// Error of an update that matched no row: a missing item,
+    /// or a versioned item modified since it was read
+    fn not_updated(&self, connection: &rusqlite::Connection, item: &T) -> InterfaceError {
+        let version = self.queryset.version();
+        let exists = match version {
+            Some(_) => {
+                let (sql, params) = (self.queryset.get_by_key(), self.key_parameters(&item.key()));
+                match query::<T>(connection, &sql, &params) {
+                    Ok(items) => !items.is_empty(),
+                    Err(err) => return err,
+                }
+            }
+            None => false,
+        };
+        not_updated(item, version, exists)
+    }
+}
+
+#[async_trait]
+impl<T, Q> Create<T> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn create(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Create(slice::from_ref(item))).await;
+        }
+        let (sql, params) = (self.queryset.create(), item_parameters(item)?);
+        self.with_connection(|connection| execute(connection, &sql, &params))
+            .await?;
+        Ok(())
+    }
+
+    async fn create_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Create(items)).await;
+        }
+        let sql = self.queryset.create();
+        let parameter_sets = items
+            .iter()
+            .map(item_parameters)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.with_connection(|connection| {
+            atomically(connection, |connection| {
+                for params in &parameter_sets {
+                    execute(connection, &sql, params)?;
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<T, Q> Get<T> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn get(&self, key: &T::Key) -> Result<Option<T>, InterfaceError> {
+        let (sql, params) = (self.queryset.get_by_key(), self.key_parameters(key));
+        let mut items: Vec<T> = self
+            .with_connection(|connection| query(connection, &sql, &params))
+            .await?;
+
+        if items.len() > 1 {
+            // There should only be one record
+            return Err(InterfaceError::Other(format!(
+                "Received multiple results for key: {:?}",
+                key
+            )));
+        }
+        Ok(items.pop())
+    }
+
+    async fn exists(&self, key: &T::Key) -> Result<bool, InterfaceError> {
+        let (sql, params) = (self.queryset.exists(), self.key_parameters(key));
+        let rows: Vec<JsonValue> = self
+            .with_connection(|connection| query(connection, &sql, &params))
+            .await?;
+        found_value(&rows)
+    }
+}
+
+#[async_trait]
+impl<T, Q> Delete<T> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn delete(&self, key: &T::Key) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Delete(slice::from_ref(key))).await;
+        }
+        let (sql, params) = (self.queryset.delete_by_key(), self.delete_parameters(key));
+        self.with_connection(|connection| execute(connection, &sql, &params))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[T::Key]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Delete(keys)).await;
+        }
+        let sql = self.queryset.delete_by_key();
+        let parameter_sets: Vec<_> = keys.iter().map(|key| self.delete_parameters(key)).collect();
+
+        self.with_connection(|connection| {
+            atomically(connection, |connection| {
+                for params in &parameter_sets {
+                    execute(connection, &sql, params)?;
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<T, Q> Update<T> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn update(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Update(slice::from_ref(item))).await;
+        }
+        let (sql, params) = (self.queryset.update(), item_parameters(item)?);
+
+        // A versioned update only matches the row if nobody updated it in between
+        self.with_connection(|connection| match execute(connection, &sql, &params)? {
+            0 => Err(self.not_updated(connection, item)),
+            _ => Ok(()),
+        })
+        .await
+    }
+
+    async fn update_many(&self, items: &[T]) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Update(items)).await;
+        }
+        let sql = self.queryset.update();
+        let parameter_sets = items
+            .iter()
+            .map(item_parameters)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.with_connection(|connection| {
+            atomically(connection, |connection| {
+                for (item, params) in items.iter().zip(&parameter_sets) {
+                    if execute(connection, &sql, params)? == 0 {
+                        return Err(self.not_updated(connection, item));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn upsert(&self, item: &T) -> Result<(), InterfaceError> {
+        if self.audits() {
+            return self.audit(Changes::Upsert(item)).await;
+        }
+        let (sql, params) = (self.queryset.upsert(), item_parameters(item)?);
+        let version = self.queryset.version();
+        let guarded = version.is_some() || self.queryset.deleted_column().is_some();
+
+        // The existing row of a versioned item is only updated if it has its version,
+        // and a soft deleted row is not updated
+        let upserted = self
+            .with_connection(|connection| execute(connection, &sql, &params))
+            .await?;
+        if upserted == 0 && guarded {
+            return Err(not_upserted(item, version));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T, Q> List<T> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn list(&self) -> Result<Vec<T>, InterfaceError> {
+        let sql = self.queryset.list();
+        self.with_connection(|connection| query(connection, &sql, &[]))
+            .await
+    }
+
+    async fn list_where(&self, filter: &Filter) -> Result<Vec<T>, InterfaceError> {
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        let sql = self.queryset.list_where(&condition);
+        self.with_connection(|connection| query(connection, &sql, &params))
+            .await
+    }
+
+    async fn list_page(&self, request: &PageRequest) -> Result<Page<T>, InterfaceError> {
+        request.validate(&self.queryset.columns())?;
+        let key = self.queryset.primary_key();
+        let (condition, params) = request
+            .condition(&key)?
+            .to_sql_with(|field| self.queryset.column(field));
+
+        // Fetch one more item to know whether there is a next page
+        let sql = self.queryset.list_page(
+            &condition,
+            &request.order_sql_with(&key, |field| self.queryset.column(field)),
+            request.size + 1,
+        );
+        let items = self
+            .with_connection(|connection| query(connection, &sql, &params))
+            .await?;
+        request.page(&key, items, field_value)
+    }
+
+    async fn count(&self, filter: Option<&Filter>) -> Result<u64, InterfaceError> {
+        let all = Filter::And(vec![]);
+        let filter = filter.unwrap_or(&all);
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        let sql = self.queryset.count(&condition);
+        let rows: Vec<JsonValue> = self
+            .with_connection(|connection| query(connection, &sql, &params))
+            .await?;
+        count_value(&rows)
+    }
+
+    async fn select_where(
+        &self,
+        fields: &[&str],
+        filter: &Filter,
+    ) -> Result<Vec<JsonValue>, InterfaceError> {
+        validate_selection(fields, &self.queryset.columns())?;
+        filter.validate(&self.queryset.columns())?;
+        let (condition, params) = filter.to_sql_with(|field| self.queryset.column(field));
+
+        let sql = self.queryset.select_where(fields, &condition);
+        self.with_connection(|connection| query(connection, &sql, &params))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T, Q, U> Join<T, U> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+    U: Val,
+{
+    async fn list_with_parent(
+        &self,
+        relation: &BelongsTo<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, U)>, InterfaceError> {
+        let (sql, params) = relation.join_sql(&*self.queryset, filter)?;
+        let rows: Vec<JsonValue> = self
+            .with_connection(|connection| query(connection, &sql, &params))
+            .await?;
+        rows.iter().map(|row| relation.decode(row)).collect()
+    }
+
+    async fn list_with_children(
+        &self,
+        relation: &HasMany<T, U>,
+        filter: &Filter,
+    ) -> Result<Vec<(T, Vec<U>)>, InterfaceError> {
+        let (sql, params) = relation.join_sql(&*self.queryset, filter)?;
+        let rows: Vec<JsonValue> = self
+            .with_connection(|connection| query(connection, &sql, &params))
+            .await?;
+        relation.decode(&rows)
+    }
+}
+
+#[async_trait]
+impl<T, Q> Repository<T> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+}
+
+#[async_trait]
+impl<T, Q> History<T> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn history(&self, key: &T::Key) -> Result<Vec<Change<T>>, InterfaceError> {
+        if self.queryset.history_table().is_none() {
+            return Err(not_audited(&self.queryset.table()));
+        }
+        let (sql, params) = (self.queryset.list_history(), self.key_parameters(key));
+        let rows: Vec<HistoryRow> = self
+            .with_connection(|connection| query(connection, &sql, &params))
+            .await?;
+        rows.into_iter().map(HistoryRow::into_change).collect()
+    }
+}
+
+#[async_trait]
+impl<T, Q> AppendHistory<T> for SqliteRepository<T, Q>
+where
+    T: Val + GetFieldsAsParams + HasKey,
+    Q: QuerySet<T> + Send + Sync,
+{
+    async fn append(
+        &self,
+        mutation: Mutation,
+        changes: Vec<(T::Key, Option<T>, Option<T>)>,
+    ) -> Result<(), InterfaceError> {
+        let actor = current_actor(&self.actor);
+        let params = history_parameters(self.queryset.primary_key(), mutation, &actor, &changes)?;
+        let insert = self.queryset.insert_history(changes.len());
+        self.with_connection(|connection| execute(connection, &insert, &params))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Transaction holding the connection of the database until it is finished,
+/// shared by the repositories built with `SqliteRepository::in_transaction`
+pub struct SqliteTransaction {
+    connection: Arc<Mutex<Option<OwnedMutexGuard<rusqlite::Connection>>>>,
+}
+
+impl SqliteTransaction {
+    /// Begin a transaction, once the transactions in progress are finished
+    pub async fn begin(database: &SqliteDatabase) -> Result<Self, InterfaceError> {
+        let connection = Arc::clone(&database.connection).lock_owned().await;
+        connection.execute_batch("BEGIN").map_err(sqlite_error)?;
+
+        Ok(SqliteTransaction {
+            connection: Arc::new(Mutex::new(Some(connection))),
+        })
+    }
+
+    /// Run the closing statement and release the connection
+    async fn end(&self, statement: &str) -> Result<(), InterfaceError> {
+        let connection = self
+            .connection
+            .lock()
+            .await
+            .take()
+            .ok_or_else(finished_error)?;
+
+        let result = connection.execute_batch(statement).map_err(sqlite_error);
+        rollback_pending(&connection);
+        result
+    }
+}
+
+/// Roll back a transaction that could not be closed, to release the connection in a clean state
+fn rollback_pending(connection: &rusqlite::Connection) {
+    if !connection.is_autocommit() {
+        if let Err(err) = connection.execute_batch("ROLLBACK") {
+            tracing::error!("Failed to roll back the SQLite transaction: {err}");
+        }
+    }
+}
+
+#[async_trait]
+impl Transaction for SqliteTransaction {
+    async fn commit(&self) -> Result<(), InterfaceError> {
+        self.end("COMMIT").await
+    }
+
+    async fn rollback(&self) -> Result<(), InterfaceError> {
+        self.end("ROLLBACK").await
+    }
+}
+
+impl Drop for SqliteTransaction {
+    /// A transaction that was neither committed nor rolled back is rolled back
+    fn drop(&mut self) {
+        if let Ok(mut connection) = self.connection.try_lock() {
+            if let Some(connection) = connection.take() {
+                rollback_pending(&connection);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{
+        ConformanceItem, ConformanceItemQuerySet, RepositoryFactory, VersionedConformanceItem,
+        VersionedConformanceItemQuerySet,
+    };
+    use crate::query::{Column, Direction};
+    use crate::relation::tests::{gen_owner, gen_pet, Owner, OwnerQuerySet, Pet, PetQuerySet};
+    use crate::usecase::rds::{FromRow, RdsRow};
+    use pretty_assertions::assert_eq;
+    use serde::{Deserialize, Serialize};
+    use sql_macros::struct_to_sql;
+    use uuid::Uuid;
+
+    // Define structures
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    struct Item1 {
+        uuid: Uuid,
+        field1: i32,
+        name: String,
+    }
+
+    // Gen item
+    fn gen_item() -> Item1 {
+        Item1 {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+            name: "item".to_string(),
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    struct VersionedItem {
+        uuid: Uuid,
+        field1: i32,
+        #[sql(version)]
+        version: i32,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql(soft_delete, audit)]
+    struct AuditedItem {
+        uuid: Uuid,
+        field1: i32,
+        #[sql(version)]
+        version: i32,
+    }
+
+    #[derive(Default, Debug, Clone, Deserialize, PartialEq, Serialize)]
+    enum Status {
+        #[default]
+        Active,
+        Blocked,
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[struct_to_sql]
+    struct TypedItem {
+        uuid: Uuid,
+        active: bool,
+        amount: i64,
+        rate: f64,
+        balance: rust_decimal::Decimal,
+        opened_on: chrono::NaiveDate,
+        opened_at: chrono::DateTime<chrono::Utc>,
+        closed_at: Option<chrono::NaiveDateTime>,
+        logo: Vec<u8>,
+        settings: serde_json::Value,
+        #[sql(enum)]
+        status: Status,
+    }
+
+    async fn get_audited_repository(
+        database: &SqliteDatabase,
+    ) -> Result<SqliteRepository<AuditedItem, AuditedItemQuerySet<AuditedItem>>, InterfaceError>
+    {
+        let queryset = Box::new(AuditedItem::queryset());
+        let repo = SqliteRepository::new(database.clone(), queryset).with_actor("teller");
+        repo.create_table().await?;
+        Ok(repo)
+    }
+
+    async fn get_item1_repository(
+        database: &SqliteDatabase,
+    ) -> Result<SqliteRepository<Item1, Item1QuerySet<Item1>>, InterfaceError> {
+        let queryset: Box<Item1QuerySet<Item1>> = Box::new(Item1::queryset());
+        let repo = SqliteRepository::new(database.clone(), queryset);
+        repo.create_table().await?;
+        Ok(repo)
+    }
+
+    async fn get_relation_repositories(
+        database: &SqliteDatabase,
+    ) -> Result<
+        (
+            SqliteRepository<Owner, OwnerQuerySet<Owner>>,
+            SqliteRepository<Pet, PetQuerySet<Pet>>,
+        ),
+        InterfaceError,
+    > {
+        let owners = SqliteRepository::new(database.clone(), Box::new(Owner::queryset()));
+        owners.create_table().await?;
+        let pets = SqliteRepository::new(database.clone(), Box::new(Pet::queryset()));
+        pets.create_table().await?;
+        Ok((owners, pets))
+    }
+
+    #[tokio::test]
+    async fn test_join() -> Result<(), InterfaceError> {
+        // GIVEN two owners, the pets of one of them, a stray pet and a deleted pet
+        let database = SqliteDatabase::open_in_memory()?;
+        let (owners, pets) = get_relation_repositories(&database).await?;
+        let alice = gen_owner("Alice");
+        let bob = gen_owner("Bob");
+        owners.create_many(&[alice.clone(), bob.clone()]).await?;
+        let rex = gen_pet(Some(&alice), "Rex");
+        let stray = gen_pet(None, "Stray");
+        let tom = gen_pet(Some(&alice), "Tom");
+        pets.create_many(&[rex.clone(), stray, tom.clone()]).await?;
+        let deleted = gen_pet(Some(&bob), "Old");
+        pets.create(&deleted).await?;
+        pets.delete(&deleted.uuid).await?;
+
+        // WHEN the pets are listed with their owner
+        let with_owner = pets
+            .list_with_parent(&Pet::owner(), &Filter::And(vec![]))
+            .await?;
+
+        // THEN only the pets with an owner are listed, ordered by key
+        let mut expected = vec![(rex.clone(), alice.clone()), (tom.clone(), alice.clone())];
+        expected.sort_by_key(|(pet, _)| pet.uuid);
+        assert_eq!(with_owner, expected);
+
+        // WHEN the owners are listed with their pets
+        let with_pets = owners
+            .list_with_children(&Owner::pets(), &Filter::And(vec![]))
+            .await?;
+
+        // THEN each owner is listed once, without the deleted pet
+        let mut pets_of_alice = vec![rex, tom];
+        pets_of_alice.sort_by_key(|pet| pet.uuid);
+        let mut expected = vec![(alice, pets_of_alice), (bob, vec![])];
+        expected.sort_by_key(|(owner, _)| owner.uuid);
+        assert_eq!(with_pets, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_foreign_key() -> Result<(), InterfaceError> {
+        // GIVEN an owner with a pet
+        let database = SqliteDatabase::open_in_memory()?;
+        let (owners, pets) = get_relation_repositories(&database).await?;
+        let alice = gen_owner("Alice");
+        owners.create(&alice).await?;
+        pets.create(&gen_pet(Some(&alice), "Rex")).await?;
+
+        // WHEN a pet of a missing owner is created
+        let result = pets.create(&gen_pet(Some(&gen_owner("Bob")), "Tom")).await;
+
+        // THEN it is rejected
+        assert!(matches!(result, Err(InterfaceError::Validation(_))));
+
+        // WHEN the owner of the pet is deleted
+        let result = owners.delete(&alice.uuid).await;
+
+        // THEN it is rejected
+        assert!(matches!(result, Err(InterfaceError::Validation(_))));
+        assert_eq!(owners.get(&alice.uuid).await?, Some(alice));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_get_and_delete() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an empty table
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = get_item1_repository(&database).await?;
+        let (item1, item2) = (gen_item(), gen_item());
+
+        // WHEN we create two entries and delete the first one
+        repo.create(&item1).await?;
+        repo.create(&item2).await?;
+        let created = repo.get(&item1.uuid).await?;
+        repo.delete(&item1.uuid).await?;
+
+        // THEN only the second one remains
+        assert_eq!(created, Some(item1.clone()));
+        assert_eq!(repo.get(&item1.uuid).await?, None);
+        assert_eq!(repo.list().await?, vec![item2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an entry
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = get_item1_repository(&database).await?;
+        let mut item = gen_item();
+        repo.create(&item).await?;
+
+        // WHEN we update it
+        item.field1 += 1;
+        item.name = "it's updated".to_string();
+        repo.update(&item).await?;
+
+        // THEN the modifications were applied
+        assert_eq!(repo.get(&item.uuid).await?, Some(item));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_where() -> Result<(), InterfaceError> {
+        // GIVEN a repository with items
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = get_item1_repository(&database).await?;
+        let items: Vec<Item1> = (0..5)
+            .map(|field1| Item1 {
+                field1,
+                ..gen_item()
+            })
+            .collect();
+        repo.create_many(&items).await?;
+
+        // WHEN we filter them
+        let filter = Filter::ge("field1", 3).or(Filter::eq("uuid", items[0].uuid));
+        let mut found = repo.list_where(&filter).await?;
+        let unknown = repo.list_where(&Filter::eq("unknown", 1)).await;
+
+        // THEN only the matching items are returned
+        found.sort_by_key(|item| item.field1);
+        let fields: Vec<i32> = found.iter().map(|item| item.field1).collect();
+        assert_eq!(fields, vec![0, 3, 4]);
+        assert!(matches!(unknown, Err(InterfaceError::InvalidQuery(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_page() -> Result<(), InterfaceError> {
+        // GIVEN a repository with items sharing some values
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = get_item1_repository(&database).await?;
+        let items: Vec<Item1> = (0..7)
+            .map(|i| Item1 {
+                field1: i / 2,
+                ..gen_item()
+            })
+            .collect();
+        repo.create_many(&items).await?;
+
+        // WHEN we read them three by three in descending order
+        let mut request = PageRequest::new("field1", 3).direction(Direction::Desc);
+        let mut pages = Vec::new();
+        loop {
+            let page = repo.list_page(&request).await?;
+            pages.push(page.items);
+            match page.next {
+                Some(cursor) => request = request.after(Some(cursor)),
+                None => break,
+            }
+        }
+
+        // THEN every item is read once, in order
+        let fields: Vec<i32> = pages.concat().iter().map(|item| item.field1).collect();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(fields, vec![3, 2, 2, 1, 1, 0, 0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_version() -> Result<(), InterfaceError> {
+        // GIVEN a versioned repository with an item
+        let database = SqliteDatabase::open_in_memory()?;
+        let queryset: Box<VersionedItemQuerySet<VersionedItem>> =
+            Box::new(VersionedItem::queryset());
+        let repo = SqliteRepository::new(database, queryset);
+        repo.create_table().await?;
+        let item = VersionedItem {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+            version: 0,
+        };
+        repo.create(&item).await?;
+
+        // WHEN two clients update the item they read
+        let first = repo.get(&item.uuid).await?.unwrap();
+        let second = repo.get(&item.uuid).await?.unwrap();
+        repo.update(&first).await?;
+        let result = repo.update(&second).await;
+
+        // THEN the second update is rejected and the version was incremented once
+        let stored = repo.get(&item.uuid).await?.unwrap();
+        assert!(matches!(result, Err(InterfaceError::Conflict(_))));
+        assert_eq!(stored.version, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_many_version() -> Result<(), InterfaceError> {
+        // GIVEN a versioned repository with two items
+        let database = SqliteDatabase::open_in_memory()?;
+        let queryset: Box<VersionedItemQuerySet<VersionedItem>> =
+            Box::new(VersionedItem::queryset());
+        let repo = SqliteRepository::new(database, queryset);
+        repo.create_table().await?;
+        let items: Vec<VersionedItem> = (0..2)
+            .map(|field1| VersionedItem {
+                uuid: Uuid::new_v4(),
+                field1,
+                version: 0,
+            })
+            .collect();
+        repo.create_many(&items).await?;
+
+        // WHEN a batch contains a stale item
+        let mut stale = items.clone();
+        stale[1].version = 5;
+        let result = repo.update_many(&stale).await;
+
+        // THEN none of the items are updated
+        assert!(matches!(result, Err(InterfaceError::Conflict(_))));
+        assert_eq!(repo.get(&items[0].uuid).await?.unwrap().version, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an empty table
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = get_item1_repository(&database).await?;
+        let (item1, item2, item3) = (gen_item(), gen_item(), gen_item());
+
+        // WHEN we create an item in a rolled back transaction, another one
+        // in a committed transaction and a last one in a dropped transaction
+        let transaction = SqliteTransaction::begin(&database).await?;
+        repo.in_transaction(&transaction).create(&item1).await?;
+        transaction.rollback().await?;
+
+        let transaction = SqliteTransaction::begin(&database).await?;
+        let outcome = repo.in_transaction(&transaction).create(&item2).await;
+        transaction.finish(outcome).await?;
+
+        let transaction = SqliteTransaction::begin(&database).await?;
+        repo.in_transaction(&transaction).create(&item3).await?;
+        drop(transaction);
+
+        // THEN only the committed item is in the table
+        // and a finished transaction cannot be used anymore
+        assert_eq!(repo.list().await?, vec![item2]);
+        let transaction = SqliteTransaction::begin(&database).await?;
+        let repo_in_transaction = repo.in_transaction(&transaction);
+        transaction.commit().await?;
+        assert!(repo_in_transaction.list().await.is_err());
+        assert!(transaction.commit().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete() -> Result<(), InterfaceError> {
+        // GIVEN a soft deleted item
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = get_audited_repository(&database).await?;
+        let item = AuditedItem {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+            version: 0,
+        };
+        repo.create(&item).await?;
+        repo.delete(&item.uuid).await?;
+
+        // WHEN we read it, then write it again
+        let created = repo.create(&item).await;
+        let updated = repo.update(&item).await;
+        let upserted = repo.upsert(&item).await;
+
+        // THEN it is hidden from the reads, its key cannot be reused,
+        // and its row is kept
+        assert_eq!(repo.get(&item.uuid).await?, None);
+        assert!(!repo.exists(&item.uuid).await?);
+        assert!(repo.list().await?.is_empty());
+        assert_eq!(repo.count(None).await?, 0);
+        assert!(matches!(created, Err(InterfaceError::Conflict(_))));
+        assert!(matches!(updated, Err(InterfaceError::MissingItem(_))));
+        assert!(matches!(upserted, Err(InterfaceError::Conflict(_))));
+        let rows: Vec<JsonValue> = repo
+            .with_connection(|connection| {
+                query(
+                    connection,
+                    r#"SELECT "deleted_at" FROM "audited_item""#,
+                    &[],
+                )
+            })
+            .await?;
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0]["deleted_at"].as_i64().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history() -> Result<(), InterfaceError> {
+        // GIVEN an audited repository
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = get_audited_repository(&database).await?;
+        let item = AuditedItem {
+            uuid: Uuid::new_v4(),
+            field1: 3,
+            version: 0,
+        };
+
+        // WHEN an item is created, updated, updated with a stale version,
+        // updated in a rolled back transaction, then deleted twice
+        repo.create(&item).await?;
+        repo.update(&AuditedItem { field1: 4, ..item }).await?;
+        let stale = repo.update(&AuditedItem { field1: 5, ..item }).await;
+        let stored = repo.get(&item.uuid).await?.unwrap();
+        let transaction = SqliteTransaction::begin(&database).await?;
+        repo.in_transaction(&transaction).update(&stored).await?;
+        transaction.rollback().await?;
+        repo.delete(&item.uuid).await?;
+        repo.delete(&item.uuid).await?;
+
+        // THEN the applied mutations are recorded with the images of the item
+        assert!(matches!(stale, Err(InterfaceError::Conflict(_))));
+        let history = repo.history(&item.uuid).await?;
+        let operations: Vec<(i64, Mutation)> = history
+            .iter()
+            .map(|change| (change.revision, change.operation))
+            .collect();
+        assert_eq!(
+            operations,
+            vec![
+                (1, Mutation::Create),
+                (2, Mutation::Update),
+                (3, Mutation::Delete)
+            ]
+        );
+        assert_eq!(history[0].before, None);
+        assert_eq!(history[0].after, Some(item.clone()));
+        let updated = AuditedItem {
+            field1: 4,
+            version: 1,
+            ..item
+        };
+        assert_eq!(history[1].after, Some(updated.clone()));
+        assert_eq!(history[2].before, Some(updated));
+        assert_eq!(history[2].after, None);
+        assert!(history.iter().all(|change| change.actor == "teller"));
+
+        // and the history of a repository that is not audited cannot be read
+        let unaudited = get_item1_repository(&database).await?;
+        let result = unaudited.history(&item.uuid).await;
+        assert!(matches!(result, Err(InterfaceError::InvalidQuery(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_types() -> Result<(), InterfaceError> {
+        // GIVEN a repository of items with all the supported types
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = SqliteRepository::new(database.clone(), Box::new(TypedItem::queryset()));
+        repo.create_table().await?;
+        let item = TypedItem {
+            uuid: Uuid::new_v4(),
+            active: true,
+            amount: 1 << 40,
+            rate: 0.25,
+            balance: "12.50".parse().unwrap(),
+            opened_on: "2024-01-02".parse().unwrap(),
+            opened_at: "2024-01-02T03:04:05.250Z".parse().unwrap(),
+            closed_at: None,
+            logo: vec![0, 255],
+            settings: serde_json::json!({"theme": "dark", "limits": [1, 2]}),
+            status: Status::Blocked,
+        };
+
+        // WHEN we create it, then filter on its boolean and binary fields
+        repo.create(&item).await?;
+        let active = repo.list_where(&Filter::eq("active", true)).await?;
+        let blocked = repo
+            .list_where(&Filter::eq("status", "Blocked").and(Filter::eq("logo", vec![0u8, 255])))
+            .await?;
+
+        // THEN it is read back unchanged
+        assert_eq!(repo.get(&item.uuid).await?, Some(item.clone()));
+        assert_eq!(active, vec![item.clone()]);
+        assert_eq!(blocked, vec![item]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch() -> Result<(), InterfaceError> {
+        // GIVEN a repository with an empty table
+        let database = SqliteDatabase::open_in_memory()?;
+        let repo = get_item1_repository(&database).await?;
+        let mut items: Vec<Item1> = (0..10).map(|_| gen_item()).collect();
+
+        // WHEN we create, update and delete the items in batches
+        repo.create_many(&items).await?;
+        items.iter_mut().for_each(|item| item.field1 = 7);
+        repo.update_many(&items).await?;
+        let keys: Vec<Uuid> = items.iter().take(4).map(|item| item.uuid).collect();
+        repo.delete_many(&keys).await?;
+
+        // THEN the remaining items were all updated
+        let all = repo.list_where(&Filter::eq("field1", 7)).await?;
+        assert_eq!(all.len(), 6);
+        Ok(())
+    }
+
+    #[async_trait]
+    impl RepositoryFactory for SqliteDatabase {
+        type Repo = SqliteRepository<ConformanceItem, ConformanceItemQuerySet<ConformanceItem>>;
+        type VersionedRepo = SqliteRepository<
+            VersionedConformanceItem,
+            VersionedConformanceItemQuerySet<VersionedConformanceItem>,
+        >;
+
+        async fn repository(&self) -> Result<Self::Repo, InterfaceError> {
+            let repo = SqliteRepository::new(self.clone(), Box::new(ConformanceItem::queryset()));
+            repo.drop_table().await?;
+            repo.create_table().await?;
+            Ok(repo)
+        }
+
+        async fn versioned_repository(&self) -> Result<Self::VersionedRepo, InterfaceError> {
+            let queryset = Box::new(VersionedConformanceItem::queryset());
+            let repo = SqliteRepository::new(self.clone(), queryset);
+            repo.drop_table().await?;
+            repo.create_table().await?;
+            Ok(repo)
+        }
+    }
+
+    crate::repository_conformance_tests!(SqliteDatabase::open_in_memory()?);
+}